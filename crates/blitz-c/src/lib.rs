@@ -37,6 +37,79 @@
 //! - **Optimized mode**: tracks stack depth statically; generates direct indices
 //!   (`stack[N]`) with no runtime counter
 //!
+//! The operand stack holds as many values as the function's
+//! [`FnData::max_stack`](portal_solutions_blitz_common::ops::FnData::max_stack)
+//! bound, plus the unused slot 0 in optimized mode, or `WASM_STACK_SIZE`
+//! values where the bound is unknown.
+//!
+//! # Traps
+//!
+//! Traps call `abort()` by default; set [`State::trap_handler`] to call a
+//! handler of your own instead. Division and remainder trap on a zero
+//! divisor, and signed division on overflow.
+//!
+//! # Integers
+//!
+//! `clz`, `ctz` and `popcnt` use the `__builtin_clz` family, so the output
+//! needs GCC or Clang.
+//!
+//! # Floats
+//!
+//! Floats are kept as their bits. [`CWrite::floats`] emits the helpers that
+//! convert them and includes `<math.h>`, so programs must be linked with the
+//! math library. Conversions to integers trap on NaN and out-of-range
+//! values, except the saturating ones, and NaN results are canonicalised
+//! when [`State::nans`] asks for it.
+//!
+//! # Linear memory
+//!
+//! Memory `N` is a `struct blitz_mem blitz_mem_N` holding its data pointer
+//! and length in bytes. Defined memories are declared by
+//! [`CWrite::memories`] as zeroed static buffers of their initial size;
+//! imported ones are declared `extern` for the embedder to define. Every
+//! access is bounds-checked and traps when out of bounds. Values are
+//! copied with `memcpy`, so the host must be little-endian. `memory.grow`
+//! moves a defined memory to a larger buffer from `calloc`; imported
+//! memories never grow.
+//!
+//! # Globals
+//!
+//! Global `N` is a `uint64_t blitz_global_N`. Defined globals are declared
+//! by [`CWrite::globals`] as statics holding their initial value; imported
+//! ones are declared `extern` for the embedder to define.
+//!
+//! # Tables
+//!
+//! A reference is the address of a `struct blitz_func` describing the
+//! function, or `0` for null. Table `N` is a `struct blitz_table
+//! blitz_table_N` holding its slots; element segment `N` is an array
+//! `blitz_elem_N`. Both are declared by [`CWrite::tables`] and
+//! [`CWrite::elems`]; imported tables are declared `extern` and never grow.
+//! `call_indirect` traps on null or out-of-bounds slots and on signature
+//! mismatches.
+//!
+//! # Instantiation
+//!
+//! Data segment `N` is an array `blitz_data_N` declared by
+//! [`CWrite::data`]. [`CWrite::init`] emits `void blitz_init(void)`, which
+//! copies the active element and data segments into their tables and
+//! memories; the embedder must call it before any other function.
+//!
+//! # Imports
+//!
+//! [`CWrite::imports`] defines `fn_N` for imported function `N` as set by
+//! [`State::imports`]: by default, as a call of the host function named
+//! like the import, which takes and returns values like `fn_N` does.
+//!
+//! # Exports
+//!
+//! [`CWrite::exports`] ends the generated code with an external definition
+//! of every export under its
+//! [`Symbol`](portal_solutions_blitz_common::export::Symbol): a wrapper around `fn_N` for a
+//! function, and a constant pointer to its descriptor or value for a table,
+//! memory or global. [`CWrite::header`] writes the matching header for host
+//! programs. `blitz_init` calls the start function last.
+//!
 //! # Bugs fixed relative to the JS backend
 //!
 //! - `LocalSet`/`LocalTee`: missing `]` before `=` in array index expression
@@ -60,11 +133,21 @@ pub mod __ {
     pub use portal_solutions_blitz_common::DisplayFn;
 }
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 use portal_solutions_blitz_common::{
-    DisplayFn,
-    ops::MachOperator,
-    wasm_encoder::{BlockType, FuncType, Instruction, reencode::Reencode},
+    CompileError, DisplayFn,
+    backend::{Backend, BackendContext, ForkBackend},
+    export::{Exported, Symbol},
+    float::{CANONICAL_NAN_F32, CANONICAL_NAN_F64, FloatOp, Nans},
+    global::{self, ConstValue, Global},
+    import::{Binding, Imports},
+    memory::{self, Access, DataMode, DataSegment, LinearMemory},
+    module::Module,
+    ops::{MachOperator, ToWasmInfo},
+    table::{self, ElemMode, ElemSegment, Table},
+    typed::BlockArity,
+    wasm_encoder::{BlockType, FuncType, Instruction, MemArg, reencode::Reencode},
+    wasmparser::ValType,
 };
 use portal_solutions_blitz_opt::{self as blitz_opt, OptCodegen, OptState};
 use spin::Mutex;
@@ -110,6 +193,26 @@ impl OptCodegen for CCodegen {
     }
 }
 
+// ---------------------------------------------------------------------------
+// Descriptors
+// ---------------------------------------------------------------------------
+
+/// Definition of the descriptor of a linear memory.
+const MEM_STRUCT: &str = "struct blitz_mem{uint8_t*data;uint64_t len;};";
+
+/// Definition of the descriptor of a table.
+const TABLE_STRUCT: &str = "struct blitz_table{uint64_t*data;uint64_t len;uint64_t max;};";
+
+/// Qualifier of the `uint64_t` holding `global`: `const` for immutable
+/// globals the module defines.
+fn global_qual(global: &Global) -> &'static str {
+    if global.mutable || global.init.is_none() {
+        ""
+    } else {
+        "const "
+    }
+}
+
 // ---------------------------------------------------------------------------
 // push / pop helpers
 // ---------------------------------------------------------------------------
@@ -149,6 +252,9 @@ pub use portal_solutions_blitz_opt::pop_display;
 #[non_exhaustive]
 pub struct State {
     stack: Vec<Frame>,
+    /// Number of frames opened so far in the function, which numbers their
+    /// labels.
+    labels: usize,
     opt_state: OnceCell<Mutex<OptState>>,
 
     /// Number of parameters for the function currently being compiled.
@@ -159,6 +265,22 @@ pub struct State {
     pub fn_id: u32,
     /// Running count of non-parameter local variables accumulated so far.
     pub local_count: usize,
+    /// Maximum control-frame nesting depth of the function currently being
+    /// compiled.
+    pub control_depth: usize,
+    /// Maximum operand-stack height of the function currently being
+    /// compiled, or `None` to size its stack with `WASM_STACK_SIZE`.
+    pub max_stack: Option<usize>,
+    /// Name of the C function called when a trap is raised, or `None` to
+    /// call `abort()`.
+    ///
+    /// The handler takes no arguments, must be declared before the generated
+    /// code and must not return.
+    pub trap_handler: Option<String>,
+    /// What imported functions are bound to.
+    pub imports: Imports,
+    /// What NaNs produced by float arithmetic look like.
+    pub nans: Nans,
 }
 
 impl State {
@@ -170,18 +292,67 @@ impl State {
     fn opt(&self) -> Option<&Mutex<OptState>> {
         self.opt_state.get()
     }
+
+    /// Checks that a branch `depth` targets an enclosing frame or the
+    /// function body itself.
+    fn check_depth(&self, depth: u32) -> Result<(), CompileError> {
+        if depth as usize <= self.stack.len() {
+            Ok(())
+        } else {
+            Err(CompileError::malformed())
+        }
+    }
+
+    /// Open a control frame of type `blockty` whose parameters lie below
+    /// `popped` values consumed by the instruction itself, returning its
+    /// label index.
+    fn push_frame(
+        &mut self,
+        kind: FrameKind,
+        blockty: &BlockType,
+        sigs: &[FuncType],
+        popped: usize,
+    ) -> Result<usize, CompileError> {
+        let arity = BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+        let base = match self.opt() {
+            Some(o) => o
+                .lock()
+                .depth
+                .checked_sub(popped + arity.params)
+                .ok_or_else(CompileError::malformed)?,
+            None => 0,
+        };
+        self.labels += 1;
+        self.stack.push(Frame {
+            kind,
+            arity,
+            base,
+            label: self.labels,
+        });
+        Ok(self.stack.len())
+    }
 }
 
 // ---------------------------------------------------------------------------
 // Frame
 // ---------------------------------------------------------------------------
 
-enum Frame {
-    Block(BlockType),
-    Loop(BlockType),
+struct Frame {
+    kind: FrameKind,
+    arity: BlockArity,
+    /// Stack depth below the block's parameters, in optimised mode.
+    base: usize,
+    /// Number of the frame's labels, unique within the function.
+    label: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    Block,
+    Loop,
     /// `If` is like `Block` for branching purposes: `br N` that targets an `if`
     /// frame is a forward exit out of the if/else body.
-    If(BlockType),
+    If,
 }
 
 // ---------------------------------------------------------------------------
@@ -198,9 +369,6 @@ pub trait CWrite: Write {
     // ------------------------------------------------------------------
 
     /// Emit a C function call, including runtime signature validation.
-    ///
-    /// **Bug fix vs JS backend**: the JS opt-mode argument/result loops used
-    /// `s..od` which is off by one (items are 1-indexed at `s+1..=od`).
     // TODO: Remove the Sized bound once push/pop can work with ?Sized types
     fn call(
         &mut self,
@@ -217,7 +385,24 @@ pub trait CWrite: Write {
             sig.params().len(),
             sig.results().len()
         )?;
+        self.invoke(state, sig, &format_args!("fn_{function_index}"))
+    }
 
+    /// Emit a call of `callee`, a function pointer, passing the arguments
+    /// of `sig` from the stack and pushing its results.
+    ///
+    /// **Bug fix vs JS backend**: the JS opt-mode argument/result loops used
+    /// `s..od` which is off by one (items are 1-indexed at `s+1..=od`).
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn invoke(
+        &mut self,
+        state: &mut State,
+        sig: &FuncType,
+        callee: &(dyn Display + '_),
+    ) -> core::fmt::Result
+    where
+        Self: Sized,
+    {
         if let Some(opt) = state.opt() {
             let mut o = opt.lock();
             // s = index of element just *below* the first argument (1-based stack).
@@ -228,7 +413,7 @@ pub trait CWrite: Write {
             o.depth += sig.results().len();
 
             // BUG FIX: pass stack+s+1 so callee's locals[0] == stack[s+1]
-            write!(self, "tmp_locals=fn_{function_index}(stack+{});", s + 1)?;
+            write!(self, "tmp_locals={callee}(stack+{});", s + 1)?;
 
             // BUG FIX: results start at s2+1, not s2
             for i in 0..sig.results().len() {
@@ -239,62 +424,856 @@ pub trait CWrite: Write {
             let m = sig.results().len();
             write!(
                 self,
-                "{{uint64_t*_ca=stack+sp-{n};sp-={n};tmp_locals=fn_{function_index}(_ca);memcpy(stack+sp,tmp_locals,{m}*sizeof(uint64_t));sp+={m};}}"
-            )?;
-        }
+                "{{uint64_t*_ca=stack+sp-{n};sp-={n};tmp_locals={callee}(_ca);memcpy(stack+sp,tmp_locals,{m}*sizeof(uint64_t));sp+={m};}}"
+            )?;
+        }
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------
+    // ret()
+    // ------------------------------------------------------------------
+
+    /// Emit a return of the top `ret_count` stack values.
+    fn ret(&mut self, state: &State) -> core::fmt::Result {
+        let id = state.fn_id;
+        let rets = state.ret_count;
+        if let Some(opt) = state.opt() {
+            // In opt mode the stack items are 1-indexed; top `rets` items
+            // start at stack[depth - rets + 1].
+            let depth = opt.lock().depth;
+            let start = depth.saturating_sub(rets) + 1;
+            write!(
+                self,
+                "memcpy(__rets_{id},stack+{start},{rets}*sizeof(uint64_t));return __rets_{id};"
+            )
+        } else {
+            write!(
+                self,
+                "memcpy(__rets_{id},stack+sp-{rets},{rets}*sizeof(uint64_t));return __rets_{id};"
+            )
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // trap()
+    // ------------------------------------------------------------------
+
+    /// Emit a call to the trap handler configured in `state`.
+    fn trap(&mut self, state: &State) -> core::fmt::Result {
+        match &state.trap_handler {
+            Some(handler) => write!(self, "{handler}()"),
+            None => write!(self, "abort()"),
+        }
+    }
+
+    /// Emit a trap for a division or remainder by a zero `tmp`, and, given
+    /// the minimum of a signed division, for dividing it in `tmp2` by -1.
+    ///
+    /// `ty` is the unsigned C type of the operands.
+    fn div_guard(&mut self, state: &State, ty: &str, min: Option<&str>) -> core::fmt::Result {
+        write!(self, "if(({ty})tmp==0){{")?;
+        self.trap(state)?;
+        write!(self, ";}}")?;
+        if let Some(min) = min {
+            write!(self, "if(({ty})tmp==({ty})-1&&({ty})tmp2=={min}){{")?;
+            self.trap(state)?;
+            write!(self, ";}}")?;
+        }
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------
+    // br()
+    // ------------------------------------------------------------------
+
+    /// Emit a C `goto` for a branch instruction targeting a Block or Loop frame.
+    ///
+    /// **Bug fix vs JS backend**: the JS opt-mode Loop path emitted `break l{n}`
+    /// instead of `continue l{n}`. For C we use `goto` throughout, so there is
+    /// no such ambiguity.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn br(&mut self, _sigs: &[FuncType], state: &State, relative_depth: u32) -> core::fmt::Result
+    where
+        Self: Sized,
+    {
+        let Some((enum_idx, frame)) = state.stack.iter().enumerate().rev().nth(relative_depth as usize)
+        else {
+            // A branch out of the function body is a return.
+            return self.ret(state);
+        };
+
+        // Height index: same convention as JS (`enumerate` is 0-based, depths are 1-based).
+        let depth = enum_idx + 1;
+        let label = frame.label;
+
+        // Move the values the label takes down to the height the frame was
+        // entered at, dropping everything in between.
+        let is_loop = frame.kind == FrameKind::Loop;
+        let carried = frame.arity.label(is_loop);
+        if let Some(o) = state.opt() {
+            // Opt-mode stack items are 1-indexed.
+            let depth = o.lock().depth;
+            write!(
+                self,
+                "memmove(stack+{},stack+{},{carried}*sizeof(uint64_t));",
+                frame.base + 1,
+                depth - carried + 1
+            )?;
+        } else {
+            write!(
+                self,
+                "memmove(stack+heights[{depth}],stack+sp-{carried},{carried}*sizeof(uint64_t));sp=heights[{depth}]+{carried};"
+            )?;
+        }
+
+        if is_loop {
+            // BUG FIX vs JS: Loop branch is a *back*-edge (continue), not a break.
+            write!(self, "goto lp_s_{label};")
+        } else {
+            // Branch to a Block or If = forward jump to its exit label.
+            write!(self, "goto blk_e_{label};")
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // memories() / globals() / funcs() / tables() / elems() / data() /
+    // init() / exports() / header() / address()
+    // ------------------------------------------------------------------
+
+    /// Emit the helpers float operations are lowered with.
+    ///
+    /// `blitz_f32` and `blitz_f64` read a float from the bits of a value,
+    /// `blitz_bits_f32` and `blitz_bits_f64` write it back, and
+    /// `blitz_canon_f32` and `blitz_canon_f64` do so replacing NaNs with the
+    /// canonical NaN.
+    fn floats(&mut self) -> core::fmt::Result {
+        write!(
+            self,
+            "#include <math.h>\n\
+            static inline float blitz_f32(uint64_t v){{uint32_t b=(uint32_t)v;float f;memcpy(&f,&b,4);return f;}}\
+            static inline double blitz_f64(uint64_t v){{double f;memcpy(&f,&v,8);return f;}}\
+            static inline uint64_t blitz_bits_f32(float f){{uint32_t b;memcpy(&b,&f,4);return b;}}\
+            static inline uint64_t blitz_bits_f64(double f){{uint64_t b;memcpy(&b,&f,8);return b;}}\
+            static inline uint64_t blitz_canon_f32(float f){{return f!=f?{CANONICAL_NAN_F32:#x}ull:blitz_bits_f32(f);}}\
+            static inline uint64_t blitz_canon_f64(double f){{return f!=f?{CANONICAL_NAN_F64:#x}ull:blitz_bits_f64(f);}}\
+            "
+        )?;
+        // `min` and `max` propagate NaN and order -0 below 0: when the
+        // operands compare equal, or-ing or and-ing their bits picks the
+        // right zero.
+        for (ty, w) in [("float", "f32"), ("double", "f64")] {
+            write!(
+                self,
+                "static inline {ty} blitz_min_{w}({ty} a,{ty} b){{return a!=a||b!=b?a+b:a==b?blitz_{w}(blitz_bits_{w}(a)|blitz_bits_{w}(b)):a<b?a:b;}}\
+                static inline {ty} blitz_max_{w}({ty} a,{ty} b){{return a!=a||b!=b?a+b:a==b?blitz_{w}(blitz_bits_{w}(a)&blitz_bits_{w}(b)):a>b?a:b;}}"
+            )?;
+        }
+        writeln!(self)
+    }
+
+    /// Emit the declarations of every linear memory.
+    ///
+    /// Defined memories get a zeroed static buffer of their initial size;
+    /// imported ones are declared `extern`.
+    fn memories(&mut self, memories: &[LinearMemory]) -> Result<(), CompileError> {
+        if memories.is_empty() {
+            return Ok(());
+        }
+        write!(self, "{MEM_STRUCT}")?;
+        for mem in memories {
+            let i = mem.index;
+            if mem.imported {
+                write!(self, "extern struct blitz_mem blitz_mem_{i};")?;
+                continue;
+            }
+            let bytes = mem
+                .min_bytes()
+                .ok_or_else(|| CompileError::feature("memories larger than the address space"))?;
+            write!(
+                self,
+                "static uint8_t blitz_mem_{i}_init[{buf_sz}];static struct blitz_mem blitz_mem_{i}={{blitz_mem_{i}_init,{bytes}ull}};",
+                buf_sz = bytes.max(1),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Emit the declarations of every global.
+    ///
+    /// Global `N` is a `uint64_t blitz_global_N` holding the value as it
+    /// would be on the stack. Defined globals are statics initialised with
+    /// their constant value, `const` when immutable; imported ones are
+    /// declared `extern`.
+    fn globals(&mut self, globals: &[Global]) -> Result<(), CompileError> {
+        for global in globals {
+            let i = global.index;
+            let Some(init) = global.init else {
+                write!(self, "extern uint64_t blitz_global_{i};")?;
+                continue;
+            };
+            let value = match init {
+                ConstValue::I32(v) => v as u32 as u64,
+                ConstValue::I64(v) => v as u64,
+                ConstValue::F32(bits) => bits as u64,
+                ConstValue::F64(bits) => bits,
+                ConstValue::RefNull | ConstValue::RefFunc(_) => {
+                    return Err(CompileError::feature("reference-typed globals"));
+                }
+                ConstValue::GlobalGet(_) => {
+                    return Err(CompileError::feature("globals initialised from globals"));
+                }
+            };
+            let qual = global_qual(global);
+            write!(self, "static {qual}uint64_t blitz_global_{i}={value}ull;")?;
+        }
+        Ok(())
+    }
+
+    /// Emit a function descriptor for every function, so that tables can
+    /// hold references to them.
+    ///
+    /// Function `N` is described by a `struct blitz_func blitz_func_N`
+    /// holding its parameter and result counts and a pointer to `fn_N`.
+    fn funcs(&mut self, module: &Module<'_>) -> Result<(), CompileError> {
+        write!(
+            self,
+            "struct blitz_func{{int params;int rets;uint64_t*(*fn)(uint64_t*restrict);}};"
+        )?;
+        for id in 0..module.funcs.len() as u32 {
+            let ty = module.func_type(id);
+            write!(
+                self,
+                "static uint64_t*fn_{id}(uint64_t*restrict);static const struct blitz_func blitz_func_{id}={{{},{},fn_{id}}};",
+                ty.params().len(),
+                ty.results().len()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Emit a definition of every imported function of `module`, as bound
+    /// by `imports`.
+    ///
+    /// Import `N` is a `fn_N` with a signature like the module's own
+    /// functions. A host function is declared `extern` under its
+    /// [`Symbol`](portal_solutions_blitz_common::export::Symbol) and called
+    /// like `fn_N`; an unbound import traps.
+    fn imports(
+        &mut self,
+        state: &State,
+        module: &Module<'_>,
+        imports: &Imports,
+    ) -> Result<(), CompileError> {
+        for import in module.func_import_items() {
+            let i = import.index;
+            let ty = module.func_type(i);
+            write!(
+                self,
+                "static const struct{{int params;int rets;}}__sig_{i}={{.params={},.rets={}}};",
+                ty.params().len(),
+                ty.results().len()
+            )?;
+            match imports.bind(&import) {
+                Binding::Host { name, .. } => {
+                    let sym = Symbol(&name);
+                    write!(
+                        self,
+                        "uint64_t*{sym}(uint64_t*restrict);static uint64_t*fn_{i}(uint64_t*restrict args){{return {sym}(args);}}"
+                    )?;
+                }
+                _ => {
+                    write!(self, "static uint64_t*fn_{i}(uint64_t*restrict args){{(void)args;")?;
+                    self.trap(state)?;
+                    write!(self, ";return 0;}}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the declarations of every table.
+    ///
+    /// Table `N` is a `struct blitz_table blitz_table_N` holding its slots,
+    /// its length and the length it may grow to. Defined tables start out
+    /// with a static buffer of null references; imported ones are declared
+    /// `extern`.
+    fn tables(&mut self, tables: &[Table]) -> Result<(), CompileError> {
+        write!(self, "{TABLE_STRUCT}")?;
+        for table in tables {
+            let i = table.index;
+            match table.init {
+                None => write!(self, "extern struct blitz_table blitz_table_{i};")?,
+                Some(ConstValue::RefNull) => write!(
+                    self,
+                    "static uint64_t blitz_table_{i}_init[{buf_sz}];static struct blitz_table blitz_table_{i}={{blitz_table_{i}_init,{min}ull,{max}ull}};",
+                    buf_sz = table.min.max(1),
+                    min = table.min,
+                    // Capped so that the size of a grown buffer cannot overflow.
+                    max = table.max_len().min(u32::MAX as u64),
+                )?,
+                Some(_) => {
+                    return Err(CompileError::feature("tables initialised with references"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the items of every element segment.
+    ///
+    /// Segment `N` is a `const uint64_t blitz_elem_N[]` of references, with
+    /// `blitz_elem_N_len` items still available to `table.init`.
+    fn elems(&mut self, elems: &[ElemSegment]) -> Result<(), CompileError> {
+        for elem in elems {
+            let i = elem.index;
+            let items = &elem.items;
+            write!(
+                self,
+                "static const uint64_t blitz_elem_{i}[{}]={{",
+                items.len().max(1)
+            )?;
+            if items.is_empty() {
+                write!(self, "0")?;
+            }
+            for item in items {
+                self.func_ref(*item)?;
+                write!(self, ",")?;
+            }
+            write!(
+                self,
+                "}};static uint64_t blitz_elem_{i}_len={};",
+                elem.live_items().len()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Emit the bytes of every data segment.
+    ///
+    /// Segment `N` is a `const uint8_t blitz_data_N[]`, with
+    /// `blitz_data_N_len` bytes still available to `memory.init`.
+    fn data(&mut self, data: &[DataSegment<'_>]) -> Result<(), CompileError> {
+        for segment in data {
+            let i = segment.index;
+            write!(
+                self,
+                "static const uint8_t blitz_data_{i}[{}]={{",
+                segment.bytes.len().max(1)
+            )?;
+            if segment.bytes.is_empty() {
+                write!(self, "0")?;
+            }
+            for byte in segment.bytes {
+                write!(self, "{byte},")?;
+            }
+            write!(
+                self,
+                "}};static uint64_t blitz_data_{i}_len={};",
+                segment.live_bytes().len()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Emit `blitz_init`, which copies the active element and data segments
+    /// into their tables and memories, in that order, trapping when a
+    /// segment does not fit, then calls the start function `start`. The
+    /// embedder must call it first.
+    fn init(
+        &mut self,
+        state: &State,
+        memories: &[LinearMemory],
+        tables: &[Table],
+        elems: &[ElemSegment],
+        data: &[DataSegment<'_>],
+        start: Option<u32>,
+    ) -> Result<(), CompileError> {
+        if let Some(start) = start {
+            write!(self, "static uint64_t*fn_{start}(uint64_t*restrict);")?;
+        }
+        write!(self, "void blitz_init(void){{uint64_t tmp=0;")?;
+        for elem in elems {
+            let ElemMode::Active { table, offset } = elem.mode else {
+                continue;
+            };
+            let t = tables
+                .get(table as usize)
+                .ok_or_else(|| CompileError::unknown("table", table))?;
+            self.segment_copy(
+                state,
+                &format_args!("blitz_table_{table}"),
+                offset,
+                t.table64,
+                &format_args!("blitz_elem_{}", elem.index),
+                elem.items.len(),
+                "sizeof(uint64_t)",
+            )?;
+        }
+        for segment in data {
+            let DataMode::Active { memory, offset } = segment.mode else {
+                continue;
+            };
+            let mem = memory::lookup(memories, memory)?;
+            self.segment_copy(
+                state,
+                &format_args!("blitz_mem_{memory}"),
+                offset,
+                mem.memory64,
+                &format_args!("blitz_data_{}", segment.index),
+                segment.bytes.len(),
+                "1",
+            )?;
+        }
+        if let Some(start) = start {
+            write!(self, "{{uint64_t _a[1]={{0}};fn_{start}(_a);}}")?;
+        }
+        write!(self, "}}")?;
+        Ok(())
+    }
+
+    /// Emit an external definition of every export, under its
+    /// [`Symbol`](portal_solutions_blitz_common::export::Symbol).
+    ///
+    /// Functions are wrappers around `fn_N`; memories, tables and globals
+    /// are constant pointers to `blitz_mem_N`, `blitz_table_N` and
+    /// `blitz_global_N`.
+    fn exports(&mut self, module: &Module<'_>) -> Result<(), CompileError> {
+        for export in module.export_items() {
+            let export = export?;
+            let sym = export.symbol();
+            match export.item {
+                Exported::Func(i) => write!(
+                    self,
+                    "uint64_t*{sym}(uint64_t*restrict args){{return fn_{i}(args);}}"
+                )?,
+                Exported::Table(i) => {
+                    write!(self, "struct blitz_table*const {sym}=&blitz_table_{i};")?
+                }
+                Exported::Memory(i) => {
+                    write!(self, "struct blitz_mem*const {sym}=&blitz_mem_{i};")?
+                }
+                Exported::Global(i) => write!(
+                    self,
+                    "{}uint64_t*const {sym}=&blitz_global_{i};",
+                    global_qual(&module.global(i)?)
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a C header declaring `blitz_init` and every export of `module`
+    /// as defined by [`CWrite::exports`], for host programs to include.
+    fn header(&mut self, module: &Module<'_>) -> Result<(), CompileError> {
+        write!(self, "#include<stdint.h>\n{MEM_STRUCT}{TABLE_STRUCT}")?;
+        write!(self, "void blitz_init(void);")?;
+        for export in module.export_items() {
+            let export = export?;
+            let sym = export.symbol();
+            match export.item {
+                Exported::Func(_) => write!(self, "uint64_t*{sym}(uint64_t*args);")?,
+                Exported::Table(_) => write!(self, "extern struct blitz_table*const {sym};")?,
+                Exported::Memory(_) => write!(self, "extern struct blitz_mem*const {sym};")?,
+                Exported::Global(i) => write!(
+                    self,
+                    "extern {}uint64_t*const {sym};",
+                    global_qual(&module.global(i)?)
+                )?,
+            }
+        }
+        writeln!(self)?;
+        Ok(())
+    }
+
+    /// Emit code copying the `len` items of `src`, each `size` bytes, into
+    /// the table or memory described by `dst` at `offset`, trapping when
+    /// they do not fit.
+    #[allow(clippy::too_many_arguments)]
+    fn segment_copy(
+        &mut self,
+        state: &State,
+        dst: &(dyn Display + '_),
+        offset: ConstValue,
+        wide: bool,
+        src: &(dyn Display + '_),
+        len: usize,
+        size: &str,
+    ) -> Result<(), CompileError> {
+        let cast = if wide { "" } else { "(uint32_t)" };
+        match offset {
+            ConstValue::I32(v) => write!(self, "tmp={}ull;", v as u32)?,
+            ConstValue::I64(v) => write!(self, "tmp={}ull;", v as u64)?,
+            ConstValue::GlobalGet(g) => write!(self, "tmp=(uint64_t){cast}blitz_global_{g};")?,
+            _ => return Err(CompileError::malformed()),
+        }
+        write!(self, "if(tmp>{dst}.len||{len}ull>{dst}.len-tmp){{")?;
+        self.trap(state)?;
+        write!(self, ";}}memcpy({dst}.data+tmp,{src},{len}*{size});")?;
+        Ok(())
+    }
+
+    /// Emit a reference as it is held on the stack: the address of the
+    /// function's descriptor, or `0` for null.
+    fn func_ref(&mut self, value: ConstValue) -> Result<(), CompileError> {
+        match value {
+            ConstValue::RefNull => write!(self, "0")?,
+            ConstValue::RefFunc(f) => write!(self, "(uint64_t)(uintptr_t)&blitz_func_{f}")?,
+            _ => return Err(CompileError::malformed()),
+        }
+        Ok(())
+    }
+
+    /// Emit code popping an index into `table` and truncating it to the
+    /// table's index width.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn table_index(&mut self, table: &Table, state: &State, var: &str) -> core::fmt::Result
+    where
+        Self: Sized,
+    {
+        let cast = if table.table64 { "" } else { "(uint32_t)" };
+        write!(self, "{var}=(uint64_t){cast}{};", pop!(state))
+    }
+
+    /// Emit code copying `tmp` references from `src` at `tmp2` into table
+    /// `dst` at the popped index, trapping when either range is out of
+    /// bounds.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn table_copy(
+        &mut self,
+        state: &State,
+        dst: &Table,
+        src: &(dyn Display + '_),
+        src_len: &(dyn Display + '_),
+    ) -> core::fmt::Result
+    where
+        Self: Sized,
+    {
+        let d = dst.index;
+        write!(self, "{{uint64_t _d;")?;
+        self.table_index(dst, state, "_d")?;
+        write!(
+            self,
+            "if(tmp2>{src_len}||tmp>{src_len}-tmp2||_d>blitz_table_{d}.len||tmp>blitz_table_{d}.len-_d){{"
+        )?;
+        self.trap(state)?;
+        write!(
+            self,
+            ";}}memmove(blitz_table_{d}.data+_d,{src}+tmp2,tmp*sizeof(uint64_t));}}"
+        )
+    }
+
+    /// Emit code computing the offset into memory of an access of `size`
+    /// bytes into `tmp2`, trapping when it is out of bounds.
+    ///
+    /// Pops the address, which is truncated to 32 bits for 32-bit memories.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn address(
+        &mut self,
+        memories: &[LinearMemory],
+        state: &State,
+        memarg: &MemArg,
+        size: u64,
+    ) -> Result<(), CompileError>
+    where
+        Self: Sized,
+    {
+        let mem = memory::lookup(memories, memarg.memory_index)?;
+        let i = mem.index;
+        let cast = if mem.memory64 { "" } else { "(uint32_t)" };
+        write!(self, "tmp2=(uint64_t){cast}{};", pop!(state))?;
+        match mem.access_end(memarg.offset, size) {
+            Some(end) => {
+                write!(
+                    self,
+                    "if(tmp2>blitz_mem_{i}.len||blitz_mem_{i}.len-tmp2<{end}ull){{"
+                )?;
+                self.trap(state)?;
+                write!(self, ";}}")?;
+            }
+            // No address can bring the access in bounds.
+            None => {
+                self.trap(state)?;
+                write!(self, ";")?;
+            }
+        }
+        write!(self, "tmp2+={}ull;", memarg.offset)?;
+        Ok(())
+    }
+
+    /// Emit code for the load or store `access`, copying its bytes with
+    /// `memcpy`.
+    ///
+    /// Narrow loads are zero-extended, then sign-extended through a signed
+    /// type of their width when signed; narrow stores keep the low bytes.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn access(
+        &mut self,
+        memories: &[LinearMemory],
+        state: &State,
+        access: &Access,
+    ) -> Result<(), CompileError>
+    where
+        Self: Sized,
+    {
+        let i = access.memarg.memory_index;
+        let size = access.size;
+        if access.store {
+            write!(self, "tmp={};", pop!(state))?;
+            self.address(memories, state, &access.memarg, size)?;
+            write!(self, "memcpy(blitz_mem_{i}.data+tmp2,&tmp,{size})")?;
+            return Ok(());
+        }
+        self.address(memories, state, &access.memarg, size)?;
+        write!(self, "tmp=0;memcpy(&tmp,blitz_mem_{i}.data+tmp2,{size});")?;
+        if access.signed {
+            let width = if access.wide { "uint64_t" } else { "uint32_t" };
+            push(
+                state,
+                self,
+                &format_args!("(uint64_t)({width})(int{}_t)tmp", size * 8),
+            )?;
+        } else {
+            push(state, self, &"tmp")?;
+        }
+        Ok(())
+    }
+
+    /// Emit C for the floating-point operator `op`, described by `float`.
+    ///
+    /// Operands are read from their bits with the helpers emitted by
+    /// [`CWrite::floats`]. Conversions to integers trap on NaN and
+    /// out-of-range values, except the saturating ones.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn float(
+        &mut self,
+        state: &State,
+        op: &Instruction<'_>,
+        float: &FloatOp,
+    ) -> Result<(), CompileError>
+    where
+        Self: Sized,
+    {
+        let wide = float.params[0] == ValType::F64;
+        let get = if wide { "blitz_f64" } else { "blitz_f32" };
+        let res = match (float.result, float.canonicalises(state.nans)) {
+            (ValType::F64, true) => "blitz_canon_f64",
+            (ValType::F64, false) => "blitz_bits_f64",
+            (_, true) => "blitz_canon_f32",
+            (_, false) => "blitz_bits_f32",
+        };
+        let sign: u64 = if wide { 1 << 63 } else { 1 << 31 };
+        // Functions of <math.h> taking a float are suffixed with `f`.
+        let suffix = if wide { "" } else { "f" };
+        let unary = |w: &mut Self, f: &str| {
+            push(
+                state,
+                w,
+                &format_args!("{res}({f}{suffix}({get}({})))", pop!(state)),
+            )
+        };
+        let binary = |w: &mut Self, f: &str| -> core::fmt::Result {
+            write!(w, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+            push(
+                state,
+                w,
+                &format_args!("{res}({f}({get}(tmp2),{get}(tmp)))"),
+            )
+        };
+        let infix = |w: &mut Self, f: &str| -> core::fmt::Result {
+            write!(w, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+            push(state, w, &format_args!("{res}({get}(tmp2){f}{get}(tmp))"))
+        };
+        let compare = |w: &mut Self, f: &str| -> core::fmt::Result {
+            write!(w, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+            push(
+                state,
+                w,
+                &format_args!("(uint64_t)({get}(tmp2){f}{get}(tmp))"),
+            )
+        };
+        match op {
+            // Sign operations work on the bits and keep NaN payloads.
+            Instruction::F32Abs | Instruction::F64Abs => push(
+                state,
+                self,
+                &format_args!("({}&{:#x}ull)", pop!(state), sign - 1),
+            ),
+            Instruction::F32Neg | Instruction::F64Neg => {
+                push(state, self, &format_args!("({}^{sign:#x}ull)", pop!(state)))
+            }
+            Instruction::F32Copysign | Instruction::F64Copysign => {
+                write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!("((tmp2&{:#x}ull)|(tmp&{sign:#x}ull))", sign - 1),
+                )
+            }
+            Instruction::F32Ceil | Instruction::F64Ceil => unary(self, "ceil"),
+            Instruction::F32Floor | Instruction::F64Floor => unary(self, "floor"),
+            Instruction::F32Trunc | Instruction::F64Trunc => unary(self, "trunc"),
+            // `rint` rounds ties to even in the default rounding mode.
+            Instruction::F32Nearest | Instruction::F64Nearest => unary(self, "rint"),
+            Instruction::F32Sqrt | Instruction::F64Sqrt => unary(self, "sqrt"),
+            Instruction::F32Add | Instruction::F64Add => infix(self, "+"),
+            Instruction::F32Sub | Instruction::F64Sub => infix(self, "-"),
+            Instruction::F32Mul | Instruction::F64Mul => infix(self, "*"),
+            Instruction::F32Div | Instruction::F64Div => infix(self, "/"),
+            Instruction::F32Min => binary(self, "blitz_min_f32"),
+            Instruction::F64Min => binary(self, "blitz_min_f64"),
+            Instruction::F32Max => binary(self, "blitz_max_f32"),
+            Instruction::F64Max => binary(self, "blitz_max_f64"),
+            Instruction::F32Eq | Instruction::F64Eq => compare(self, "=="),
+            Instruction::F32Ne | Instruction::F64Ne => compare(self, "!="),
+            Instruction::F32Lt | Instruction::F64Lt => compare(self, "<"),
+            Instruction::F32Gt | Instruction::F64Gt => compare(self, ">"),
+            Instruction::F32Le | Instruction::F64Le => compare(self, "<="),
+            Instruction::F32Ge | Instruction::F64Ge => compare(self, ">="),
+            Instruction::I32TruncF32S
+            | Instruction::I32TruncF64S
+            | Instruction::I32TruncF32U
+            | Instruction::I32TruncF64U
+            | Instruction::I64TruncF32S
+            | Instruction::I64TruncF64S
+            | Instruction::I64TruncF32U
+            | Instruction::I64TruncF64U => {
+                // Bounds exclusive of the truncated range, which are exact
+                // doubles except for -2^63, where the bound is inclusive.
+                let (lo, hi, cast) = match (float.result, float.name.ends_with("_s")) {
+                    (ValType::I32, true) => (
+                        ">-2147483649.0",
+                        "2147483648.0",
+                        "(uint64_t)(uint32_t)(int32_t)",
+                    ),
+                    (ValType::I32, false) => (">-1.0", "4294967296.0", "(uint64_t)(uint32_t)"),
+                    (_, true) => (
+                        ">=-9223372036854775808.0",
+                        "9223372036854775808.0",
+                        "(uint64_t)(int64_t)",
+                    ),
+                    (_, false) => (">-1.0", "18446744073709551616.0", "(uint64_t)"),
+                };
+                // NaN compares false, so it traps like out-of-range values.
+                write!(
+                    self,
+                    "tmp={};if(!((double){get}(tmp){lo}&&(double){get}(tmp)<{hi})){{",
+                    pop!(state)
+                )?;
+                self.trap(state)?;
+                write!(self, ";}}")?;
+                push(state, self, &format_args!("{cast}{get}(tmp)"))
+            }
+            Instruction::I32TruncSatF32S
+            | Instruction::I32TruncSatF64S
+            | Instruction::I32TruncSatF32U
+            | Instruction::I32TruncSatF64U
+            | Instruction::I64TruncSatF32S
+            | Instruction::I64TruncSatF64S
+            | Instruction::I64TruncSatF32U
+            | Instruction::I64TruncSatF64U => {
+                // Bounds at or past which the result saturates, with the
+                // bits of the result there.
+                let (lo, min, hi, max, cast): (_, u64, _, u64, _) =
+                    match (float.result, float.name.ends_with("_s")) {
+                        (ValType::I32, true) => (
+                            "-2147483648.0",
+                            0x8000_0000,
+                            "2147483647.0",
+                            0x7fff_ffff,
+                            "(uint64_t)(uint32_t)(int32_t)",
+                        ),
+                        (ValType::I32, false) => (
+                            "0.0",
+                            0,
+                            "4294967295.0",
+                            0xffff_ffff,
+                            "(uint64_t)(uint32_t)",
+                        ),
+                        (_, true) => (
+                            "-9223372036854775808.0",
+                            1 << 63,
+                            "9223372036854775808.0",
+                            i64::MAX as u64,
+                            "(uint64_t)(int64_t)",
+                        ),
+                        (_, false) => ("0.0", 0, "18446744073709551616.0", u64::MAX, "(uint64_t)"),
+                    };
+                write!(self, "tmp={};", pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "({get}(tmp)!={get}(tmp)?0ull:{get}(tmp)<={lo}?{min:#x}ull:{get}(tmp)>={hi}?{max:#x}ull:{cast}{get}(tmp))"
+                    ),
+                )
+            }
+            Instruction::F32ConvertI32S
+            | Instruction::F32ConvertI32U
+            | Instruction::F32ConvertI64S
+            | Instruction::F32ConvertI64U
+            | Instruction::F64ConvertI32S
+            | Instruction::F64ConvertI32U
+            | Instruction::F64ConvertI64S
+            | Instruction::F64ConvertI64U => {
+                let ty = if float.result == ValType::F64 {
+                    "double"
+                } else {
+                    "float"
+                };
+                let int = match (float.params[0], float.name.ends_with("_s")) {
+                    (ValType::I32, true) => "(int32_t)",
+                    (ValType::I32, false) => "(uint32_t)",
+                    (_, true) => "(int64_t)",
+                    (_, false) => "",
+                };
+                push(
+                    state,
+                    self,
+                    &format_args!("{res}(({ty}){int}{})", pop!(state)),
+                )
+            }
+            Instruction::F32DemoteF64 => push(
+                state,
+                self,
+                &format_args!("{res}((float)blitz_f64({}))", pop!(state)),
+            ),
+            Instruction::F64PromoteF32 => push(
+                state,
+                self,
+                &format_args!("{res}((double)blitz_f32({}))", pop!(state)),
+            ),
+            _ => return Err(CompileError::unsupported(op)),
+        }?;
         Ok(())
     }
 
-    // ------------------------------------------------------------------
-    // br()
-    // ------------------------------------------------------------------
-
-    /// Emit a C `goto` for a branch instruction targeting a Block or Loop frame.
-    ///
-    /// **Bug fix vs JS backend**: the JS opt-mode Loop path emitted `break l{n}`
-    /// instead of `continue l{n}`. For C we use `goto` throughout, so there is
-    /// no such ambiguity.
-    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
-    fn br(&mut self, _sigs: &[FuncType], state: &State, relative_depth: u32) -> core::fmt::Result
-    where
-        Self: Sized,
-    {
-        let (enum_idx, frame) = state
-            .stack
-            .iter()
-            .enumerate()
-            .rev()
-            .nth(relative_depth as usize)
-            .unwrap();
-
-        // Label index: same convention as JS (`enumerate` is 0-based, labels are 1-based).
-        let label = enum_idx + 1;
-
-        match frame {
-            // Branch to a Block or If = forward jump to its exit label.
-            Frame::Block(_) | Frame::If(_) => write!(self, "goto blk_e_{label};"),
-            // BUG FIX vs JS: Loop branch is a *back*-edge (continue), not a break.
-            Frame::Loop(_) => write!(self, "goto lp_s_{label};"),
-        }
-    }
-
     // ------------------------------------------------------------------
     // on_op()
     // ------------------------------------------------------------------
 
     /// Translate a single WASM instruction into C.
     // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    #[allow(clippy::too_many_arguments)]
     fn on_op(
         &mut self,
         sigs: &[FuncType],
         fsigs: &[u32],
         _func_imports: &[(&str, &str)],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         state: &mut State,
         op: &Instruction<'_>,
-    ) -> core::fmt::Result
+    ) -> Result<(), CompileError>
     where
         Self: Sized,
     {
+        if let Some(access) = Access::of(op) {
+            return self.access(memories, state, &access);
+        }
+        if let Some(float) = FloatOp::of(op) {
+            return self.float(state, op, &float);
+        }
         match op {
             // ---- constants ------------------------------------------------
             Instruction::I32Const(value) => {
@@ -303,6 +1282,17 @@ pub trait CWrite: Write {
             Instruction::I64Const(value) => {
                 push(state, self, &format_args!("(uint64_t){}ull", *value as u64))
             }
+            Instruction::F32Const(value) => {
+                push(state, self, &format_args!("(uint64_t){:#x}ull", value.bits()))
+            }
+            Instruction::F64Const(value) => {
+                push(state, self, &format_args!("(uint64_t){:#x}ull", value.bits()))
+            }
+            // Values are kept as bits, so reinterpreting changes nothing.
+            Instruction::I32ReinterpretF32
+            | Instruction::I64ReinterpretF64
+            | Instruction::F32ReinterpretI32
+            | Instruction::F64ReinterpretI64 => Ok(()),
 
             // ---- zero tests -----------------------------------------------
             Instruction::I32Eqz => push(
@@ -351,6 +1341,7 @@ pub trait CWrite: Write {
             }
             Instruction::I32DivU => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint32_t", None)?;
                 push(
                     state,
                     self,
@@ -359,6 +1350,7 @@ pub trait CWrite: Write {
             }
             Instruction::I32RemU => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint32_t", None)?;
                 push(
                     state,
                     self,
@@ -367,18 +1359,23 @@ pub trait CWrite: Write {
             }
             Instruction::I32DivS => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint32_t", Some("0x80000000u"))?;
                 push(
                     state,
                     self,
                     &format_args!("(uint64_t)(uint32_t)((int32_t)tmp2/(int32_t)tmp)"),
                 )
             }
+            // `x % -1` is zero, but C's `%` overflows on the minimum.
             Instruction::I32RemS => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint32_t", None)?;
                 push(
                     state,
                     self,
-                    &format_args!("(uint64_t)(uint32_t)((int32_t)tmp2%(int32_t)tmp)"),
+                    &format_args!(
+                        "(uint64_t)(uint32_t)((int32_t)tmp==-1?0:(int32_t)tmp2%(int32_t)tmp)"
+                    ),
                 )
             }
             Instruction::I32Shl => {
@@ -419,7 +1416,7 @@ pub trait CWrite: Write {
                     state,
                     self,
                     &format_args!(
-                        "(uint64_t)(uint32_t)(((uint32_t)tmp2<<((uint32_t)tmp%32u))|((uint32_t)tmp2>>(32u-(uint32_t)tmp%32u)))"
+                        "(uint64_t)(uint32_t)(((uint32_t)tmp2<<((uint32_t)tmp&31u))|((uint32_t)tmp2>>((0u-(uint32_t)tmp)&31u)))"
                     ),
                 )
             }
@@ -429,7 +1426,7 @@ pub trait CWrite: Write {
                     state,
                     self,
                     &format_args!(
-                        "(uint64_t)(uint32_t)(((uint32_t)tmp2>>((uint32_t)tmp%32u))|((uint32_t)tmp2<<(32u-(uint32_t)tmp%32u)))"
+                        "(uint64_t)(uint32_t)(((uint32_t)tmp2>>((uint32_t)tmp&31u))|((uint32_t)tmp2<<((0u-(uint32_t)tmp)&31u)))"
                     ),
                 )
             }
@@ -453,26 +1450,31 @@ pub trait CWrite: Write {
             }
             Instruction::I64DivU => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint64_t", None)?;
                 push(state, self, &format_args!("tmp2/tmp"))
             }
             Instruction::I64RemU => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint64_t", None)?;
                 push(state, self, &format_args!("tmp2%tmp"))
             }
             Instruction::I64DivS => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint64_t", Some("0x8000000000000000ull"))?;
                 push(
                     state,
                     self,
                     &format_args!("(uint64_t)((int64_t)tmp2/(int64_t)tmp)"),
                 )
             }
+            // Same as I32RemS.
             Instruction::I64RemS => {
                 write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                self.div_guard(state, "uint64_t", None)?;
                 push(
                     state,
                     self,
-                    &format_args!("(uint64_t)((int64_t)tmp2%(int64_t)tmp)"),
+                    &format_args!("(uint64_t)((int64_t)tmp==-1?0:(int64_t)tmp2%(int64_t)tmp)"),
                 )
             }
             Instruction::I64Shl => {
@@ -497,7 +1499,7 @@ pub trait CWrite: Write {
                 push(
                     state,
                     self,
-                    &format_args!("(tmp2<<(tmp%64ull))|(tmp2>>(64ull-tmp%64ull))"),
+                    &format_args!("(tmp2<<(tmp&63ull))|(tmp2>>((0ull-tmp)&63ull))"),
                 )
             }
             Instruction::I64Rotr => {
@@ -505,36 +1507,394 @@ pub trait CWrite: Write {
                 push(
                     state,
                     self,
-                    &format_args!("(tmp2>>(tmp%64ull))|(tmp2<<(64ull-tmp%64ull))"),
+                    &format_args!("(tmp2>>(tmp&63ull))|(tmp2<<((0ull-tmp)&63ull))"),
+                )
+            }
+
+            // ---- bitwise ops (both widths; operands are already in range) -
+            Instruction::I32And | Instruction::I64And => {
+                push(state, self, &format_args!("({}&{})", pop!(state), pop!(state)))
+            }
+            Instruction::I32Or | Instruction::I64Or => {
+                push(state, self, &format_args!("({}|{})", pop!(state), pop!(state)))
+            }
+            Instruction::I32Xor | Instruction::I64Xor => {
+                push(state, self, &format_args!("({}^{})", pop!(state), pop!(state)))
+            }
+
+            // ---- comparisons (tmp = rhs, tmp2 = lhs) ----------------------
+            Instruction::I32Eq
+            | Instruction::I32Ne
+            | Instruction::I32LtS
+            | Instruction::I32LtU
+            | Instruction::I32GtS
+            | Instruction::I32GtU
+            | Instruction::I32LeS
+            | Instruction::I32LeU
+            | Instruction::I32GeS
+            | Instruction::I32GeU
+            | Instruction::I64Eq
+            | Instruction::I64Ne
+            | Instruction::I64LtS
+            | Instruction::I64LtU
+            | Instruction::I64GtS
+            | Instruction::I64GtU
+            | Instruction::I64LeS
+            | Instruction::I64LeU
+            | Instruction::I64GeS
+            | Instruction::I64GeU => {
+                let (cmp, ty) = match op {
+                    Instruction::I32Eq => ("==", "uint32_t"),
+                    Instruction::I32Ne => ("!=", "uint32_t"),
+                    Instruction::I32LtS => ("<", "int32_t"),
+                    Instruction::I32LtU => ("<", "uint32_t"),
+                    Instruction::I32GtS => (">", "int32_t"),
+                    Instruction::I32GtU => (">", "uint32_t"),
+                    Instruction::I32LeS => ("<=", "int32_t"),
+                    Instruction::I32LeU => ("<=", "uint32_t"),
+                    Instruction::I32GeS => (">=", "int32_t"),
+                    Instruction::I32GeU => (">=", "uint32_t"),
+                    Instruction::I64Eq => ("==", "uint64_t"),
+                    Instruction::I64Ne => ("!=", "uint64_t"),
+                    Instruction::I64LtS => ("<", "int64_t"),
+                    Instruction::I64LtU => ("<", "uint64_t"),
+                    Instruction::I64GtS => (">", "int64_t"),
+                    Instruction::I64GtU => (">", "uint64_t"),
+                    Instruction::I64LeS => ("<=", "int64_t"),
+                    Instruction::I64LeU => ("<=", "uint64_t"),
+                    Instruction::I64GeS => (">=", "int64_t"),
+                    _ => (">=", "uint64_t"),
+                };
+                write!(self, "tmp={};tmp2={};", pop!(state), pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!("(uint64_t)(({ty})tmp2{cmp}({ty})tmp)"),
+                )
+            }
+
+            // ---- bit counts (GCC/Clang builtins; zero is undefined there) -
+            Instruction::I32Clz => {
+                write!(self, "tmp={};", pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "(uint64_t)((uint32_t)tmp==0u?32:__builtin_clz((uint32_t)tmp))"
+                    ),
+                )
+            }
+            Instruction::I64Clz => {
+                write!(self, "tmp={};", pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!("(uint64_t)(tmp==0ull?64:__builtin_clzll(tmp))"),
+                )
+            }
+            Instruction::I32Ctz => {
+                write!(self, "tmp={};", pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "(uint64_t)((uint32_t)tmp==0u?32:__builtin_ctz((uint32_t)tmp))"
+                    ),
+                )
+            }
+            Instruction::I64Ctz => {
+                write!(self, "tmp={};", pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!("(uint64_t)(tmp==0ull?64:__builtin_ctzll(tmp))"),
+                )
+            }
+            Instruction::I32Popcnt => push(
+                state,
+                self,
+                &format_args!("(uint64_t)__builtin_popcount((uint32_t){})", pop!(state)),
+            ),
+            Instruction::I64Popcnt => push(
+                state,
+                self,
+                &format_args!("(uint64_t)__builtin_popcountll({})", pop!(state)),
+            ),
+
+            // ---- width changes and sign extension -------------------------
+            Instruction::I32WrapI64 | Instruction::I64ExtendI32U => {
+                push(state, self, &format_args!("(uint64_t)(uint32_t){}", pop!(state)))
+            }
+            Instruction::I64ExtendI32S
+            | Instruction::I32Extend8S
+            | Instruction::I32Extend16S
+            | Instruction::I64Extend8S
+            | Instruction::I64Extend16S
+            | Instruction::I64Extend32S => {
+                let (from, to) = match op {
+                    Instruction::I32Extend8S => ("int8_t", "(uint32_t)(int32_t)"),
+                    Instruction::I32Extend16S => ("int16_t", "(uint32_t)(int32_t)"),
+                    Instruction::I64Extend8S => ("int8_t", "(int64_t)"),
+                    Instruction::I64Extend16S => ("int16_t", "(int64_t)"),
+                    _ => ("int32_t", "(int64_t)"),
+                };
+                push(
+                    state,
+                    self,
+                    &format_args!("(uint64_t){to}({from}){}", pop!(state)),
                 )
             }
 
             // ---- control flow ---------------------------------------------
-            Instruction::Return => {
-                let id = state.fn_id;
-                let rets = state.ret_count;
-                if let Some(opt) = state.opt() {
-                    // In opt mode the stack items are 1-indexed; top `rets` items
-                    // start at stack[depth - rets + 1].
-                    let depth = opt.lock().depth;
-                    let start = depth.saturating_sub(rets) + 1;
+            Instruction::Return => self.ret(state),
+
+            Instruction::Call(function_index) => self.call(
+                state,
+                &sigs[fsigs[*function_index as usize] as usize],
+                *function_index,
+            ),
+
+            Instruction::GlobalGet(global_index) => {
+                global::lookup(&module.globals, *global_index)?;
+                push(state, self, &format_args!("blitz_global_{global_index}"))
+            }
+
+            Instruction::GlobalSet(global_index) => {
+                global::lookup(&module.globals, *global_index)?;
+                write!(self, "blitz_global_{global_index}={}", pop!(state))
+            }
+
+            Instruction::CallIndirect {
+                type_index,
+                table_index,
+            } => {
+                let t = module.table(*table_index)?;
+                let sig = sigs
+                    .get(*type_index as usize)
+                    .ok_or_else(|| CompileError::unknown("type", *type_index))?;
+                self.table_index(&t, state, "tmp")?;
+                write!(self, "if(tmp>=blitz_table_{table_index}.len){{")?;
+                self.trap(state)?;
+                write!(
+                    self,
+                    ";}}{{const struct blitz_func*_f=(const struct blitz_func*)(uintptr_t)blitz_table_{table_index}.data[tmp];if(!_f||_f->params!={}||_f->rets!={}){{",
+                    sig.params().len(),
+                    sig.results().len()
+                )?;
+                self.trap(state)?;
+                write!(self, ";}}")?;
+                self.invoke(state, sig, &"_f->fn")?;
+                write!(self, "}}")
+            }
+
+            Instruction::RefNull(_) => push(state, self, &"0"),
+
+            Instruction::RefIsNull => {
+                push(state, self, &format_args!("(uint64_t)({}==0)", pop!(state)))
+            }
+
+            Instruction::RefFunc(function_index) => {
+                if *function_index as usize >= fsigs.len() {
+                    return Err(CompileError::unknown("func", *function_index));
+                }
+                let mut f = String::new();
+                f.func_ref(ConstValue::RefFunc(*function_index))?;
+                push(state, self, &f)
+            }
+
+            Instruction::TableGet(table) => {
+                let t = module.table(*table)?;
+                self.table_index(&t, state, "tmp")?;
+                write!(self, "if(tmp>=blitz_table_{table}.len){{")?;
+                self.trap(state)?;
+                write!(self, ";}}")?;
+                push(state, self, &format_args!("blitz_table_{table}.data[tmp]"))
+            }
+
+            Instruction::TableSet(table) => {
+                let t = module.table(*table)?;
+                write!(self, "tmp2={};", pop!(state))?;
+                self.table_index(&t, state, "tmp")?;
+                write!(self, "if(tmp>=blitz_table_{table}.len){{")?;
+                self.trap(state)?;
+                write!(self, ";}}blitz_table_{table}.data[tmp]=tmp2")
+            }
+
+            Instruction::TableSize(table) => {
+                table::lookup(&module.tables, *table)?;
+                push(state, self, &format_args!("blitz_table_{table}.len"))
+            }
+
+            Instruction::TableGrow(table) => {
+                let t = module.table(*table)?;
+                let fail = if t.table64 { u64::MAX } else { u32::MAX as u64 };
+                self.table_index(&t, state, "tmp")?;
+                write!(self, "tmp2={};", pop!(state))?;
+                if t.imported {
+                    // The embedder owns the slots of an imported table.
+                    push(state, self, &format_args!("{fail}ull"))
+                } else {
                     write!(
                         self,
-                        "memcpy(__rets_{id},stack+{start},{rets}*sizeof(uint64_t));return __rets_{id};"
-                    )
+                        "{{uint64_t _r={fail}ull,*_d;if(tmp<=blitz_table_{table}.max-blitz_table_{table}.len&&(_d=malloc((blitz_table_{table}.len+tmp+1)*sizeof(uint64_t)))){{memcpy(_d,blitz_table_{table}.data,blitz_table_{table}.len*sizeof(uint64_t));for(uint64_t _i=0;_i<tmp;_i++)_d[blitz_table_{table}.len+_i]=tmp2;if(blitz_table_{table}.data!=blitz_table_{table}_init)free(blitz_table_{table}.data);blitz_table_{table}.data=_d;_r=blitz_table_{table}.len;blitz_table_{table}.len+=tmp;}}"
+                    )?;
+                    push(state, self, &"_r")?;
+                    write!(self, ";}}")
+                }
+            }
+
+            Instruction::TableFill(table) => {
+                let t = module.table(*table)?;
+                self.table_index(&t, state, "tmp")?;
+                write!(self, "tmp2={};{{uint64_t _d;", pop!(state))?;
+                self.table_index(&t, state, "_d")?;
+                write!(
+                    self,
+                    "if(_d>blitz_table_{table}.len||tmp>blitz_table_{table}.len-_d){{"
+                )?;
+                self.trap(state)?;
+                write!(
+                    self,
+                    ";}}while(tmp--)blitz_table_{table}.data[_d++]=tmp2;}}"
+                )
+            }
+
+            Instruction::TableCopy {
+                dst_table,
+                src_table,
+            } => {
+                let dst = module.table(*dst_table)?;
+                let src = module.table(*src_table)?;
+                // The length is only 64-bit when both tables are.
+                let len = if dst.table64 { &src } else { &dst };
+                self.table_index(len, state, "tmp")?;
+                self.table_index(&src, state, "tmp2")?;
+                self.table_copy(
+                    state,
+                    &dst,
+                    &format_args!("blitz_table_{src_table}.data"),
+                    &format_args!("blitz_table_{src_table}.len"),
+                )
+            }
+
+            Instruction::TableInit { elem_index, table } => {
+                let dst = module.table(*table)?;
+                table::lookup_elem(&module.elements, *elem_index)?;
+                write!(
+                    self,
+                    "tmp=(uint64_t)(uint32_t){};tmp2=(uint64_t)(uint32_t){};",
+                    pop!(state),
+                    pop!(state)
+                )?;
+                self.table_copy(
+                    state,
+                    &dst,
+                    &format_args!("blitz_elem_{elem_index}"),
+                    &format_args!("blitz_elem_{elem_index}_len"),
+                )
+            }
+
+            Instruction::MemoryInit { mem, data_index } => {
+                let mem = memory::lookup(memories, *mem)?;
+                memory::lookup_data(&module.data, *data_index)?;
+                let i = mem.index;
+                let cast = if mem.memory64 { "" } else { "(uint32_t)" };
+                write!(
+                    self,
+                    "tmp=(uint64_t)(uint32_t){};tmp2=(uint64_t)(uint32_t){};{{uint64_t _d=(uint64_t){cast}{};if(tmp2>blitz_data_{data_index}_len||tmp>blitz_data_{data_index}_len-tmp2||_d>blitz_mem_{i}.len||tmp>blitz_mem_{i}.len-_d){{",
+                    pop!(state),
+                    pop!(state),
+                    pop!(state)
+                )?;
+                self.trap(state)?;
+                write!(
+                    self,
+                    ";}}memcpy(blitz_mem_{i}.data+_d,blitz_data_{data_index}+tmp2,tmp);}}"
+                )
+            }
+
+            Instruction::MemorySize(mem) => {
+                let mem = memory::lookup(memories, *mem)?;
+                push(
+                    state,
+                    self,
+                    &format_args!("(blitz_mem_{}.len>>{})", mem.index, mem.page_size_log2),
+                )
+            }
+
+            Instruction::MemoryGrow(mem) => {
+                let mem = memory::lookup(memories, *mem)?;
+                let i = mem.index;
+                let cast = if mem.memory64 { "" } else { "(uint32_t)" };
+                let fail = if mem.memory64 { u64::MAX } else { u32::MAX as u64 };
+                write!(self, "tmp=(uint64_t){cast}{};", pop!(state))?;
+                if mem.imported {
+                    // The embedder owns the buffer of an imported memory.
+                    push(state, self, &format_args!("{fail}ull"))
                 } else {
                     write!(
                         self,
-                        "memcpy(__rets_{id},stack+sp-{rets},{rets}*sizeof(uint64_t));return __rets_{id};"
-                    )
+                        "{{uint64_t _r={fail}ull,_p=blitz_mem_{i}.len>>{log2};uint8_t*_d;if(!tmp)_r=_p;else if(tmp<={max}ull-_p&&(_d=calloc(_p+tmp,{page}))){{memcpy(_d,blitz_mem_{i}.data,blitz_mem_{i}.len);if(blitz_mem_{i}.data!=blitz_mem_{i}_init)free(blitz_mem_{i}.data);blitz_mem_{i}.data=_d;blitz_mem_{i}.len=(_p+tmp)<<{log2};_r=_p;}}",
+                        log2 = mem.page_size_log2,
+                        max = mem.max_size(),
+                        page = mem.page_size(),
+                    )?;
+                    push(state, self, &"_r")?;
+                    write!(self, ";}}")
                 }
             }
 
-            Instruction::Call(function_index) => self.call(
-                state,
-                &sigs[fsigs[*function_index as usize] as usize],
-                *function_index,
-            ),
+            Instruction::MemoryFill(mem) => {
+                let mem = memory::lookup(memories, *mem)?;
+                let i = mem.index;
+                let cast = if mem.memory64 { "" } else { "(uint32_t)" };
+                write!(
+                    self,
+                    "tmp=(uint64_t){cast}{};tmp2={};{{uint64_t _d=(uint64_t){cast}{};if(_d>blitz_mem_{i}.len||tmp>blitz_mem_{i}.len-_d){{",
+                    pop!(state),
+                    pop!(state),
+                    pop!(state)
+                )?;
+                self.trap(state)?;
+                write!(
+                    self,
+                    ";}}memset(blitz_mem_{i}.data+_d,(int)(uint8_t)tmp2,tmp);}}"
+                )
+            }
+
+            Instruction::MemoryCopy { dst_mem, src_mem } => {
+                let dst = memory::lookup(memories, *dst_mem)?;
+                let src = memory::lookup(memories, *src_mem)?;
+                let cast = |wide: bool| if wide { "" } else { "(uint32_t)" };
+                let (d, s) = (dst.index, src.index);
+                // The length is only 64-bit when both memories are.
+                write!(
+                    self,
+                    "tmp=(uint64_t){}{};tmp2=(uint64_t){}{};{{uint64_t _d=(uint64_t){}{};if(tmp2>blitz_mem_{s}.len||tmp>blitz_mem_{s}.len-tmp2||_d>blitz_mem_{d}.len||tmp>blitz_mem_{d}.len-_d){{",
+                    cast(dst.memory64 && src.memory64),
+                    pop!(state),
+                    cast(src.memory64),
+                    pop!(state),
+                    cast(dst.memory64),
+                    pop!(state)
+                )?;
+                self.trap(state)?;
+                // `memmove` handles overlapping ranges.
+                write!(
+                    self,
+                    ";}}memmove(blitz_mem_{d}.data+_d,blitz_mem_{s}.data+tmp2,tmp);}}"
+                )
+            }
+
+            Instruction::DataDrop(data_index) => {
+                memory::lookup_data(&module.data, *data_index)?;
+                write!(self, "blitz_data_{data_index}_len=0")
+            }
+
+            Instruction::ElemDrop(elem_index) => {
+                table::lookup_elem(&module.elements, *elem_index)?;
+                write!(self, "blitz_elem_{elem_index}_len=0")
+            }
 
             Instruction::LocalGet(local_index) => {
                 push(state, self, &format_args!("locals[{local_index}]"))
@@ -555,106 +1915,104 @@ pub trait CWrite: Write {
 
             // ---- blocks / loops / if -------------------------------------
             Instruction::Block(blockty) => {
-                state.stack.push(Frame::Block(blockty.clone()));
-                if let Some(o) = state.opt() {
-                    let mut o = o.lock();
-                    o.depth = match blockty {
-                        BlockType::Empty => 0,
-                        BlockType::Result(_) => 0,
-                        BlockType::FunctionType(f) => sigs[*f as usize].params().len(),
-                    };
+                let n = state.push_frame(FrameKind::Block, blockty, sigs, 0)?;
+                write!(self, "{{/*blk_s_{}*/", state.stack[n - 1].label)?;
+                if state.opt().is_none() {
+                    write!(
+                        self,
+                        "heights[{n}]=sp-{};",
+                        state.stack[n - 1].arity.params
+                    )?;
                 }
-                write!(self, "{{/*blk_s_{}*/", state.stack.len())
+                Ok(())
             }
 
             Instruction::Loop(blockty) => {
-                state.stack.push(Frame::Loop(blockty.clone()));
-                if let Some(o) = state.opt() {
-                    let mut o = o.lock();
-                    o.depth = match blockty {
-                        BlockType::Empty => 0,
-                        BlockType::Result(_) => 0,
-                        BlockType::FunctionType(f) => sigs[*f as usize].params().len(),
-                    };
+                let n = state.push_frame(FrameKind::Loop, blockty, sigs, 0)?;
+                if state.opt().is_none() {
+                    write!(
+                        self,
+                        "heights[{n}]=sp-{};",
+                        state.stack[n - 1].arity.params
+                    )?;
                 }
                 // Emit the back-edge label before the loop body.
-                write!(self, "lp_s_{0}:;{{", state.stack.len())
+                write!(self, "lp_s_{}:;{{", state.stack[n - 1].label)
             }
 
             Instruction::If(blockty) => {
-                state.stack.push(Frame::If(blockty.clone()));
+                let n = state.push_frame(FrameKind::If, blockty, sigs, 1)?;
+                if state.opt().is_none() {
+                    // The condition is still on the stack at this point.
+                    write!(
+                        self,
+                        "heights[{n}]=sp-{};",
+                        state.stack[n - 1].arity.params + 1
+                    )?;
+                }
                 write!(self, "if((uint64_t){}!=0ull){{", pop!(state))
             }
 
-            Instruction::Else => write!(self, "}}else{{"),
+            Instruction::Else => {
+                let Some(Frame {
+                    kind: FrameKind::If,
+                    arity,
+                    base,
+                    ..
+                }) = state.stack.last()
+                else {
+                    return Err(CompileError::malformed());
+                };
+                // The else arm starts from the if's parameters again.
+                if let Some(o) = state.opt() {
+                    o.lock().depth = base + arity.params;
+                }
+                write!(self, "}}else{{")
+            }
 
             Instruction::End => {
-                // Retrieve the label *before* popping so we can emit it.
-                let label = state.stack.len();
                 let frame = match state.stack.pop() {
                     Some(f) => f,
                     // Function-level end (implicit outer block) — no frame to close.
                     None => return Ok(()),
                 };
-                match frame {
-                    Frame::Block(blockty) => {
-                        if let Some(o) = state.opt() {
-                            let mut o = o.lock();
-                            o.depth = match blockty {
-                                BlockType::Empty => 0,
-                                BlockType::Result(_) => 1,
-                                BlockType::FunctionType(f) => {
-                                    sigs[f as usize].results().len()
-                                }
-                            };
-                        }
-                        // Label as empty statement at the exit point of the block.
-                        write!(self, "blk_e_{label}:;}}")
-                    }
-                    Frame::Loop(blockty) => {
-                        if let Some(o) = state.opt() {
-                            let mut o = o.lock();
-                            o.depth = match blockty {
-                                BlockType::Empty => 0,
-                                BlockType::Result(_) => 1,
-                                BlockType::FunctionType(f) => {
-                                    sigs[f as usize].results().len()
-                                }
-                            };
-                        }
-                        // Close loop scope; no explicit back-edge needed here because
-                        // WASM's fall-through off a loop end exits the loop.
-                        write!(self, "}}")
-                    }
-                    Frame::If(blockty) => {
-                        if let Some(o) = state.opt() {
-                            let mut o = o.lock();
-                            o.depth = match blockty {
-                                BlockType::Empty => 0,
-                                BlockType::Result(_) => 1,
-                                BlockType::FunctionType(f) => {
-                                    sigs[f as usize].results().len()
-                                }
-                            };
-                        }
-                        // Emit exit label so `goto blk_e_{label}` can target it.
-                        write!(self, "blk_e_{label}:;}}")
-                    }
+                if let Some(o) = state.opt() {
+                    o.lock().depth = frame.base + frame.arity.results;
+                }
+                match frame.kind {
+                    // Label as empty statement at the exit point of the block;
+                    // `goto blk_e_{label}` targets it for Blocks and Ifs.
+                    // Labels have function scope, so each frame has its own.
+                    FrameKind::Block | FrameKind::If => write!(self, "blk_e_{}:;}}", frame.label),
+                    // Close loop scope; no explicit back-edge needed here because
+                    // WASM's fall-through off a loop end exits the loop.
+                    FrameKind::Loop => write!(self, "}}"),
                 }
             }
 
-            Instruction::Br(relative_depth) => self.br(sigs, state, *relative_depth),
+            Instruction::Unreachable => self.trap(state),
 
-            Instruction::BrIf(relative_depth) => write!(
-                self,
-                "if((uint64_t){}!=0ull){{{}}}",
-                pop!(state),
-                DisplayFn(&|f| f.br(sigs, state, *relative_depth))
-            ),
+            Instruction::Br(relative_depth) => {
+                state.check_depth(*relative_depth)?;
+                self.br(sigs, state, *relative_depth)
+            }
+
+            Instruction::BrIf(relative_depth) => {
+                state.check_depth(*relative_depth)?;
+                write!(
+                    self,
+                    "if((uint64_t){}!=0ull){{{}}}",
+                    pop!(state),
+                    DisplayFn(&|f| f.br(sigs, state, *relative_depth))
+                )
+            }
 
             // BUG FIX vs JS: JS wrote `write!(self, "{}", pop!(state))` which
             // evaluated the pop as a void expression — `tmp` was never set.
             Instruction::BrTable(targets, default_target) => {
+                for t in targets.iter().chain([default_target]) {
+                    state.check_depth(*t)?;
+                }
                 write!(self, "tmp={};", pop!(state))?;
                 for t in targets.iter().cloned() {
                     write!(
@@ -667,7 +2025,7 @@ pub trait CWrite: Write {
                 Ok(())
             }
 
-            _ => todo!(),
+            _ => return Err(CompileError::unsupported(op)),
         }?;
         Ok(())
     }
@@ -683,15 +2041,18 @@ pub trait CWrite: Write {
     /// `Local` processing and the full function header (including the locals
     /// buffer) is emitted during `StartBody` once all counts are known.
     // TODO: Remove the Sized bound once push/pop can work with ?Sized types
-    fn on_mach<Annot>(
+    #[allow(clippy::too_many_arguments)]
+    fn on_mach<Annot: ToWasmInfo>(
         &mut self,
         sigs: &[FuncType],
         fsigs: &[u32],
         func_imports: &[(&str, &str)],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         state: &mut State,
         m: &MachOperator<'_, Annot>,
         r: &mut impl Reencode,
-    ) -> core::fmt::Result
+    ) -> Result<(), CompileError>
     where
         Self: Sized,
     {
@@ -703,6 +2064,9 @@ pub trait CWrite: Write {
                 state.param_count = data.num_params;
                 state.ret_count = data.num_returns;
                 state.local_count = 0;
+                state.labels = 0;
+                state.control_depth = data.control_depth;
+                state.max_stack = data.max_stack;
 
                 // Emit the signature struct and result buffer.
                 // The function body itself is emitted in StartBody once we know
@@ -713,7 +2077,8 @@ pub trait CWrite: Write {
                     params = data.num_params,
                     rets   = data.num_returns,
                     rets_sz = data.num_returns.max(1),
-                )
+                )?;
+                Ok(())
             }
 
             // Accumulate local variable counts; all WASM locals are zero-initialised
@@ -729,44 +2094,151 @@ pub trait CWrite: Write {
                 let id = state.fn_id;
                 let params = state.param_count;
                 let locals = state.local_count;
+                // Optimized mode indexes the stack from 1.
+                let stack_sz = DisplayFn(&|f| match state.max_stack {
+                    Some(n) if state.opt().is_some() => write!(f, "{}", n + 1),
+                    Some(n) => write!(f, "{}", n.max(1)),
+                    None => write!(f, "WASM_STACK_SIZE"),
+                });
                 write!(
                     self,
-                    "static uint64_t*fn_{id}(uint64_t*restrict locals_in){{uint64_t locals_buf[{buf_sz}];memcpy(locals_buf,locals_in,{params}*sizeof(uint64_t));memset(locals_buf+{params},0,{locals}*sizeof(uint64_t));uint64_t*locals=locals_buf;uint64_t stack[WASM_STACK_SIZE];uint64_t tmp=0,tmp2=0,*tmp_locals=0;int sp=0;",
+                    "static uint64_t*fn_{id}(uint64_t*restrict locals_in){{uint64_t locals_buf[{buf_sz}];memcpy(locals_buf,locals_in,{params}*sizeof(uint64_t));memset(locals_buf+{params},0,{locals}*sizeof(uint64_t));uint64_t*locals=locals_buf;uint64_t stack[{stack_sz}];uint64_t tmp=0,tmp2=0,*tmp_locals=0;int sp=0,heights[{heights_sz}];",
                     buf_sz = (params + locals).max(1),
-                )
+                    heights_sz = state.control_depth + 1,
+                )?;
+                Ok(())
             }
 
-            MachOperator::Instruction { op, annot: _ } => {
-                self.on_op(sigs, fsigs, func_imports, state, op)?;
+            MachOperator::Instruction { op, annot } => {
+                self.on_op(sigs, fsigs, func_imports, memories, module, state, op)
+                    .map_err(|e| e.at(annot))?;
                 write!(self, ";")?;
                 Ok(())
             }
 
-            MachOperator::Operator { op, annot: _ } => {
+            MachOperator::Operator { op, annot } => {
                 let Some(op) = op.as_ref() else {
                     return Ok(());
                 };
                 let Ok(op) = r.instruction(op.clone()) else {
-                    return Ok(());
+                    return Err(CompileError::unsupported(op).at(annot));
                 };
-                self.on_op(sigs, fsigs, func_imports, state, &op)?;
+                self.on_op(sigs, fsigs, func_imports, memories, module, state, &op)
+                    .map_err(|e| e.at(annot))?;
                 write!(self, ";")?;
                 Ok(())
             }
 
+            MachOperator::Trap { conditional, .. } => {
+                if *conditional {
+                    write!(self, "if((uint64_t){}!=0ull){{", pop!(state))?;
+                    self.trap(state)?;
+                    write!(self, ";}}")?;
+                } else {
+                    self.trap(state)?;
+                    write!(self, ";")?;
+                }
+                Ok(())
+            }
+
             MachOperator::EndBody => {
                 let id = state.fn_id;
                 let rets = state.ret_count;
                 write!(
                     self,
                     "memcpy(__rets_{id},stack+sp-{rets},{rets}*sizeof(uint64_t));return __rets_{id};}}"
-                )
+                )?;
+                Ok(())
             }
 
-            _ => todo!(),
+            _ => Err(CompileError::unsupported_mach(m)),
         }
     }
 }
 
 /// Blanket implementation of `CWrite` for all `Write` types.
 impl<T: Write + ?Sized> CWrite for T {}
+
+// ---------------------------------------------------------------------------
+// Backend
+// ---------------------------------------------------------------------------
+
+/// A [`Backend`] that writes C source to a writer.
+pub struct CBackend<W> {
+    /// The writer receiving C source.
+    pub out: W,
+    /// Code generation state.
+    pub state: State,
+}
+
+impl<W: Write> CBackend<W> {
+    /// Creates a backend writing to `out`.
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            state: State::default(),
+        }
+    }
+}
+
+impl<W: Write, R: Reencode, Annot: ToWasmInfo> Backend<R, Annot> for CBackend<W> {
+    type Error = CompileError;
+    fn on_module(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        self.out.floats()?;
+        self.out.imports(&self.state, cx.module, &self.state.imports)?;
+        self.out.memories(&cx.memories)?;
+        let globals = cx.module.global_vars().collect::<Result<Vec<_>, _>>()?;
+        self.out.globals(&globals)?;
+        let tables = cx.module.table_defs().collect::<Result<Vec<_>, _>>()?;
+        let elems = cx.module.elem_segments().collect::<Result<Vec<_>, _>>()?;
+        if !tables.is_empty() || !elems.is_empty() {
+            self.out.funcs(cx.module)?;
+            self.out.tables(&tables)?;
+            self.out.elems(&elems)?;
+        }
+        let data = cx.module.data_segments().collect::<Result<Vec<_>, _>>()?;
+        self.out.data(&data)?;
+        let start = cx.module.start_func()?;
+        self.out
+            .init(&self.state, &cx.memories, &tables, &elems, &data, start)
+    }
+    fn on_end(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        self.out.exports(cx.module)
+    }
+    fn on_mach(
+        &mut self,
+        cx: &mut BackendContext<'_, R>,
+        op: &MachOperator<'_, Annot>,
+    ) -> Result<(), Self::Error> {
+        self.out.on_mach(
+            &cx.sigs,
+            cx.fsigs(),
+            cx.func_imports(),
+            &cx.memories,
+            cx.module,
+            &mut self.state,
+            op,
+            cx.rewriter,
+        )
+    }
+}
+
+impl<W: Write + Default + Display, R: Reencode, Annot: ToWasmInfo> ForkBackend<R, Annot>
+    for CBackend<W>
+{
+    fn fork(&self) -> Self {
+        let mut fork = Self::new(W::default());
+        if self.state.opt().is_some() {
+            fork.state.enable_opt(OptState::default);
+        }
+        fork.state.trap_handler = self.state.trap_handler.clone();
+        fork.state.imports = self.state.imports.clone();
+        fork.state.nans = self.state.nans;
+        fork
+    }
+
+    fn link(&mut self, fork: Self) -> Result<(), Self::Error> {
+        write!(self.out, "{}", fork.out)?;
+        Ok(())
+    }
+}
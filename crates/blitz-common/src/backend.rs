@@ -0,0 +1,133 @@
+//! Target-agnostic backend interface.
+//!
+//! Every code generator (JS, C, x86-64, RISC-V) implements [`Backend`], which
+//! consumes one `MachOperator` at a time together with a shared
+//! [`BackendContext`]. Code that only needs "some backend" can be written
+//! once against this trait and run on any target.
+
+use alloc::vec::Vec;
+use wasm_encoder::reencode::{self, Reencode, RoundtripReencoder};
+
+use crate::{memory::LinearMemory, module::Module, *};
+
+/// State shared by every backend while compiling a module.
+///
+/// Carries the module being compiled, its function signatures converted for
+/// `wasm_encoder`, its linear memories, and the rewriter used to turn parsed operators into
+/// encoded instructions.
+pub struct BackendContext<'m, R = RoundtripReencoder> {
+    /// The module being compiled.
+    pub module: &'m Module<'m>,
+    /// Function types from the type section, as `wasm_encoder` types.
+    pub sigs: Vec<wasm_encoder::FuncType>,
+    /// Every linear memory, imports included.
+    pub memories: Vec<LinearMemory>,
+    /// Rewriter converting `wasmparser` operators into `wasm_encoder` instructions.
+    pub rewriter: &'m mut R,
+}
+
+/// A function in the function index space, resolved against the import table.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FuncRef<'m> {
+    /// An imported function.
+    Import { module: &'m str, name: &'m str },
+    /// A function defined in the module, by its index among defined functions.
+    Defined(u32),
+}
+
+impl<'m, R> BackendContext<'m, R> {
+    /// Creates a context for compiling `module` with `rewriter`.
+    pub fn new(module: &'m Module<'m>, rewriter: &'m mut R) -> Result<Self, reencode::Error> {
+        Ok(Self {
+            module,
+            sigs: module.encoder_types()?,
+            memories: module.linear_memories().collect(),
+            rewriter,
+        })
+    }
+
+    /// Type index of every function, imports included.
+    pub fn fsigs(&self) -> &'m [u32] {
+        &self.module.funcs
+    }
+
+    /// `(module, name)` pairs of the imported functions.
+    pub fn func_imports(&self) -> &'m [(&'m str, &'m str)] {
+        &self.module.func_imports
+    }
+
+    /// Resolves `index` in the function index space.
+    pub fn func(&self, index: u32) -> FuncRef<'m> {
+        match self.func_imports().get(index as usize) {
+            Some((module, name)) => FuncRef::Import { module, name },
+            None => FuncRef::Defined(index - self.module.num_func_imports()),
+        }
+    }
+
+    /// Maps the index of a defined function (as carried by `StartFn`) into
+    /// the function index space.
+    pub fn absolute(&self, defined: u32) -> u32 {
+        defined + self.module.num_func_imports()
+    }
+
+    /// The signature of the function at `index` in the function index space.
+    pub fn func_sig(&self, index: u32) -> &wasm_encoder::FuncType {
+        &self.sigs[self.fsigs()[index as usize] as usize]
+    }
+}
+
+/// A code generator that can be driven over a whole module.
+///
+/// `R` is the rewriter type held by the [`BackendContext`]; most callers use
+/// the default `RoundtripReencoder`.
+pub trait Backend<R: Reencode = RoundtripReencoder, Annot = ()> {
+    /// The error produced while emitting code.
+    type Error;
+    /// Emits module-level code, such as storage for linear memories and
+    /// globals, before any function is compiled.
+    fn on_module(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        let _ = cx;
+        Ok(())
+    }
+    /// Emits module-level code, such as exports, after every function has
+    /// been compiled.
+    fn on_end(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        let _ = cx;
+        Ok(())
+    }
+    /// Emits code for a single machine operator.
+    fn on_mach(
+        &mut self,
+        cx: &mut BackendContext<'_, R>,
+        op: &MachOperator<'_, Annot>,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A backend whose functions can be compiled independently.
+///
+/// A fork starts out empty, with the configuration of the backend it was
+/// forked from, and compiles whole functions into its own buffer. Linking
+/// appends a fork's output, so a module can be split across forks (and
+/// threads) at function boundaries and linked back in function order.
+pub trait ForkBackend<R: Reencode = RoundtripReencoder, Annot = ()>:
+    Backend<R, Annot> + Sized
+{
+    /// Creates an empty backend with the same configuration.
+    fn fork(&self) -> Self;
+    /// Appends the output of `fork`, which was forked from this backend.
+    fn link(&mut self, fork: Self) -> Result<(), Self::Error>;
+}
+
+/// Drives `backend` over an operator stream.
+///
+/// The stream is usually `Module::mach_operators` with any passes applied.
+pub fn drive<'b, R: Reencode, Annot, E: From<B::Error>, B: Backend<R, Annot> + ?Sized>(
+    cx: &mut BackendContext<'_, R>,
+    ops: impl IntoIterator<Item = Result<MachOperator<'b, Annot>, E>>,
+    backend: &mut B,
+) -> Result<(), E> {
+    for op in ops {
+        backend.on_mach(cx, &op?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Operator coverage.
+//!
+//! [`Group`] sorts operators into groups following the specification and
+//! its proposals, and [`COVERAGE`] records how much of each group every
+//! backend lowers. Operators outside the groups, such as SIMD, are not
+//! lowered by any backend.
+
+use wasm_encoder::Instruction;
+
+use crate::float::FloatOp;
+use crate::memory::Access;
+
+/// A group of operators.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum Group {
+    /// The MVP integer operators: constants, arithmetic, bitwise operators,
+    /// comparisons, bit counts, `i32.wrap_i64` and `i64.extend_i32_s/u`.
+    Int,
+    /// The sign-extension operators, such as `i32.extend8_s`.
+    SignExt,
+    /// Float constants, arithmetic, comparisons and conversions, and the
+    /// reinterpretations between floats and integers.
+    Float,
+    /// Loads, stores, `memory.size` and `memory.grow`.
+    Memory,
+    /// `memory.fill`, `memory.copy`, `memory.init` and `data.drop`.
+    BulkMemory,
+    /// Table and reference operators, `call_indirect` and `elem.drop`.
+    Table,
+    /// Local and global variable operators.
+    Variable,
+    /// Blocks, branches, calls, `return` and `unreachable`.
+    Control,
+    /// `drop`, `nop` and `select`.
+    Parametric,
+}
+
+impl Group {
+    /// The group `op` belongs to, if any.
+    pub fn of(op: &Instruction<'_>) -> Option<Self> {
+        use Instruction::*;
+        if Access::of(op).is_some() {
+            return Some(Group::Memory);
+        }
+        if FloatOp::of(op).is_some() {
+            return Some(Group::Float);
+        }
+        Some(match op {
+            I32Const(_) | I64Const(_) | I32Eqz | I32Eq | I32Ne | I32LtS | I32LtU | I32GtS
+            | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU | I64Eqz | I64Eq | I64Ne | I64LtS
+            | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU | I32Clz | I32Ctz
+            | I32Popcnt | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU
+            | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Clz
+            | I64Ctz | I64Popcnt | I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS
+            | I64RemU | I64And | I64Or | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl
+            | I64Rotr | I32WrapI64 | I64ExtendI32S | I64ExtendI32U => Group::Int,
+            I32Extend8S | I32Extend16S | I64Extend8S | I64Extend16S | I64Extend32S => {
+                Group::SignExt
+            }
+            F32Const(_) | F64Const(_) | I32ReinterpretF32 | I64ReinterpretF64
+            | F32ReinterpretI32 | F64ReinterpretI64 => Group::Float,
+            MemorySize(_) | MemoryGrow(_) => Group::Memory,
+            MemoryFill(_) | MemoryCopy { .. } | MemoryInit { .. } | DataDrop(_) => {
+                Group::BulkMemory
+            }
+            CallIndirect { .. }
+            | RefNull(_)
+            | RefIsNull
+            | RefFunc(_)
+            | TableGet(_)
+            | TableSet(_)
+            | TableSize(_)
+            | TableGrow(_)
+            | TableFill(_)
+            | TableCopy { .. }
+            | TableInit { .. }
+            | ElemDrop(_) => Group::Table,
+            LocalGet(_) | LocalSet(_) | LocalTee(_) | GlobalGet(_) | GlobalSet(_) => {
+                Group::Variable
+            }
+            Unreachable | Block(_) | Loop(_) | If(_) | Else | End | Br(_) | BrIf(_)
+            | BrTable(..) | Return | Call(_) => Group::Control,
+            Drop | Nop | Select | TypedSelect(_) => Group::Parametric,
+            _ => return None,
+        })
+    }
+}
+
+/// How much of a [`Group`] a backend lowers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Support {
+    /// Every operator, in every form.
+    Full,
+    /// Some operators, or some forms of them; the rest are rejected with a
+    /// [`CompileError`](crate::CompileError).
+    Partial,
+    /// None of the operators.
+    Missing,
+}
+
+/// The groups a backend lowers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Coverage {
+    /// Name of the backend's crate, without the `portal-solutions-blitz-`
+    /// prefix, followed by the code generator for backends with several.
+    pub backend: &'static str,
+    /// The groups the backend lowers at least in part. Groups not listed
+    /// are [`Support::Missing`].
+    pub groups: &'static [(Group, Support)],
+}
+
+impl Coverage {
+    /// How much of `group` the backend lowers.
+    pub fn of(&self, group: Group) -> Support {
+        self.groups
+            .iter()
+            .find(|(g, _)| *g == group)
+            .map_or(Support::Missing, |(_, support)| *support)
+    }
+
+    /// How much of the group of `op` the backend lowers.
+    pub fn op(&self, op: &Instruction<'_>) -> Support {
+        Group::of(op).map_or(Support::Missing, |group| self.of(group))
+    }
+
+    /// The coverage of `backend`, if it is listed in [`COVERAGE`].
+    pub fn lookup(backend: &str) -> Option<&'static Coverage> {
+        COVERAGE.iter().find(|c| c.backend == backend)
+    }
+}
+
+/// The coverage of every backend.
+///
+/// Partial groups are missing:
+///
+/// - `c`: reference-typed globals
+/// - `x86-64 naive`, `x86-64 fast`: bulk table operators, references to
+///   imported functions and `select`; the fast generator also lacks
+///   `local.tee`
+/// - `riscv64 naive`: bulk table operators, function references, branches
+///   to the function body and `select`
+pub const COVERAGE: &[Coverage] = &[
+    Coverage {
+        backend: "js",
+        groups: &[
+            (Group::Int, Support::Full),
+            (Group::SignExt, Support::Full),
+            (Group::Float, Support::Full),
+            (Group::Memory, Support::Full),
+            (Group::BulkMemory, Support::Full),
+            (Group::Table, Support::Full),
+            (Group::Variable, Support::Full),
+            (Group::Control, Support::Full),
+        ],
+    },
+    Coverage {
+        backend: "c",
+        groups: &[
+            (Group::Int, Support::Full),
+            (Group::SignExt, Support::Full),
+            (Group::Float, Support::Full),
+            (Group::Memory, Support::Full),
+            (Group::BulkMemory, Support::Full),
+            (Group::Table, Support::Full),
+            (Group::Variable, Support::Partial),
+            (Group::Control, Support::Full),
+        ],
+    },
+    Coverage {
+        backend: "x86-64 naive",
+        groups: &[
+            (Group::Int, Support::Full),
+            (Group::SignExt, Support::Full),
+            (Group::Float, Support::Full),
+            (Group::Memory, Support::Full),
+            (Group::BulkMemory, Support::Full),
+            (Group::Table, Support::Partial),
+            (Group::Variable, Support::Full),
+            (Group::Control, Support::Full),
+            (Group::Parametric, Support::Partial),
+        ],
+    },
+    Coverage {
+        backend: "x86-64 fast",
+        groups: &[
+            (Group::Int, Support::Full),
+            (Group::SignExt, Support::Full),
+            (Group::Float, Support::Full),
+            (Group::Memory, Support::Full),
+            (Group::BulkMemory, Support::Full),
+            (Group::Table, Support::Partial),
+            (Group::Variable, Support::Partial),
+            (Group::Control, Support::Full),
+            (Group::Parametric, Support::Partial),
+        ],
+    },
+    Coverage {
+        backend: "riscv64 naive",
+        groups: &[
+            (Group::Int, Support::Full),
+            (Group::SignExt, Support::Full),
+            (Group::Float, Support::Full),
+            (Group::Memory, Support::Full),
+            (Group::BulkMemory, Support::Full),
+            (Group::Table, Support::Partial),
+            (Group::Variable, Support::Full),
+            (Group::Control, Support::Partial),
+            (Group::Parametric, Support::Partial),
+        ],
+    },
+];
@@ -0,0 +1,204 @@
+//! Compilation errors shared by every backend.
+//!
+//! Backends report problems with the input module through [`CompileError`]
+//! rather than panicking or silently emitting nothing, so that a bad module
+//! produces a diagnostic pointing at the offending operator.
+
+use alloc::string::String;
+use core::fmt::{self, Debug, Display, Formatter};
+use wasm_encoder::reencode;
+
+use crate::{
+    ops::{ToWasmInfo, WasmInfo},
+    *,
+};
+
+/// An error produced while compiling a module.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum CompileError {
+    /// An operator the backend cannot compile.
+    UnsupportedOperator {
+        /// Name of the operator, e.g. `F32Add`.
+        name: String,
+        /// Where the operator appears in the input, if known.
+        info: Option<WasmInfo>,
+    },
+    /// A WASM feature the front end or backend does not support.
+    UnsupportedFeature {
+        /// Short description of the feature.
+        feature: &'static str,
+        /// Where the feature is used in the input, if known.
+        info: Option<WasmInfo>,
+    },
+    /// A control instruction without a matching frame, e.g. an `else`
+    /// outside of an `if` or a branch deeper than the control stack.
+    MalformedControl {
+        /// Where the instruction appears in the input, if known.
+        info: Option<WasmInfo>,
+    },
+    /// A reference to an entity outside its index space, e.g. a memory
+    /// index past the last memory of the module.
+    UnknownEntity {
+        /// The index space, e.g. `memory`.
+        kind: &'static str,
+        /// The index that was referenced.
+        index: u32,
+        /// Where the reference appears in the input, if known.
+        info: Option<WasmInfo>,
+    },
+    /// A `MachOperator` variant the backend does not handle.
+    UnsupportedMachOperator {
+        /// Name of the variant, e.g. `Trap`.
+        variant: &'static str,
+    },
+    /// The input module could not be parsed.
+    Reader(BinaryReaderError),
+    /// A parsed item could not be converted for `wasm_encoder`.
+    Reencode(&'static str),
+    /// Writing the generated code failed.
+    Fmt,
+}
+
+impl CompileError {
+    /// An unsupported operator, named after its `Debug` representation.
+    pub fn unsupported(op: &impl Debug) -> Self {
+        let mut name = String::new();
+        let _ = fmt::write(&mut name, format_args!("{op:?}"));
+        if let Some(end) = name.find(|c: char| !c.is_alphanumeric() && c != '_') {
+            name.truncate(end);
+        }
+        CompileError::UnsupportedOperator { name, info: None }
+    }
+
+    /// An unsupported feature.
+    pub fn feature(feature: &'static str) -> Self {
+        CompileError::UnsupportedFeature {
+            feature,
+            info: None,
+        }
+    }
+
+    /// A malformed control stack.
+    pub fn malformed() -> Self {
+        CompileError::MalformedControl { info: None }
+    }
+
+    /// A reference to entity `index` of an index space that has no such
+    /// entity.
+    pub fn unknown(kind: &'static str, index: u32) -> Self {
+        CompileError::UnknownEntity {
+            kind,
+            index,
+            info: None,
+        }
+    }
+
+    /// An unsupported `MachOperator` variant.
+    pub fn unsupported_mach<Annot>(op: &MachOperator<'_, Annot>) -> Self {
+        CompileError::UnsupportedMachOperator {
+            variant: match op {
+                MachOperator::Operator { .. } => "Operator",
+                MachOperator::Instruction { .. } => "Instruction",
+                MachOperator::Trap { .. } => "Trap",
+                MachOperator::Local { .. } => "Local",
+                MachOperator::StartFn { .. } => "StartFn",
+                MachOperator::StartBody => "StartBody",
+                MachOperator::EndBody => "EndBody",
+            },
+        }
+    }
+
+    /// Attaches the location carried by `annot`, unless one is already set.
+    pub fn at(mut self, annot: &impl ToWasmInfo) -> Self {
+        match &mut self {
+            CompileError::UnsupportedOperator { info, .. }
+            | CompileError::UnsupportedFeature { info, .. }
+            | CompileError::MalformedControl { info }
+            | CompileError::UnknownEntity { info, .. }
+                if info.is_none() =>
+            {
+                *info = annot.to_wasm_info()
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// The location of the error in the input, if known.
+    pub fn info(&self) -> Option<WasmInfo> {
+        match self {
+            CompileError::UnsupportedOperator { info, .. }
+            | CompileError::UnsupportedFeature { info, .. }
+            | CompileError::MalformedControl { info }
+            | CompileError::UnknownEntity { info, .. } => *info,
+            CompileError::Reader(e) => Some(WasmInfo { offset: e.offset() }),
+            _ => None,
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UnsupportedOperator { name, .. } => {
+                write!(f, "unsupported operator `{name}`")?
+            }
+            CompileError::UnsupportedFeature { feature, .. } => {
+                write!(f, "unsupported feature: {feature}")?
+            }
+            CompileError::MalformedControl { .. } => write!(f, "malformed control stack")?,
+            CompileError::UnknownEntity { kind, index, .. } => {
+                write!(f, "unknown {kind} {index}")?
+            }
+            CompileError::UnsupportedMachOperator { variant } => {
+                return write!(f, "unsupported machine operator `{variant}`");
+            }
+            CompileError::Reader(e) => return Display::fmt(e, f),
+            CompileError::Reencode(what) => return write!(f, "cannot re-encode: {what}"),
+            CompileError::Fmt => return write!(f, "error writing generated code"),
+        }
+        if let Some(info) = self.info() {
+            write!(f, " at offset {:#x}", info.offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for CompileError {}
+
+impl From<BinaryReaderError> for CompileError {
+    fn from(e: BinaryReaderError) -> Self {
+        CompileError::Reader(e)
+    }
+}
+
+impl From<core::convert::Infallible> for CompileError {
+    fn from(e: core::convert::Infallible) -> Self {
+        match e {}
+    }
+}
+
+impl<U: Into<CompileError>> From<reencode::Error<U>> for CompileError {
+    fn from(e: reencode::Error<U>) -> Self {
+        use reencode::Error as E;
+        match e {
+            E::ParseError(e) => CompileError::Reader(e),
+            E::UserError(e) => e.into(),
+            E::CanonicalizedHeapTypeReference => {
+                CompileError::Reencode("canonicalized heap type reference")
+            }
+            E::InvalidConstExpr => CompileError::Reencode("invalid constant expression"),
+            E::InvalidCodeSectionSize => CompileError::Reencode("invalid code section size"),
+            E::UnexpectedNonCoreModuleSection
+            | E::UnexpectedNonComponentSection
+            | E::UnsupportedCoreTypeInComponent => CompileError::Reencode("component model item"),
+        }
+    }
+}
+
+impl From<fmt::Error> for CompileError {
+    fn from(_: fmt::Error) -> Self {
+        CompileError::Fmt
+    }
+}
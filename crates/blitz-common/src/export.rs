@@ -0,0 +1,98 @@
+//! Exports and the symbols they are exposed under.
+//!
+//! [`ExportItem`] describes one export with the entity it names resolved in
+//! its index space. Backends that expose exports as symbols (C, native
+//! assembly) name them with [`Symbol`], which keeps export names that are
+//! already identifiers and escapes the others.
+
+use core::fmt::{self, Display, Formatter};
+use wasmparser::{Export, ExternalKind};
+
+use crate::CompileError;
+
+/// The entity an export names.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exported {
+    /// A function, by its index in the function index space.
+    Func(u32),
+    /// A table, by its index in the table index space.
+    Table(u32),
+    /// A memory, by its index in the memory index space.
+    Memory(u32),
+    /// A global, by its index in the global index space.
+    Global(u32),
+}
+
+/// An export of the module.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct ExportItem<'a> {
+    /// The name the export is known by.
+    pub name: &'a str,
+    /// The entity it names.
+    pub item: Exported,
+}
+
+impl<'a> ExportItem<'a> {
+    /// Describes `export`, checking that the entity it names exists given
+    /// the number of functions, tables, memories and globals in `counts`.
+    pub fn new(export: &Export<'a>, counts: [usize; 4]) -> Result<Self, CompileError> {
+        let (item, kind, count) = match export.kind {
+            ExternalKind::Func => (Exported::Func(export.index), "func", counts[0]),
+            ExternalKind::Table => (Exported::Table(export.index), "table", counts[1]),
+            ExternalKind::Memory => (Exported::Memory(export.index), "memory", counts[2]),
+            ExternalKind::Global => (Exported::Global(export.index), "global", counts[3]),
+            ExternalKind::Tag => return Err(CompileError::feature("tag exports")),
+        };
+        if export.index as usize >= count {
+            return Err(CompileError::unknown(kind, export.index));
+        }
+        Ok(Self {
+            name: export.name,
+            item,
+        })
+    }
+
+    /// The symbol the export is exposed under.
+    pub fn symbol(&self) -> Symbol<'a> {
+        Symbol(self.name)
+    }
+}
+
+/// An export name as a C-style identifier.
+///
+/// Names that are already identifiers are written unchanged. Others are
+/// prefixed with `blitz_x`, with `_` doubled and every byte other than an
+/// ASCII letter or digit written as `_` and two hex digits, so distinct
+/// names give distinct symbols unless an identifier starts with `blitz_`,
+/// a prefix the backends reserve.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Symbol<'a>(pub &'a str);
+
+impl Symbol<'_> {
+    /// Whether the name is an identifier, and so is its own symbol.
+    pub fn is_identifier(&self) -> bool {
+        let mut bytes = self.0.bytes();
+        bytes
+            .next()
+            .is_some_and(|b| b.is_ascii_alphabetic() || b == b'_')
+            && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    }
+}
+
+impl Display for Symbol<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_identifier() {
+            return f.write_str(self.0);
+        }
+        f.write_str("blitz_x")?;
+        for b in self.0.bytes() {
+            match b {
+                b'_' => f.write_str("__")?,
+                b if b.is_ascii_alphanumeric() => write!(f, "{}", b as char)?,
+                b => write!(f, "_{b:02x}")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Floating-point operators.
+//!
+//! [`FloatOp`] describes one f32/f64 operator other than constants, loads,
+//! stores and reinterpretations: the values it reads and produces, and
+//! whether it is a bit operation, may trap or saturates. Native backends
+//! lower most of them to calls of runtime routines named after
+//! [`FloatOp::name`], which [`softfloat`](crate::softfloat) builds.
+//! [`Nans`] chooses what NaN results look like.
+
+use wasm_encoder::Instruction;
+use wasmparser::ValType;
+
+/// The bits of the canonical f32 NaN.
+pub const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+
+/// The bits of the canonical f64 NaN.
+pub const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// What NaNs produced by arithmetic look like.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub enum Nans {
+    /// Whatever the target produces, as the specification allows.
+    #[default]
+    Propagate,
+    /// Always the positive canonical NaN, so that results are deterministic.
+    Canonical,
+}
+
+/// How a [`FloatOp`] computes its result.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum FloatKind {
+    /// `abs`, `neg` and `copysign`, which only touch the sign bit and never
+    /// canonicalise.
+    Sign,
+    /// Arithmetic, rounding and conversions producing a float.
+    Arith,
+    /// Comparisons, producing an i32.
+    Compare,
+    /// Conversions to integers, trapping on NaN and out-of-range values.
+    Trunc,
+    /// Conversions to integers, saturating out-of-range values and mapping
+    /// NaN to zero.
+    TruncSat,
+}
+
+/// A floating-point operator.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct FloatOp {
+    /// Name of the operator, such as `f32_add`.
+    pub name: &'static str,
+    /// Types of the operands, deepest first.
+    pub params: &'static [ValType],
+    /// Type of the result.
+    pub result: ValType,
+    /// How the result is computed.
+    pub kind: FloatKind,
+}
+
+impl FloatOp {
+    /// The operator `op` is, if it is a floating-point operator other than a
+    /// constant, load, store or reinterpretation.
+    pub fn of(op: &Instruction<'_>) -> Option<Self> {
+        use FloatKind::*;
+        use Instruction::*;
+        use ValType::{F32, F64, I32, I64};
+        const F32_1: &[ValType] = &[F32];
+        const F32_2: &[ValType] = &[F32, F32];
+        const F64_1: &[ValType] = &[F64];
+        const F64_2: &[ValType] = &[F64, F64];
+        const I32_1: &[ValType] = &[I32];
+        const I64_1: &[ValType] = &[I64];
+        let (name, params, result, kind) = match op {
+            F32Abs => ("f32_abs", F32_1, F32, Sign),
+            F32Neg => ("f32_neg", F32_1, F32, Sign),
+            F32Copysign => ("f32_copysign", F32_2, F32, Sign),
+            F64Abs => ("f64_abs", F64_1, F64, Sign),
+            F64Neg => ("f64_neg", F64_1, F64, Sign),
+            F64Copysign => ("f64_copysign", F64_2, F64, Sign),
+            F32Ceil => ("f32_ceil", F32_1, F32, Arith),
+            F32Floor => ("f32_floor", F32_1, F32, Arith),
+            F32Trunc => ("f32_trunc", F32_1, F32, Arith),
+            F32Nearest => ("f32_nearest", F32_1, F32, Arith),
+            F32Sqrt => ("f32_sqrt", F32_1, F32, Arith),
+            F32Add => ("f32_add", F32_2, F32, Arith),
+            F32Sub => ("f32_sub", F32_2, F32, Arith),
+            F32Mul => ("f32_mul", F32_2, F32, Arith),
+            F32Div => ("f32_div", F32_2, F32, Arith),
+            F32Min => ("f32_min", F32_2, F32, Arith),
+            F32Max => ("f32_max", F32_2, F32, Arith),
+            F64Ceil => ("f64_ceil", F64_1, F64, Arith),
+            F64Floor => ("f64_floor", F64_1, F64, Arith),
+            F64Trunc => ("f64_trunc", F64_1, F64, Arith),
+            F64Nearest => ("f64_nearest", F64_1, F64, Arith),
+            F64Sqrt => ("f64_sqrt", F64_1, F64, Arith),
+            F64Add => ("f64_add", F64_2, F64, Arith),
+            F64Sub => ("f64_sub", F64_2, F64, Arith),
+            F64Mul => ("f64_mul", F64_2, F64, Arith),
+            F64Div => ("f64_div", F64_2, F64, Arith),
+            F64Min => ("f64_min", F64_2, F64, Arith),
+            F64Max => ("f64_max", F64_2, F64, Arith),
+            F32Eq => ("f32_eq", F32_2, I32, Compare),
+            F32Ne => ("f32_ne", F32_2, I32, Compare),
+            F32Lt => ("f32_lt", F32_2, I32, Compare),
+            F32Gt => ("f32_gt", F32_2, I32, Compare),
+            F32Le => ("f32_le", F32_2, I32, Compare),
+            F32Ge => ("f32_ge", F32_2, I32, Compare),
+            F64Eq => ("f64_eq", F64_2, I32, Compare),
+            F64Ne => ("f64_ne", F64_2, I32, Compare),
+            F64Lt => ("f64_lt", F64_2, I32, Compare),
+            F64Gt => ("f64_gt", F64_2, I32, Compare),
+            F64Le => ("f64_le", F64_2, I32, Compare),
+            F64Ge => ("f64_ge", F64_2, I32, Compare),
+            I32TruncF32S => ("i32_trunc_f32_s", F32_1, I32, Trunc),
+            I32TruncF32U => ("i32_trunc_f32_u", F32_1, I32, Trunc),
+            I32TruncF64S => ("i32_trunc_f64_s", F64_1, I32, Trunc),
+            I32TruncF64U => ("i32_trunc_f64_u", F64_1, I32, Trunc),
+            I64TruncF32S => ("i64_trunc_f32_s", F32_1, I64, Trunc),
+            I64TruncF32U => ("i64_trunc_f32_u", F32_1, I64, Trunc),
+            I64TruncF64S => ("i64_trunc_f64_s", F64_1, I64, Trunc),
+            I64TruncF64U => ("i64_trunc_f64_u", F64_1, I64, Trunc),
+            I32TruncSatF32S => ("i32_trunc_sat_f32_s", F32_1, I32, TruncSat),
+            I32TruncSatF32U => ("i32_trunc_sat_f32_u", F32_1, I32, TruncSat),
+            I32TruncSatF64S => ("i32_trunc_sat_f64_s", F64_1, I32, TruncSat),
+            I32TruncSatF64U => ("i32_trunc_sat_f64_u", F64_1, I32, TruncSat),
+            I64TruncSatF32S => ("i64_trunc_sat_f32_s", F32_1, I64, TruncSat),
+            I64TruncSatF32U => ("i64_trunc_sat_f32_u", F32_1, I64, TruncSat),
+            I64TruncSatF64S => ("i64_trunc_sat_f64_s", F64_1, I64, TruncSat),
+            I64TruncSatF64U => ("i64_trunc_sat_f64_u", F64_1, I64, TruncSat),
+            F32ConvertI32S => ("f32_convert_i32_s", I32_1, F32, Arith),
+            F32ConvertI32U => ("f32_convert_i32_u", I32_1, F32, Arith),
+            F32ConvertI64S => ("f32_convert_i64_s", I64_1, F32, Arith),
+            F32ConvertI64U => ("f32_convert_i64_u", I64_1, F32, Arith),
+            F64ConvertI32S => ("f64_convert_i32_s", I32_1, F64, Arith),
+            F64ConvertI32U => ("f64_convert_i32_u", I32_1, F64, Arith),
+            F64ConvertI64S => ("f64_convert_i64_s", I64_1, F64, Arith),
+            F64ConvertI64U => ("f64_convert_i64_u", I64_1, F64, Arith),
+            F32DemoteF64 => ("f32_demote_f64", F64_1, F32, Arith),
+            F64PromoteF32 => ("f64_promote_f32", F32_1, F64, Arith),
+            _ => return None,
+        };
+        Some(Self {
+            name,
+            params,
+            result,
+            kind,
+        })
+    }
+
+    /// Whether `nans` requires the result to be canonicalised.
+    pub fn canonicalises(&self, nans: Nans) -> bool {
+        nans == Nans::Canonical && self.kind == FloatKind::Arith
+    }
+}
@@ -0,0 +1,130 @@
+//! Global variables and constant expressions.
+//!
+//! [`Global`] describes one global of the global index space: its value
+//! type, whether it may be set, and, for defined globals, the value it
+//! starts with. Initialisers are evaluated to a [`ConstValue`] once, so
+//! backends only have to emit a literal or a copy of another global.
+
+use wasmparser::{ConstExpr, GlobalType, Operator, ValType};
+
+use crate::CompileError;
+
+/// The value of a constant expression.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConstValue {
+    /// An `i32` value.
+    I32(i32),
+    /// An `i64` value.
+    I64(i64),
+    /// The bits of an `f32` value.
+    F32(u32),
+    /// The bits of an `f64` value.
+    F64(u64),
+    /// A null reference.
+    RefNull,
+    /// A reference to the function at this index of the function index space.
+    RefFunc(u32),
+    /// The value of the global at this index, known only once it is
+    /// instantiated.
+    GlobalGet(u32),
+}
+
+impl ConstValue {
+    /// Evaluates a constant expression.
+    ///
+    /// Extended constant arithmetic is folded as long as every operand is a
+    /// literal.
+    pub fn eval(expr: &ConstExpr<'_>) -> Result<Self, CompileError> {
+        let mut stack = alloc::vec::Vec::new();
+        for op in expr.get_operators_reader() {
+            let value = match op? {
+                Operator::I32Const { value } => ConstValue::I32(value),
+                Operator::I64Const { value } => ConstValue::I64(value),
+                Operator::F32Const { value } => ConstValue::F32(value.bits()),
+                Operator::F64Const { value } => ConstValue::F64(value.bits()),
+                Operator::RefNull { .. } => ConstValue::RefNull,
+                Operator::RefFunc { function_index } => ConstValue::RefFunc(function_index),
+                Operator::GlobalGet { global_index } => ConstValue::GlobalGet(global_index),
+                op @ (Operator::I32Add
+                | Operator::I32Sub
+                | Operator::I32Mul
+                | Operator::I64Add
+                | Operator::I64Sub
+                | Operator::I64Mul) => {
+                    let (Some(b), Some(a)) = (stack.pop(), stack.pop()) else {
+                        return Err(CompileError::malformed());
+                    };
+                    match (a, b) {
+                        (ConstValue::I32(a), ConstValue::I32(b)) => ConstValue::I32(match op {
+                            Operator::I32Add => a.wrapping_add(b),
+                            Operator::I32Sub => a.wrapping_sub(b),
+                            _ => a.wrapping_mul(b),
+                        }),
+                        (ConstValue::I64(a), ConstValue::I64(b)) => ConstValue::I64(match op {
+                            Operator::I64Add => a.wrapping_add(b),
+                            Operator::I64Sub => a.wrapping_sub(b),
+                            _ => a.wrapping_mul(b),
+                        }),
+                        _ => {
+                            return Err(CompileError::feature(
+                                "extended constant expressions over globals",
+                            ));
+                        }
+                    }
+                }
+                Operator::End => break,
+                op => return Err(CompileError::unsupported(&op)),
+            };
+            stack.push(value);
+        }
+        match stack[..] {
+            [value] => Ok(value),
+            _ => Err(CompileError::malformed()),
+        }
+    }
+}
+
+/// A global in the global index space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Global {
+    /// Index in the global index space.
+    pub index: u32,
+    /// Whether the global is imported rather than defined by the module.
+    pub imported: bool,
+    /// Type of the global's value.
+    pub ty: ValType,
+    /// Whether the global may be set.
+    pub mutable: bool,
+    /// Whether the global is shared between threads.
+    pub shared: bool,
+    /// Initial value of a defined global; `None` for imports.
+    pub init: Option<ConstValue>,
+}
+
+impl Global {
+    /// Describes the global at `index`, of type `ty`, with the initialiser
+    /// `init` if it is defined by the module.
+    pub fn new(
+        index: u32,
+        ty: &GlobalType,
+        init: Option<&ConstExpr<'_>>,
+    ) -> Result<Self, CompileError> {
+        Ok(Self {
+            index,
+            imported: init.is_none(),
+            ty: ty.content_type,
+            mutable: ty.mutable,
+            shared: ty.shared,
+            init: init.map(ConstValue::eval).transpose()?,
+        })
+    }
+}
+
+/// Looks up global `index` among `globals`, reporting an index past the
+/// last global as a [`CompileError`].
+pub fn lookup(globals: &[GlobalType], index: u32) -> Result<&GlobalType, CompileError> {
+    globals
+        .get(index as usize)
+        .ok_or_else(|| CompileError::unknown("global", index))
+}
@@ -0,0 +1,109 @@
+//! Binding imported functions to the host.
+//!
+//! A module calls its imported functions like its own, so every backend
+//! needs to know what each import stands for on the host. [`Resolve`] is
+//! how the embedder says so: it maps each [`FuncImport`] to a [`Binding`],
+//! and [`Imports`] holds the resolver a backend is configured with.
+//!
+//! Host functions follow the calling convention of the backend's own
+//! functions, so the backends bind them without marshalling arguments.
+
+use alloc::{borrow::Cow, sync::Arc};
+
+/// A function import of the module.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct FuncImport<'a> {
+    /// Index of the function in the function index space.
+    pub index: u32,
+    /// The module the function is imported from.
+    pub module: &'a str,
+    /// The name the function is imported under.
+    pub name: &'a str,
+    /// Index of the function's type.
+    pub ty: u32,
+}
+
+impl<'a> FuncImport<'a> {
+    /// Describes import `index`, `name` from `module`, of type `ty`.
+    pub fn new(index: u32, module: &'a str, name: &'a str, ty: u32) -> Self {
+        Self {
+            index,
+            module,
+            name,
+            ty,
+        }
+    }
+}
+
+/// What calls to an imported function invoke.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum Binding<'a> {
+    /// The host function `name` provided under `module`.
+    ///
+    /// The JS backend imports `name` from the ES module `module`; the C and
+    /// native backends link against `name` as a
+    /// [`Symbol`](crate::export::Symbol) and ignore `module`.
+    Host {
+        /// Where the host function is provided.
+        module: Cow<'a, str>,
+        /// The name the host provides it under.
+        name: Cow<'a, str>,
+    },
+    /// Nothing: calls trap.
+    Trap,
+}
+
+/// Decides what each imported function is bound to.
+pub trait Resolve {
+    /// The binding of `import`.
+    fn resolve<'a>(&self, import: &FuncImport<'a>) -> Binding<'a>;
+}
+
+impl<F: for<'a> Fn(&FuncImport<'a>) -> Binding<'a>> Resolve for F {
+    fn resolve<'a>(&self, import: &FuncImport<'a>) -> Binding<'a> {
+        self(import)
+    }
+}
+
+/// Binds every import to the host function of the same module and name.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ByName;
+
+impl Resolve for ByName {
+    fn resolve<'a>(&self, import: &FuncImport<'a>) -> Binding<'a> {
+        Binding::Host {
+            module: Cow::Borrowed(import.module),
+            name: Cow::Borrowed(import.name),
+        }
+    }
+}
+
+/// The resolver a backend binds imports with; [`ByName`] unless set.
+#[derive(Clone, Default)]
+pub struct Imports(Option<Arc<dyn Resolve + Send + Sync>>);
+
+impl Imports {
+    /// Binds imports with `resolver`.
+    pub fn new(resolver: impl Resolve + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(resolver)))
+    }
+
+    /// The binding of `import`.
+    pub fn bind<'a>(&self, import: &FuncImport<'a>) -> Binding<'a> {
+        match &self.0 {
+            Some(resolver) => resolver.resolve(import),
+            None => ByName.resolve(import),
+        }
+    }
+}
+
+impl core::fmt::Debug for Imports {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self.0 {
+            Some(_) => "Imports(..)",
+            None => "Imports(ByName)",
+        })
+    }
+}
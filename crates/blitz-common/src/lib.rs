@@ -4,6 +4,7 @@
 //! compilation targets. It includes:
 //!
 //! - Machine operator abstractions for representing WASM instructions
+//! - A whole-module front end and a common `Backend` trait for all targets
 //! - Dead code elimination (DCE) optimization passes
 //! - Assembly abstractions for various target architectures
 //! - Label and display utilities for code generation
@@ -11,6 +12,8 @@
 //! # Features
 //!
 //! - `asm`: Enables assembly-related functionality (enabled by default)
+//! - `validate`: Enables the validating front end
+//! - `std`: Enables compiling functions on multiple threads
 //!
 //! # Example
 //!
@@ -22,6 +25,8 @@
 #![no_std]
 #[doc(hidden)]
 pub extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 #[doc(hidden)]
 pub mod __ {
     pub use core;
@@ -47,6 +52,7 @@ pub use wasm_encoder;
 pub use wasmparser;
 use wasmparser::{BinaryReaderError, FuncType, FunctionBody, Operator, ValType};
 
+pub use crate::error::CompileError;
 pub use crate::ops::MachOperator;
 
 /// Dead code elimination module.
@@ -97,7 +103,90 @@ pub mod asm;
 /// Defines the intermediate representation used for WASM instructions.
 pub mod ops;
 
+/// Whole-module compilation front end.
+///
+/// Parses complete WASM binaries and drives backends over them.
+pub mod module;
+
+/// Linear memory model.
+///
+/// Describes the address width, limits and sharing of each linear memory.
+pub mod memory;
+
+/// Floating-point operators.
+///
+/// Describes each f32/f64 operator and how NaN results are produced.
+pub mod float;
+
+/// Software floating point.
+///
+/// Builds the runtime routines of float operators from integer operators,
+/// for backends without float instructions.
+pub mod softfloat;
+
+/// Peephole optimisation.
+///
+/// Rewrites short runs of operators with declarative rules.
+pub mod peephole;
+
+/// Operator coverage.
+///
+/// Groups operators and records how much of each group every backend lowers.
+pub mod coverage;
+
+/// Global variables.
+///
+/// Describes each global and evaluates the constant expressions that
+/// initialise them.
+pub mod global;
+
+/// Tables and element segments.
+///
+/// Describes each table and evaluates the items of each element segment.
+pub mod table;
+
+/// Exports.
+///
+/// Resolves each export and names the symbols backends expose them under.
+pub mod export;
+
+/// Imports.
+///
+/// Lets the embedder decide what each imported function is bound to.
+pub mod import;
+
+/// Compilation errors.
+///
+/// Defines `CompileError`, returned by every backend.
+pub mod error;
+
+/// Validating front end.
+///
+/// Validates whole modules and records operand-stack types per operator.
+#[cfg(feature = "validate")]
+pub mod validate;
+
+/// Operand-stack type tracking.
+///
+/// Annotates `MachOperator` streams with the types each operator consumes and produces.
+pub mod typed;
+
+/// Target-agnostic backend interface.
+///
+/// Defines the `Backend` trait and the context shared by all targets.
+pub mod backend;
+
 /// Compiler optimization passes.
 ///
 /// Contains various optimization and transformation passes for WASM code.
 pub mod passes;
+
+/// Liveness of locals.
+///
+/// Finds the locals whose values may still be read around every operator.
+pub mod liveness;
+
+/// Module-level reachability.
+///
+/// Finds the entities of a module that can still be used and renumbers them.
+pub mod reach;
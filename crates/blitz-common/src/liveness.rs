@@ -0,0 +1,317 @@
+//! Liveness of locals.
+//!
+//! [`Liveness`] finds, before and after every operator of a function body,
+//! the locals whose current value may still be read. It follows the
+//! structured control flow of the body, so values read again around a loop
+//! stay live through the whole loop.
+//!
+//! A body is the part of a `MachOperator` stream between `StartBody` and
+//! `EndBody`. Bodies using exception handling or branching reference
+//! operators are not analysed.
+
+use alloc::vec::Vec;
+use wasm_encoder::Instruction;
+use wasmparser::Operator;
+
+use crate::ops::MachOperator;
+
+/// How a local is accessed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    /// `local.get`.
+    Get(u32),
+    /// `local.set`.
+    Set(u32),
+    /// `local.tee`.
+    Tee(u32),
+}
+
+impl Access {
+    /// The local access `o` makes, if any.
+    pub fn of<Annot>(o: &MachOperator<'_, Annot>) -> Option<Self> {
+        match o {
+            MachOperator::Operator { op: Some(op), .. } => match *op {
+                Operator::LocalGet { local_index } => Some(Access::Get(local_index)),
+                Operator::LocalSet { local_index } => Some(Access::Set(local_index)),
+                Operator::LocalTee { local_index } => Some(Access::Tee(local_index)),
+                _ => None,
+            },
+            MachOperator::Instruction { op, .. } => match *op {
+                Instruction::LocalGet(l) => Some(Access::Get(l)),
+                Instruction::LocalSet(l) => Some(Access::Set(l)),
+                Instruction::LocalTee(l) => Some(Access::Tee(l)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The local accessed.
+    pub fn local(self) -> u32 {
+        match self {
+            Access::Get(l) | Access::Set(l) | Access::Tee(l) => l,
+        }
+    }
+}
+
+/// The live locals before and after every operator of a body.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    /// Number of words in each set.
+    words: usize,
+    /// The locals live before each operator, `words` words per operator.
+    live_in: Vec<u64>,
+    /// The locals live after each operator, `words` words per operator.
+    live_out: Vec<u64>,
+}
+
+impl Liveness {
+    /// Analyses `body`, a function body with `num_locals` locals, parameters
+    /// included.
+    ///
+    /// Returns `None` if the body uses control flow the analysis does not
+    /// follow, or accesses a local past `num_locals`.
+    pub fn new<Annot>(body: &[MachOperator<'_, Annot>], num_locals: u32) -> Option<Self> {
+        let n = body.len();
+        let words = (num_locals as usize).div_ceil(64);
+        let steps = body.iter().map(Step::of).collect::<Option<Vec<_>>>()?;
+        let succs = successors(&steps)?;
+        let mut uses = alloc::vec![0u64; n * words];
+        let mut defs = alloc::vec![0u64; n * words];
+        for (i, o) in body.iter().enumerate() {
+            let Some(access) = Access::of(o) else {
+                continue;
+            };
+            let l = access.local();
+            if l >= num_locals {
+                return None;
+            }
+            let set = match access {
+                Access::Get(_) => &mut uses,
+                Access::Set(_) | Access::Tee(_) => &mut defs,
+            };
+            set[i * words + l as usize / 64] |= 1 << (l % 64);
+        }
+        let mut live = Liveness {
+            words,
+            live_in: alloc::vec![0; n * words],
+            live_out: alloc::vec![0; n * words],
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..n).rev() {
+                for w in 0..words {
+                    let out = succs[i]
+                        .iter()
+                        .fold(0, |out, &s| out | live.live_in[s * words + w]);
+                    let at = i * words + w;
+                    let inn = uses[at] | (out & !defs[at]);
+                    changed |= inn != live.live_in[at] || out != live.live_out[at];
+                    live.live_in[at] = inn;
+                    live.live_out[at] = out;
+                }
+            }
+        }
+        Some(live)
+    }
+
+    /// Whether `local` is live before operator `i`.
+    pub fn live_in(&self, i: usize, local: u32) -> bool {
+        self.live_in[i * self.words + local as usize / 64] & (1 << (local % 64)) != 0
+    }
+
+    /// Whether `local` is live after operator `i`.
+    pub fn live_out(&self, i: usize, local: u32) -> bool {
+        self.live_out[i * self.words + local as usize / 64] & (1 << (local % 64)) != 0
+    }
+
+    /// Whether `local` is live on entry to the body, so that its initial
+    /// value may be read.
+    pub fn live_at_entry(&self, local: u32) -> bool {
+        !self.live_in.is_empty() && self.live_in(0, local)
+    }
+
+    /// The locals live after operator `i`.
+    pub fn live_out_locals(&self, i: usize) -> impl Iterator<Item = u32> + '_ {
+        bits(&self.live_out[i * self.words..][..self.words])
+    }
+
+    /// The locals live on entry to the body.
+    pub fn live_at_entry_locals(&self) -> impl Iterator<Item = u32> + '_ {
+        bits(self.live_in.get(..self.words).unwrap_or_default())
+    }
+}
+
+fn bits(words: &[u64]) -> impl Iterator<Item = u32> + '_ {
+    words.iter().enumerate().flat_map(|(w, &word)| {
+        (0..64)
+            .filter(move |b| word & (1 << b) != 0)
+            .map(move |b| w as u32 * 64 + b)
+    })
+}
+
+/// How an operator moves control.
+enum Step {
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>),
+    /// Leaves the function.
+    Exit,
+    /// Falls through to the next operator.
+    Next,
+}
+
+impl Step {
+    fn of<Annot>(o: &MachOperator<'_, Annot>) -> Option<Self> {
+        Some(match o {
+            MachOperator::Operator { op: Some(op), .. } => match op {
+                Operator::Block { .. } => Step::Block,
+                Operator::Loop { .. } => Step::Loop,
+                Operator::If { .. } => Step::If,
+                Operator::Else => Step::Else,
+                Operator::End => Step::End,
+                Operator::Br { relative_depth } => Step::Br(*relative_depth),
+                Operator::BrIf { relative_depth } => Step::BrIf(*relative_depth),
+                Operator::BrTable { targets } => Step::BrTable(
+                    targets
+                        .targets()
+                        .chain([Ok(targets.default())])
+                        .collect::<Result<_, _>>()
+                        .ok()?,
+                ),
+                Operator::Return
+                | Operator::Unreachable
+                | Operator::ReturnCall { .. }
+                | Operator::ReturnCallIndirect { .. }
+                | Operator::ReturnCallRef { .. } => Step::Exit,
+                Operator::Try { .. }
+                | Operator::TryTable { .. }
+                | Operator::Catch { .. }
+                | Operator::CatchAll
+                | Operator::Delegate { .. }
+                | Operator::Throw { .. }
+                | Operator::Rethrow { .. }
+                | Operator::ThrowRef
+                | Operator::BrOnNull { .. }
+                | Operator::BrOnNonNull { .. }
+                | Operator::BrOnCast { .. }
+                | Operator::BrOnCastFail { .. } => return None,
+                _ => Step::Next,
+            },
+            MachOperator::Instruction { op, .. } => match op {
+                Instruction::Block(_) => Step::Block,
+                Instruction::Loop(_) => Step::Loop,
+                Instruction::If(_) => Step::If,
+                Instruction::Else => Step::Else,
+                Instruction::End => Step::End,
+                Instruction::Br(l) => Step::Br(*l),
+                Instruction::BrIf(l) => Step::BrIf(*l),
+                Instruction::BrTable(ls, l) => {
+                    Step::BrTable(ls.iter().copied().chain([*l]).collect())
+                }
+                Instruction::Return
+                | Instruction::Unreachable
+                | Instruction::ReturnCall(_)
+                | Instruction::ReturnCallIndirect { .. }
+                | Instruction::ReturnCallRef(_) => Step::Exit,
+                Instruction::Try(_)
+                | Instruction::TryTable(..)
+                | Instruction::Catch(_)
+                | Instruction::CatchAll
+                | Instruction::Delegate(_)
+                | Instruction::Throw(_)
+                | Instruction::Rethrow(_)
+                | Instruction::ThrowRef
+                | Instruction::BrOnNull(_)
+                | Instruction::BrOnNonNull(_)
+                | Instruction::BrOnCast { .. }
+                | Instruction::BrOnCastFail { .. } => return None,
+                _ => Step::Next,
+            },
+            MachOperator::Trap {
+                conditional: false, ..
+            } => Step::Exit,
+            _ => Step::Next,
+        })
+    }
+}
+
+/// The operators control may move to after each step; an empty list leaves
+/// the function.
+fn successors(steps: &[Step]) -> Option<Vec<Vec<usize>>> {
+    let n = steps.len();
+    // The `else` and `end` of every `block`, `loop` and `if`.
+    let mut elses = alloc::vec![None; n];
+    let mut ends = alloc::vec![None; n];
+    let mut open = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        match step {
+            Step::Block | Step::Loop | Step::If => open.push(i),
+            Step::Else => elses[*open.last()?] = Some(i),
+            Step::End => {
+                if let Some(start) = open.pop() {
+                    ends[start] = Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    // Frames enclosing each step, innermost last; `None` is the function.
+    let mut frames: Vec<Option<usize>> = alloc::vec![None];
+    let next = |i: usize| {
+        if i + 1 < n {
+            alloc::vec![i + 1]
+        } else {
+            Vec::new()
+        }
+    };
+    let mut succs = Vec::with_capacity(n);
+    for (i, step) in steps.iter().enumerate() {
+        let target = |depth: u32| -> Option<Option<usize>> {
+            let frame = *frames.get(frames.len().checked_sub(depth as usize + 1)?)?;
+            Some(frame.map(|start| match steps[start] {
+                Step::Loop => start,
+                _ => ends[start].unwrap_or(n - 1),
+            }))
+        };
+        let succ = match step {
+            Step::Block | Step::Loop => next(i),
+            Step::If => {
+                let mut succ = next(i);
+                succ.push(elses[i].map_or(ends[i]?, |e| e + 1));
+                succ
+            }
+            Step::Else => alloc::vec![ends[(*frames.last()?)?]?],
+            Step::End | Step::Next => next(i),
+            Step::Br(depth) => target(*depth)?.into_iter().collect(),
+            Step::BrIf(depth) => {
+                let mut succ = next(i);
+                succ.extend(target(*depth)?);
+                succ
+            }
+            Step::BrTable(depths) => depths
+                .iter()
+                .map(|&d| target(d))
+                .collect::<Option<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect(),
+            Step::Exit => Vec::new(),
+        };
+        succs.push(succ);
+        match step {
+            Step::Block | Step::Loop | Step::If => frames.push(Some(i)),
+            Step::End => {
+                frames.pop();
+            }
+            _ => {}
+        }
+    }
+    Some(succs)
+}
@@ -0,0 +1,237 @@
+//! Linear memory model.
+//!
+//! [`LinearMemory`] describes one memory of the memory index space in the
+//! terms backends need to lower memory instructions: the width of its
+//! addresses, the size it starts at and may grow to, and whether it is
+//! shared between threads. [`DataSegment`] describes one data segment with
+//! its offset evaluated, and [`Access`] one load or store.
+
+use wasm_encoder::{Instruction, MemArg};
+use wasmparser::{Data, DataKind, MemoryType, ValType};
+
+use crate::{CompileError, global::ConstValue};
+
+/// Log2 of the size of a WebAssembly page, for memories that do not declare
+/// a custom page size.
+pub const PAGE_SIZE_LOG2: u32 = 16;
+
+/// A linear memory in the memory index space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct LinearMemory {
+    /// Index in the memory index space.
+    pub index: u32,
+    /// Whether the memory is imported rather than defined by the module.
+    pub imported: bool,
+    /// Whether addresses are 64-bit (memory64) rather than 32-bit.
+    pub memory64: bool,
+    /// Initial size, in pages.
+    pub min_pages: u64,
+    /// Maximum size, in pages, if the memory declares one.
+    pub max_pages: Option<u64>,
+    /// Whether the memory is shared between threads.
+    pub shared: bool,
+    /// Log2 of the page size in bytes.
+    pub page_size_log2: u32,
+}
+
+impl LinearMemory {
+    /// Describes the memory at `index`, of type `ty`.
+    pub fn new(index: u32, imported: bool, ty: &MemoryType) -> Self {
+        Self {
+            index,
+            imported,
+            memory64: ty.memory64,
+            min_pages: ty.initial,
+            max_pages: ty.maximum,
+            shared: ty.shared,
+            page_size_log2: ty.page_size_log2.unwrap_or(PAGE_SIZE_LOG2),
+        }
+    }
+
+    /// The type of addresses into this memory.
+    pub fn addr_type(&self) -> ValType {
+        if self.memory64 {
+            ValType::I64
+        } else {
+            ValType::I32
+        }
+    }
+
+    /// Size of a page, in bytes.
+    pub fn page_size(&self) -> u64 {
+        1 << self.page_size_log2
+    }
+
+    /// Initial size in bytes, or `None` if it does not fit in a `u64`.
+    pub fn min_bytes(&self) -> Option<u64> {
+        self.min_pages.checked_mul(self.page_size())
+    }
+
+    /// The largest size in bytes the memory can grow to: its maximum, if it
+    /// declares one, capped at what its addresses can reach.
+    pub fn max_bytes(&self) -> u64 {
+        let limit = if self.memory64 { u64::MAX } else { 1 << 32 };
+        self.max_pages
+            .and_then(|max| max.checked_mul(self.page_size()))
+            .map_or(limit, |max| max.min(limit))
+    }
+
+    /// The largest size in pages the memory can grow to, as [`max_bytes`]
+    /// rounded down to whole pages.
+    ///
+    /// [`max_bytes`]: Self::max_bytes
+    pub fn max_size(&self) -> u64 {
+        self.max_bytes() >> self.page_size_log2
+    }
+
+    /// End of an access of `size` bytes at static `offset`, relative to the
+    /// dynamic address: the access is in bounds when the address plus this
+    /// value is at most the current size.
+    ///
+    /// Returns `None` when no address can make the access in bounds, so the
+    /// access always traps.
+    pub fn access_end(&self, offset: u64, size: u64) -> Option<u64> {
+        offset
+            .checked_add(size)
+            .filter(|&end| end <= self.max_bytes())
+    }
+}
+
+/// A load or store between the operand stack and a linear memory.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Access {
+    /// The memory, static offset and alignment of the access.
+    pub memarg: MemArg,
+    /// Number of bytes accessed: 1, 2, 4 or 8.
+    pub size: u64,
+    /// Whether the value is 64 bits wide rather than 32.
+    pub wide: bool,
+    /// Whether the value is a float rather than an integer.
+    pub float: bool,
+    /// Whether a load narrower than the value sign-extends the bytes it
+    /// reads rather than zero-extending them.
+    pub signed: bool,
+    /// Whether the value is stored rather than loaded. Stores narrower than
+    /// the value keep its low bytes.
+    pub store: bool,
+}
+
+impl Access {
+    /// The access `op` makes, if it is a load or store.
+    pub fn of(op: &Instruction<'_>) -> Option<Self> {
+        use Instruction::*;
+        let (memarg, size, wide, float, signed, store) = match op {
+            I32Load(m) => (m, 4, false, false, false, false),
+            I64Load(m) => (m, 8, true, false, false, false),
+            F32Load(m) => (m, 4, false, true, false, false),
+            F64Load(m) => (m, 8, true, true, false, false),
+            I32Load8S(m) => (m, 1, false, false, true, false),
+            I32Load8U(m) => (m, 1, false, false, false, false),
+            I32Load16S(m) => (m, 2, false, false, true, false),
+            I32Load16U(m) => (m, 2, false, false, false, false),
+            I64Load8S(m) => (m, 1, true, false, true, false),
+            I64Load8U(m) => (m, 1, true, false, false, false),
+            I64Load16S(m) => (m, 2, true, false, true, false),
+            I64Load16U(m) => (m, 2, true, false, false, false),
+            I64Load32S(m) => (m, 4, true, false, true, false),
+            I64Load32U(m) => (m, 4, true, false, false, false),
+            I32Store(m) => (m, 4, false, false, false, true),
+            I64Store(m) => (m, 8, true, false, false, true),
+            F32Store(m) => (m, 4, false, true, false, true),
+            F64Store(m) => (m, 8, true, true, false, true),
+            I32Store8(m) => (m, 1, false, false, false, true),
+            I32Store16(m) => (m, 2, false, false, false, true),
+            I64Store8(m) => (m, 1, true, false, false, true),
+            I64Store16(m) => (m, 2, true, false, false, true),
+            I64Store32(m) => (m, 4, true, false, false, true),
+            _ => return None,
+        };
+        Some(Self {
+            memarg: *memarg,
+            size,
+            wide,
+            float,
+            signed,
+            store,
+        })
+    }
+
+    /// Width of the value in bits.
+    pub fn bits(&self) -> u32 {
+        if self.wide { 64 } else { 32 }
+    }
+}
+
+/// How a data segment is used.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DataMode {
+    /// Copied into a memory with `memory.init`.
+    Passive,
+    /// Copied into `memory` at `offset` on instantiation, then dropped.
+    Active {
+        /// Index of the memory the segment initialises.
+        memory: u32,
+        /// Address of the first byte initialised.
+        offset: ConstValue,
+    },
+}
+
+/// A data segment, with its offset evaluated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct DataSegment<'a> {
+    /// Index of the segment in the data section.
+    pub index: u32,
+    /// How the segment is used.
+    pub mode: DataMode,
+    /// The bytes the segment holds.
+    pub bytes: &'a [u8],
+}
+
+impl<'a> DataSegment<'a> {
+    /// Describes the data segment `data`, at `index` in the data section.
+    pub fn new(index: u32, data: &Data<'a>) -> Result<Self, CompileError> {
+        let mode = match &data.kind {
+            DataKind::Passive => DataMode::Passive,
+            DataKind::Active {
+                memory_index,
+                offset_expr,
+            } => DataMode::Active {
+                memory: *memory_index,
+                offset: ConstValue::eval(offset_expr)?,
+            },
+        };
+        Ok(Self {
+            index,
+            mode,
+            bytes: data.data,
+        })
+    }
+
+    /// The bytes still available to `memory.init`: those of passive
+    /// segments, as active segments are dropped once the module is
+    /// instantiated.
+    pub fn live_bytes(&self) -> &'a [u8] {
+        match self.mode {
+            DataMode::Passive => self.bytes,
+            DataMode::Active { .. } => &[],
+        }
+    }
+}
+
+/// Looks up memory `index` among `memories`, reporting an index past the
+/// last memory as a [`CompileError`].
+pub fn lookup(memories: &[LinearMemory], index: u32) -> Result<&LinearMemory, CompileError> {
+    memories
+        .get(index as usize)
+        .ok_or_else(|| CompileError::unknown("memory", index))
+}
+
+/// Looks up data segment `index` among `data`, reporting an index past the
+/// last segment as a [`CompileError`].
+pub fn lookup_data<'a, 'b>(data: &'b [Data<'a>], index: u32) -> Result<&'b Data<'a>, CompileError> {
+    data.get(index as usize)
+        .ok_or_else(|| CompileError::unknown("data", index))
+}
@@ -0,0 +1,410 @@
+//! Whole-module compilation front end.
+//!
+//! This module provides [`Module`], which parses a complete WASM binary and
+//! exposes every section a backend needs in the function, table, memory and
+//! global index spaces (imports first, then definitions). It is the single
+//! entry point for turning raw bytes into a `MachOperator` stream and driving
+//! a [`Backend`](crate::backend::Backend) over it.
+
+use alloc::vec::Vec;
+use wasmparser::{
+    ConstExpr, Data, Element, Export, GlobalType, Import, MemoryType, Parser, Payload, TableInit,
+    TableType, TypeRef,
+};
+
+use wasm_encoder::reencode::RoundtripReencoder;
+
+use crate::{
+    backend::{self, Backend, BackendContext},
+    export::ExportItem,
+    global::{self, Global},
+    import::FuncImport,
+    memory::{self, DataSegment, LinearMemory},
+    ops::{FromWasmInfo, FuncRewriter, fn_mach_operators, mach_operators, mach_operators_with},
+    table::{self, ElemSegment, Table},
+    *,
+};
+
+/// A parsed WASM module, ready to be handed to a backend.
+///
+/// All index spaces follow the WASM convention: imported entities come
+/// first, followed by the entities defined in the module itself.
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub struct Module<'a> {
+    /// Function types from the type section.
+    pub types: Vec<FuncType>,
+    /// Every import, in declaration order.
+    pub imports: Vec<Import<'a>>,
+    /// `(module, name)` pairs of the imported functions.
+    pub func_imports: Vec<(&'a str, &'a str)>,
+    /// Type index of every function, imports included.
+    pub funcs: Vec<u32>,
+    /// Bodies of the defined functions.
+    pub bodies: Vec<FunctionBody<'a>>,
+    /// Types of every table, imports included.
+    pub tables: Vec<TableType>,
+    /// Initialisers of the defined tables; `None` for null references.
+    pub table_inits: Vec<Option<ConstExpr<'a>>>,
+    /// Types of every memory, imports included.
+    pub memories: Vec<MemoryType>,
+    /// Types of every global, imports included.
+    pub globals: Vec<GlobalType>,
+    /// Initialisers of the defined globals.
+    pub global_inits: Vec<ConstExpr<'a>>,
+    /// Data segments.
+    pub data: Vec<Data<'a>>,
+    /// Element segments.
+    pub elements: Vec<Element<'a>>,
+    /// Exports, in declaration order.
+    pub exports: Vec<Export<'a>>,
+    /// The start function, if any.
+    pub start: Option<u32>,
+    /// The binary the module was parsed from.
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Module<'a> {
+    /// Parses a complete WASM binary.
+    ///
+    /// The returned module borrows from `bytes`; function bodies are not
+    /// decoded until `mach_operators` is iterated. Modules defining or
+    /// importing tags are rejected.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, CompileError> {
+        let mut m = Module {
+            bytes,
+            ..Module::default()
+        };
+        for payload in Parser::new(0).parse_all(bytes) {
+            match payload? {
+                Payload::TypeSection(r) => {
+                    for group in r {
+                        for ty in group?.into_types() {
+                            match ty.composite_type.inner {
+                                wasmparser::CompositeInnerType::Func(f) => m.types.push(f),
+                                _ => {
+                                    return Err(CompileError::feature(
+                                        "non-function composite types",
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+                Payload::ImportSection(r) => {
+                    for i in r {
+                        let i = i?;
+                        match i.ty {
+                            TypeRef::Func(t) => {
+                                m.funcs.push(t);
+                                m.func_imports.push((i.module, i.name));
+                            }
+                            TypeRef::Table(t) => m.tables.push(t),
+                            TypeRef::Memory(t) => m.memories.push(t),
+                            TypeRef::Global(t) => m.globals.push(t),
+                            TypeRef::Tag(_) => return Err(CompileError::feature("tag imports")),
+                        }
+                        m.imports.push(i);
+                    }
+                }
+                Payload::FunctionSection(r) => {
+                    for f in r {
+                        m.funcs.push(f?);
+                    }
+                }
+                Payload::TableSection(r) => {
+                    for t in r {
+                        let t = t?;
+                        m.tables.push(t.ty);
+                        m.table_inits.push(match t.init {
+                            TableInit::RefNull => None,
+                            TableInit::Expr(e) => Some(e),
+                        });
+                    }
+                }
+                Payload::MemorySection(r) => {
+                    for t in r {
+                        m.memories.push(t?);
+                    }
+                }
+                Payload::GlobalSection(r) => {
+                    for g in r {
+                        let g = g?;
+                        m.globals.push(g.ty);
+                        m.global_inits.push(g.init_expr);
+                    }
+                }
+                Payload::ExportSection(r) => {
+                    for e in r {
+                        m.exports.push(e?);
+                    }
+                }
+                Payload::StartSection { func, .. } => m.start = Some(func),
+                Payload::ElementSection(r) => {
+                    for e in r {
+                        m.elements.push(e?);
+                    }
+                }
+                Payload::DataSection(r) => {
+                    for d in r {
+                        m.data.push(d?);
+                    }
+                }
+                // exception handling is not compiled, and nothing keeps the
+                // types tags name live when pruning
+                Payload::TagSection(_) => return Err(CompileError::feature("tags")),
+                Payload::CodeSectionEntry(body) => m.bodies.push(body),
+                _ => {}
+            }
+        }
+        Ok(m)
+    }
+
+    /// Number of imported functions.
+    pub fn num_func_imports(&self) -> u32 {
+        self.func_imports.len() as u32
+    }
+
+    /// The type of the function at `index` in the function index space.
+    pub fn func_type(&self, index: u32) -> &FuncType {
+        &self.types[self.funcs[index as usize] as usize]
+    }
+
+    /// The linear memory at `index` in the memory index space.
+    pub fn memory(&self, index: u32) -> Option<LinearMemory> {
+        let ty = self.memories.get(index as usize)?;
+        let imported = self
+            .imports
+            .iter()
+            .filter(|i| matches!(i.ty, TypeRef::Memory(_)))
+            .count();
+        Some(LinearMemory::new(index, (index as usize) < imported, ty))
+    }
+
+    /// Every linear memory, imports included.
+    pub fn linear_memories(&self) -> impl Iterator<Item = LinearMemory> + '_ {
+        (0..self.memories.len() as u32).filter_map(|index| self.memory(index))
+    }
+
+    /// The global at `index` in the global index space, with its
+    /// initialiser evaluated.
+    pub fn global(&self, index: u32) -> Result<Global, CompileError> {
+        let ty = global::lookup(&self.globals, index)?;
+        let imported = self.globals.len() - self.global_inits.len();
+        let init = (index as usize)
+            .checked_sub(imported)
+            .map(|i| &self.global_inits[i]);
+        Global::new(index, ty, init)
+    }
+
+    /// Every global, imports included.
+    pub fn global_vars(&self) -> impl Iterator<Item = Result<Global, CompileError>> + '_ {
+        (0..self.globals.len() as u32).map(|index| self.global(index))
+    }
+
+    /// The table at `index` in the table index space, with its initialiser
+    /// evaluated.
+    pub fn table(&self, index: u32) -> Result<Table, CompileError> {
+        let ty = table::lookup(&self.tables, index)?;
+        let imported = self.tables.len() - self.table_inits.len();
+        match (index as usize).checked_sub(imported) {
+            None => Table::new(index, true, ty, None),
+            Some(i) => Table::new(index, false, ty, self.table_inits[i].as_ref()),
+        }
+    }
+
+    /// Every table, imports included.
+    pub fn table_defs(&self) -> impl Iterator<Item = Result<Table, CompileError>> + '_ {
+        (0..self.tables.len() as u32).map(|index| self.table(index))
+    }
+
+    /// The element segment at `index`, with its items evaluated.
+    pub fn elem_segment(&self, index: u32) -> Result<ElemSegment, CompileError> {
+        ElemSegment::new(index, table::lookup_elem(&self.elements, index)?)
+    }
+
+    /// Every element segment, in declaration order.
+    pub fn elem_segments(&self) -> impl Iterator<Item = Result<ElemSegment, CompileError>> + '_ {
+        (0..self.elements.len() as u32).map(|index| self.elem_segment(index))
+    }
+
+    /// The data segment at `index`, with its offset evaluated.
+    pub fn data_segment(&self, index: u32) -> Result<DataSegment<'a>, CompileError> {
+        DataSegment::new(index, memory::lookup_data(&self.data, index)?)
+    }
+
+    /// Every data segment, in declaration order.
+    pub fn data_segments(
+        &self,
+    ) -> impl Iterator<Item = Result<DataSegment<'a>, CompileError>> + '_ {
+        (0..self.data.len() as u32).map(|index| self.data_segment(index))
+    }
+
+    /// Describes every function import, in the function index space.
+    pub fn func_import_items(&self) -> impl Iterator<Item = FuncImport<'a>> + '_ {
+        self.func_imports
+            .iter()
+            .zip(&self.funcs)
+            .enumerate()
+            .map(|(index, (&(module, name), &ty))| FuncImport::new(index as u32, module, name, ty))
+    }
+
+    /// Describes every export, in declaration order.
+    pub fn export_items(&self) -> impl Iterator<Item = Result<ExportItem<'a>, CompileError>> + '_ {
+        let counts = [
+            self.funcs.len(),
+            self.tables.len(),
+            self.memories.len(),
+            self.globals.len(),
+        ];
+        self.exports.iter().map(move |e| ExportItem::new(e, counts))
+    }
+
+    /// The start function, by its index in the function index space.
+    pub fn start_func(&self) -> Result<Option<u32>, CompileError> {
+        match self.start {
+            Some(f) if f as usize >= self.funcs.len() => Err(CompileError::unknown("func", f)),
+            start => Ok(start),
+        }
+    }
+
+    /// Function types converted for the `wasm_encoder`-based backends.
+    pub fn encoder_types(
+        &self,
+    ) -> Result<Vec<wasm_encoder::FuncType>, wasm_encoder::reencode::Error> {
+        self.types
+            .iter()
+            .cloned()
+            .map(wasm_encoder::FuncType::try_from)
+            .collect()
+    }
+
+    /// Produces the `MachOperator` stream for every defined function.
+    ///
+    /// See [`mach_operators`] for the shape of the stream.
+    pub fn mach_operators<Annot: FromWasmInfo, E: From<BinaryReaderError>>(
+        &self,
+    ) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+        mach_operators(
+            &self.bodies,
+            &self.funcs,
+            &self.types,
+            self.num_func_imports(),
+        )
+    }
+
+    /// Produces the `MachOperator` stream for every defined function,
+    /// passing every operator through `rewriter`.
+    ///
+    /// See [`mach_operators_with`] for how the rewriter is applied.
+    pub fn mach_operators_with<
+        Annot: FromWasmInfo,
+        E: From<BinaryReaderError>,
+        R: FuncRewriter<'a, E>,
+    >(
+        &self,
+        rewriter: R,
+    ) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+        mach_operators_with(
+            &self.bodies,
+            &self.funcs,
+            &self.types,
+            self.num_func_imports(),
+            rewriter,
+        )
+    }
+
+    /// Produces the `MachOperator` stream of a single defined function.
+    ///
+    /// `index` counts defined functions only, as `StartFn` does. The stream
+    /// is the part of [`mach_operators`](Module::mach_operators) belonging
+    /// to that function, so functions can be compiled independently.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not the index of a defined function.
+    pub fn fn_mach_operators<Annot: FromWasmInfo, E: From<BinaryReaderError>>(
+        &self,
+        index: u32,
+    ) -> Result<impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> + use<'a, Annot, E>, E>
+    {
+        fn_mach_operators(index, &self.bodies[index as usize], self)
+    }
+
+    /// Drives `backend` over an operator stream derived from this module,
+    /// rewriting operators with a `RoundtripReencoder`.
+    ///
+    /// Module-level code is emitted first, with
+    /// [`on_module`](Backend::on_module), and last, with
+    /// [`on_end`](Backend::on_end). The stream is usually
+    /// `mach_operators` with any passes applied; use [`backend::drive`] to
+    /// supply a different rewriter.
+    pub fn drive<'b, Annot, E, B: Backend<RoundtripReencoder, Annot> + ?Sized>(
+        &self,
+        ops: impl IntoIterator<Item = Result<MachOperator<'b, Annot>, E>>,
+        backend: &mut B,
+    ) -> Result<(), E>
+    where
+        E: From<B::Error> + From<wasm_encoder::reencode::Error>,
+    {
+        let mut rewriter = RoundtripReencoder;
+        let mut cx = BackendContext::new(self, &mut rewriter)?;
+        backend.on_module(&mut cx)?;
+        backend::drive(&mut cx, ops, backend)?;
+        Ok(backend.on_end(&mut cx)?)
+    }
+
+    /// Compiles every defined function with `backend`, without any passes.
+    pub fn compile<E, B: Backend + ?Sized>(&self, backend: &mut B) -> Result<(), E>
+    where
+        E: From<BinaryReaderError> + From<B::Error> + From<wasm_encoder::reencode::Error>,
+    {
+        self.drive(self.mach_operators::<(), E>(), backend)
+    }
+
+    /// Compiles every defined function with forks of `backend` on up to
+    /// `threads` threads, without any passes.
+    ///
+    /// The functions are split into contiguous runs, each compiled into its
+    /// own fork; the forks are linked back in function order, between the
+    /// module-level code `backend` emits itself, so the output matches
+    /// [`compile`](Module::compile).
+    #[cfg(feature = "std")]
+    pub fn compile_parallel<E, B: backend::ForkBackend + Send>(
+        &self,
+        backend: &mut B,
+        threads: core::num::NonZeroUsize,
+    ) -> Result<(), E>
+    where
+        E: From<BinaryReaderError> + From<B::Error> + From<wasm_encoder::reencode::Error> + Send,
+    {
+        backend.on_module(&mut BackendContext::new(self, &mut RoundtripReencoder)?)?;
+        let n = self.bodies.len();
+        let run = n.div_ceil(threads.get()).max(1);
+        let forks = std::thread::scope(|s| {
+            let handles = (0..n)
+                .step_by(run)
+                .map(|start| {
+                    let mut fork = backend.fork();
+                    s.spawn(move || {
+                        let mut rewriter = RoundtripReencoder;
+                        let mut cx = BackendContext::new(self, &mut rewriter)?;
+                        for index in start..(start + run).min(n) {
+                            let ops = self.fn_mach_operators::<(), E>(index as u32)?;
+                            backend::drive(&mut cx, ops, &mut fork)?;
+                        }
+                        Ok::<_, E>(fork)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|p| std::panic::resume_unwind(p)))
+                .collect::<Vec<_>>()
+        });
+        for fork in forks {
+            backend.link(fork?)?;
+        }
+        Ok(backend.on_end(&mut BackendContext::new(self, &mut RoundtripReencoder)?)?)
+    }
+}
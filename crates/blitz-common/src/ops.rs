@@ -7,7 +7,10 @@
 use alloc::boxed::Box;
 use wasm_encoder::Instruction;
 
-use crate::*;
+use crate::{
+    typed::{TypeEnv, max_stack_height},
+    *,
+};
 
 /// Macro for pattern matching on machine operators.
 ///
@@ -74,6 +77,40 @@ impl FromWasmInfo for WasmInfo {
     }
 }
 
+/// Trait for annotation types that may carry WASM metadata.
+///
+/// The counterpart of `FromWasmInfo`, used to locate errors reported
+/// against an annotated operator.
+pub trait ToWasmInfo {
+    /// Returns the WASM metadata carried by this annotation, if any.
+    fn to_wasm_info(&self) -> Option<WasmInfo>;
+}
+impl ToWasmInfo for () {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        None
+    }
+}
+impl<T: ToWasmInfo> ToWasmInfo for Option<T> {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        self.as_ref().and_then(T::to_wasm_info)
+    }
+}
+impl ToWasmInfo for WasmInfo {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        Some(*self)
+    }
+}
+impl<T: ToWasmInfo + ?Sized> ToWasmInfo for &T {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        (**self).to_wasm_info()
+    }
+}
+impl<T: ToWasmInfo + ?Sized> ToWasmInfo for &mut T {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        (**self).to_wasm_info()
+    }
+}
+
 /// Represents either an encoded instruction or a parsed operator.
 ///
 /// This enum allows code to work with WASM operations in either their
@@ -85,28 +122,49 @@ pub enum InstructionOrOperator<'a> {
     /// A parsed WASM operator.
     Operator(Operator<'a>),
 }
-// pub trait FuncRewriter<E>:
-//     for<'c, 'b> FnMut(
-//     u32,
-//     &'c [u32],
-//     &'c [FuncType],
-//     &'c Operator<'b>,
-// ) -> Box<dyn Iterator<Item = Result<InstructionOrOperator<'b>, E>> + 'c>
-// {
-// }
-// impl<
-//     E,
-//     T: for<'c, 'b> FnMut(
-//             u32,
-//             &'c [u32],
-//             &'c [FuncType],
-//             &'c Operator<'b>,
-//         )
-//             -> Box<dyn Iterator<Item = Result<InstructionOrOperator<'b>, E>> + 'c>
-//         + ?Sized,
-// > FuncRewriter<E> for T
-// {
-// }
+
+/// What a [`FuncRewriter`] knows about the operator it is rewriting.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct RewriteCx<'c> {
+    /// Index of the function among the defined functions, as in `StartFn`.
+    pub id: u32,
+    /// Metadata of the function.
+    pub data: &'c FnData,
+    /// Number of locals declared so far, parameters included.
+    pub num_locals: u32,
+    /// Type index of every function, imports included.
+    pub sigs_per: &'c [u32],
+    /// Function types from the type section.
+    pub sigs: &'c [FuncType],
+    /// Number of imported functions.
+    pub imports: u32,
+    /// Offset of the operator in the WASM binary.
+    pub offset: usize,
+}
+
+/// A per-operator rewriting hook for [`mach_operators_with`].
+///
+/// Called with every operator of every function body, including the
+/// trailing `Return`; the returned instructions and operators replace it in
+/// the stream, annotated with its offset. Returning the operator unchanged
+/// keeps it. Implemented for every closure of the right shape.
+///
+/// [`RewriteCx::data`] describes the body before rewriting. As replacements
+/// can push deeper or add calls, loops and memory accesses, the `StartFn`
+/// of the rewritten stream carries no `max_stack` bound and sets
+/// `has_calls`, `has_loops` and `uses_memory`.
+pub trait FuncRewriter<'a, E>:
+    FnMut(&RewriteCx<'_>, Operator<'a>) -> Result<Vec<InstructionOrOperator<'a>>, E>
+{
+}
+impl<
+    'a,
+    E,
+    T: FnMut(&RewriteCx<'_>, Operator<'a>) -> Result<Vec<InstructionOrOperator<'a>>, E> + ?Sized,
+> FuncRewriter<'a, E> for T
+{
+}
 
 /// Converts WASM function bodies into a stream of machine operators.
 ///
@@ -124,80 +182,264 @@ pub enum InstructionOrOperator<'a> {
 /// # Returns
 ///
 /// An iterator yielding `MachOperator` items or errors during parsing.
-pub fn mach_operators<'a, 'b, Annot: FromWasmInfo, E: From<BinaryReaderError>>(
+pub fn mach_operators<'a, Annot: FromWasmInfo, E: From<BinaryReaderError>>(
     code: &[FunctionBody<'a>],
     sigs_per: &[u32],
     sigs: &[FuncType],
     imports: u32,
-    // mut func_rewriter: Option<&'b mut (dyn FuncRewriter<E> + '_)>,
 ) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
     return code
         .iter()
-        .zip(
-            sigs_per
-                .iter()
-                .cloned()
-                .skip(imports as usize)
-                .map(|a| &sigs[a as usize]),
-        )
+        .take(sigs_per.len().saturating_sub(imports as usize))
         .enumerate()
-        .flat_map(move |(i, (a, sig))| {
-            // let mut func_rewriter = match &mut func_rewriter {
-            //     None => None,
-            //     Some(a) => Some(match &mut **a {
-            //         b => unsafe { transmute::<_, &'b mut (dyn FuncRewriter<E> + '_)>(b) },
-            //     }),
-            // };
-            let v = a.get_operators_reader()?;
-            let l = a.get_locals_reader()?;
-            Ok::<_, E>(
-                [MachOperator::StartFn {
-                    id: i as u32,
-                    data: FnData {
-                        num_params: sig.params().len(),
-                        num_returns: sig.results().len(),
-                        control_depth: control_depth(a),
-                    },
-                }]
-                .into_iter()
-                .map(Ok)
-                .chain(l.into_iter().map(|a| {
-                    a.map(|(a, b)| MachOperator::Local { count: a, ty: b })
-                        .map_err(E::from)
-                }))
-                .chain([MachOperator::StartBody].map(Ok))
-                .chain(v.into_iter_with_offsets().flat_map(
-                    move |v: Result<(Operator<'_>, usize), BinaryReaderError>| {
-                        [v.map(|(op, offset)| MachOperator::Operator {
-                            op: Some(op),
-                            annot: Annot::from_wasm_info(WasmInfo { offset }),
-                        })
-                        .map_err(E::from)]
-                        .into_iter()
-                        .collect::<Vec<_>>()
-                    },
-                ))
-                .chain(
-                    [
-                        MachOperator::Operator {
-                            op: Some(Operator::Return),
-                            annot: Annot::from_wasm_info(WasmInfo {
-                                offset: a.range().end,
-                            }),
-                        },
-                        MachOperator::EndBody,
-                    ]
-                    .map(Ok),
-                ),
+        .flat_map(move |(i, a)| {
+            fn_mach_operators(
+                i as u32,
+                a,
+                &Signatures {
+                    sigs_per,
+                    sigs,
+                    imports,
+                },
             )
         })
         .flatten();
 }
 
+/// Converts WASM function bodies into a stream of machine operators,
+/// passing every operator through `rewriter`.
+///
+/// Like [`mach_operators`], but each operator is replaced by what the
+/// [`FuncRewriter`] returns for it, so instrumentation and lowering happen
+/// while the stream is produced.
+pub fn mach_operators_with<
+    'a,
+    Annot: FromWasmInfo,
+    E: From<BinaryReaderError>,
+    R: FuncRewriter<'a, E>,
+>(
+    code: &[FunctionBody<'a>],
+    sigs_per: &[u32],
+    sigs: &[FuncType],
+    imports: u32,
+    mut rewriter: R,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    let mut id = 0;
+    let mut data = FnData::default();
+    let mut num_locals = 0;
+    mach_operators::<WasmInfo, E>(code, sigs_per, sigs, imports).flat_map(move |o| {
+        let (op, info) = match o {
+            Ok(MachOperator::Operator {
+                op: Some(op),
+                annot,
+            }) => (op, annot),
+            o => {
+                let o = match o {
+                    Ok(MachOperator::StartFn { id: i, data: d }) => {
+                        id = i;
+                        num_locals = d.num_params as u32;
+                        data = d.clone();
+                        // The rewriter may change any of these.
+                        Ok(MachOperator::StartFn {
+                            id: i,
+                            data: FnData {
+                                max_stack: None,
+                                has_calls: true,
+                                has_loops: true,
+                                uses_memory: true,
+                                ..d
+                            },
+                        })
+                    }
+                    Ok(MachOperator::Local { count, ty }) => {
+                        num_locals += count;
+                        Ok(MachOperator::Local { count, ty })
+                    }
+                    o => o,
+                };
+                return [o.and_then(|o| o.map(&mut |a| Ok(Annot::from_wasm_info(a))))]
+                    .into_iter()
+                    .collect::<Vec<_>>();
+            }
+        };
+        let cx = RewriteCx {
+            id,
+            data: &data,
+            num_locals,
+            sigs_per,
+            sigs,
+            imports,
+            offset: info.offset,
+        };
+        match rewriter(&cx, op) {
+            Ok(ops) => ops
+                .into_iter()
+                .map(|op| {
+                    let annot = Annot::from_wasm_info(info);
+                    Ok(match op {
+                        InstructionOrOperator::Instruction(op) => {
+                            MachOperator::Instruction { op, annot }
+                        }
+                        InstructionOrOperator::Operator(op) => MachOperator::Operator {
+                            op: Some(op),
+                            annot,
+                        },
+                    })
+                })
+                .collect(),
+            Err(e) => [Err(e)].into_iter().collect(),
+        }
+    })
+}
+
+/// Converts a single WASM function body into a stream of machine operators.
+///
+/// Produces exactly the items [`mach_operators`] yields for the function, so
+/// functions can be processed independently of each other.
+///
+/// # Arguments
+///
+/// * `id` - Index of the function among the defined functions, carried by `StartFn`
+/// * `body` - The function body to process
+/// * `module` - Types of the module the function belongs to
+///
+/// # Panics
+///
+/// Panics if `module` has no function type for `id`.
+pub fn fn_mach_operators<'a, Annot: FromWasmInfo, E: From<BinaryReaderError>>(
+    id: u32,
+    body: &FunctionBody<'a>,
+    module: &dyn TypeEnv,
+) -> Result<impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> + use<'a, Annot, E>, E> {
+    let sig = module
+        .func_type_at(id + module.num_func_imports())
+        .expect("function index out of range");
+    let v = body.get_operators_reader()?;
+    let l = body.get_locals_reader()?;
+    let end = body.range().end;
+    Ok([MachOperator::StartFn {
+        id,
+        data: fn_data(body, sig, module),
+    }]
+    .into_iter()
+    .map(Ok)
+    .chain(l.into_iter().map(|a| {
+        a.map(|(a, b)| MachOperator::Local { count: a, ty: b })
+            .map_err(E::from)
+    }))
+    .chain([MachOperator::StartBody].map(Ok))
+    .chain(v.into_iter_with_offsets().flat_map(
+        move |v: Result<(Operator<'_>, usize), BinaryReaderError>| {
+            [v.map(|(op, offset)| MachOperator::Operator {
+                op: Some(op),
+                annot: Annot::from_wasm_info(WasmInfo { offset }),
+            })
+            .map_err(E::from)]
+            .into_iter()
+            .collect::<Vec<_>>()
+        },
+    ))
+    .chain(
+        [
+            MachOperator::Operator {
+                op: Some(Operator::Return),
+                annot: Annot::from_wasm_info(WasmInfo { offset: end }),
+            },
+            MachOperator::EndBody,
+        ]
+        .map(Ok),
+    ))
+}
+
+/// The signature tables [`mach_operators`] works from, as a [`TypeEnv`].
+struct Signatures<'s> {
+    sigs_per: &'s [u32],
+    sigs: &'s [FuncType],
+    imports: u32,
+}
+
+impl TypeEnv for Signatures<'_> {
+    fn type_at(&self, index: u32) -> Option<&FuncType> {
+        self.sigs.get(index as usize)
+    }
+    fn func_type_at(&self, index: u32) -> Option<&FuncType> {
+        self.type_at(*self.sigs_per.get(index as usize)?)
+    }
+    fn num_func_imports(&self) -> u32 {
+        self.imports
+    }
+}
+
+/// Computes the [`FnData`] of a function body.
+///
+/// Reader errors are left for the operator stream to report.
+fn fn_data(body: &FunctionBody<'_>, sig: &FuncType, module: &(impl TypeEnv + ?Sized)) -> FnData {
+    let locals = body
+        .get_locals_reader()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .flat_map(|(count, ty)| core::iter::repeat_n(ty, count as usize))
+        .collect::<Vec<_>>();
+    let mut data = FnData {
+        num_params: sig.params().len(),
+        num_returns: sig.results().len(),
+        control_depth: control_depth(body),
+        params: sig.params().to_vec(),
+        results: sig.results().to_vec(),
+        max_stack: max_stack_height(module, sig, &locals, body),
+        locals,
+        ..FnData::default()
+    };
+    for op in body.get_operators_reader().into_iter().flatten().flatten() {
+        match op {
+            Operator::Call { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::CallRef { .. }
+            | Operator::ReturnCall { .. }
+            | Operator::ReturnCallIndirect { .. }
+            | Operator::ReturnCallRef { .. } => data.has_calls = true,
+            Operator::Loop { .. } => data.has_loops = true,
+            Operator::I32Load { .. }
+            | Operator::I64Load { .. }
+            | Operator::F32Load { .. }
+            | Operator::F64Load { .. }
+            | Operator::I32Load8S { .. }
+            | Operator::I32Load8U { .. }
+            | Operator::I32Load16S { .. }
+            | Operator::I32Load16U { .. }
+            | Operator::I64Load8S { .. }
+            | Operator::I64Load8U { .. }
+            | Operator::I64Load16S { .. }
+            | Operator::I64Load16U { .. }
+            | Operator::I64Load32S { .. }
+            | Operator::I64Load32U { .. }
+            | Operator::I32Store { .. }
+            | Operator::I64Store { .. }
+            | Operator::F32Store { .. }
+            | Operator::F64Store { .. }
+            | Operator::I32Store8 { .. }
+            | Operator::I32Store16 { .. }
+            | Operator::I64Store8 { .. }
+            | Operator::I64Store16 { .. }
+            | Operator::I64Store32 { .. }
+            | Operator::MemorySize { .. }
+            | Operator::MemoryGrow { .. }
+            | Operator::MemoryFill { .. }
+            | Operator::MemoryCopy { .. }
+            | Operator::MemoryInit { .. } => data.uses_memory = true,
+            _ => {}
+        }
+    }
+    data
+}
+
 /// Metadata about a WASM function.
 ///
 /// Contains information needed by code generators about the function's
-/// signature and structure.
+/// signature and structure. [`mach_operators`] computes it once per
+/// function, so backends can size frames up front; passes that add locals
+/// or deepen the operand stack update it.
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 #[non_exhaustive]
 pub struct FnData {
@@ -207,6 +449,22 @@ pub struct FnData {
     pub num_returns: usize,
     /// Maximum nesting depth of control flow structures in the function.
     pub control_depth: usize,
+    /// Parameter types.
+    pub params: Vec<ValType>,
+    /// Result types.
+    pub results: Vec<ValType>,
+    /// Types of the declared locals, one entry per local, following the
+    /// parameters in the local index space.
+    pub locals: Vec<ValType>,
+    /// Maximum operand-stack height, or `None` if the body contains
+    /// operators whose stack effect is not known.
+    pub max_stack: Option<usize>,
+    /// Whether the function contains direct, indirect or tail calls.
+    pub has_calls: bool,
+    /// Whether the function contains loops.
+    pub has_loops: bool,
+    /// Whether the function accesses linear memory.
+    pub uses_memory: bool,
 }
 
 /// A machine-level operation in the compilation pipeline.
@@ -429,9 +687,8 @@ impl<
                 if let MachOperator::Local { count: a, ty: b } = &o {
                     self.locals += *a;
                 }
-                let mut tmp = self.data.clone();
                 return Some(Ok((self.handler)(
-                    &mut tmp,
+                    &mut self.data,
                     self.locals,
                     o,
                     &mut self.userdata,
@@ -458,5 +715,34 @@ pub trait IteratorExt: Iterator {
             locals: 0,
         }
     }
+    /// Like [`scan_mach`](IteratorExt::scan_mach), for handlers returning
+    /// the operators to emit in place of each one: yields them one at a
+    /// time, followed by any error of the input.
+    fn flat_scan_mach<
+        'a,
+        A,
+        F: FnMut(&mut FnData, u32, MachOperator<'a, A>, &mut D) -> Vec<T>,
+        T,
+        D,
+        E,
+    >(
+        self,
+        handler: F,
+        userdata: D,
+    ) -> impl Iterator<Item = Result<T, E>>
+    where
+        Self: Sized + Iterator<Item = Result<MachOperator<'a, A>, E>>,
+    {
+        self.scan_mach(handler, userdata).flat_map(flatten_results)
+    }
 }
 impl<T: Iterator + ?Sized> IteratorExt for T {}
+
+/// Yields the items of `r` as `Ok`s, or its error alone.
+pub fn flatten_results<T, E>(r: Result<Vec<T>, E>) -> impl Iterator<Item = Result<T, E>> {
+    let (items, err) = match r {
+        Ok(items) => (items, None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    items.into_iter().map(Ok).chain(err.map(Err))
+}
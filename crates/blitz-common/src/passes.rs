@@ -4,8 +4,14 @@
 //! can be applied to WASM code during compilation. Each pass transforms the
 //! stream of machine operators in some way.
 
-use crate::{ops::*, *};
+use crate::{
+    liveness::{Access, Liveness},
+    ops::*,
+    reach::Reach,
+    *,
+};
 use alloc::vec::Vec;
+use wasm_encoder::Instruction;
 
 /// Macro to apply dead code elimination pass to a machine operator stream.
 ///
@@ -50,226 +56,1319 @@ macro_rules! dce_pass {
         }
     };
 }
-// #[macro_export]
-// macro_rules! load_coalescing {
-//     ($a:expr) => {
-//         match match $a {
-//             a => $crate::ops::IteratorExt::scan_mach(a, $crate::passes::load_coalescing_pass, ()),
-//         } {
-//             a => $crate::__::core::iter::Iterator::flatten(a),
-//         }
-//     };
-// }
-// pub fn load_coalescing_pass<'a, Annot: Clone>(
-//     d: &mut FnData,
-//     l: u32,
-//     o: MachOperator<'a, Annot>,
-//     x: &mut (),
-// ) -> Vec<MachOperator<'a, Annot>> {
-//     match o {
-//         MachOperator::StartBody => [
-//             MachOperator::Local {
-//                 count: 2,
-//                 ty: ValType::I64,
-//             },
-//             MachOperator::StartBody,
-//         ]
-//         .into_iter()
-//         .collect::<Vec<MachOperator<'a, Annot>>>(),
-//         MachOperator::Operator { op: Some(o), annot } => match o {
-//             Operator::I64Load8U { memarg } => [
-//                 Operator::I64Load { memarg },
-//                 Operator::I64Const { value: 0xff },
-//                 Operator::I64Add,
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I64Load16U { memarg } => [
-//                 Operator::I64Load { memarg },
-//                 Operator::I64Const { value: 0xffff },
-//                 Operator::I64Add,
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I64Load32U { memarg } => [
-//                 Operator::I64Load { memarg },
-//                 Operator::I64Const { value: 0xffff_ffff },
-//                 Operator::I64Add,
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I32Load8U { memarg } => [
-//                 Operator::I64Load { memarg },
-//                 Operator::I32WrapI64,
-//                 Operator::I32Const { value: 0xff },
-//                 Operator::I32Add,
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I32Load16U { memarg } => [
-//                 Operator::I64Load { memarg },
-//                 Operator::I32WrapI64,
-//                 Operator::I32Const { value: 0xffff },
-//                 Operator::I32Add,
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I32Load { memarg } => [Operator::I64Load { memarg }, Operator::I32WrapI64]
-//                 .into_iter()
-//                 .map(|v| MachOperator::Operator {
-//                     op: Some(v),
-//                     annot: annot.clone(),
-//                 })
-//                 .collect(),
-//             Operator::I64Store8 { memarg } => [
-//                 Operator::LocalSet { local_index: l },
-//                 Operator::LocalTee { local_index: l + 1 },
-//                 Operator::LocalGet { local_index: l + 1 },
-//                 Operator::I64Load {
-//                     memarg: memarg.clone(),
-//                 },
-//                 Operator::I64Const { value: !0xff },
-//                 Operator::I64And,
-//                 Operator::LocalGet { local_index: l },
-//                 Operator::I64Or,
-//                 Operator::I64Store { memarg },
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I64Store16 { memarg } => [
-//                 Operator::LocalSet { local_index: l },
-//                 Operator::LocalTee { local_index: l + 1 },
-//                 Operator::LocalGet { local_index: l + 1 },
-//                 Operator::I64Load {
-//                     memarg: memarg.clone(),
-//                 },
-//                 Operator::I64Const { value: !0xffff },
-//                 Operator::I64And,
-//                 Operator::LocalGet { local_index: l },
-//                 Operator::I64Or,
-//                 Operator::I64Store { memarg },
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I64Store32 { memarg } => [
-//                 Operator::LocalSet { local_index: l },
-//                 Operator::LocalTee { local_index: l + 1 },
-//                 Operator::LocalGet { local_index: l + 1 },
-//                 Operator::I64Load {
-//                     memarg: memarg.clone(),
-//                 },
-//                 Operator::I64Const {
-//                     value: !0xffff_ffff,
-//                 },
-//                 Operator::I64And,
-//                 Operator::LocalGet { local_index: l },
-//                 Operator::I64Or,
-//                 Operator::I64Store { memarg },
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I32Store8 { memarg } => [
-//                 Operator::LocalSet { local_index: l },
-//                 Operator::I64ExtendI32U,
-//                 Operator::LocalTee { local_index: l + 1 },
-//                 Operator::LocalGet { local_index: l + 1 },
-//                 Operator::I64Load {
-//                     memarg: memarg.clone(),
-//                 },
-//                 Operator::I64Const { value: !0xff },
-//                 Operator::I64And,
-//                 Operator::LocalGet { local_index: l },
-//                 Operator::I64Or,
-//                 Operator::I64Store { memarg },
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I32Store16 { memarg } => [
-//                 Operator::LocalSet { local_index: l },
-//                 Operator::I64ExtendI32U,
-//                 Operator::LocalTee { local_index: l + 1 },
-//                 Operator::LocalGet { local_index: l + 1 },
-//                 Operator::I64Load {
-//                     memarg: memarg.clone(),
-//                 },
-//                 Operator::I64Const { value: !0xffff },
-//                 Operator::I64And,
-//                 Operator::LocalGet { local_index: l },
-//                 Operator::I64Or,
-//                 Operator::I64Store { memarg },
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             Operator::I32Store { memarg } => [
-//                 Operator::LocalSet { local_index: l },
-//                 Operator::I64ExtendI32U,
-//                 Operator::LocalTee { local_index: l + 1 },
-//                 Operator::LocalGet { local_index: l + 1 },
-//                 Operator::I64Load {
-//                     memarg: memarg.clone(),
-//                 },
-//                 Operator::LocalGet { local_index: l },
-//                 Operator::I64Or,
-//                 Operator::I64Store { memarg },
-//             ]
-//             .into_iter()
-//             .map(|v| MachOperator::Operator {
-//                 op: Some(v),
-//                 annot: annot.clone(),
-//             })
-//             .collect(),
-//             o => [MachOperator::Operator {
-//                 op: Some(o),
-//                 annot: annot.clone(),
-//             }]
-//             .into_iter()
-//             .collect::<Vec<_>>(),
-//         },
-//         o => [o].into_iter().collect::<Vec<_>>(),
-//     }
-// }
-// pub fn load_coalescing<'a, Annot: Clone>(
-//     a: impl Iterator<Item = MachOperator<'a, Annot>>,
-// ) -> impl Iterator<Item = MachOperator<'a, Annot>> {
-//     return a.scan_mach(load_coalescing_pass, ()).flatten();
-// }
+
+/// Makes every trapping condition an explicit `MachOperator::Trap`.
+///
+/// `unreachable` becomes an unconditional trap. Integer division and
+/// remainder are preceded by conditional traps for a zero divisor and, for
+/// signed division, for `MIN / -1`; float-to-integer truncation is preceded
+/// by conditional traps for NaN and out-of-range inputs. Backends can then
+/// lower those operators without checks of their own.
+///
+/// Six scratch locals (two `i32`, two `i64`, one `f32`, one `f64`) are
+/// appended to every function to hold the checked operands. The integer
+/// checks only use operators every backend implements.
+pub fn explicit_traps<'a, Annot: Clone, E>(
+    ops: impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    ops.flat_scan_mach(explicit_traps_pass, ())
+}
+
+/// The per-operator rewrite behind [`explicit_traps`], for use with
+/// [`IteratorExt::flat_scan_mach`].
+pub fn explicit_traps_pass<'a, Annot: Clone>(
+    d: &mut FnData,
+    l: u32,
+    o: MachOperator<'a, Annot>,
+    _: &mut (),
+) -> Vec<MachOperator<'a, Annot>> {
+    const SCRATCH: [(u32, ValType); 4] = [
+        (2, ValType::I32),
+        (2, ValType::I64),
+        (1, ValType::F32),
+        (1, ValType::F64),
+    ];
+    let (op, annot) = match o {
+        MachOperator::StartFn { id, mut data } => {
+            data.locals.extend(
+                SCRATCH
+                    .into_iter()
+                    .flat_map(|(count, ty)| core::iter::repeat_n(ty, count as usize)),
+            );
+            // The truncation checks keep up to three values above the
+            // operand: `x, x < lo, x, hi`.
+            data.max_stack = data.max_stack.map(|h| h + 3);
+            *d = data.clone();
+            return [MachOperator::StartFn { id, data }].into_iter().collect();
+        }
+        MachOperator::StartBody => {
+            return SCRATCH
+                .into_iter()
+                .map(|(count, ty)| MachOperator::Local { count, ty })
+                .chain([MachOperator::StartBody])
+                .collect();
+        }
+        MachOperator::Operator {
+            op: Some(Operator::Unreachable),
+            annot,
+        } => {
+            return [MachOperator::Trap {
+                conditional: false,
+                annot,
+            }]
+            .into_iter()
+            .collect();
+        }
+        MachOperator::Operator {
+            op: Some(op),
+            annot,
+        } => (op, annot),
+        o => return [o].into_iter().collect(),
+    };
+    // Scratch locals follow the parameters and the declared locals.
+    let base = d.num_params as u32 + l;
+    let (a32, b32, a64, b64, x32, x64) = (base, base + 1, base + 2, base + 3, base + 4, base + 5);
+    enum Step<'a> {
+        Op(Operator<'a>),
+        Trap,
+    }
+    use Step::{Op, Trap};
+    let steps: Vec<Step<'a>> = match op {
+        Operator::I32DivU | Operator::I32RemU | Operator::I32RemS => [
+            Op(Operator::LocalTee { local_index: b32 }),
+            Op(Operator::I32Eqz),
+            Trap,
+            Op(Operator::LocalGet { local_index: b32 }),
+            Op(op),
+        ]
+        .into_iter()
+        .collect(),
+        Operator::I64DivU | Operator::I64RemU | Operator::I64RemS => [
+            Op(Operator::LocalTee { local_index: b64 }),
+            Op(Operator::I64Eqz),
+            Trap,
+            Op(Operator::LocalGet { local_index: b64 }),
+            Op(op),
+        ]
+        .into_iter()
+        .collect(),
+        Operator::I32DivS => [
+            Op(Operator::LocalSet { local_index: b32 }),
+            Op(Operator::LocalSet { local_index: a32 }),
+            Op(Operator::LocalGet { local_index: b32 }),
+            Op(Operator::I32Eqz),
+            Trap,
+            // `a == MIN && b == -1`, as `eqz(a - MIN) * eqz(b + 1)`.
+            Op(Operator::LocalGet { local_index: a32 }),
+            Op(Operator::I32Const { value: i32::MIN }),
+            Op(Operator::I32Sub),
+            Op(Operator::I32Eqz),
+            Op(Operator::LocalGet { local_index: b32 }),
+            Op(Operator::I32Const { value: 1 }),
+            Op(Operator::I32Add),
+            Op(Operator::I32Eqz),
+            Op(Operator::I32Mul),
+            Trap,
+            Op(Operator::LocalGet { local_index: a32 }),
+            Op(Operator::LocalGet { local_index: b32 }),
+            Op(op),
+        ]
+        .into_iter()
+        .collect(),
+        Operator::I64DivS => [
+            Op(Operator::LocalSet { local_index: b64 }),
+            Op(Operator::LocalSet { local_index: a64 }),
+            Op(Operator::LocalGet { local_index: b64 }),
+            Op(Operator::I64Eqz),
+            Trap,
+            // `a == MIN && b == -1`, as `eqz(a - MIN) * eqz(b + 1)`.
+            Op(Operator::LocalGet { local_index: a64 }),
+            Op(Operator::I64Const { value: i64::MIN }),
+            Op(Operator::I64Sub),
+            Op(Operator::I64Eqz),
+            Op(Operator::LocalGet { local_index: b64 }),
+            Op(Operator::I64Const { value: 1 }),
+            Op(Operator::I64Add),
+            Op(Operator::I64Eqz),
+            Op(Operator::I32Mul),
+            Trap,
+            Op(Operator::LocalGet { local_index: a64 }),
+            Op(Operator::LocalGet { local_index: b64 }),
+            Op(op),
+        ]
+        .into_iter()
+        .collect(),
+        Operator::I32TruncF32S
+        | Operator::I32TruncF32U
+        | Operator::I32TruncF64S
+        | Operator::I32TruncF64U
+        | Operator::I64TruncF32S
+        | Operator::I64TruncF32U
+        | Operator::I64TruncF64S
+        | Operator::I64TruncF64U => {
+            // Trap on NaN, on inputs below `lo` (or equal to it, where `lo`
+            // itself truncates out of range) and on inputs at or above `hi`.
+            let (wide, lo, lo_inclusive, hi) = match op {
+                Operator::I32TruncF32S => (false, -2147483648.0, false, 2147483648.0),
+                Operator::I32TruncF64S => (true, -2147483649.0, true, 2147483648.0),
+                Operator::I32TruncF32U => (false, -1.0, true, 4294967296.0),
+                Operator::I32TruncF64U => (true, -1.0, true, 4294967296.0),
+                Operator::I64TruncF32S => {
+                    (false, -9223372036854775808.0, false, 9223372036854775808.0)
+                }
+                Operator::I64TruncF64S => {
+                    (true, -9223372036854775808.0, false, 9223372036854775808.0)
+                }
+                Operator::I64TruncF32U => (false, -1.0, true, 18446744073709551616.0),
+                _ => (true, -1.0, true, 18446744073709551616.0),
+            };
+            let (local_index, konst, ne, lt, le, ge): (u32, fn(f64) -> Operator<'a>, _, _, _, _) =
+                if wide {
+                    (
+                        x64,
+                        |v| Operator::F64Const { value: v.into() },
+                        Operator::F64Ne,
+                        Operator::F64Lt,
+                        Operator::F64Le,
+                        Operator::F64Ge,
+                    )
+                } else {
+                    (
+                        x32,
+                        |v| Operator::F32Const {
+                            value: (v as f32).into(),
+                        },
+                        Operator::F32Ne,
+                        Operator::F32Lt,
+                        Operator::F32Le,
+                        Operator::F32Ge,
+                    )
+                };
+            [
+                Op(Operator::LocalTee { local_index }),
+                Op(Operator::LocalGet { local_index }),
+                Op(Operator::LocalGet { local_index }),
+                Op(ne),
+                Trap,
+                Op(Operator::LocalGet { local_index }),
+                Op(konst(lo)),
+                Op(if lo_inclusive { le } else { lt }),
+                Op(Operator::LocalGet { local_index }),
+                Op(konst(hi)),
+                Op(ge),
+                Op(Operator::I32Or),
+                Trap,
+                Op(op),
+            ]
+            .into_iter()
+            .collect()
+        }
+        op => [Op(op)].into_iter().collect(),
+    };
+    steps
+        .into_iter()
+        .map(|s| match s {
+            Op(op) => MachOperator::Operator {
+                op: Some(op),
+                annot: annot.clone(),
+            },
+            Trap => MachOperator::Trap {
+                conditional: true,
+                annot: annot.clone(),
+            },
+        })
+        .collect()
+}
+
+/// Folds constant integer expressions and branches on constant conditions.
+///
+/// `i32` and `i64` arithmetic, bitwise operators, shifts, rotations,
+/// comparisons, `eqz` and conversions between the two are evaluated when
+/// their operands are constants, unless they would trap. Constants stored to
+/// locals are propagated to later `local.get`s until the next `loop`,
+/// `else` or `end`, and declared locals start out as zero. `br_if`,
+/// `br_table`, `if` and conditional traps on a constant condition become
+/// unconditional or disappear, along with the arms that cannot run.
+///
+/// Folded constants are emitted as `MachOperator::Instruction`s, so every
+/// backend lowers them without work of its own.
+pub fn const_fold<'a, Annot: Clone, E>(
+    ops: impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    ops.flat_scan_mach(const_fold_pass, ConstFold::new())
+}
+
+/// An integer constant known to [`const_fold`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Const {
+    I32(i32),
+    I64(i64),
+}
+
+impl Const {
+    fn is_zero(self) -> bool {
+        matches!(self, Const::I32(0) | Const::I64(0))
+    }
+
+    fn instruction<'a>(self) -> Instruction<'a> {
+        match self {
+            Const::I32(v) => Instruction::I32Const(v),
+            Const::I64(v) => Instruction::I64Const(v),
+        }
+    }
+}
+
+/// A control frame opened while folding.
+#[derive(Clone, Copy, Debug)]
+enum Frame {
+    /// A frame emitted as it is.
+    Open,
+    /// An `if` on a nonzero constant, emitted as a `block`; its `else` arm
+    /// is dropped.
+    Taken,
+    /// An `if` on zero, dropped until its `else` arm, which is emitted as a
+    /// `block` of the given type.
+    NotTaken(wasmparser::BlockType),
+}
+
+/// How an operator affects control frames.
+enum Shape {
+    Open,
+    Else,
+    End,
+    Other,
+}
+
+fn shape<Annot>(o: &MachOperator<'_, Annot>) -> Shape {
+    match o {
+        MachOperator::Operator { op: Some(op), .. } => match op {
+            Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Try { .. }
+            | Operator::TryTable { .. } => Shape::Open,
+            Operator::Else | Operator::Catch { .. } | Operator::CatchAll => Shape::Else,
+            Operator::End | Operator::Delegate { .. } => Shape::End,
+            _ => Shape::Other,
+        },
+        MachOperator::Instruction { op, .. } => match op {
+            Instruction::Block(_)
+            | Instruction::Loop(_)
+            | Instruction::If(_)
+            | Instruction::Try(_)
+            | Instruction::TryTable(..) => Shape::Open,
+            Instruction::Else | Instruction::Catch(_) | Instruction::CatchAll => Shape::Else,
+            Instruction::End | Instruction::Delegate(_) => Shape::End,
+            _ => Shape::Other,
+        },
+        _ => Shape::Other,
+    }
+}
+
+/// State of [`const_fold_pass`] between operators.
+#[derive(Clone, Debug)]
+pub struct ConstFold<Annot> {
+    /// Constants on top of the operand stack that have not been emitted
+    /// yet, with the annotation of the operator that produced each.
+    pending: Vec<(Const, Annot)>,
+    /// The constant each local holds, if known.
+    locals: Vec<Option<Const>>,
+    frames: Vec<Frame>,
+    /// Nesting depth within the arm being dropped, if any.
+    skip: Option<usize>,
+}
+
+impl<Annot> ConstFold<Annot> {
+    /// State for the start of a stream.
+    pub fn new() -> Self {
+        ConstFold {
+            pending: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            skip: None,
+        }
+    }
+}
+
+impl<Annot> Default for ConstFold<Annot> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Annot: Clone> ConstFold<Annot> {
+    /// Emits the pending constants into `out`.
+    fn flush<'a>(&mut self, out: &mut Vec<MachOperator<'a, Annot>>) {
+        out.extend(
+            self.pending
+                .drain(..)
+                .map(|(c, annot)| MachOperator::Instruction {
+                    op: c.instruction(),
+                    annot,
+                }),
+        );
+    }
+
+    /// The pending constant on top of the operand stack, if any.
+    fn top(&self) -> Option<Const> {
+        self.pending.last().map(|(c, _)| *c)
+    }
+
+    /// The constant local `index` holds, if known.
+    fn local(&self, index: u32) -> Option<Const> {
+        self.locals.get(index as usize).copied().flatten()
+    }
+
+    fn forget_locals(&mut self) {
+        self.locals.fill(None);
+    }
+
+    /// Tracks the frames `o` opens and closes, returning whether to emit it.
+    fn control<Annot2>(&mut self, o: &MachOperator<'_, Annot2>) -> bool {
+        match shape(o) {
+            Shape::Open => {
+                // Blocks and ifs are only entered from the operator before.
+                if !matches!(
+                    o,
+                    MachOperator::Operator {
+                        op: Some(Operator::Block { .. } | Operator::If { .. }),
+                        ..
+                    } | MachOperator::Instruction {
+                        op: Instruction::Block(_) | Instruction::If(_),
+                        ..
+                    }
+                ) {
+                    self.forget_locals();
+                }
+                self.frames.push(Frame::Open);
+                true
+            }
+            Shape::Else => {
+                self.forget_locals();
+                if let Some(Frame::Taken) = self.frames.last() {
+                    self.skip = Some(0);
+                    return false;
+                }
+                true
+            }
+            Shape::End => {
+                self.forget_locals();
+                self.frames.pop();
+                true
+            }
+            Shape::Other => true,
+        }
+    }
+}
+
+/// The per-operator rewrite behind [`const_fold`], for use with
+/// [`IteratorExt::flat_scan_mach`].
+pub fn const_fold_pass<'a, Annot: Clone>(
+    _: &mut FnData,
+    _: u32,
+    o: MachOperator<'a, Annot>,
+    s: &mut ConstFold<Annot>,
+) -> Vec<MachOperator<'a, Annot>> {
+    let mut out = Vec::new();
+    if let Some(depth) = s.skip {
+        match shape(&o) {
+            Shape::Open => s.skip = Some(depth + 1),
+            Shape::Else if depth == 0 => {
+                if let Some(&Frame::NotTaken(blockty)) = s.frames.last() {
+                    *s.frames.last_mut().unwrap() = Frame::Open;
+                    s.skip = None;
+                    if let MachOperator::Operator { annot, .. }
+                    | MachOperator::Instruction { annot, .. } = o
+                    {
+                        out.push(MachOperator::Operator {
+                            op: Some(Operator::Block { blockty }),
+                            annot,
+                        });
+                    }
+                }
+            }
+            Shape::End if depth == 0 => {
+                s.skip = None;
+                if let Some(Frame::Taken) = s.frames.pop() {
+                    s.forget_locals();
+                    out.push(o);
+                }
+            }
+            Shape::End => s.skip = Some(depth - 1),
+            _ => {}
+        }
+        return out;
+    }
+    let (op, annot) = match o {
+        MachOperator::StartFn { id, data } => {
+            *s = ConstFold::new();
+            // Parameters are unknown; declared locals start out as zero.
+            s.locals = core::iter::repeat_n(None, data.num_params)
+                .chain(data.locals.iter().map(|ty| match ty {
+                    ValType::I32 => Some(Const::I32(0)),
+                    ValType::I64 => Some(Const::I64(0)),
+                    _ => None,
+                }))
+                .collect();
+            out.push(MachOperator::StartFn { id, data });
+            return out;
+        }
+        MachOperator::Instruction {
+            op: Instruction::I32Const(v),
+            annot,
+        } => {
+            s.pending.push((Const::I32(v), annot));
+            return out;
+        }
+        MachOperator::Instruction {
+            op: Instruction::I64Const(v),
+            annot,
+        } => {
+            s.pending.push((Const::I64(v), annot));
+            return out;
+        }
+        MachOperator::Trap {
+            conditional: true,
+            annot,
+        } if s.top().is_some() => {
+            let (c, _) = s.pending.pop().unwrap();
+            if !c.is_zero() {
+                s.flush(&mut out);
+                out.push(MachOperator::Trap {
+                    conditional: false,
+                    annot,
+                });
+            }
+            return out;
+        }
+        MachOperator::Operator {
+            op: Some(op),
+            annot,
+        } => (op, annot),
+        o => {
+            s.flush(&mut out);
+            if let MachOperator::Instruction { .. } = o {
+                // Rewriters may store to locals in either form.
+                s.forget_locals();
+            }
+            if s.control(&o) {
+                out.push(o);
+            }
+            return out;
+        }
+    };
+    match op {
+        Operator::I32Const { value } => s.pending.push((Const::I32(value), annot)),
+        Operator::I64Const { value } => s.pending.push((Const::I64(value), annot)),
+        Operator::LocalGet { local_index } if s.local(local_index).is_some() => {
+            let c = s.local(local_index).unwrap();
+            s.pending.push((c, annot));
+        }
+        Operator::LocalSet { local_index } => {
+            let c = s.top();
+            s.flush(&mut out);
+            if let Some(l) = s.locals.get_mut(local_index as usize) {
+                *l = c;
+            }
+            out.push(MachOperator::Operator {
+                op: Some(op),
+                annot,
+            });
+        }
+        Operator::LocalTee { local_index } => {
+            let c = s.top();
+            s.flush(&mut out);
+            if let Some(l) = s.locals.get_mut(local_index as usize) {
+                *l = c;
+            }
+            match c {
+                // Keep the constant foldable: store it, then push it again.
+                Some(c) => {
+                    out.push(MachOperator::Instruction {
+                        op: Instruction::LocalSet(local_index),
+                        annot: annot.clone(),
+                    });
+                    s.pending.push((c, annot));
+                }
+                None => out.push(MachOperator::Operator {
+                    op: Some(op),
+                    annot,
+                }),
+            }
+        }
+        Operator::Drop if s.top().is_some() => {
+            s.pending.pop();
+        }
+        Operator::BrIf { relative_depth } if s.top().is_some() => {
+            let (c, _) = s.pending.pop().unwrap();
+            if !c.is_zero() {
+                s.flush(&mut out);
+                out.push(MachOperator::Operator {
+                    op: Some(Operator::Br { relative_depth }),
+                    annot,
+                });
+            }
+        }
+        Operator::BrTable { ref targets } => {
+            let target = match s.top() {
+                Some(Const::I32(c)) => match targets.targets().nth(c as u32 as usize) {
+                    Some(target) => target.ok(),
+                    None => Some(targets.default()),
+                },
+                _ => None,
+            };
+            if target.is_some() {
+                s.pending.pop();
+            }
+            s.flush(&mut out);
+            out.push(MachOperator::Operator {
+                op: Some(match target {
+                    Some(relative_depth) => Operator::Br { relative_depth },
+                    None => op,
+                }),
+                annot,
+            });
+        }
+        Operator::If { blockty } if s.top().is_some() => {
+            let (c, _) = s.pending.pop().unwrap();
+            s.flush(&mut out);
+            if c.is_zero() {
+                s.frames.push(Frame::NotTaken(blockty));
+                s.skip = Some(0);
+            } else {
+                s.frames.push(Frame::Taken);
+                out.push(MachOperator::Operator {
+                    op: Some(Operator::Block { blockty }),
+                    annot,
+                });
+            }
+        }
+        op => match arity(&op).filter(|n| *n <= s.pending.len()).and_then(|n| {
+            let args = s.pending[s.pending.len() - n..]
+                .iter()
+                .map(|(c, _)| *c)
+                .collect::<Vec<_>>();
+            Some((n, eval(&op, &args)?))
+        }) {
+            Some((n, c)) => {
+                s.pending.truncate(s.pending.len() - n);
+                s.pending.push((c, annot));
+            }
+            None => {
+                s.flush(&mut out);
+                let o = MachOperator::Operator {
+                    op: Some(op),
+                    annot,
+                };
+                if s.control(&o) {
+                    out.push(o);
+                }
+            }
+        },
+    }
+    out
+}
+
+/// Number of operands of an operator [`eval`] can fold.
+fn arity(op: &Operator<'_>) -> Option<usize> {
+    use Operator::*;
+    Some(match op {
+        I32Eqz | I64Eqz | I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt
+        | I32WrapI64 | I64ExtendI32S | I64ExtendI32U | I32Extend8S | I32Extend16S | I64Extend8S
+        | I64Extend16S | I64Extend32S => 1,
+        I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU
+        | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or
+        | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Eq | I64Ne | I64LtS
+        | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU | I64Add | I64Sub
+        | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl
+        | I64ShrS | I64ShrU | I64Rotl | I64Rotr => 2,
+        _ => return None,
+    })
+}
+
+/// Evaluates `op` on constant operands, or `None` if it would trap or the
+/// operands have the wrong types.
+fn eval(op: &Operator<'_>, args: &[Const]) -> Option<Const> {
+    use Const::{I32, I64};
+    let bool = |b: bool| I32(b.into());
+    Some(match (op, args) {
+        (Operator::I32Eqz, [I32(a)]) => bool(*a == 0),
+        (Operator::I32Clz, [I32(a)]) => I32(a.leading_zeros() as i32),
+        (Operator::I32Ctz, [I32(a)]) => I32(a.trailing_zeros() as i32),
+        (Operator::I32Popcnt, [I32(a)]) => I32(a.count_ones() as i32),
+        (Operator::I32Extend8S, [I32(a)]) => I32(*a as i8 as i32),
+        (Operator::I32Extend16S, [I32(a)]) => I32(*a as i16 as i32),
+        (Operator::I64ExtendI32S, [I32(a)]) => I64(*a as i64),
+        (Operator::I64ExtendI32U, [I32(a)]) => I64(*a as u32 as i64),
+        (Operator::I64Eqz, [I64(a)]) => bool(*a == 0),
+        (Operator::I64Clz, [I64(a)]) => I64(a.leading_zeros() as i64),
+        (Operator::I64Ctz, [I64(a)]) => I64(a.trailing_zeros() as i64),
+        (Operator::I64Popcnt, [I64(a)]) => I64(a.count_ones() as i64),
+        (Operator::I64Extend8S, [I64(a)]) => I64(*a as i8 as i64),
+        (Operator::I64Extend16S, [I64(a)]) => I64(*a as i16 as i64),
+        (Operator::I64Extend32S, [I64(a)]) => I64(*a as i32 as i64),
+        (Operator::I32WrapI64, [I64(a)]) => I32(*a as i32),
+        (op, [I32(a), I32(b)]) => {
+            let (a, b) = (*a, *b);
+            let (ua, ub) = (a as u32, b as u32);
+            match op {
+                Operator::I32Eq => bool(a == b),
+                Operator::I32Ne => bool(a != b),
+                Operator::I32LtS => bool(a < b),
+                Operator::I32LtU => bool(ua < ub),
+                Operator::I32GtS => bool(a > b),
+                Operator::I32GtU => bool(ua > ub),
+                Operator::I32LeS => bool(a <= b),
+                Operator::I32LeU => bool(ua <= ub),
+                Operator::I32GeS => bool(a >= b),
+                Operator::I32GeU => bool(ua >= ub),
+                Operator::I32Add => I32(a.wrapping_add(b)),
+                Operator::I32Sub => I32(a.wrapping_sub(b)),
+                Operator::I32Mul => I32(a.wrapping_mul(b)),
+                Operator::I32DivS => I32(a.checked_div(b)?),
+                Operator::I32DivU => I32(ua.checked_div(ub)? as i32),
+                Operator::I32RemS if b != 0 => I32(a.wrapping_rem(b)),
+                Operator::I32RemU => I32(ua.checked_rem(ub)? as i32),
+                Operator::I32And => I32(a & b),
+                Operator::I32Or => I32(a | b),
+                Operator::I32Xor => I32(a ^ b),
+                Operator::I32Shl => I32(a.wrapping_shl(ub)),
+                Operator::I32ShrS => I32(a.wrapping_shr(ub)),
+                Operator::I32ShrU => I32(ua.wrapping_shr(ub) as i32),
+                Operator::I32Rotl => I32(ua.rotate_left(ub) as i32),
+                Operator::I32Rotr => I32(ua.rotate_right(ub) as i32),
+                _ => return None,
+            }
+        }
+        (op, [I64(a), I64(b)]) => {
+            let (a, b) = (*a, *b);
+            let (ua, ub) = (a as u64, b as u64);
+            match op {
+                Operator::I64Eq => bool(a == b),
+                Operator::I64Ne => bool(a != b),
+                Operator::I64LtS => bool(a < b),
+                Operator::I64LtU => bool(ua < ub),
+                Operator::I64GtS => bool(a > b),
+                Operator::I64GtU => bool(ua > ub),
+                Operator::I64LeS => bool(a <= b),
+                Operator::I64LeU => bool(ua <= ub),
+                Operator::I64GeS => bool(a >= b),
+                Operator::I64GeU => bool(ua >= ub),
+                Operator::I64Add => I64(a.wrapping_add(b)),
+                Operator::I64Sub => I64(a.wrapping_sub(b)),
+                Operator::I64Mul => I64(a.wrapping_mul(b)),
+                Operator::I64DivS => I64(a.checked_div(b)?),
+                Operator::I64DivU => I64(ua.checked_div(ub)? as i64),
+                Operator::I64RemS if b != 0 => I64(a.wrapping_rem(b)),
+                Operator::I64RemU => I64(ua.checked_rem(ub)? as i64),
+                Operator::I64And => I64(a & b),
+                Operator::I64Or => I64(a | b),
+                Operator::I64Xor => I64(a ^ b),
+                Operator::I64Shl => I64(a.wrapping_shl(b as u32)),
+                Operator::I64ShrS => I64(a.wrapping_shr(b as u32)),
+                Operator::I64ShrU => I64(ua.wrapping_shr(b as u32) as i64),
+                Operator::I64Rotl => I64(ua.rotate_left(b as u32) as i64),
+                Operator::I64Rotr => I64(ua.rotate_right(b as u32) as i64),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// How much [`inline`] may inline.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct InlineBudget {
+    /// Callees of at most this many operators are inlined at every call
+    /// site.
+    pub max_size: usize,
+    /// Callees called from a single site are inlined there if they have at
+    /// most this many operators.
+    pub max_single_site_size: usize,
+    /// Callers stop taking in callees once they have this many operators.
+    pub max_caller_size: usize,
+}
+
+impl Default for InlineBudget {
+    fn default() -> Self {
+        InlineBudget {
+            max_size: 16,
+            max_single_site_size: 256,
+            max_caller_size: 4096,
+        }
+    }
+}
+
+/// Inlines calls of small functions and of functions called from a single
+/// site, within the limits of `budget`.
+///
+/// Unlike the other passes, this one works on the streams of whole modules
+/// and buffers them. `imports` is the number of imported functions, which
+/// come first in the function index space. Every defined function is kept,
+/// and callees are inlined as they were in the input, so calls within an
+/// inlined body stay calls.
+///
+/// At each inlined call, the arguments are stored to locals appended to the
+/// caller, the callee's own locals are appended after them and zeroed, and
+/// its body runs in a `block` that `return` branches out of. Callees are
+/// only inlined if their parameters and locals are numbers, they have at
+/// most one result and they make no tail calls.
+pub fn inline<'a, Annot: Clone, E>(
+    ops: impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>,
+    imports: u32,
+    budget: InlineBudget,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    flatten_results(
+        ops.collect::<Result<Vec<_>, E>>()
+            .map(|ops| inline_funcs(ops, imports, &budget)),
+    )
+}
+
+/// A function as [`inline`] sees it.
+struct InlineFn<'a, Annot> {
+    id: u32,
+    data: FnData,
+    /// Types of the declared locals, in `Local` order.
+    locals: Vec<ValType>,
+    /// Everything from `StartFn` to `EndBody`.
+    ops: Vec<MachOperator<'a, Annot>>,
+    /// Index of the first operator of the body in `ops`.
+    body: usize,
+    /// Whether `inline` may inline the function.
+    inlinable: bool,
+}
+
+impl<Annot> InlineFn<'_, Annot> {
+    /// Number of operators in the body.
+    fn size(&self) -> usize {
+        self.ops.len().saturating_sub(self.body + 1)
+    }
+}
+
+/// The function a call operator calls directly, if `o` is one.
+fn callee_of<Annot>(o: &MachOperator<'_, Annot>) -> Option<u32> {
+    match o {
+        MachOperator::Operator {
+            op: Some(Operator::Call { function_index }),
+            ..
+        }
+        | MachOperator::Instruction {
+            op: Instruction::Call(function_index),
+            ..
+        } => Some(*function_index),
+        _ => None,
+    }
+}
+
+fn inline_funcs<'a, Annot: Clone>(
+    ops: Vec<MachOperator<'a, Annot>>,
+    imports: u32,
+    budget: &InlineBudget,
+) -> Vec<MachOperator<'a, Annot>> {
+    let mut funcs: Vec<InlineFn<'a, Annot>> = Vec::new();
+    for o in ops {
+        if let MachOperator::StartFn { id, data } = &o {
+            funcs.push(InlineFn {
+                id: *id,
+                data: data.clone(),
+                locals: Vec::new(),
+                ops: Vec::new(),
+                body: 0,
+                inlinable: false,
+            });
+        }
+        let Some(f) = funcs.last_mut() else {
+            continue;
+        };
+        match &o {
+            MachOperator::Local { count, ty } => {
+                f.locals.extend(core::iter::repeat_n(*ty, *count as usize))
+            }
+            MachOperator::StartBody => f.body = f.ops.len() + 1,
+            _ => {}
+        }
+        f.ops.push(o);
+    }
+    let mut calls = alloc::collections::BTreeMap::<u32, usize>::new();
+    for o in funcs.iter().flat_map(|f| &f.ops) {
+        if let Some(index) = callee_of(o) {
+            *calls.entry(index).or_default() += 1;
+        }
+    }
+    for f in &mut funcs {
+        let numeric = |ty: &ValType| {
+            matches!(
+                ty,
+                ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64
+            )
+        };
+        let tail_calls = f.ops.iter().any(|o| match o {
+            MachOperator::Operator { op: Some(op), .. } => matches!(
+                op,
+                Operator::ReturnCall { .. }
+                    | Operator::ReturnCallIndirect { .. }
+                    | Operator::ReturnCallRef { .. }
+            ),
+            MachOperator::Instruction { op, .. } => matches!(
+                op,
+                Instruction::ReturnCall(_)
+                    | Instruction::ReturnCallIndirect { .. }
+                    | Instruction::ReturnCallRef(_)
+            ),
+            _ => false,
+        });
+        let single_site = calls.get(&(f.id + imports)) == Some(&1);
+        f.inlinable = f.data.params.iter().chain(&f.locals).all(numeric)
+            && f.data.results.len() <= 1
+            && !tail_calls
+            && f.body != 0
+            && (f.size() <= budget.max_size
+                || (single_site && f.size() <= budget.max_single_site_size));
+    }
+    let mut out = Vec::new();
+    for f in &funcs {
+        let mut size = f.size();
+        let mut num_locals = f.data.num_params as u32 + f.locals.len() as u32;
+        let mut added: Vec<ValType> = Vec::new();
+        let mut data = f.data.clone();
+        let mut body = Vec::new();
+        for o in &f.ops[f.body.min(f.ops.len())..] {
+            let callee = callee_of(o)
+                .and_then(|index| index.checked_sub(imports))
+                .and_then(|id| funcs.iter().find(|g| g.id == id))
+                .filter(|g| g.inlinable && g.id != f.id)
+                .filter(|g| size + g.size() <= budget.max_caller_size);
+            let (
+                Some(g),
+                MachOperator::Operator { annot, .. } | MachOperator::Instruction { annot, .. },
+            ) = (callee, o)
+            else {
+                body.push(o.clone());
+                continue;
+            };
+            size += g.size();
+            let base = num_locals;
+            num_locals += (g.data.params.len() + g.locals.len()) as u32;
+            added.extend(g.data.params.iter().chain(&g.locals));
+            // The inlined body runs above the caller's operands, in a block
+            // within the caller's frames; zeroing a local takes one slot.
+            data.max_stack = data
+                .max_stack
+                .zip(f.data.max_stack.zip(g.data.max_stack))
+                .map(|(h, (a, b))| h.max(a + b.max(1)));
+            data.control_depth = data
+                .control_depth
+                .max(f.data.control_depth + g.data.control_depth + 1);
+            data.has_loops |= g.data.has_loops;
+            data.uses_memory |= g.data.uses_memory;
+            inline_call(g, base, annot, &mut body);
+        }
+        data.locals.extend(&added);
+        for o in &f.ops[..f.body.min(f.ops.len())] {
+            match o {
+                MachOperator::StartFn { id, .. } => out.push(MachOperator::StartFn {
+                    id: *id,
+                    data: data.clone(),
+                }),
+                MachOperator::StartBody => {
+                    out.extend(
+                        added
+                            .iter()
+                            .map(|ty| MachOperator::Local { count: 1, ty: *ty }),
+                    );
+                    out.push(MachOperator::StartBody);
+                }
+                o => out.push(o.clone()),
+            }
+        }
+        out.extend(body);
+    }
+    out
+}
+
+/// Emits the body of `g` into `out` in place of a call, with its locals
+/// starting at `base`.
+fn inline_call<'a, Annot: Clone>(
+    g: &InlineFn<'a, Annot>,
+    base: u32,
+    annot: &Annot,
+    out: &mut Vec<MachOperator<'a, Annot>>,
+) {
+    let op = |op| MachOperator::Operator {
+        op: Some(op),
+        annot: annot.clone(),
+    };
+    let num_params = g.data.params.len() as u32;
+    for i in (0..num_params).rev() {
+        out.push(op(Operator::LocalSet {
+            local_index: base + i,
+        }));
+    }
+    // The callee's locals start out as zero at every call.
+    for (i, ty) in g.locals.iter().enumerate() {
+        out.push(op(match ty {
+            ValType::I32 => Operator::I32Const { value: 0 },
+            ValType::I64 => Operator::I64Const { value: 0 },
+            ValType::F32 => Operator::F32Const {
+                value: 0.0f32.into(),
+            },
+            _ => Operator::F64Const {
+                value: 0.0f64.into(),
+            },
+        }));
+        out.push(op(Operator::LocalSet {
+            local_index: base + num_params + i as u32,
+        }));
+    }
+    out.push(op(Operator::Block {
+        blockty: match g.data.results.first() {
+            Some(ty) => wasmparser::BlockType::Type(*ty),
+            None => wasmparser::BlockType::Empty,
+        },
+    }));
+    // Depth of the operator within the body, which the block replaces.
+    let mut depth = 0u32;
+    for o in &g.ops[g.body..] {
+        let o = match o.clone() {
+            MachOperator::Operator {
+                op: Some(callee_op),
+                annot,
+            } => MachOperator::Operator {
+                op: Some(match callee_op {
+                    Operator::LocalGet { local_index } => Operator::LocalGet {
+                        local_index: local_index + base,
+                    },
+                    Operator::LocalSet { local_index } => Operator::LocalSet {
+                        local_index: local_index + base,
+                    },
+                    Operator::LocalTee { local_index } => Operator::LocalTee {
+                        local_index: local_index + base,
+                    },
+                    Operator::Return => Operator::Br {
+                        relative_depth: depth,
+                    },
+                    op => op,
+                }),
+                annot,
+            },
+            MachOperator::Instruction {
+                op: callee_op,
+                annot,
+            } => MachOperator::Instruction {
+                op: match callee_op {
+                    Instruction::LocalGet(i) => Instruction::LocalGet(i + base),
+                    Instruction::LocalSet(i) => Instruction::LocalSet(i + base),
+                    Instruction::LocalTee(i) => Instruction::LocalTee(i + base),
+                    Instruction::Return => Instruction::Br(depth),
+                    op => op,
+                },
+                annot,
+            },
+            MachOperator::EndBody => break,
+            o => o,
+        };
+        match shape(&o) {
+            Shape::Open => depth += 1,
+            Shape::End if depth == 0 => {
+                // The end of the body, past which there is only the
+                // trailing `return`.
+                out.push(o);
+                return;
+            }
+            Shape::End => depth -= 1,
+            _ => {}
+        }
+        out.push(o);
+    }
+    out.push(op(Operator::End));
+}
+
+/// Drops the functions `reach` finds dead and renumbers the functions,
+/// globals, types and data segments the rest refer to.
+///
+/// `StartFn` ids are renumbered among the live defined functions, so the
+/// stream describes the module [`Reach`] numbers.
+pub fn prune<'a, Annot, E>(
+    ops: impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>,
+    reach: &Reach,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    ops.flat_scan_mach(prune_pass, Prune::new(reach))
+}
+
+/// State of [`prune_pass`] between operators.
+#[derive(Clone, Debug)]
+pub struct Prune<'r> {
+    reach: &'r Reach,
+    /// Whether the current function is dead.
+    dead: bool,
+}
+
+impl<'r> Prune<'r> {
+    /// State for the start of a stream pruned with `reach`.
+    pub fn new(reach: &'r Reach) -> Self {
+        Prune { reach, dead: false }
+    }
+}
+
+/// The per-operator rewrite behind [`prune`], for use with
+/// [`IteratorExt::flat_scan_mach`].
+pub fn prune_pass<'a, Annot>(
+    _: &mut FnData,
+    _: u32,
+    o: MachOperator<'a, Annot>,
+    s: &mut Prune<'_>,
+) -> Vec<MachOperator<'a, Annot>> {
+    match o {
+        MachOperator::StartFn { id, data } => match s.reach.body(id) {
+            Some(id) => {
+                s.dead = false;
+                alloc::vec![MachOperator::StartFn { id, data }]
+            }
+            None => {
+                s.dead = true;
+                Vec::new()
+            }
+        },
+        _ if s.dead => Vec::new(),
+        MachOperator::Operator {
+            op: Some(op),
+            annot,
+        } => alloc::vec![MachOperator::Operator {
+            op: Some(s.reach.operator(op)),
+            annot,
+        }],
+        MachOperator::Instruction { op, annot } => alloc::vec![MachOperator::Instruction {
+            op: s.reach.instruction(op),
+            annot,
+        }],
+        o => alloc::vec![o],
+    }
+}
+
+/// Removes dead stores to locals and merges locals of the same type whose
+/// values are never live at the same time, renumbering the locals of every
+/// function.
+///
+/// A `local.tee` whose value is never read is removed, and so is a
+/// `local.set` whose value is never read when it stores a constant or a
+/// variable, along with the operator pushing it. Other dead stores are kept,
+/// as their value would need a `drop`. Locals that are never accessed are
+/// removed. Parameters keep their indices, and locals whose initial value is
+/// read only share slots of declared locals, which start out zeroed.
+///
+/// Functions [`Liveness`](crate::liveness::Liveness) does not analyse are
+/// left as they are.
+pub fn coalesce_locals<'a, Annot, E>(
+    ops: impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    ops.flat_scan_mach(coalesce_locals_pass, CoalesceLocals::new())
+}
+
+/// State of [`coalesce_locals_pass`] between operators.
+#[derive(Clone, Debug)]
+pub struct CoalesceLocals<'a, Annot> {
+    /// The current function from its `StartFn`, held back until its
+    /// `EndBody`.
+    func: Vec<MachOperator<'a, Annot>>,
+}
+
+impl<Annot> CoalesceLocals<'_, Annot> {
+    /// State for the start of a stream.
+    pub fn new() -> Self {
+        CoalesceLocals { func: Vec::new() }
+    }
+}
+
+impl<Annot> Default for CoalesceLocals<'_, Annot> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The per-operator rewrite behind [`coalesce_locals`], for use with
+/// [`IteratorExt::flat_scan_mach`].
+pub fn coalesce_locals_pass<'a, Annot>(
+    _: &mut FnData,
+    _: u32,
+    o: MachOperator<'a, Annot>,
+    s: &mut CoalesceLocals<'a, Annot>,
+) -> Vec<MachOperator<'a, Annot>> {
+    if let MachOperator::EndBody = o {
+        let mut out = coalesce_fn(core::mem::take(&mut s.func));
+        out.push(o);
+        return out;
+    }
+    s.func.push(o);
+    Vec::new()
+}
+
+/// Whether `o` only pushes a value, so that it can be removed along with a
+/// store of it.
+fn pure_push<Annot>(o: &MachOperator<'_, Annot>) -> bool {
+    match o {
+        MachOperator::Operator { op: Some(op), .. } => matches!(
+            op,
+            Operator::I32Const { .. }
+                | Operator::I64Const { .. }
+                | Operator::F32Const { .. }
+                | Operator::F64Const { .. }
+                | Operator::LocalGet { .. }
+                | Operator::GlobalGet { .. }
+        ),
+        MachOperator::Instruction { op, .. } => matches!(
+            op,
+            Instruction::I32Const(_)
+                | Instruction::I64Const(_)
+                | Instruction::F32Const(_)
+                | Instruction::F64Const(_)
+                | Instruction::LocalGet(_)
+                | Instruction::GlobalGet(_)
+        ),
+        _ => false,
+    }
+}
+
+/// Coalesces the locals of `func`, everything from its `StartFn` to the end
+/// of its body.
+fn coalesce_fn<'a, Annot>(mut func: Vec<MachOperator<'a, Annot>>) -> Vec<MachOperator<'a, Annot>> {
+    let (Some(MachOperator::StartFn { data, .. }), Some(start)) = (
+        func.first(),
+        func.iter()
+            .position(|o| matches!(o, MachOperator::StartBody)),
+    ) else {
+        return func;
+    };
+    let types = data
+        .params
+        .iter()
+        .chain(&data.locals)
+        .copied()
+        .collect::<Vec<_>>();
+    let num_params = data.params.len();
+    let n = types.len();
+    let mut body = func.split_off(start + 1);
+    let live = loop {
+        let Some(live) = Liveness::new(&body, n as u32) else {
+            func.extend(body);
+            return func;
+        };
+        let mut dead = alloc::vec![false; body.len()];
+        for (i, o) in body.iter().enumerate() {
+            match Access::of(o) {
+                Some(Access::Tee(l)) if !live.live_out(i, l) => dead[i] = true,
+                Some(Access::Set(l))
+                    if !live.live_out(i, l) && i > 0 && !dead[i - 1] && pure_push(&body[i - 1]) =>
+                {
+                    dead[i - 1] = true;
+                    dead[i] = true;
+                }
+                _ => {}
+            }
+        }
+        if !dead.contains(&true) {
+            break live;
+        }
+        let mut dead = dead.into_iter();
+        body.retain(|_| !dead.next().unwrap());
+    };
+
+    // Two locals conflict if one is written while the other is live. Every
+    // local is written on entry, but only the initial values of the locals
+    // live there matter.
+    let mut conflicts = alloc::vec![false; n * n];
+    let entry = live.live_at_entry_locals().collect::<Vec<_>>();
+    for &a in &entry {
+        for &b in &entry {
+            conflicts[a as usize * n + b as usize] = a != b;
+        }
+    }
+    let mut used = alloc::vec![false; n];
+    for (i, o) in body.iter().enumerate() {
+        let Some(access) = Access::of(o) else {
+            continue;
+        };
+        let a = access.local();
+        used[a as usize] = true;
+        if let Access::Set(_) | Access::Tee(_) = access {
+            for b in live.live_out_locals(i).filter(|&b| b != a) {
+                conflicts[a as usize * n + b as usize] = true;
+                conflicts[b as usize * n + a as usize] = true;
+            }
+        }
+    }
+
+    // Parameters keep their slots; every other local takes the first slot
+    // of its type it does not conflict with.
+    let mut slots = (0..num_params)
+        .map(|p| (types[p], alloc::vec![p]))
+        .collect::<Vec<_>>();
+    let mut slot_of = (0..n)
+        .map(|l| (l < num_params).then_some(l as u32))
+        .collect::<Vec<_>>();
+    for l in (num_params..n).filter(|&l| used[l]) {
+        let initial = live.live_at_entry(l as u32);
+        let slot = slots.iter().enumerate().position(|(s, (ty, members))| {
+            *ty == types[l]
+                && !(initial && s < num_params)
+                && members.iter().all(|&m| !conflicts[l * n + m])
+        });
+        let slot = slot.unwrap_or_else(|| {
+            slots.push((types[l], Vec::new()));
+            slots.len() - 1
+        });
+        slots[slot].1.push(l);
+        slot_of[l] = Some(slot as u32);
+    }
+
+    let locals = slots[num_params..]
+        .iter()
+        .map(|(ty, _)| *ty)
+        .collect::<Vec<_>>();
+    let start_body = func.pop();
+    func.retain(|o| !matches!(o, MachOperator::Local { .. }));
+    let mut declared = Vec::<MachOperator<'a, Annot>>::new();
+    for &ty in &locals {
+        match declared.last_mut() {
+            Some(MachOperator::Local { count, ty: last }) if *last == ty => *count += 1,
+            _ => declared.push(MachOperator::Local { count: 1, ty }),
+        }
+    }
+    if let Some(MachOperator::StartFn { data, .. }) = func.first_mut() {
+        data.locals = locals;
+    }
+    func.extend(declared);
+    func.extend(start_body);
+    let slot = |l: u32| slot_of[l as usize].expect("accessed locals have slots");
+    func.extend(body.into_iter().map(|o| match o {
+        MachOperator::Operator {
+            op: Some(op),
+            annot,
+        } => MachOperator::Operator {
+            op: Some(match op {
+                Operator::LocalGet { local_index } => Operator::LocalGet {
+                    local_index: slot(local_index),
+                },
+                Operator::LocalSet { local_index } => Operator::LocalSet {
+                    local_index: slot(local_index),
+                },
+                Operator::LocalTee { local_index } => Operator::LocalTee {
+                    local_index: slot(local_index),
+                },
+                op => op,
+            }),
+            annot,
+        },
+        MachOperator::Instruction { op, annot } => MachOperator::Instruction {
+            op: match op {
+                Instruction::LocalGet(l) => Instruction::LocalGet(slot(l)),
+                Instruction::LocalSet(l) => Instruction::LocalSet(slot(l)),
+                Instruction::LocalTee(l) => Instruction::LocalTee(slot(l)),
+                op => op,
+            },
+            annot,
+        },
+        o => o,
+    }));
+    func
+}
@@ -0,0 +1,236 @@
+//! Peephole optimisation.
+//!
+//! A [`Rule`] rewrites a short run of consecutive operators, such as
+//! `local.set x; local.get x`, into an equivalent one, such as
+//! `local.tee x`. Rules are written with [`peephole_rule!`](crate::peephole_rule)
+//! as slice patterns over [`Instruction`]s and gathered into [`Rules`];
+//! [`peephole`] applies them to a `MachOperator` stream.
+//!
+//! Operators are matched in their encoded form whichever form they arrive
+//! in, and only rewritten operators are emitted as
+//! `MachOperator::Instruction`s. Anything other than an operator, such as a
+//! trap or the end of a body, ends the run being matched.
+
+use alloc::vec::Vec;
+use wasm_encoder::{
+    Instruction,
+    reencode::{Reencode, RoundtripReencoder},
+};
+
+use crate::ops::{FnData, IteratorExt, MachOperator};
+
+/// Defines a [`Rule`](crate::peephole::Rule).
+///
+/// Takes the rule's name, a slice pattern over the matched
+/// [`Instruction`](crate::wasm_encoder::Instruction)s with an optional
+/// guard, and the instructions replacing them. The variants of
+/// `Instruction` are in scope, and bindings are references.
+///
+/// # Example
+///
+/// ```ignore
+/// let rule = peephole_rule!("set-get": [LocalSet(x), LocalGet(y)] if x == y => [LocalTee(*x)]);
+/// ```
+#[macro_export]
+macro_rules! peephole_rule {
+    ($name:literal: [$($pat:pat),+ $(,)?] $(if $guard:expr)? => [$($rep:expr),* $(,)?]) => {
+        $crate::peephole::Rule {
+            name: $name,
+            len: [$(stringify!($pat)),+].len(),
+            rewrite: {
+                #[allow(unused_imports, unused_variables)]
+                fn rewrite<'a>(
+                    ops: &[$crate::wasm_encoder::Instruction<'a>],
+                ) -> $crate::__::core::option::Option<
+                    $crate::alloc::vec::Vec<$crate::wasm_encoder::Instruction<'a>>,
+                > {
+                    use $crate::wasm_encoder::Instruction::*;
+                    match ops {
+                        [$($pat),+] $(if $guard)? => {
+                            $crate::__::core::option::Option::Some($crate::alloc::vec![$($rep),*])
+                        }
+                        _ => $crate::__::core::option::Option::None,
+                    }
+                }
+                rewrite
+            },
+        }
+    };
+}
+
+/// A rewrite of a run of consecutive operators.
+#[derive(Clone, Copy, Debug)]
+pub struct Rule {
+    /// Name of the rule, for diagnostics.
+    pub name: &'static str,
+    /// Number of operators the rule matches.
+    pub len: usize,
+    /// Returns the replacement of `len` operators, or `None` if the rule
+    /// does not match them.
+    ///
+    /// Replacements are matched again, so they must not let the rules
+    /// rewrite them forever, for instance by growing.
+    pub rewrite: for<'a> fn(&[Instruction<'a>]) -> Option<Vec<Instruction<'a>>>,
+}
+
+/// The rules [`Rules::default`] starts with.
+///
+/// They drop identities such as `i32.const 0; i32.add`, merge
+/// `local.set x; local.get x` into `local.tee x`, drop values that are
+/// pushed only to be dropped, and drop double negations of branch
+/// conditions.
+pub const DEFAULT_RULES: &[Rule] = &[
+    peephole_rule!("set-get": [LocalSet(x), LocalGet(y)] if x == y => [LocalTee(*x)]),
+    peephole_rule!("tee-drop": [LocalTee(x), Drop] => [LocalSet(*x)]),
+    peephole_rule!("get-drop": [LocalGet(_), Drop] => []),
+    peephole_rule!("i32-const-drop": [I32Const(_), Drop] => []),
+    peephole_rule!("i64-const-drop": [I64Const(_), Drop] => []),
+    peephole_rule!("nop": [Nop] => []),
+    peephole_rule!("i32-add-0": [I32Const(0), I32Add | I32Sub | I32Or | I32Xor] => []),
+    peephole_rule!(
+        "i32-shift-0": [I32Const(0), I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr] => []
+    ),
+    peephole_rule!("i32-mul-1": [I32Const(1), I32Mul | I32DivS | I32DivU] => []),
+    peephole_rule!("i32-and-1s": [I32Const(-1), I32And] => []),
+    peephole_rule!("i64-add-0": [I64Const(0), I64Add | I64Sub | I64Or | I64Xor] => []),
+    peephole_rule!(
+        "i64-shift-0": [I64Const(0), I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr] => []
+    ),
+    peephole_rule!("i64-mul-1": [I64Const(1), I64Mul | I64DivS | I64DivU] => []),
+    peephole_rule!("i64-and-1s": [I64Const(-1), I64And] => []),
+    peephole_rule!("eqz-eqz-br_if": [I32Eqz, I32Eqz, BrIf(l)] => [BrIf(*l)]),
+    peephole_rule!("eqz-eqz-if": [I32Eqz, I32Eqz, If(ty)] => [If(*ty)]),
+];
+
+/// A set of rules, tried in order.
+#[derive(Clone, Debug)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// A set without any rules.
+    pub fn empty() -> Self {
+        Rules { rules: Vec::new() }
+    }
+
+    /// Adds `rule`, tried after the rules already in the set.
+    pub fn push(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// The rules in the set.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Number of operators the longest rule matches.
+    fn window(&self) -> usize {
+        self.rules.iter().map(|r| r.len).max().unwrap_or(0)
+    }
+}
+
+impl Default for Rules {
+    /// The [`DEFAULT_RULES`].
+    fn default() -> Self {
+        Rules {
+            rules: DEFAULT_RULES.to_vec(),
+        }
+    }
+}
+
+impl Extend<Rule> for Rules {
+    fn extend<T: IntoIterator<Item = Rule>>(&mut self, iter: T) {
+        self.rules.extend(iter);
+    }
+}
+
+/// Applies `rules` to a machine operator stream.
+///
+/// After each operator, the first rule matching the operators that end
+/// with it replaces them, and matching starts again with the replacement,
+/// so rewrites can enable each other.
+pub fn peephole<'a, Annot: Clone, E>(
+    ops: impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>,
+    rules: &Rules,
+) -> impl Iterator<Item = Result<MachOperator<'a, Annot>, E>> {
+    ops.flat_scan_mach(peephole_pass, Peephole::new(rules))
+}
+
+/// State of [`peephole_pass`] between operators.
+#[derive(Clone, Debug)]
+pub struct Peephole<'r, 'a, Annot> {
+    rules: &'r Rules,
+    /// Operators held back for matching.
+    window: Vec<MachOperator<'a, Annot>>,
+    /// The encoded form of every operator in `window`.
+    encoded: Vec<Instruction<'a>>,
+}
+
+impl<'r, 'a, Annot> Peephole<'r, 'a, Annot> {
+    /// State for the start of a stream rewritten with `rules`.
+    pub fn new(rules: &'r Rules) -> Self {
+        Peephole {
+            rules,
+            window: Vec::new(),
+            encoded: Vec::new(),
+        }
+    }
+}
+
+/// The per-operator rewrite behind [`peephole`], for use with
+/// [`IteratorExt::flat_scan_mach`].
+pub fn peephole_pass<'a, Annot: Clone>(
+    _: &mut FnData,
+    _: u32,
+    o: MachOperator<'a, Annot>,
+    s: &mut Peephole<'_, 'a, Annot>,
+) -> Vec<MachOperator<'a, Annot>> {
+    let instr = match &o {
+        MachOperator::Instruction { op, .. } => Some(op.clone()),
+        MachOperator::Operator { op: Some(op), .. } => {
+            RoundtripReencoder.instruction(op.clone()).ok()
+        }
+        _ => None,
+    };
+    let Some(instr) = instr else {
+        // Not an operator: emit everything held back, then `o`.
+        s.encoded.clear();
+        let mut out = core::mem::take(&mut s.window);
+        out.push(o);
+        return out;
+    };
+    s.window.push(o);
+    s.encoded.push(instr);
+    'matching: loop {
+        for rule in s.rules.rules() {
+            let Some(start) = s.encoded.len().checked_sub(rule.len) else {
+                continue;
+            };
+            if let Some(replacement) = (rule.rewrite)(&s.encoded[start..]) {
+                let annot = match &s.window[start] {
+                    MachOperator::Operator { annot, .. }
+                    | MachOperator::Instruction { annot, .. } => annot.clone(),
+                    _ => unreachable!("only operators are held back"),
+                };
+                s.window.truncate(start);
+                s.encoded.truncate(start);
+                for op in replacement {
+                    s.window.push(MachOperator::Instruction {
+                        op: op.clone(),
+                        annot: annot.clone(),
+                    });
+                    s.encoded.push(op);
+                }
+                continue 'matching;
+            }
+        }
+        break;
+    }
+    // Keep enough operators for the longest rule to match with the next one.
+    let keep = s.rules.window().saturating_sub(1);
+    let emit = s.window.len().saturating_sub(keep);
+    s.encoded.drain(..emit);
+    s.window.drain(..emit).collect()
+}
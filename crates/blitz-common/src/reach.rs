@@ -0,0 +1,551 @@
+//! Module-level reachability.
+//!
+//! [`Reach`] finds the functions, globals, types and data segments of a
+//! [`Module`] that can still be used once it is instantiated, starting from
+//! its exports, its start function and its element segments, and numbers
+//! the live entities of each index space consecutively.
+//! [`prune`](crate::passes::prune) applies the numbering to a
+//! `MachOperator` stream, dropping the bodies of dead functions.
+//!
+//! Imports are always live, as removing one changes what the embedder has
+//! to provide, and so are active data segments, which write to memory when
+//! the module is instantiated. Types are only pruned when no reference type
+//! or GC operator names one, since the value types carried by the stream
+//! are not renumbered.
+
+use alloc::vec::Vec;
+use wasm_encoder::Instruction;
+use wasmparser::{
+    BlockType, ConstExpr, DataKind, ElementItems, ElementKind, ExternalKind, HeapType, Operator,
+    RefType, TryTable, ValType,
+};
+
+use crate::{CompileError, module::Module};
+
+/// The live entities of a module, with their new indices.
+#[derive(Clone, Debug, Default)]
+pub struct Reach {
+    /// New index of every function, imports included; `None` if dead.
+    funcs: Vec<Option<u32>>,
+    /// New index of every global, imports included; `None` if dead.
+    globals: Vec<Option<u32>>,
+    /// New index of every type; `None` if dead.
+    types: Vec<Option<u32>>,
+    /// New index of every data segment; `None` if dead.
+    data: Vec<Option<u32>>,
+    num_func_imports: u32,
+    num_global_imports: u32,
+}
+
+impl Reach {
+    /// Finds the live entities of `module`.
+    ///
+    /// Fails if the module refers to an entity it does not have.
+    pub fn new(module: &Module<'_>) -> Result<Self, CompileError> {
+        let num_func_imports = module.num_func_imports();
+        let num_global_imports = (module.globals.len() - module.global_inits.len()) as u32;
+        let mut m = Marker {
+            funcs: alloc::vec![false; module.funcs.len()],
+            globals: alloc::vec![false; module.globals.len()],
+            types: alloc::vec![false; module.types.len()],
+            data: alloc::vec![false; module.data.len()],
+            pinned: false,
+            work: Vec::new(),
+        };
+        for f in 0..num_func_imports {
+            m.func(f)?;
+        }
+        for g in 0..num_global_imports {
+            m.global(g)?;
+        }
+        for export in &module.exports {
+            match export.kind {
+                ExternalKind::Func => m.func(export.index)?,
+                ExternalKind::Global => m.global(export.index)?,
+                _ => {}
+            }
+        }
+        if let Some(start) = module.start {
+            m.func(start)?;
+        }
+        for elem in &module.elements {
+            if let ElementKind::Active { offset_expr, .. } = &elem.kind {
+                m.const_expr(offset_expr)?;
+            }
+            match &elem.items {
+                ElementItems::Functions(r) => {
+                    for f in r.clone() {
+                        m.func(f?)?;
+                    }
+                }
+                ElementItems::Expressions(ty, r) => {
+                    m.ref_type(*ty);
+                    for e in r.clone() {
+                        m.const_expr(&e?)?;
+                    }
+                }
+            }
+        }
+        for (index, data) in module.data.iter().enumerate() {
+            if let DataKind::Active { offset_expr, .. } = &data.kind {
+                m.data(index as u32)?;
+                m.const_expr(offset_expr)?;
+            }
+        }
+        for init in module.table_inits.iter().flatten() {
+            m.const_expr(init)?;
+        }
+        for table in &module.tables {
+            m.ref_type(table.element_type);
+        }
+        for global in &module.globals {
+            m.val_type(global.content_type);
+        }
+        for ty in &module.types {
+            for &v in ty.params().iter().chain(ty.results()) {
+                m.val_type(v);
+            }
+        }
+        while let Some(item) = m.work.pop() {
+            match item {
+                Item::Func(f) => {
+                    m.ty(module.funcs[f as usize])?;
+                    let Some(id) = f.checked_sub(num_func_imports) else {
+                        continue;
+                    };
+                    let body = module
+                        .bodies
+                        .get(id as usize)
+                        .ok_or(CompileError::unknown("func", f))?;
+                    for local in body.get_locals_reader()? {
+                        m.val_type(local?.1);
+                    }
+                    for op in body.get_operators_reader()? {
+                        m.operator(&op?)?;
+                    }
+                }
+                Item::Global(g) => {
+                    if let Some(id) = g.checked_sub(num_global_imports) {
+                        m.const_expr(&module.global_inits[id as usize])?;
+                    }
+                }
+            }
+        }
+        if m.pinned {
+            m.types.fill(true);
+        }
+        Ok(Reach {
+            funcs: number(&m.funcs),
+            globals: number(&m.globals),
+            types: number(&m.types),
+            data: number(&m.data),
+            num_func_imports,
+            num_global_imports,
+        })
+    }
+
+    /// New index of the function at `index` in the function index space,
+    /// or `None` if it is dead.
+    pub fn func(&self, index: u32) -> Option<u32> {
+        self.funcs.get(index as usize).copied().flatten()
+    }
+
+    /// New index of defined function `id`, counted among the defined
+    /// functions like the `id` of `MachOperator::StartFn`, or `None` if it
+    /// is dead.
+    pub fn body(&self, id: u32) -> Option<u32> {
+        self.func(id + self.num_func_imports)
+            .map(|f| f - self.num_func_imports)
+    }
+
+    /// New index of the global at `index` in the global index space, or
+    /// `None` if it is dead.
+    pub fn global(&self, index: u32) -> Option<u32> {
+        self.globals.get(index as usize).copied().flatten()
+    }
+
+    /// New index of the type at `index`, or `None` if it is dead.
+    pub fn ty(&self, index: u32) -> Option<u32> {
+        self.types.get(index as usize).copied().flatten()
+    }
+
+    /// New index of data segment `index`, or `None` if it is dead.
+    pub fn data(&self, index: u32) -> Option<u32> {
+        self.data.get(index as usize).copied().flatten()
+    }
+
+    /// Number of imported functions, which keep their indices.
+    pub fn num_func_imports(&self) -> u32 {
+        self.num_func_imports
+    }
+
+    /// Number of imported globals, which keep their indices.
+    pub fn num_global_imports(&self) -> u32 {
+        self.num_global_imports
+    }
+
+    /// Renumbers the functions, globals, types and data segments `op`
+    /// refers to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `op` refers to a dead entity, which no operator of a live
+    /// function does.
+    pub fn operator<'a>(&self, op: Operator<'a>) -> Operator<'a> {
+        match op {
+            Operator::Call { function_index } => Operator::Call {
+                function_index: live(&self.funcs, "func", function_index),
+            },
+            Operator::ReturnCall { function_index } => Operator::ReturnCall {
+                function_index: live(&self.funcs, "func", function_index),
+            },
+            Operator::RefFunc { function_index } => Operator::RefFunc {
+                function_index: live(&self.funcs, "func", function_index),
+            },
+            Operator::GlobalGet { global_index } => Operator::GlobalGet {
+                global_index: live(&self.globals, "global", global_index),
+            },
+            Operator::GlobalSet { global_index } => Operator::GlobalSet {
+                global_index: live(&self.globals, "global", global_index),
+            },
+            Operator::CallIndirect {
+                type_index,
+                table_index,
+            } => Operator::CallIndirect {
+                type_index: live(&self.types, "type", type_index),
+                table_index,
+            },
+            Operator::ReturnCallIndirect {
+                type_index,
+                table_index,
+            } => Operator::ReturnCallIndirect {
+                type_index: live(&self.types, "type", type_index),
+                table_index,
+            },
+            Operator::Block { blockty } => Operator::Block {
+                blockty: self.block_type(blockty),
+            },
+            Operator::Loop { blockty } => Operator::Loop {
+                blockty: self.block_type(blockty),
+            },
+            Operator::If { blockty } => Operator::If {
+                blockty: self.block_type(blockty),
+            },
+            Operator::MemoryInit { data_index, mem } => Operator::MemoryInit {
+                data_index: live(&self.data, "data", data_index),
+                mem,
+            },
+            Operator::DataDrop { data_index } => Operator::DataDrop {
+                data_index: live(&self.data, "data", data_index),
+            },
+            Operator::ArrayNewData {
+                array_type_index,
+                array_data_index,
+            } => Operator::ArrayNewData {
+                array_type_index,
+                array_data_index: live(&self.data, "data", array_data_index),
+            },
+            Operator::ArrayInitData {
+                array_type_index,
+                array_data_index,
+            } => Operator::ArrayInitData {
+                array_type_index,
+                array_data_index: live(&self.data, "data", array_data_index),
+            },
+            op => op,
+        }
+    }
+
+    /// Renumbers the functions, globals, types and data segments `op`
+    /// refers to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `op` refers to a dead entity, which no operator of a live
+    /// function does.
+    pub fn instruction<'a>(&self, op: Instruction<'a>) -> Instruction<'a> {
+        use wasm_encoder::BlockType as B;
+        let block_type = |ty| match ty {
+            B::FunctionType(t) => B::FunctionType(live(&self.types, "type", t)),
+            ty => ty,
+        };
+        match op {
+            Instruction::Call(f) => Instruction::Call(live(&self.funcs, "func", f)),
+            Instruction::ReturnCall(f) => Instruction::ReturnCall(live(&self.funcs, "func", f)),
+            Instruction::RefFunc(f) => Instruction::RefFunc(live(&self.funcs, "func", f)),
+            Instruction::GlobalGet(g) => Instruction::GlobalGet(live(&self.globals, "global", g)),
+            Instruction::GlobalSet(g) => Instruction::GlobalSet(live(&self.globals, "global", g)),
+            Instruction::CallIndirect {
+                type_index,
+                table_index,
+            } => Instruction::CallIndirect {
+                type_index: live(&self.types, "type", type_index),
+                table_index,
+            },
+            Instruction::ReturnCallIndirect {
+                type_index,
+                table_index,
+            } => Instruction::ReturnCallIndirect {
+                type_index: live(&self.types, "type", type_index),
+                table_index,
+            },
+            Instruction::Block(ty) => Instruction::Block(block_type(ty)),
+            Instruction::Loop(ty) => Instruction::Loop(block_type(ty)),
+            Instruction::If(ty) => Instruction::If(block_type(ty)),
+            Instruction::MemoryInit { mem, data_index } => Instruction::MemoryInit {
+                mem,
+                data_index: live(&self.data, "data", data_index),
+            },
+            Instruction::DataDrop(d) => Instruction::DataDrop(live(&self.data, "data", d)),
+            Instruction::ArrayNewData {
+                array_type_index,
+                array_data_index,
+            } => Instruction::ArrayNewData {
+                array_type_index,
+                array_data_index: live(&self.data, "data", array_data_index),
+            },
+            Instruction::ArrayInitData {
+                array_type_index,
+                array_data_index,
+            } => Instruction::ArrayInitData {
+                array_type_index,
+                array_data_index: live(&self.data, "data", array_data_index),
+            },
+            op => op,
+        }
+    }
+
+    fn block_type(&self, ty: BlockType) -> BlockType {
+        match ty {
+            BlockType::FuncType(t) => BlockType::FuncType(live(&self.types, "type", t)),
+            ty => ty,
+        }
+    }
+}
+
+/// New index of live entity `index` of the `kind` index space.
+fn live(map: &[Option<u32>], kind: &str, index: u32) -> u32 {
+    map.get(index as usize)
+        .copied()
+        .flatten()
+        .unwrap_or_else(|| panic!("{kind} {index} is not live"))
+}
+
+/// Numbers the live entities of an index space consecutively.
+fn number(live: &[bool]) -> Vec<Option<u32>> {
+    let mut next = 0;
+    live.iter()
+        .map(|&live| {
+            live.then(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect()
+}
+
+enum Item {
+    Func(u32),
+    Global(u32),
+}
+
+/// The entities found live so far.
+struct Marker {
+    funcs: Vec<bool>,
+    globals: Vec<bool>,
+    types: Vec<bool>,
+    data: Vec<bool>,
+    /// Whether a reference type or an operator outside the MVP names a
+    /// type, keeping every type.
+    pinned: bool,
+    /// Live functions and globals whose references are yet to be marked.
+    work: Vec<Item>,
+}
+
+impl Marker {
+    fn func(&mut self, index: u32) -> Result<(), CompileError> {
+        let live = self
+            .funcs
+            .get_mut(index as usize)
+            .ok_or(CompileError::unknown("func", index))?;
+        if !*live {
+            *live = true;
+            self.work.push(Item::Func(index));
+        }
+        Ok(())
+    }
+
+    fn global(&mut self, index: u32) -> Result<(), CompileError> {
+        let live = self
+            .globals
+            .get_mut(index as usize)
+            .ok_or(CompileError::unknown("global", index))?;
+        if !*live {
+            *live = true;
+            self.work.push(Item::Global(index));
+        }
+        Ok(())
+    }
+
+    fn ty(&mut self, index: u32) -> Result<(), CompileError> {
+        *self
+            .types
+            .get_mut(index as usize)
+            .ok_or(CompileError::unknown("type", index))? = true;
+        Ok(())
+    }
+
+    fn data(&mut self, index: u32) -> Result<(), CompileError> {
+        *self
+            .data
+            .get_mut(index as usize)
+            .ok_or(CompileError::unknown("data", index))? = true;
+        Ok(())
+    }
+
+    fn val_type(&mut self, ty: ValType) {
+        if let ValType::Ref(ty) = ty {
+            self.ref_type(ty);
+        }
+    }
+
+    fn ref_type(&mut self, ty: RefType) {
+        if ty.type_index().is_some() {
+            self.pinned = true;
+        }
+    }
+
+    fn heap_type(&mut self, hty: HeapType) {
+        if !matches!(hty, HeapType::Abstract { .. }) {
+            self.pinned = true;
+        }
+    }
+
+    fn const_expr(&mut self, expr: &ConstExpr<'_>) -> Result<(), CompileError> {
+        for op in expr.get_operators_reader() {
+            self.operator(&op?)?;
+        }
+        Ok(())
+    }
+
+    fn operator(&mut self, op: &Operator<'_>) -> Result<(), CompileError> {
+        match *op {
+            Operator::Call { function_index }
+            | Operator::ReturnCall { function_index }
+            | Operator::RefFunc { function_index } => self.func(function_index),
+            Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                self.global(global_index)
+            }
+            Operator::CallIndirect { type_index, .. }
+            | Operator::ReturnCallIndirect { type_index, .. } => self.ty(type_index),
+            Operator::Block { blockty } | Operator::Loop { blockty } | Operator::If { blockty } => {
+                match blockty {
+                    BlockType::Empty => {}
+                    BlockType::Type(ty) => self.val_type(ty),
+                    BlockType::FuncType(t) => self.ty(t)?,
+                }
+                Ok(())
+            }
+            Operator::Try { blockty }
+            | Operator::TryTable {
+                try_table: TryTable { ty: blockty, .. },
+            } => {
+                match blockty {
+                    BlockType::Empty => {}
+                    BlockType::Type(ty) => self.val_type(ty),
+                    BlockType::FuncType(_) => self.pinned = true,
+                }
+                Ok(())
+            }
+            Operator::MemoryInit { data_index, .. } | Operator::DataDrop { data_index } => {
+                self.data(data_index)
+            }
+            Operator::TypedSelect { ty } => {
+                self.val_type(ty);
+                Ok(())
+            }
+            Operator::RefNull { hty }
+            | Operator::RefTestNonNull { hty }
+            | Operator::RefTestNullable { hty }
+            | Operator::RefCastNonNull { hty }
+            | Operator::RefCastNullable { hty } => {
+                self.heap_type(hty);
+                Ok(())
+            }
+            Operator::BrOnCast {
+                from_ref_type,
+                to_ref_type,
+                ..
+            }
+            | Operator::BrOnCastFail {
+                from_ref_type,
+                to_ref_type,
+                ..
+            } => {
+                self.ref_type(from_ref_type);
+                self.ref_type(to_ref_type);
+                Ok(())
+            }
+            Operator::ArrayNewData {
+                array_data_index, ..
+            }
+            | Operator::ArrayInitData {
+                array_data_index, ..
+            } => {
+                self.pinned = true;
+                self.data(array_data_index)
+            }
+            // Type indices the stream is not renumbered for.
+            Operator::CallRef { .. }
+            | Operator::ReturnCallRef { .. }
+            | Operator::StructNew { .. }
+            | Operator::StructNewDefault { .. }
+            | Operator::StructGet { .. }
+            | Operator::StructGetS { .. }
+            | Operator::StructGetU { .. }
+            | Operator::StructSet { .. }
+            | Operator::ArrayNew { .. }
+            | Operator::ArrayNewDefault { .. }
+            | Operator::ArrayNewFixed { .. }
+            | Operator::ArrayNewElem { .. }
+            | Operator::ArrayGet { .. }
+            | Operator::ArrayGetS { .. }
+            | Operator::ArrayGetU { .. }
+            | Operator::ArraySet { .. }
+            | Operator::ArrayFill { .. }
+            | Operator::ArrayCopy { .. }
+            | Operator::ArrayInitElem { .. }
+            | Operator::StructAtomicGet { .. }
+            | Operator::StructAtomicGetS { .. }
+            | Operator::StructAtomicGetU { .. }
+            | Operator::StructAtomicSet { .. }
+            | Operator::StructAtomicRmwAdd { .. }
+            | Operator::StructAtomicRmwSub { .. }
+            | Operator::StructAtomicRmwAnd { .. }
+            | Operator::StructAtomicRmwOr { .. }
+            | Operator::StructAtomicRmwXor { .. }
+            | Operator::StructAtomicRmwXchg { .. }
+            | Operator::StructAtomicRmwCmpxchg { .. }
+            | Operator::ArrayAtomicGet { .. }
+            | Operator::ArrayAtomicGetS { .. }
+            | Operator::ArrayAtomicGetU { .. }
+            | Operator::ArrayAtomicSet { .. }
+            | Operator::ArrayAtomicRmwAdd { .. }
+            | Operator::ArrayAtomicRmwSub { .. }
+            | Operator::ArrayAtomicRmwAnd { .. }
+            | Operator::ArrayAtomicRmwOr { .. }
+            | Operator::ArrayAtomicRmwXor { .. }
+            | Operator::ArrayAtomicRmwXchg { .. }
+            | Operator::ArrayAtomicRmwCmpxchg { .. }
+            | Operator::ContNew { .. }
+            | Operator::ContBind { .. }
+            | Operator::Resume { .. }
+            | Operator::ResumeThrow { .. }
+            | Operator::Switch { .. } => {
+                self.pinned = true;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
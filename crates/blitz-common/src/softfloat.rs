@@ -0,0 +1,897 @@
+//! Software floating point.
+//!
+//! Native backends lower most [`FloatOp`]s to calls of a routine named after
+//! [`FloatOp::name`]. [`module`] builds those routines as a WASM module that
+//! only uses integer operators, so a backend compiles them with the same
+//! code generator as the module calling them; [`Routines`] records which
+//! ones a module needs and drives the backend over them.
+//!
+//! Every routine takes and returns floats as their bits, f32s in an i32 and
+//! f64s in an i64, rounds to nearest with ties to even, and produces the
+//! positive canonical NaN for every NaN result. The trapping conversions
+//! execute `unreachable` on NaN and out-of-range operands.
+
+use alloc::{vec, vec::Vec};
+use wasm_encoder::{
+    BlockType, CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction,
+    TypeSection,
+    reencode::{Reencode, RoundtripReencoder},
+};
+use wasmparser::ValType;
+
+use crate::{
+    CompileError, MachOperator,
+    backend::BackendContext,
+    float::{CANONICAL_NAN_F32, CANONICAL_NAN_F64, FloatKind, FloatOp},
+    module::Module,
+};
+
+/// The float operators whose routines a module calls, in the order they
+/// were first called.
+#[derive(Clone, Debug, Default)]
+pub struct Routines(Vec<FloatOp>);
+
+impl Routines {
+    /// Records a call to the routine of `float`.
+    pub fn call(&mut self, float: &FloatOp) {
+        if !self.0.contains(float) {
+            self.0.push(*float);
+        }
+    }
+
+    /// Drives `f` over the routines recorded, compiled from [`module`].
+    ///
+    /// `f` receives the context of that module, the operator whose routine
+    /// is being compiled and each of the routine's operators, already
+    /// converted to instructions. Nothing is compiled if no routine was
+    /// recorded.
+    pub fn compile<E: From<CompileError>>(
+        &self,
+        mut f: impl FnMut(&BackendContext<'_>, &FloatOp, &MachOperator<'_>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let wasm = module(&self.0);
+        let module = Module::new(&wasm)?;
+        let mut rewriter = RoundtripReencoder;
+        let cx = BackendContext::new(&module, &mut rewriter).map_err(CompileError::from)?;
+        let mut float = &self.0[0];
+        for op in module.mach_operators::<(), CompileError>() {
+            let op = match op? {
+                MachOperator::Operator {
+                    op: Some(op),
+                    annot,
+                } => MachOperator::Instruction {
+                    op: RoundtripReencoder
+                        .instruction(op)
+                        .map_err(CompileError::from)?,
+                    annot,
+                },
+                op => op,
+            };
+            if let MachOperator::StartFn { id, .. } = op {
+                float = &self.0[id as usize];
+            }
+            f(&cx, float, &op)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a module whose function `i` is the routine of `ops[i]`, exported
+/// under the operator's name.
+pub fn module(ops: &[FloatOp]) -> Vec<u8> {
+    let mut types = TypeSection::new();
+    let mut funcs = FunctionSection::new();
+    let mut exports = ExportSection::new();
+    let mut code = CodeSection::new();
+    for (index, float) in ops.iter().enumerate() {
+        types.ty().function(
+            float.params.iter().map(|&ty| bits(ty)),
+            [bits(float.result)],
+        );
+        funcs.function(index as u32);
+        exports.export(float.name, ExportKind::Func, index as u32);
+        code.function(&routine(float));
+    }
+    let mut module = wasm_encoder::Module::new();
+    module
+        .section(&types)
+        .section(&funcs)
+        .section(&exports)
+        .section(&code);
+    module.finish()
+}
+
+/// The integer type holding values of type `ty`.
+fn bits(ty: ValType) -> wasm_encoder::ValType {
+    match ty {
+        ValType::I64 | ValType::F64 => wasm_encoder::ValType::I64,
+        _ => wasm_encoder::ValType::I32,
+    }
+}
+
+/// The layout of a binary float format.
+#[derive(Clone, Copy)]
+struct Format {
+    /// Width of the fraction field.
+    mant: u32,
+    /// Width of the exponent field.
+    exp: u32,
+}
+
+const F32: Format = Format { mant: 23, exp: 8 };
+const F64: Format = Format { mant: 52, exp: 11 };
+
+impl Format {
+    fn of(ty: ValType) -> Self {
+        match ty {
+            ValType::F64 => F64,
+            _ => F32,
+        }
+    }
+    fn sign(self) -> u64 {
+        1 << (self.mant + self.exp)
+    }
+    fn abs(self) -> u64 {
+        self.sign() - 1
+    }
+    fn max_exp(self) -> u64 {
+        (1 << self.exp) - 1
+    }
+    fn bias(self) -> u64 {
+        (1 << (self.exp - 1)) - 1
+    }
+    fn frac(self) -> u64 {
+        (1 << self.mant) - 1
+    }
+    fn hidden(self) -> u64 {
+        1 << self.mant
+    }
+    fn inf(self) -> u64 {
+        self.max_exp() << self.mant
+    }
+    fn one(self) -> u64 {
+        self.bias() << self.mant
+    }
+    fn nan(self) -> u64 {
+        match self.mant {
+            23 => CANONICAL_NAN_F32 as u64,
+            _ => CANONICAL_NAN_F64,
+        }
+    }
+}
+
+/// An i64 local of a routine.
+#[derive(Clone, Copy)]
+struct Var(u32);
+
+/// An expression over i64 locals, compiled to stack code.
+///
+/// Comparisons produce i32s, as in WASM; [`Ex::ext`] widens them.
+#[derive(Clone)]
+enum Expr {
+    Get(Var),
+    Const(u64),
+    /// An operator applied to the values of its operands, in order.
+    Op(Instruction<'static>, Vec<Expr>),
+}
+
+impl From<Var> for Expr {
+    fn from(var: Var) -> Self {
+        Expr::Get(var)
+    }
+}
+
+/// An i64 constant.
+fn k(value: u64) -> Expr {
+    Expr::Const(value)
+}
+
+trait Ex: Into<Expr> + Sized {
+    fn unary(self, op: Instruction<'static>) -> Expr {
+        Expr::Op(op, vec![self.into()])
+    }
+    fn binary(self, op: Instruction<'static>, rhs: impl Ex) -> Expr {
+        Expr::Op(op, vec![self.into(), rhs.into()])
+    }
+    fn add(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Add, rhs)
+    }
+    fn sub(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Sub, rhs)
+    }
+    fn mul(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Mul, rhs)
+    }
+    fn and(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64And, rhs)
+    }
+    fn or(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Or, rhs)
+    }
+    fn xor(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Xor, rhs)
+    }
+    fn shl(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Shl, rhs)
+    }
+    fn shr_u(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64ShrU, rhs)
+    }
+    fn shr_s(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64ShrS, rhs)
+    }
+    fn clz(self) -> Expr {
+        self.unary(Instruction::I64Clz)
+    }
+    fn eqz(self) -> Expr {
+        self.unary(Instruction::I64Eqz)
+    }
+    fn eq(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Eq, rhs)
+    }
+    fn ne(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64Ne, rhs)
+    }
+    fn lt_u(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64LtU, rhs)
+    }
+    fn gt_u(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64GtU, rhs)
+    }
+    fn ge_u(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64GeU, rhs)
+    }
+    fn lt_s(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64LtS, rhs)
+    }
+    fn gt_s(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64GtS, rhs)
+    }
+    fn le_s(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64LeS, rhs)
+    }
+    fn ge_s(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I64GeS, rhs)
+    }
+    /// Both conditions hold.
+    fn both(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I32And, rhs)
+    }
+    /// Either condition holds.
+    fn either(self, rhs: impl Ex) -> Expr {
+        self.binary(Instruction::I32Or, rhs)
+    }
+    /// A condition as an i64, 0 or 1.
+    fn ext(self) -> Expr {
+        self.unary(Instruction::I64ExtendI32U)
+    }
+}
+
+impl Ex for Var {}
+impl Ex for Expr {}
+
+/// The body of one routine, with its locals allocated as it is built.
+///
+/// Routines only use empty block types, leave functions with `return` and
+/// never call, so any backend able to compile integer code can compile
+/// them.
+struct Body {
+    params: &'static [ValType],
+    result: ValType,
+    /// Number of i64 locals after the parameters.
+    locals: u32,
+    code: Vec<Instruction<'static>>,
+}
+
+impl Body {
+    fn new(float: &FloatOp) -> Self {
+        Self {
+            params: float.params,
+            result: float.result,
+            locals: 0,
+            code: Vec::new(),
+        }
+    }
+
+    /// The bits of parameter `index` as an i64, sign-extended if `signed`.
+    fn arg(&mut self, index: u32, signed: bool) -> Var {
+        if bits(self.params[index as usize]) == wasm_encoder::ValType::I64 {
+            return Var(index);
+        }
+        let var = self.var();
+        self.code.push(Instruction::LocalGet(index));
+        self.code.push(match signed {
+            true => Instruction::I64ExtendI32S,
+            false => Instruction::I64ExtendI32U,
+        });
+        self.code.push(Instruction::LocalSet(var.0));
+        var
+    }
+
+    fn var(&mut self) -> Var {
+        self.locals += 1;
+        Var(self.params.len() as u32 + self.locals - 1)
+    }
+
+    fn push(&mut self, expr: Expr) {
+        match expr {
+            Expr::Get(var) => self.code.push(Instruction::LocalGet(var.0)),
+            Expr::Const(value) => self.code.push(Instruction::I64Const(value as i64)),
+            Expr::Op(op, operands) => {
+                for operand in operands {
+                    self.push(operand);
+                }
+                self.code.push(op);
+            }
+        }
+    }
+
+    fn set(&mut self, var: Var, value: impl Ex) {
+        self.push(value.into());
+        self.code.push(Instruction::LocalSet(var.0));
+    }
+
+    /// A new local holding `value`.
+    fn let_(&mut self, value: impl Ex) -> Var {
+        let var = self.var();
+        self.set(var, value);
+        var
+    }
+
+    /// Returns `value`, wrapped to an i32 if the result is one.
+    fn ret(&mut self, value: impl Ex) {
+        self.push(value.into());
+        if bits(self.result) == wasm_encoder::ValType::I32 {
+            self.code.push(Instruction::I32WrapI64);
+        }
+        self.code.push(Instruction::Return);
+    }
+
+    fn trap(&mut self) {
+        self.code.push(Instruction::Unreachable);
+    }
+
+    fn when(&mut self, cond: impl Ex, then: impl FnOnce(&mut Self)) {
+        self.push(cond.into());
+        self.code.push(Instruction::If(BlockType::Empty));
+        then(self);
+        self.code.push(Instruction::End);
+    }
+
+    fn branch(&mut self, cond: impl Ex, then: impl FnOnce(&mut Self), els: impl FnOnce(&mut Self)) {
+        self.push(cond.into());
+        self.code.push(Instruction::If(BlockType::Empty));
+        then(self);
+        self.code.push(Instruction::Else);
+        els(self);
+        self.code.push(Instruction::End);
+    }
+
+    /// Runs `body` until `cond` no longer holds, at least once.
+    fn repeat(&mut self, body: impl FnOnce(&mut Self), cond: impl Ex) {
+        self.code.push(Instruction::Loop(BlockType::Empty));
+        body(self);
+        self.push(cond.into());
+        self.code.push(Instruction::BrIf(0));
+        self.code.push(Instruction::End);
+    }
+
+    fn finish(self) -> Function {
+        let mut func = Function::new([(self.locals, wasm_encoder::ValType::I64)]);
+        for op in &self.code {
+            func.instruction(op);
+        }
+        // every path returns explicitly
+        func.instruction(&Instruction::Unreachable);
+        func.instruction(&Instruction::End);
+        func
+    }
+
+    /// Returns the canonical NaN if `x` or `y` is a NaN.
+    fn nans(&mut self, fmt: Format, x: Var, y: Option<Var>) {
+        let mut cond = x.and(k(fmt.abs())).gt_u(k(fmt.inf()));
+        if let Some(y) = y {
+            cond = cond.either(y.and(k(fmt.abs())).gt_u(k(fmt.inf())));
+        }
+        self.when(cond, |f| f.ret(k(fmt.nan())));
+    }
+
+    /// Shifts `m` right by `shift`, setting its lowest bit if any bit
+    /// shifted out was set.
+    fn shr_sticky(&mut self, m: Var, shift: impl Ex) {
+        let s = self.let_(shift);
+        self.branch(
+            s.ge_u(k(64)),
+            |f| f.set(m, m.ne(k(0)).ext()),
+            |f| {
+                let lost = m.and(k(1).shl(s).sub(k(1))).ne(k(0)).ext();
+                f.set(m, m.shr_u(s).or(lost))
+            },
+        );
+    }
+
+    /// The biased exponent and the significand of finite `x`, with the
+    /// implicit bit made explicit but subnormals left as they are.
+    fn fields(&mut self, fmt: Format, x: Var) -> (Var, Var) {
+        let e = self.let_(x.shr_u(k(fmt.mant as u64)).and(k(fmt.max_exp())));
+        let m = self.let_(x.and(k(fmt.frac())));
+        self.branch(
+            e.eqz(),
+            |f| f.set(e, k(1)),
+            |f| f.set(m, m.or(k(fmt.hidden()))),
+        );
+        (e, m)
+    }
+
+    /// Like [`fields`](Self::fields) for nonzero `x`, with subnormals
+    /// normalised so that the significand's top bit is the implicit one; the
+    /// exponent may then be below 1.
+    fn unpack(&mut self, fmt: Format, x: Var) -> (Var, Var) {
+        let e = self.let_(x.shr_u(k(fmt.mant as u64)).and(k(fmt.max_exp())));
+        let m = self.let_(x.and(k(fmt.frac())));
+        self.branch(
+            e.eqz(),
+            |f| {
+                let s = f.let_(m.clz().sub(k(63 - fmt.mant as u64)));
+                f.set(m, m.shl(s));
+                f.set(e, k(1).sub(s));
+            },
+            |f| f.set(m, m.or(k(fmt.hidden()))),
+        );
+        (e, m)
+    }
+
+    /// Returns `sign` with the magnitude `m * 2^(e - bias - mant - 3)`,
+    /// rounded. `m` is below `2^(mant + 4)` and, unless the value is
+    /// subnormal, at least `2^(mant + 3)`; its three low bits are the
+    /// guard, round and sticky bits.
+    fn round_pack(&mut self, fmt: Format, sign: Var, e: Var, m: Var) {
+        let mant = fmt.mant as u64;
+        self.when(e.lt_s(k(1)), |f| {
+            f.shr_sticky(m, k(1).sub(e));
+            f.set(e, k(1));
+        });
+        let low = self.let_(m.and(k(7)));
+        self.set(m, m.shr_u(k(3)));
+        let up = low
+            .gt_u(k(4))
+            .either(low.eq(k(4)).both(m.and(k(1)).ne(k(0))));
+        self.when(up, |f| f.set(m, m.add(k(1))));
+        self.when(m.ge_u(k(2 << mant)), |f| {
+            f.set(m, m.shr_u(k(1)));
+            f.set(e, e.add(k(1)));
+        });
+        self.when(e.ge_s(k(fmt.max_exp())), |f| f.ret(sign.or(k(fmt.inf()))));
+        self.when(m.lt_u(k(fmt.hidden())), |f| f.set(e, k(0)));
+        self.ret(sign.or(e.shl(k(mant))).or(m.and(k(fmt.frac()))));
+    }
+
+    /// `x` as a key ordering floats like their values, for non-NaN `x`.
+    fn key(&mut self, fmt: Format, x: Var) -> Var {
+        let key = self.let_(x.and(k(fmt.abs())));
+        self.when(x.and(k(fmt.sign())).ne(k(0)), |f| f.set(key, k(0).sub(key)));
+        key
+    }
+}
+
+/// Builds the routine of `float`.
+fn routine(float: &FloatOp) -> Function {
+    let mut f = Body::new(float);
+    let name = float.name;
+    match float.kind {
+        FloatKind::Sign => sign(&mut f, Format::of(float.result), &name[4..]),
+        FloatKind::Compare => compare(&mut f, Format::of(float.params[0]), &name[4..]),
+        FloatKind::Trunc | FloatKind::TruncSat => trunc(
+            &mut f,
+            Format::of(float.params[0]),
+            float.result == ValType::I64,
+            name.ends_with("_s"),
+            float.kind == FloatKind::TruncSat,
+        ),
+        FloatKind::Arith => {
+            let fmt = Format::of(float.result);
+            match &name[4..] {
+                "add" => add(&mut f, fmt, false),
+                "sub" => add(&mut f, fmt, true),
+                "mul" => mul(&mut f, fmt),
+                "div" => div(&mut f, fmt),
+                "sqrt" => sqrt(&mut f, fmt),
+                "min" => min_max(&mut f, fmt, false),
+                "max" => min_max(&mut f, fmt, true),
+                "demote_f64" => demote(&mut f),
+                "promote_f32" => promote(&mut f),
+                op if op.starts_with("convert") => convert(&mut f, fmt, op.ends_with("_s")),
+                op => round(&mut f, fmt, op),
+            }
+        }
+    }
+    f.finish()
+}
+
+/// `abs`, `neg` and `copysign`.
+fn sign(f: &mut Body, fmt: Format, op: &str) {
+    let x = f.arg(0, false);
+    match op {
+        "abs" => f.ret(x.and(k(fmt.abs()))),
+        "neg" => f.ret(x.xor(k(fmt.sign()))),
+        _ => {
+            let y = f.arg(1, false);
+            f.ret(x.and(k(fmt.abs())).or(y.and(k(fmt.sign()))))
+        }
+    }
+}
+
+/// `add`, or `sub` if `negate`.
+fn add(f: &mut Body, fmt: Format, negate: bool) {
+    let x = f.arg(0, false);
+    let y = f.arg(1, false);
+    if negate {
+        f.set(y, y.xor(k(fmt.sign())));
+    }
+    f.nans(fmt, x, Some(y));
+    let ax = f.let_(x.and(k(fmt.abs())));
+    let ay = f.let_(y.and(k(fmt.abs())));
+    f.when(ax.eq(k(fmt.inf())), |f| {
+        let opposite = x.xor(y).and(k(fmt.sign())).ne(k(0));
+        f.when(ay.eq(k(fmt.inf())).both(opposite), |f| f.ret(k(fmt.nan())));
+        f.ret(x);
+    });
+    f.when(ay.eq(k(fmt.inf())), |f| f.ret(y));
+    f.when(ax.eqz(), |f| {
+        f.when(ay.eqz(), |f| f.ret(x.and(y)));
+        f.ret(y);
+    });
+    f.when(ay.eqz(), |f| f.ret(x));
+    // order the operands by magnitude
+    f.when(ax.lt_u(ay), |f| {
+        let t = f.let_(x);
+        f.set(x, y);
+        f.set(y, t);
+    });
+    let sign = f.let_(x.and(k(fmt.sign())));
+    let (ex, mx) = f.fields(fmt, x);
+    let (ey, my) = f.fields(fmt, y);
+    f.set(mx, mx.shl(k(3)));
+    f.set(my, my.shl(k(3)));
+    f.shr_sticky(my, ex.sub(ey));
+    let top = fmt.mant as u64 + 3;
+    f.branch(
+        x.xor(y).and(k(fmt.sign())).eqz(),
+        |f| {
+            f.set(mx, mx.add(my));
+            f.when(mx.ge_u(k(2 << top)), |f| {
+                f.shr_sticky(mx, k(1));
+                f.set(ex, ex.add(k(1)));
+            });
+        },
+        |f| {
+            f.set(mx, mx.sub(my));
+            f.when(mx.eqz(), |f| f.ret(k(0)));
+            let s = f.let_(mx.clz().sub(k(63 - top)));
+            f.when(s.gt_s(ex.sub(k(1))), |f| f.set(s, ex.sub(k(1))));
+            f.set(mx, mx.shl(s));
+            f.set(ex, ex.sub(s));
+        },
+    );
+    f.round_pack(fmt, sign, ex, mx);
+}
+
+fn mul(f: &mut Body, fmt: Format) {
+    let x = f.arg(0, false);
+    let y = f.arg(1, false);
+    f.nans(fmt, x, Some(y));
+    let sign = f.let_(x.xor(y).and(k(fmt.sign())));
+    let ax = f.let_(x.and(k(fmt.abs())));
+    let ay = f.let_(y.and(k(fmt.abs())));
+    for (a, b) in [(ax, ay), (ay, ax)] {
+        f.when(a.eq(k(fmt.inf())), |f| {
+            f.when(b.eqz(), |f| f.ret(k(fmt.nan())));
+            f.ret(sign.or(k(fmt.inf())));
+        });
+    }
+    f.when(ax.eqz().either(ay.eqz()), |f| f.ret(sign));
+    let (ex, mx) = f.unpack(fmt, x);
+    let (ey, my) = f.unpack(fmt, y);
+    // the 128-bit product, from 32-bit halves
+    let low = || k(0xffff_ffff);
+    let ll = f.let_(mx.and(low()).mul(my.and(low())));
+    let lh = f.let_(mx.and(low()).mul(my.shr_u(k(32))));
+    let hl = f.let_(mx.shr_u(k(32)).mul(my.and(low())));
+    let hh = f.let_(mx.shr_u(k(32)).mul(my.shr_u(k(32))));
+    let mid = f.let_(ll.shr_u(k(32)).add(lh.and(low())).add(hl.and(low())));
+    let lo = f.let_(ll.and(low()).or(mid.shl(k(32))));
+    let hi = f.let_(
+        hh.add(lh.shr_u(k(32)))
+            .add(hl.shr_u(k(32)))
+            .add(mid.shr_u(k(32))),
+    );
+    let shift = fmt.mant as u64 - 3;
+    let lost = lo.and(k((1 << shift) - 1)).ne(k(0)).ext();
+    let m = f.let_(hi.shl(k(64 - shift)).or(lo.shr_u(k(shift))).or(lost));
+    let e = f.let_(ex.add(ey).sub(k(fmt.bias())));
+    f.when(m.ge_u(k(1 << (fmt.mant + 4))), |f| {
+        f.shr_sticky(m, k(1));
+        f.set(e, e.add(k(1)));
+    });
+    f.round_pack(fmt, sign, e, m);
+}
+
+fn div(f: &mut Body, fmt: Format) {
+    let x = f.arg(0, false);
+    let y = f.arg(1, false);
+    f.nans(fmt, x, Some(y));
+    let sign = f.let_(x.xor(y).and(k(fmt.sign())));
+    let ax = f.let_(x.and(k(fmt.abs())));
+    let ay = f.let_(y.and(k(fmt.abs())));
+    f.when(ax.eq(k(fmt.inf())), |f| {
+        f.when(ay.eq(k(fmt.inf())), |f| f.ret(k(fmt.nan())));
+        f.ret(sign.or(k(fmt.inf())));
+    });
+    f.when(ay.eq(k(fmt.inf())), |f| f.ret(sign));
+    f.when(ay.eqz(), |f| {
+        f.when(ax.eqz(), |f| f.ret(k(fmt.nan())));
+        f.ret(sign.or(k(fmt.inf())));
+    });
+    f.when(ax.eqz(), |f| f.ret(sign));
+    let (ex, mx) = f.unpack(fmt, x);
+    let (ey, my) = f.unpack(fmt, y);
+    let e = f.let_(ex.sub(ey).add(k(fmt.bias())));
+    f.when(mx.lt_u(my), |f| {
+        f.set(mx, mx.shl(k(1)));
+        f.set(e, e.sub(k(1)));
+    });
+    // restoring division, one quotient bit per step
+    let q = f.let_(k(0));
+    let i = f.let_(k(fmt.mant as u64 + 4));
+    f.repeat(
+        |f| {
+            f.set(q, q.shl(k(1)));
+            f.when(mx.ge_u(my), |f| {
+                f.set(mx, mx.sub(my));
+                f.set(q, q.or(k(1)));
+            });
+            f.set(mx, mx.shl(k(1)));
+            f.set(i, i.sub(k(1)));
+        },
+        i.ne(k(0)),
+    );
+    f.set(q, q.or(mx.ne(k(0)).ext()));
+    f.round_pack(fmt, sign, e, q);
+}
+
+fn sqrt(f: &mut Body, fmt: Format) {
+    let x = f.arg(0, false);
+    f.nans(fmt, x, None);
+    f.when(x.and(k(fmt.abs())).eqz(), |f| f.ret(x));
+    f.when(x.and(k(fmt.sign())).ne(k(0)), |f| f.ret(k(fmt.nan())));
+    f.when(x.eq(k(fmt.inf())), |f| f.ret(x));
+    let (e, m) = f.unpack(fmt, x);
+    // x = m * 2^t, with m in [2^top, 2^(top + 2)) and t even
+    let t = f.let_(e.sub(k(fmt.bias() + fmt.mant as u64)));
+    let top = fmt.mant as u64 + (fmt.mant as u64 & 1);
+    if fmt.mant & 1 != 0 {
+        f.set(m, m.shl(k(1)));
+        f.set(t, t.sub(k(1)));
+    }
+    f.when(t.and(k(1)).ne(k(0)), |f| {
+        f.set(m, m.shl(k(1)));
+        f.set(t, t.sub(k(1)));
+    });
+    // digit by digit, two bits of m per bit of the root
+    let root = f.let_(k(0));
+    let rem = f.let_(k(0));
+    let i = f.let_(k(fmt.mant as u64 + 4));
+    f.repeat(
+        |f| {
+            f.set(rem, rem.shl(k(2)).or(m.shr_u(k(top)).and(k(3))));
+            f.set(m, m.shl(k(2)));
+            f.set(root, root.shl(k(1)));
+            f.when(rem.ge_u(root.shl(k(1)).or(k(1))), |f| {
+                f.set(rem, rem.sub(root.shl(k(1)).or(k(1))));
+                f.set(root, root.or(k(1)));
+            });
+            f.set(i, i.sub(k(1)));
+        },
+        i.ne(k(0)),
+    );
+    f.set(root, root.or(rem.ne(k(0)).ext()));
+    let e = f.let_(t.shr_s(k(1)).add(k(top / 2 + fmt.bias())));
+    let sign = f.let_(k(0));
+    f.round_pack(fmt, sign, e, root);
+}
+
+/// `min`, or `max` if `max`.
+fn min_max(f: &mut Body, fmt: Format, max: bool) {
+    let x = f.arg(0, false);
+    let y = f.arg(1, false);
+    f.nans(fmt, x, Some(y));
+    // -0 is below +0
+    f.when(x.or(y).and(k(fmt.abs())).eqz(), |f| match max {
+        true => f.ret(x.and(y)),
+        false => f.ret(x.or(y)),
+    });
+    let kx = f.key(fmt, x);
+    let ky = f.key(fmt, y);
+    let first = match max {
+        true => kx.gt_s(ky),
+        false => kx.lt_s(ky),
+    };
+    f.when(first, |f| f.ret(x));
+    f.ret(y);
+}
+
+fn compare(f: &mut Body, fmt: Format, op: &str) {
+    let x = f.arg(0, false);
+    let y = f.arg(1, false);
+    let unordered = x
+        .and(k(fmt.abs()))
+        .gt_u(k(fmt.inf()))
+        .either(y.and(k(fmt.abs())).gt_u(k(fmt.inf())));
+    f.when(unordered, |f| f.ret(k((op == "ne") as u64)));
+    let kx = f.key(fmt, x);
+    let ky = f.key(fmt, y);
+    let holds = match op {
+        "eq" => kx.eq(ky),
+        "ne" => kx.ne(ky),
+        "lt" => kx.lt_s(ky),
+        "gt" => kx.gt_s(ky),
+        "le" => kx.le_s(ky),
+        _ => kx.ge_s(ky),
+    };
+    f.ret(holds.ext());
+}
+
+/// `ceil`, `floor`, `trunc` and `nearest`.
+fn round(f: &mut Body, fmt: Format, op: &str) {
+    let x = f.arg(0, false);
+    f.nans(fmt, x, None);
+    let sign = f.let_(x.and(k(fmt.sign())));
+    let exp = f.let_(
+        x.shr_u(k(fmt.mant as u64))
+            .and(k(fmt.max_exp()))
+            .sub(k(fmt.bias())),
+    );
+    // already integral, infinite or zero
+    f.when(exp.ge_s(k(fmt.mant as u64)), |f| f.ret(x));
+    f.when(x.and(k(fmt.abs())).eqz(), |f| f.ret(x));
+    f.when(exp.lt_s(k(0)), |f| {
+        match op {
+            "ceil" => f.when(sign.eqz(), |f| f.ret(k(fmt.one()))),
+            "floor" => f.when(sign.ne(k(0)), |f| f.ret(sign.or(k(fmt.one())))),
+            "nearest" => {
+                let half = (fmt.bias() - 1) << fmt.mant;
+                let above = exp
+                    .eq(k(-1i64 as u64))
+                    .both(x.and(k(fmt.abs())).gt_u(k(half)));
+                f.when(above, |f| f.ret(sign.or(k(fmt.one()))))
+            }
+            _ => {}
+        }
+        f.ret(sign);
+    });
+    let unit = f.let_(k(1).shl(k(fmt.mant as u64).sub(exp)));
+    let rest = f.let_(x.and(unit.sub(k(1))));
+    f.when(rest.eqz(), |f| f.ret(x));
+    let base = f.let_(x.xor(rest));
+    let away = match op {
+        "ceil" => Some(sign.eqz()),
+        "floor" => Some(sign.ne(k(0))),
+        "nearest" => {
+            let half = unit.shr_u(k(1));
+            Some(
+                rest.gt_u(half.clone())
+                    .either(rest.eq(half).both(base.and(unit).ne(k(0)))),
+            )
+        }
+        _ => None,
+    };
+    if let Some(away) = away {
+        f.when(away, |f| f.ret(base.add(unit)));
+    }
+    f.ret(base);
+}
+
+/// Conversions to i64 if `wide` and to i32 otherwise, saturating if `sat`
+/// and trapping otherwise.
+fn trunc(f: &mut Body, fmt: Format, wide: bool, signed: bool, sat: bool) {
+    let width: u64 = if wide { 64 } else { 32 };
+    let x = f.arg(0, false);
+    let sign = f.let_(x.and(k(fmt.sign())));
+    let out_of_range = |f: &mut Body| match (sat, signed) {
+        (false, _) => f.trap(),
+        (true, true) => {
+            f.when(sign.ne(k(0)), |f| f.ret(k(1 << (width - 1))));
+            f.ret(k((1 << (width - 1)) - 1));
+        }
+        (true, false) => {
+            f.when(sign.ne(k(0)), |f| f.ret(k(0)));
+            f.ret(k(u64::MAX >> (64 - width)));
+        }
+    };
+    f.when(x.and(k(fmt.abs())).gt_u(k(fmt.inf())), |f| match sat {
+        true => f.ret(k(0)),
+        false => f.trap(),
+    });
+    let exp = f.let_(
+        x.shr_u(k(fmt.mant as u64))
+            .and(k(fmt.max_exp()))
+            .sub(k(fmt.bias())),
+    );
+    f.when(exp.lt_s(k(0)), |f| f.ret(k(0)));
+    f.when(exp.ge_s(k(64)), out_of_range);
+    // the magnitude, truncated
+    let m = f.let_(x.and(k(fmt.frac())).or(k(fmt.hidden())));
+    let mant = fmt.mant as u64;
+    f.branch(
+        exp.ge_s(k(mant)),
+        |f| f.set(m, m.shl(exp.sub(k(mant)))),
+        |f| f.set(m, m.shr_u(k(mant).sub(exp))),
+    );
+    if signed {
+        f.when(exp.ge_s(k(width - 1)), |f| {
+            // only the most negative value fits
+            let min = sign.ne(k(0)).both(m.eq(k(1 << (width - 1))));
+            f.when(min, |f| f.ret(m));
+            out_of_range(f);
+        });
+        f.when(sign.ne(k(0)), |f| f.ret(k(0).sub(m)));
+    } else {
+        f.when(sign.ne(k(0)).either(exp.ge_s(k(width))), out_of_range);
+    }
+    f.ret(m);
+}
+
+/// Conversions from integers, signed if `signed`.
+fn convert(f: &mut Body, fmt: Format, signed: bool) {
+    let x = f.arg(0, signed);
+    let sign = f.let_(k(0));
+    if signed {
+        f.when(x.lt_s(k(0)), |f| {
+            f.set(sign, k(fmt.sign()));
+            f.set(x, k(0).sub(x));
+        });
+    }
+    f.when(x.eqz(), |f| f.ret(k(0)));
+    let top = fmt.mant as u64 + 3;
+    let p = f.let_(k(63).sub(x.clz()));
+    f.branch(
+        p.gt_s(k(top)),
+        |f| f.shr_sticky(x, p.sub(k(top))),
+        |f| f.set(x, x.shl(k(top).sub(p))),
+    );
+    let e = f.let_(p.add(k(fmt.bias())));
+    f.round_pack(fmt, sign, e, x);
+}
+
+fn demote(f: &mut Body) {
+    let x = f.arg(0, false);
+    f.when(x.and(k(F64.abs())).gt_u(k(F64.inf())), |f| {
+        f.ret(k(F32.nan()))
+    });
+    let sign = f.let_(x.shr_u(k(32)).and(k(F32.sign())));
+    let ax = f.let_(x.and(k(F64.abs())));
+    f.when(ax.eq(k(F64.inf())), |f| f.ret(sign.or(k(F32.inf()))));
+    f.when(ax.eqz(), |f| f.ret(sign));
+    let (e, m) = f.unpack(F64, x);
+    f.shr_sticky(m, k((F64.mant - F32.mant - 3) as u64));
+    f.set(e, e.sub(k(F64.bias() - F32.bias())));
+    f.round_pack(F32, sign, e, m);
+}
+
+fn promote(f: &mut Body) {
+    let x = f.arg(0, false);
+    f.when(x.and(k(F32.abs())).gt_u(k(F32.inf())), |f| {
+        f.ret(k(F64.nan()))
+    });
+    let sign = f.let_(x.and(k(F32.sign())).shl(k(32)));
+    let ax = f.let_(x.and(k(F32.abs())));
+    f.when(ax.eq(k(F32.inf())), |f| f.ret(sign.or(k(F64.inf()))));
+    f.when(ax.eqz(), |f| f.ret(sign));
+    let (e, m) = f.unpack(F32, x);
+    let exp = e.add(k(F64.bias() - F32.bias())).shl(k(F64.mant as u64));
+    let frac = m.shl(k((F64.mant - F32.mant) as u64)).and(k(F64.frac()));
+    f.ret(sign.or(exp).or(frac));
+}
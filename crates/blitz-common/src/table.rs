@@ -0,0 +1,176 @@
+//! Tables and element segments.
+//!
+//! [`Table`] describes one table of the table index space: the type of
+//! references it holds, the width of its indices, the size it starts at
+//! and may grow to, and the reference its slots start out with.
+//! [`ElemSegment`] describes one element segment with its items evaluated.
+
+use alloc::vec::Vec;
+use wasmparser::{ConstExpr, Element, ElementItems, ElementKind, RefType, TableType, ValType};
+
+use crate::{CompileError, global::ConstValue};
+
+/// A table in the table index space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Table {
+    /// Index in the table index space.
+    pub index: u32,
+    /// Whether the table is imported rather than defined by the module.
+    pub imported: bool,
+    /// Type of the references the table holds.
+    pub ty: RefType,
+    /// Whether indices are 64-bit (memory64) rather than 32-bit.
+    pub table64: bool,
+    /// Initial size, in elements.
+    pub min: u64,
+    /// Maximum size, in elements, if the table declares one.
+    pub max: Option<u64>,
+    /// Reference every slot of a defined table starts out with; `None` for
+    /// imports.
+    pub init: Option<ConstValue>,
+}
+
+impl Table {
+    /// Describes the table at `index`, of type `ty`. Defined tables pass
+    /// their initialiser as `init`, which is `None` for null references.
+    pub fn new(
+        index: u32,
+        imported: bool,
+        ty: &TableType,
+        init: Option<&ConstExpr<'_>>,
+    ) -> Result<Self, CompileError> {
+        Ok(Self {
+            index,
+            imported,
+            ty: ty.element_type,
+            table64: ty.table64,
+            min: ty.initial,
+            max: ty.maximum,
+            init: match (imported, init) {
+                (true, _) => None,
+                (false, Some(init)) => Some(ConstValue::eval(init)?),
+                (false, None) => Some(ConstValue::RefNull),
+            },
+        })
+    }
+
+    /// The type of indices into this table.
+    pub fn index_type(&self) -> ValType {
+        if self.table64 {
+            ValType::I64
+        } else {
+            ValType::I32
+        }
+    }
+
+    /// The largest size the table can grow to: its maximum, if it declares
+    /// one, capped at what its indices can reach.
+    pub fn max_len(&self) -> u64 {
+        let limit = if self.table64 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        self.max.map_or(limit, |max| max.min(limit))
+    }
+}
+
+/// How an element segment is used.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElemMode {
+    /// Copied into a table with `table.init`.
+    Passive,
+    /// Copied into `table` at `offset` on instantiation, then dropped.
+    Active {
+        /// Index of the table the segment initialises.
+        table: u32,
+        /// Index of the first slot initialised.
+        offset: ConstValue,
+    },
+    /// Only declares the functions it references; dropped from the start.
+    Declared,
+}
+
+/// An element segment, with its items evaluated.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct ElemSegment {
+    /// Index of the segment in the element section.
+    pub index: u32,
+    /// How the segment is used.
+    pub mode: ElemMode,
+    /// Type of the references the segment holds.
+    pub ty: RefType,
+    /// The references the segment holds.
+    pub items: Vec<ConstValue>,
+}
+
+impl ElemSegment {
+    /// Describes the element segment `elem`, at `index` in the element
+    /// section.
+    pub fn new(index: u32, elem: &Element<'_>) -> Result<Self, CompileError> {
+        let mode = match &elem.kind {
+            ElementKind::Passive => ElemMode::Passive,
+            ElementKind::Active {
+                table_index,
+                offset_expr,
+            } => ElemMode::Active {
+                table: table_index.unwrap_or(0),
+                offset: ConstValue::eval(offset_expr)?,
+            },
+            ElementKind::Declared => ElemMode::Declared,
+        };
+        let (ty, items) = match &elem.items {
+            ElementItems::Functions(r) => (
+                RefType::FUNCREF,
+                r.clone()
+                    .into_iter()
+                    .map(|f| Ok(ConstValue::RefFunc(f?)))
+                    .collect::<Result<_, CompileError>>()?,
+            ),
+            ElementItems::Expressions(ty, r) => (
+                *ty,
+                r.clone()
+                    .into_iter()
+                    .map(|e| ConstValue::eval(&e?))
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+        Ok(Self {
+            index,
+            mode,
+            ty,
+            items,
+        })
+    }
+
+    /// The items still available to `table.init`: those of passive
+    /// segments, as active and declared segments are dropped once the
+    /// module is instantiated.
+    pub fn live_items(&self) -> &[ConstValue] {
+        match self.mode {
+            ElemMode::Passive => &self.items,
+            _ => &[],
+        }
+    }
+}
+
+/// Looks up table `index` among `tables`, reporting an index past the last
+/// table as a [`CompileError`].
+pub fn lookup(tables: &[TableType], index: u32) -> Result<&TableType, CompileError> {
+    tables
+        .get(index as usize)
+        .ok_or_else(|| CompileError::unknown("table", index))
+}
+
+/// Looks up element segment `index` among `elements`, reporting an index
+/// past the last segment as a [`CompileError`].
+pub fn lookup_elem<'a, 'b>(
+    elements: &'b [Element<'a>],
+    index: u32,
+) -> Result<&'b Element<'a>, CompileError> {
+    elements
+        .get(index as usize)
+        .ok_or_else(|| CompileError::unknown("elem", index))
+}
@@ -0,0 +1,793 @@
+//! Operand-stack type tracking.
+//!
+//! Backends need to know the types of the values each operator works on:
+//! whether a local is a float, which register class to pop from, whether a
+//! `select` picks between `i32`s or `f64`s. [`TrackTypes`] follows the
+//! operand stack through a `MachOperator` stream and annotates every
+//! operator with a [`Typed`] record, so no backend has to keep its own
+//! tracker.
+
+use alloc::vec::Vec;
+use wasmparser::{BlockType, MemoryType, RefType, TableType};
+
+use crate::{
+    module::Module,
+    ops::{FromWasmInfo, ToWasmInfo, WasmInfo},
+    *,
+};
+
+/// Annotation carrying the operand types of an operator.
+///
+/// Types are `None` only for values of unknown type in unreachable code.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Typed<Annot = WasmInfo> {
+    /// The annotation of the untyped stream.
+    pub annot: Annot,
+    /// Types of the values consumed, bottom first.
+    pub params: Vec<Option<ValType>>,
+    /// Types of the values produced, bottom first.
+    pub results: Vec<Option<ValType>>,
+    /// Operand-stack height before the operator.
+    pub height: usize,
+}
+
+impl<Annot: FromWasmInfo> FromWasmInfo for Typed<Annot> {
+    fn from_wasm_info(info: WasmInfo) -> Self {
+        Typed {
+            annot: Annot::from_wasm_info(info),
+            params: Vec::new(),
+            results: Vec::new(),
+            height: 0,
+        }
+    }
+}
+
+impl<Annot: ToWasmInfo> ToWasmInfo for Typed<Annot> {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        self.annot.to_wasm_info()
+    }
+}
+
+/// Types of the module entities an operator stream refers to.
+///
+/// Implemented by [`Module`], and by the signature tables
+/// [`mach_operators`](crate::ops::mach_operators) works from, which only
+/// know function types. Globals, memories and tables the environment does
+/// not know are treated as `i32` globals, 32-bit memories and 32-bit
+/// `funcref` tables; operand counts stay exact either way.
+pub trait TypeEnv {
+    /// The function type at `index` in the type section.
+    fn type_at(&self, index: u32) -> Option<&FuncType>;
+    /// The type of the function at `index` in the function index space.
+    fn func_type_at(&self, index: u32) -> Option<&FuncType>;
+    /// Number of imported functions.
+    fn num_func_imports(&self) -> u32;
+    /// The content type of the global at `index`.
+    fn global_type(&self, index: u32) -> Option<ValType> {
+        let _ = index;
+        None
+    }
+    /// The type of the memory at `index`.
+    fn memory_type(&self, index: u32) -> Option<&MemoryType> {
+        let _ = index;
+        None
+    }
+    /// The type of the table at `index`.
+    fn table_type(&self, index: u32) -> Option<&TableType> {
+        let _ = index;
+        None
+    }
+}
+
+impl TypeEnv for Module<'_> {
+    fn type_at(&self, index: u32) -> Option<&FuncType> {
+        self.types.get(index as usize)
+    }
+    fn func_type_at(&self, index: u32) -> Option<&FuncType> {
+        self.type_at(*self.funcs.get(index as usize)?)
+    }
+    fn num_func_imports(&self) -> u32 {
+        Module::num_func_imports(self)
+    }
+    fn global_type(&self, index: u32) -> Option<ValType> {
+        self.globals.get(index as usize).map(|g| g.content_type)
+    }
+    fn memory_type(&self, index: u32) -> Option<&MemoryType> {
+        self.memories.get(index as usize)
+    }
+    fn table_type(&self, index: u32) -> Option<&TableType> {
+        self.tables.get(index as usize)
+    }
+}
+
+/// Number of values a block consumes and produces.
+///
+/// Backends that only move untyped stack slots need the arities of a block
+/// type, not the types themselves: how many values sit below the block on
+/// entry, how many remain at its end, and how many a branch to it carries.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct BlockArity {
+    /// Number of parameters taken from the operand stack on entry.
+    pub params: usize,
+    /// Number of results left on the operand stack at the end.
+    pub results: usize,
+}
+
+impl BlockArity {
+    /// Resolves a block type against `env`'s type section.
+    ///
+    /// Returns `None` if the block type refers to a missing function type.
+    pub fn of<M: TypeEnv + ?Sized>(ty: BlockType, env: &M) -> Option<Self> {
+        Some(match ty {
+            BlockType::Empty => Self::default(),
+            BlockType::Type(_) => Self {
+                params: 0,
+                results: 1,
+            },
+            BlockType::FuncType(i) => Self::of_func(env.type_at(i)?),
+        })
+    }
+
+    /// Resolves an encoded block type against the encoded type section
+    /// backends receive.
+    ///
+    /// Returns `None` if the block type refers to a missing function type.
+    pub fn of_encoded(
+        ty: &wasm_encoder::BlockType,
+        types: &[wasm_encoder::FuncType],
+    ) -> Option<Self> {
+        Some(match ty {
+            wasm_encoder::BlockType::Empty => Self::default(),
+            wasm_encoder::BlockType::Result(_) => Self {
+                params: 0,
+                results: 1,
+            },
+            wasm_encoder::BlockType::FunctionType(i) => {
+                let f = types.get(*i as usize)?;
+                Self {
+                    params: f.params().len(),
+                    results: f.results().len(),
+                }
+            }
+        })
+    }
+
+    /// The arity of a function type, as used for the function body.
+    pub fn of_func(f: &FuncType) -> Self {
+        Self {
+            params: f.params().len(),
+            results: f.results().len(),
+        }
+    }
+
+    /// Number of values a branch to the block carries: its parameters for
+    /// a loop, its results otherwise.
+    pub fn label(&self, is_loop: bool) -> usize {
+        if is_loop { self.params } else { self.results }
+    }
+}
+
+/// Consumed and produced types of one operator.
+type Signature = (Vec<Option<ValType>>, Vec<Option<ValType>>);
+
+/// What a control frame was opened by.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum FrameKind {
+    Func,
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+/// A control frame of the function being tracked.
+#[derive(Clone, Debug)]
+struct Frame {
+    kind: FrameKind,
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    /// Operand-stack height at frame entry, excluding the parameters.
+    height: usize,
+}
+
+impl Frame {
+    /// The types a branch to this frame carries.
+    fn label_types(&self) -> &[ValType] {
+        match self.kind {
+            FrameKind::Loop => &self.params,
+            _ => &self.results,
+        }
+    }
+}
+
+/// Iterator adapter annotating a `MachOperator` stream with operand types.
+///
+/// Created by [`TrackTypes::new`] or
+/// [`Module::typed_mach_operators`]. The input is expected to be valid;
+/// use the validating front end first when that is not guaranteed.
+/// Encoded `Instruction`s carry no types to track and are rejected.
+pub struct TrackTypes<'m, I, M: ?Sized = Module<'m>> {
+    wrapped: I,
+    module: &'m M,
+    locals: Vec<ValType>,
+    /// Result types of the current function, kept after its frame is
+    /// closed for the trailing `Return`.
+    returns: Vec<ValType>,
+    stack: Vec<Option<ValType>>,
+    frames: Vec<Frame>,
+}
+
+impl<'m, I, M: TypeEnv + ?Sized> TrackTypes<'m, I, M> {
+    /// Wraps `wrapped`, a stream derived from `module`.
+    pub fn new(module: &'m M, wrapped: I) -> Self {
+        Self {
+            wrapped,
+            module,
+            locals: Vec::new(),
+            returns: Vec::new(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Resets the tracker for a function of the given signature.
+    fn start_fn(&mut self, params: &[ValType], results: &[ValType]) {
+        self.locals = params.to_vec();
+        self.returns = results.to_vec();
+        self.stack.clear();
+        self.frames.clear();
+        self.frames.push(Frame {
+            kind: FrameKind::Func,
+            params: Vec::new(),
+            results: results.to_vec(),
+            height: 0,
+        });
+    }
+
+    fn height(&self) -> usize {
+        self.frames.last().map_or(0, |f| f.height)
+    }
+
+    fn pop(&mut self) -> Option<ValType> {
+        if self.stack.len() > self.height() {
+            self.stack.pop().flatten()
+        } else {
+            None
+        }
+    }
+
+    fn pop_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.pop();
+        }
+    }
+
+    fn push_all(&mut self, tys: &[ValType]) {
+        self.stack.extend(tys.iter().copied().map(Some));
+    }
+
+    /// Marks the rest of the current frame unreachable.
+    ///
+    /// The stack is cut back to the frame's height; pops below it yield
+    /// values of unknown type until the frame ends.
+    fn set_unreachable(&mut self) {
+        let height = self.height();
+        self.stack.truncate(height);
+    }
+
+    fn label_types(&self, depth: u32) -> Vec<ValType> {
+        self.frames
+            .iter()
+            .rev()
+            .nth(depth as usize)
+            .map(|f| f.label_types().to_vec())
+            .unwrap_or_default()
+    }
+
+    fn block_type(&self, ty: BlockType) -> Option<(Vec<ValType>, Vec<ValType>)> {
+        Some(match ty {
+            BlockType::Empty => (Vec::new(), Vec::new()),
+            BlockType::Type(t) => (Vec::new(), [t].to_vec()),
+            BlockType::FuncType(i) => {
+                let f = self.module.type_at(i)?;
+                (f.params().to_vec(), f.results().to_vec())
+            }
+        })
+    }
+
+    fn push_frame(&mut self, kind: FrameKind, params: Vec<ValType>, results: Vec<ValType>) {
+        self.frames.push(Frame {
+            kind,
+            height: self.stack.len(),
+            params: params.clone(),
+            results,
+        });
+        self.push_all(&params);
+    }
+
+    fn mem_addr(&self, mem: u32) -> ValType {
+        match self.module.memory_type(mem) {
+            Some(m) if m.memory64 => ValType::I64,
+            _ => ValType::I32,
+        }
+    }
+
+    fn table_addr(&self, table: u32) -> ValType {
+        match self.module.table_type(table) {
+            Some(t) if t.table64 => ValType::I64,
+            _ => ValType::I32,
+        }
+    }
+
+    fn table_elem(&self, table: u32) -> ValType {
+        self.module
+            .table_type(table)
+            .map_or(ValType::FUNCREF, |t| ValType::Ref(t.element_type))
+    }
+
+    /// Applies `op` to the tracked stack, returning the consumed and
+    /// produced types, or `None` if the operator is not known.
+    fn step(&mut self, op: &Operator<'_>) -> Option<Signature> {
+        use ValType::{F32, F64, I32, I64};
+        let fixed = |p: &[ValType], r: &[ValType]| (p.to_vec(), r.to_vec());
+        let (params, results) = match op {
+            // Operators whose types come from the stack or change control.
+            Operator::Drop => {
+                let t = self.pop();
+                return Some(([t].to_vec(), Vec::new()));
+            }
+            Operator::Select => {
+                self.pop();
+                let b = self.pop();
+                let a = self.pop();
+                let t = a.or(b);
+                self.stack.push(t);
+                return Some(([t, t, Some(I32)].to_vec(), [t].to_vec()));
+            }
+            Operator::RefIsNull => {
+                let t = self.pop();
+                self.stack.push(Some(I32));
+                return Some(([t].to_vec(), [Some(I32)].to_vec()));
+            }
+            Operator::Unreachable => {
+                self.set_unreachable();
+                return Some((Vec::new(), Vec::new()));
+            }
+            Operator::Block { blockty } | Operator::Loop { blockty } => {
+                let (params, results) = self.block_type(*blockty)?;
+                self.pop_n(params.len());
+                let kind = match op {
+                    Operator::Loop { .. } => FrameKind::Loop,
+                    _ => FrameKind::Block,
+                };
+                let tys = params.iter().copied().map(Some).collect::<Vec<_>>();
+                self.push_frame(kind, params, results);
+                return Some((tys.clone(), tys));
+            }
+            Operator::If { blockty } => {
+                let (params, results) = self.block_type(*blockty)?;
+                self.pop_n(params.len() + 1);
+                let tys = params.iter().copied().map(Some).collect::<Vec<_>>();
+                self.push_frame(FrameKind::If, params, results);
+                let mut consumed = tys.clone();
+                consumed.push(Some(I32));
+                return Some((consumed, tys));
+            }
+            Operator::Else => {
+                let frame = self.frames.last_mut()?;
+                frame.kind = FrameKind::Else;
+                let (height, params, results) =
+                    (frame.height, frame.params.clone(), frame.results.clone());
+                self.stack.truncate(height);
+                self.push_all(&params);
+                return Some((
+                    results.into_iter().map(Some).collect(),
+                    params.into_iter().map(Some).collect(),
+                ));
+            }
+            Operator::End => {
+                let frame = self.frames.pop()?;
+                self.stack.truncate(frame.height);
+                self.push_all(&frame.results);
+                let tys = frame.results.into_iter().map(Some).collect::<Vec<_>>();
+                return Some((tys.clone(), tys));
+            }
+            Operator::Br { relative_depth } => {
+                let tys = self.label_types(*relative_depth);
+                self.set_unreachable();
+                return Some((tys.into_iter().map(Some).collect(), Vec::new()));
+            }
+            Operator::BrTable { targets } => {
+                let mut tys = self.label_types(targets.default());
+                tys.push(I32);
+                self.set_unreachable();
+                return Some((tys.into_iter().map(Some).collect(), Vec::new()));
+            }
+            Operator::Return => {
+                let tys = self.returns.clone();
+                self.set_unreachable();
+                return Some((tys.into_iter().map(Some).collect(), Vec::new()));
+            }
+            Operator::ReturnCall { function_index } => {
+                let params = self.module.func_type_at(*function_index)?.params().to_vec();
+                self.set_unreachable();
+                return Some((params.into_iter().map(Some).collect(), Vec::new()));
+            }
+            Operator::ReturnCallIndirect {
+                type_index,
+                table_index,
+            } => {
+                let mut params = self.module.type_at(*type_index)?.params().to_vec();
+                params.push(self.table_addr(*table_index));
+                self.set_unreachable();
+                return Some((params.into_iter().map(Some).collect(), Vec::new()));
+            }
+
+            // Operators with a fixed signature.
+            Operator::Nop | Operator::DataDrop { .. } | Operator::ElemDrop { .. } => {
+                fixed(&[], &[])
+            }
+            Operator::BrIf { relative_depth } => {
+                let tys = self.label_types(*relative_depth);
+                let mut params = tys.clone();
+                params.push(I32);
+                (params, tys)
+            }
+            Operator::Call { function_index } => {
+                let f = self.module.func_type_at(*function_index)?;
+                fixed(f.params(), f.results())
+            }
+            Operator::CallIndirect {
+                type_index,
+                table_index,
+            } => {
+                let f = self.module.type_at(*type_index)?;
+                let mut params = f.params().to_vec();
+                params.push(self.table_addr(*table_index));
+                (params, f.results().to_vec())
+            }
+            Operator::TypedSelect { ty } => fixed(&[*ty, *ty, I32], &[*ty]),
+            Operator::LocalGet { local_index } => {
+                let t = *self.locals.get(*local_index as usize)?;
+                fixed(&[], &[t])
+            }
+            Operator::LocalSet { local_index } => {
+                let t = *self.locals.get(*local_index as usize)?;
+                fixed(&[t], &[])
+            }
+            Operator::LocalTee { local_index } => {
+                let t = *self.locals.get(*local_index as usize)?;
+                fixed(&[t], &[t])
+            }
+            Operator::GlobalGet { global_index } => {
+                let t = self.module.global_type(*global_index).unwrap_or(I32);
+                fixed(&[], &[t])
+            }
+            Operator::GlobalSet { global_index } => {
+                let t = self.module.global_type(*global_index).unwrap_or(I32);
+                fixed(&[t], &[])
+            }
+
+            Operator::I32Load { memarg }
+            | Operator::I32Load8S { memarg }
+            | Operator::I32Load8U { memarg }
+            | Operator::I32Load16S { memarg }
+            | Operator::I32Load16U { memarg } => fixed(&[self.mem_addr(memarg.memory)], &[I32]),
+            Operator::I64Load { memarg }
+            | Operator::I64Load8S { memarg }
+            | Operator::I64Load8U { memarg }
+            | Operator::I64Load16S { memarg }
+            | Operator::I64Load16U { memarg }
+            | Operator::I64Load32S { memarg }
+            | Operator::I64Load32U { memarg } => fixed(&[self.mem_addr(memarg.memory)], &[I64]),
+            Operator::F32Load { memarg } => fixed(&[self.mem_addr(memarg.memory)], &[F32]),
+            Operator::F64Load { memarg } => fixed(&[self.mem_addr(memarg.memory)], &[F64]),
+            Operator::I32Store { memarg }
+            | Operator::I32Store8 { memarg }
+            | Operator::I32Store16 { memarg } => fixed(&[self.mem_addr(memarg.memory), I32], &[]),
+            Operator::I64Store { memarg }
+            | Operator::I64Store8 { memarg }
+            | Operator::I64Store16 { memarg }
+            | Operator::I64Store32 { memarg } => fixed(&[self.mem_addr(memarg.memory), I64], &[]),
+            Operator::F32Store { memarg } => fixed(&[self.mem_addr(memarg.memory), F32], &[]),
+            Operator::F64Store { memarg } => fixed(&[self.mem_addr(memarg.memory), F64], &[]),
+            Operator::MemorySize { mem } => fixed(&[], &[self.mem_addr(*mem)]),
+            Operator::MemoryGrow { mem } => {
+                let a = self.mem_addr(*mem);
+                fixed(&[a], &[a])
+            }
+            Operator::MemoryFill { mem } => {
+                let a = self.mem_addr(*mem);
+                fixed(&[a, I32, a], &[])
+            }
+            Operator::MemoryCopy { dst_mem, src_mem } => {
+                let (d, s) = (self.mem_addr(*dst_mem), self.mem_addr(*src_mem));
+                let n = if d == I64 && s == I64 { I64 } else { I32 };
+                fixed(&[d, s, n], &[])
+            }
+            Operator::MemoryInit { mem, .. } => fixed(&[self.mem_addr(*mem), I32, I32], &[]),
+
+            Operator::TableGet { table } => {
+                fixed(&[self.table_addr(*table)], &[self.table_elem(*table)])
+            }
+            Operator::TableSet { table } => {
+                fixed(&[self.table_addr(*table), self.table_elem(*table)], &[])
+            }
+            Operator::TableSize { table } => fixed(&[], &[self.table_addr(*table)]),
+            Operator::TableGrow { table } => {
+                let a = self.table_addr(*table);
+                fixed(&[self.table_elem(*table), a], &[a])
+            }
+            Operator::TableFill { table } => {
+                let a = self.table_addr(*table);
+                fixed(&[a, self.table_elem(*table), a], &[])
+            }
+            Operator::TableCopy {
+                dst_table,
+                src_table,
+            } => {
+                let (d, s) = (self.table_addr(*dst_table), self.table_addr(*src_table));
+                let n = if d == I64 && s == I64 { I64 } else { I32 };
+                fixed(&[d, s, n], &[])
+            }
+            Operator::TableInit { table, .. } => fixed(&[self.table_addr(*table), I32, I32], &[]),
+            Operator::RefNull { hty } => fixed(&[], &[ValType::Ref(RefType::new(true, *hty)?)]),
+            Operator::RefFunc { .. } => fixed(&[], &[ValType::FUNCREF]),
+
+            Operator::I32Const { .. } => fixed(&[], &[I32]),
+            Operator::I64Const { .. } => fixed(&[], &[I64]),
+            Operator::F32Const { .. } => fixed(&[], &[F32]),
+            Operator::F64Const { .. } => fixed(&[], &[F64]),
+
+            Operator::I32Eqz
+            | Operator::I32Clz
+            | Operator::I32Ctz
+            | Operator::I32Popcnt
+            | Operator::I32Extend8S
+            | Operator::I32Extend16S => fixed(&[I32], &[I32]),
+            Operator::I32Eq
+            | Operator::I32Ne
+            | Operator::I32LtS
+            | Operator::I32LtU
+            | Operator::I32GtS
+            | Operator::I32GtU
+            | Operator::I32LeS
+            | Operator::I32LeU
+            | Operator::I32GeS
+            | Operator::I32GeU
+            | Operator::I32Add
+            | Operator::I32Sub
+            | Operator::I32Mul
+            | Operator::I32DivS
+            | Operator::I32DivU
+            | Operator::I32RemS
+            | Operator::I32RemU
+            | Operator::I32And
+            | Operator::I32Or
+            | Operator::I32Xor
+            | Operator::I32Shl
+            | Operator::I32ShrS
+            | Operator::I32ShrU
+            | Operator::I32Rotl
+            | Operator::I32Rotr => fixed(&[I32, I32], &[I32]),
+
+            Operator::I64Eqz => fixed(&[I64], &[I32]),
+            Operator::I64Clz
+            | Operator::I64Ctz
+            | Operator::I64Popcnt
+            | Operator::I64Extend8S
+            | Operator::I64Extend16S
+            | Operator::I64Extend32S => fixed(&[I64], &[I64]),
+            Operator::I64Eq
+            | Operator::I64Ne
+            | Operator::I64LtS
+            | Operator::I64LtU
+            | Operator::I64GtS
+            | Operator::I64GtU
+            | Operator::I64LeS
+            | Operator::I64LeU
+            | Operator::I64GeS
+            | Operator::I64GeU => fixed(&[I64, I64], &[I32]),
+            Operator::I64Add
+            | Operator::I64Sub
+            | Operator::I64Mul
+            | Operator::I64DivS
+            | Operator::I64DivU
+            | Operator::I64RemS
+            | Operator::I64RemU
+            | Operator::I64And
+            | Operator::I64Or
+            | Operator::I64Xor
+            | Operator::I64Shl
+            | Operator::I64ShrS
+            | Operator::I64ShrU
+            | Operator::I64Rotl
+            | Operator::I64Rotr => fixed(&[I64, I64], &[I64]),
+
+            Operator::F32Abs
+            | Operator::F32Neg
+            | Operator::F32Ceil
+            | Operator::F32Floor
+            | Operator::F32Trunc
+            | Operator::F32Nearest
+            | Operator::F32Sqrt => fixed(&[F32], &[F32]),
+            Operator::F32Add
+            | Operator::F32Sub
+            | Operator::F32Mul
+            | Operator::F32Div
+            | Operator::F32Min
+            | Operator::F32Max
+            | Operator::F32Copysign => fixed(&[F32, F32], &[F32]),
+            Operator::F32Eq
+            | Operator::F32Ne
+            | Operator::F32Lt
+            | Operator::F32Gt
+            | Operator::F32Le
+            | Operator::F32Ge => fixed(&[F32, F32], &[I32]),
+            Operator::F64Abs
+            | Operator::F64Neg
+            | Operator::F64Ceil
+            | Operator::F64Floor
+            | Operator::F64Trunc
+            | Operator::F64Nearest
+            | Operator::F64Sqrt => fixed(&[F64], &[F64]),
+            Operator::F64Add
+            | Operator::F64Sub
+            | Operator::F64Mul
+            | Operator::F64Div
+            | Operator::F64Min
+            | Operator::F64Max
+            | Operator::F64Copysign => fixed(&[F64, F64], &[F64]),
+            Operator::F64Eq
+            | Operator::F64Ne
+            | Operator::F64Lt
+            | Operator::F64Gt
+            | Operator::F64Le
+            | Operator::F64Ge => fixed(&[F64, F64], &[I32]),
+
+            Operator::I32WrapI64 => fixed(&[I64], &[I32]),
+            Operator::I32TruncF32S
+            | Operator::I32TruncF32U
+            | Operator::I32TruncSatF32S
+            | Operator::I32TruncSatF32U
+            | Operator::I32ReinterpretF32 => fixed(&[F32], &[I32]),
+            Operator::I32TruncF64S
+            | Operator::I32TruncF64U
+            | Operator::I32TruncSatF64S
+            | Operator::I32TruncSatF64U => fixed(&[F64], &[I32]),
+            Operator::I64ExtendI32S | Operator::I64ExtendI32U => fixed(&[I32], &[I64]),
+            Operator::I64TruncF32S
+            | Operator::I64TruncF32U
+            | Operator::I64TruncSatF32S
+            | Operator::I64TruncSatF32U => fixed(&[F32], &[I64]),
+            Operator::I64TruncF64S
+            | Operator::I64TruncF64U
+            | Operator::I64TruncSatF64S
+            | Operator::I64TruncSatF64U
+            | Operator::I64ReinterpretF64 => fixed(&[F64], &[I64]),
+            Operator::F32ConvertI32S | Operator::F32ConvertI32U | Operator::F32ReinterpretI32 => {
+                fixed(&[I32], &[F32])
+            }
+            Operator::F32ConvertI64S | Operator::F32ConvertI64U => fixed(&[I64], &[F32]),
+            Operator::F32DemoteF64 => fixed(&[F64], &[F32]),
+            Operator::F64ConvertI32S | Operator::F64ConvertI32U => fixed(&[I32], &[F64]),
+            Operator::F64ConvertI64S | Operator::F64ConvertI64U | Operator::F64ReinterpretI64 => {
+                fixed(&[I64], &[F64])
+            }
+            Operator::F64PromoteF32 => fixed(&[F32], &[F64]),
+
+            _ => return None,
+        };
+        self.pop_n(params.len());
+        self.push_all(&results);
+        Some((
+            params.into_iter().map(Some).collect(),
+            results.into_iter().map(Some).collect(),
+        ))
+    }
+}
+
+impl<
+    'm,
+    'a,
+    A: ToWasmInfo,
+    E: From<CompileError>,
+    I: Iterator<Item = Result<MachOperator<'a, A>, E>>,
+    M: TypeEnv + ?Sized,
+> Iterator for TrackTypes<'m, I, M>
+{
+    type Item = Result<MachOperator<'a, Typed<A>>, E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = match self.wrapped.next()? {
+            Ok(op) => op,
+            Err(e) => return Some(Err(e)),
+        };
+        let height = self.stack.len();
+        let typed = |annot, params, results| Typed {
+            annot,
+            params,
+            results,
+            height,
+        };
+        Some(Ok(match op {
+            MachOperator::StartFn { id, data } => {
+                let module = self.module;
+                match module.func_type_at(id + module.num_func_imports()) {
+                    Some(sig) => self.start_fn(sig.params(), sig.results()),
+                    None => self.start_fn(&data.params, &data.results),
+                }
+                MachOperator::StartFn { id, data }
+            }
+            MachOperator::Local { count, ty } => {
+                self.locals.extend(core::iter::repeat_n(ty, count as usize));
+                MachOperator::Local { count, ty }
+            }
+            MachOperator::StartBody => MachOperator::StartBody,
+            MachOperator::EndBody => MachOperator::EndBody,
+            MachOperator::Operator { op: None, annot } => MachOperator::Operator {
+                op: None,
+                annot: typed(annot, Vec::new(), Vec::new()),
+            },
+            MachOperator::Operator {
+                op: Some(op),
+                annot,
+            } => {
+                let Some((params, results)) = self.step(&op) else {
+                    return Some(Err(CompileError::unsupported(&op).at(&annot).into()));
+                };
+                MachOperator::Operator {
+                    op: Some(op),
+                    annot: typed(annot, params, results),
+                }
+            }
+            MachOperator::Trap { conditional, annot } => {
+                let params = if conditional {
+                    self.pop();
+                    [Some(ValType::I32)].to_vec()
+                } else {
+                    self.set_unreachable();
+                    Vec::new()
+                };
+                MachOperator::Trap {
+                    conditional,
+                    annot: typed(annot, params, Vec::new()),
+                }
+            }
+            op => return Some(Err(CompileError::unsupported_mach(&op).into())),
+        }))
+    }
+}
+
+impl<'a> Module<'a> {
+    /// Produces the `MachOperator` stream for every defined function with
+    /// each operator annotated by its operand types.
+    pub fn typed_mach_operators<
+        Annot: FromWasmInfo,
+        E: From<BinaryReaderError> + From<CompileError>,
+    >(
+        &'a self,
+    ) -> TrackTypes<'a, impl Iterator<Item = Result<MachOperator<'a, Annot>, E>>> {
+        TrackTypes::new(self, self.mach_operators())
+    }
+}
+
+/// Maximum operand-stack height reached by `body`, or `None` if it contains
+/// an operator the tracker does not know.
+///
+/// `locals` are the declared locals, following the parameters of `sig`.
+pub(crate) fn max_stack_height<M: TypeEnv + ?Sized>(
+    module: &M,
+    sig: &FuncType,
+    locals: &[ValType],
+    body: &FunctionBody<'_>,
+) -> Option<usize> {
+    let mut t = TrackTypes::new(module, ());
+    t.start_fn(sig.params(), sig.results());
+    t.locals.extend_from_slice(locals);
+    let mut max = 0;
+    for op in body.get_operators_reader().ok()? {
+        t.step(&op.ok()?)?;
+        max = max.max(t.stack.len());
+    }
+    Some(max)
+}
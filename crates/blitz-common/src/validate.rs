@@ -0,0 +1,115 @@
+//! Validating front end.
+//!
+//! `Module::mach_operators` trusts its input: backends assume operand-stack
+//! shapes that only hold for valid modules. The methods added here run
+//! `wasmparser`'s validator over the whole module before any code is
+//! generated, rejecting invalid modules with a located [`CompileError`], and
+//! record the operand-stack types after every operator.
+
+use alloc::vec::Vec;
+use wasmparser::{
+    FuncValidatorAllocations, OperatorsReader, Parser, ValidPayload, Validator, WasmFeatures,
+};
+
+use crate::{
+    module::Module,
+    ops::{FromWasmInfo, ToWasmInfo, WasmInfo},
+    *,
+};
+
+/// Annotation attached to every operator by the validating front end.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Validated<Annot = WasmInfo> {
+    /// The annotation the plain front end would have attached.
+    pub annot: Annot,
+    /// Operand-stack types after the operator, bottom first.
+    ///
+    /// `None` marks a value of unknown type, which only occurs in
+    /// unreachable code.
+    pub stack: Vec<Option<ValType>>,
+}
+
+impl<Annot: ToWasmInfo> ToWasmInfo for Validated<Annot> {
+    fn to_wasm_info(&self) -> Option<WasmInfo> {
+        self.annot.to_wasm_info()
+    }
+}
+
+/// Operand-stack types recorded while validating a module.
+///
+/// Holds one entry per operator of every defined function, in the order the
+/// operators appear in the code section.
+#[derive(Clone, Debug, Default)]
+pub struct StackTypes {
+    /// Per defined function, the stack after each of its operators.
+    pub funcs: Vec<Vec<Vec<Option<ValType>>>>,
+}
+
+impl<'a> Module<'a> {
+    /// Validates the module with the given `features` enabled.
+    ///
+    /// Every function body is validated, so an `Ok` result means no backend
+    /// will see an ill-typed operator stream.
+    pub fn validate(&self, features: WasmFeatures) -> Result<StackTypes, CompileError> {
+        let mut validator = Validator::new_with_features(features);
+        let mut allocs = FuncValidatorAllocations::default();
+        let mut types = StackTypes::default();
+        for payload in Parser::new(0).parse_all(self.bytes) {
+            let ValidPayload::Func(func, body) = validator.payload(&payload?)? else {
+                continue;
+            };
+            let mut v = func.into_validator(allocs);
+            let mut reader = body.get_binary_reader();
+            v.read_locals(&mut reader)?;
+            let mut ops = OperatorsReader::new(reader);
+            let mut stacks = Vec::new();
+            while !ops.eof() {
+                let (op, offset) = ops.read_with_offset()?;
+                v.op(offset, &op)?;
+                let height = v.operand_stack_height() as usize;
+                stacks.push(
+                    (0..height)
+                        .rev()
+                        .map(|depth| v.get_operand_type(depth).flatten())
+                        .collect(),
+                );
+            }
+            ops.finish()?;
+            types.funcs.push(stacks);
+            allocs = v.into_allocations();
+        }
+        Ok(types)
+    }
+
+    /// Validates the module, then produces the `MachOperator` stream for
+    /// every defined function with each operator annotated by its
+    /// operand-stack types.
+    ///
+    /// The trailing `Return` added by [`mach_operators`](Module::mach_operators)
+    /// sees the same stack as the function-level `End` before it: the
+    /// function's results.
+    pub fn validated_mach_operators<Annot: FromWasmInfo, E: From<BinaryReaderError>>(
+        &self,
+        features: WasmFeatures,
+    ) -> Result<impl Iterator<Item = Result<MachOperator<'a, Validated<Annot>>, E>>, CompileError>
+    {
+        let mut funcs = self.validate(features)?.funcs.into_iter();
+        let mut stacks = Vec::new().into_iter();
+        let mut last = Vec::new();
+        Ok(self.mach_operators::<Annot, E>().map(move |op| {
+            let op = op?;
+            if let MachOperator::StartFn { .. } = &op {
+                stacks = funcs.next().unwrap_or_default().into_iter();
+            }
+            op.map(&mut |annot| {
+                if let Some(stack) = stacks.next() {
+                    last = stack;
+                }
+                Ok(Validated {
+                    annot,
+                    stack: last.clone(),
+                })
+            })
+        }))
+    }
+}
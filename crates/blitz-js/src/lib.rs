@@ -9,8 +9,10 @@
 //! - Stack-based execution model matching WASM semantics
 //! - Optimized stack management with optional depth tracking
 //! - Type checking for function signatures at runtime
-//! - Support for all core WASM integer operations
+//! - Every MVP integer operator, plus the sign-extension operators
+//! - f32 and f64 arithmetic, comparisons and conversions
 //! - Control flow constructs (blocks, loops, if/else, branches)
+//! - Traps raised as `WebAssembly.RuntimeError`
 //!
 //! # Example
 //!
@@ -27,6 +29,54 @@
 //!
 //! - **Standard mode**: Uses JavaScript array operations for stack manipulation
 //! - **Optimized mode**: Tracks stack depth statically for better performance
+//!
+//! # Floats
+//!
+//! Floats are JavaScript numbers, with f32 results rounded by
+//! `Math.fround`. Conversions to integers throw on NaN and out-of-range
+//! values, except the saturating ones. Numbers carry no NaN payload, so
+//! reinterpreting a NaN gives whichever NaN the engine writes.
+//!
+//! # Linear Memory
+//!
+//! Memory `N` is a `DataView` bound to `$memN`. Defined memories are declared
+//! by [`JsWrite::memories`] before the first function; the embedder binds
+//! imported ones. Every access is bounds-checked against the view's length
+//! and traps when out of bounds. `memory.grow` rebinds `$memN` to a view of
+//! a larger copy of the buffer; imported memories never grow.
+//!
+//! # Globals
+//!
+//! Global `N` is bound to `$gN`. Defined globals are declared by
+//! [`JsWrite::globals`] before the first function; the embedder binds
+//! imported ones.
+//!
+//! # Tables
+//!
+//! Table `N` is an array bound to `$tN` whose slots hold functions or
+//! `null`, and element segment `N` is an array bound to `$eN`. Both are
+//! declared by [`JsWrite::tables`] and [`JsWrite::elems`]; the embedder
+//! binds imported tables. `call_indirect` checks the callee's signature
+//! like a direct call and traps on null or out-of-bounds slots.
+//!
+//! # Instantiation
+//!
+//! Data segment `N` is a `Uint8Array` bound to `$dN`, declared by
+//! [`JsWrite::data`]. [`JsWrite::init`] then copies the active element and
+//! data segments into their tables and memories, so the embedder must bind
+//! imports before the generated code runs.
+//!
+//! # Imports
+//!
+//! [`JsWrite::imports`] binds imported function `N` to `$N` as set by
+//! [`State::imports`]: by default, to the export of the same name of the ES
+//! module named like the import's module.
+//!
+//! # Exports
+//!
+//! The generated code is an ES module. [`JsWrite::exports`] ends it with a
+//! call to the start function, if any, and an `export` of every function,
+//! table, memory and global the module exports, under its export name.
 
 #![no_std]
 use core::{
@@ -40,9 +90,18 @@ pub mod __ {
 }
 use alloc::vec::Vec;
 use portal_solutions_blitz_common::{
-    DisplayFn,
-    ops::MachOperator,
-    wasm_encoder::{BlockType, FuncType, Instruction, reencode::Reencode},
+    CompileError, DisplayFn,
+    backend::{Backend, BackendContext, ForkBackend},
+    export::Exported,
+    float::{FloatKind, FloatOp},
+    global::{self, ConstValue, Global},
+    import::{Binding, Imports},
+    memory::{self, Access, DataMode, DataSegment, LinearMemory},
+    module::Module,
+    ops::{MachOperator, ToWasmInfo},
+    table::{self, ElemMode, ElemSegment, Table},
+    typed::BlockArity,
+    wasm_encoder::{BlockType, FuncType, Instruction, MemArg, reencode::Reencode},
     wasmparser::{Operator, ValType},
 };
 use portal_solutions_blitz_opt::{self as blitz_opt, OptCodegen, OptState};
@@ -141,6 +200,8 @@ pub use portal_solutions_blitz_opt::pop_display;
 pub struct State {
     stack: Vec<Frame>,
     opt_state: OnceCell<Mutex<OptState>>,
+    /// What imported functions are bound to.
+    pub imports: Imports,
 }
 
 impl State {
@@ -154,15 +215,72 @@ impl State {
     fn opt(&self) -> Option<&Mutex<OptState>> {
         self.opt_state.get()
     }
+
+    /// Checks that a branch `depth` targets an enclosing frame or the
+    /// function body itself.
+    fn check_depth(&self, depth: u32) -> Result<(), CompileError> {
+        if depth as usize <= self.stack.len() {
+            Ok(())
+        } else {
+            Err(CompileError::malformed())
+        }
+    }
+
+    /// Opens a control frame of type `blockty` whose parameters lie below
+    /// `popped` values consumed by the instruction itself, returning its
+    /// label index.
+    fn push_frame(
+        &mut self,
+        kind: FrameKind,
+        blockty: &BlockType,
+        sigs: &[FuncType],
+        popped: usize,
+    ) -> Result<usize, CompileError> {
+        let arity = BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+        let base = match self.opt() {
+            Some(o) => o
+                .lock()
+                .depth
+                .checked_sub(popped + arity.params)
+                .ok_or_else(CompileError::malformed)?,
+            None => 0,
+        };
+        self.stack.push(Frame { kind, arity, base });
+        Ok(self.stack.len())
+    }
 }
 
 /// Represents a control flow frame in the compilation state.
-enum Frame {
-    Block(BlockType),
-    Loop(BlockType),
+struct Frame {
+    kind: FrameKind,
+    arity: BlockArity,
+    /// Stack depth below the block's parameters, in optimized mode.
+    base: usize,
+}
+
+/// What a control flow frame was opened by.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    Block,
+    Loop,
     /// `If` is like `Block` for branching purposes: `br N` that targets an `if`
     /// frame is a forward exit out of the if/else body.
-    If(BlockType),
+    If,
+}
+
+/// A JavaScript literal for a float.
+struct Float(f64);
+
+impl Display for Float {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if self.0.is_nan() {
+            write!(f, "NaN")
+        } else if self.0.is_infinite() {
+            write!(f, "{}Infinity", if self.0 < 0.0 { "-" } else { "" })
+        } else {
+            write!(f, "{:?}", self.0)
+        }
+    }
 }
 
 /// Trait for writing JavaScript code for WASM operations.
@@ -242,6 +360,42 @@ pub trait JsWrite: Write {
         }
     }
 
+    /// Generates JavaScript code for a return.
+    ///
+    /// Returns the top `rets` values of the stack as an array.
+    fn ret(&mut self) -> core::fmt::Result {
+        write!(
+            self,
+            "if(stack.length===rets)return stack;tmp_locals=[];for(let i = 0; i < rets;i++)tmp_locals=[...{STACK_WEAVE}(tmp_locals),stack[stack.length-rets+i]];return tmp_locals;"
+        )
+    }
+
+    /// Generates JavaScript code raising a trap.
+    ///
+    /// Throws a `WebAssembly.RuntimeError`, or a plain `Error` where the
+    /// `WebAssembly` namespace is unavailable.
+    fn trap(&mut self, message: &str) -> core::fmt::Result {
+        write!(
+            self,
+            "throw new(globalThis.WebAssembly?.RuntimeError??Error)(`{message}`)"
+        )
+    }
+
+    /// Generates JavaScript code trapping on a division or remainder of `b`
+    /// by a zero `a`, and, given the minimum of a signed division, on
+    /// dividing it by -1.
+    fn div_guard(&mut self, min: Option<&str>) -> core::fmt::Result {
+        write!(self, "if(a===0n){{")?;
+        self.trap("integer divide by zero")?;
+        write!(self, "}}")?;
+        if let Some(min) = min {
+            write!(self, "if(a===-1n&&b==={min}){{")?;
+            self.trap("integer overflow")?;
+            write!(self, "}}")?;
+        }
+        Ok(())
+    }
+
     /// Generates JavaScript code for a branch (br) instruction.
     ///
     /// Creates a break or continue statement targeting the appropriate label
@@ -253,85 +407,543 @@ pub trait JsWrite: Write {
     /// * `state` - The current compilation state
     /// * `idx` - The relative depth of the target label
     // TODO: Remove the Sized bound once push/pop can work with ?Sized types
-    fn br(&mut self, sigs: &[FuncType], state: &State, idx: u32) -> core::fmt::Result
+    fn br(&mut self, _sigs: &[FuncType], state: &State, idx: u32) -> core::fmt::Result
     where
         Self: Sized,
     {
-        let (idx, frame) = state
-            .stack
-            .iter()
-            .enumerate()
-            .rev()
-            .nth(idx as usize)
-            .unwrap();
+        let Some((idx, frame)) = state.stack.iter().enumerate().rev().nth(idx as usize) else {
+            // A branch out of the function body is a return.
+            return self.ret();
+        };
         let idx = idx + 1;
-        match frame {
-            Frame::Block(blockty) | Frame::If(blockty) => {
-                let result_count = match blockty {
-                    portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                    portal_solutions_blitz_common::wasm_encoder::BlockType::Result(_) => 1,
-                    portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => {
-                        sigs[*f as usize].results().len()
+        let is_loop = frame.kind == FrameKind::Loop;
+        let jump = if is_loop { "continue" } else { "break" };
+        // Carry the values the label takes down to the stack height the
+        // frame was entered at, dropping everything in between.
+        let carried = frame.arity.label(is_loop);
+        if let Some(o) = state.opt() {
+            let depth = o.lock().depth;
+            let base = frame.base;
+            write!(
+                self,
+                "{{{}stack.length={};{jump} l{idx};}}",
+                DisplayFn(&|f| {
+                    for i in 1..=carried {
+                        write!(f, "stack[{}]=stack[{}];", base + i, depth - carried + i)?;
                     }
-                };
-                if let Some(o) = state.opt() {
-                    let mut o = o.lock();
-                    let d = result_count;
-                    let s = o.depth - d;
-                    write!(
-                        self,
-                        "{{stack=[{}];break l{idx};}}",
-                        DisplayFn(&|f| {
-                            for n in s..o.depth {
-                                write!(f, "stack[{n}]")?;
-                                if n + 1 != o.depth {
-                                    write!(f, ",")?;
-                                }
-                            }
-                            Ok(())
-                        })
-                    )?;
-                } else if result_count == 0 {
-                    write!(self, "{{stack=[];break l{idx};}}")?;
+                    Ok(())
+                }),
+                base + carried + 1
+            )?;
+        } else {
+            write!(
+                self,
+                "{{stack.splice(heights[{idx}],stack.length-heights[{idx}]-{carried});{jump} l{idx};}}"
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript declarations for the defined linear memories.
+    ///
+    /// Each memory starts out zeroed at its initial size; shared memories
+    /// are backed by a `SharedArrayBuffer`.
+    fn memories(&mut self, memories: &[LinearMemory]) -> Result<(), CompileError> {
+        for mem in memories.iter().filter(|m| !m.imported) {
+            let bytes = mem
+                .min_bytes()
+                .ok_or_else(|| CompileError::feature("memories larger than the address space"))?;
+            write!(
+                self,
+                "let $mem{}=new DataView(new {}({bytes}));",
+                mem.index,
+                if mem.shared {
+                    "SharedArrayBuffer"
                 } else {
-                    // Carry the top `result_count` values across the branch.
-                    write!(self, "{{stack=stack.slice(-{result_count});break l{idx};}}")?;
+                    "ArrayBuffer"
                 }
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript bindings for the defined globals.
+    ///
+    /// Global `N` is bound to `$gN`, with `const` for immutable globals.
+    /// Integers are `BigInt`s and floats are numbers, as on the stack.
+    fn globals(&mut self, globals: &[Global]) -> Result<(), CompileError> {
+        for global in globals {
+            let Some(init) = global.init else {
+                continue;
+            };
+            let kind = if global.mutable { "let" } else { "const" };
+            write!(self, "{kind} $g{}=", global.index)?;
+            self.const_value(init)?;
+            write!(self, ";")?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript bindings for the defined tables.
+    ///
+    /// Table `N` is an array bound to `$tN`, each slot holding a function or
+    /// `null`.
+    fn tables(&mut self, tables: &[Table]) -> Result<(), CompileError> {
+        for table in tables {
+            let Some(init) = table.init else {
+                continue;
+            };
+            write!(self, "let $t{}=new Array({}).fill(", table.index, table.min)?;
+            self.const_value(init)?;
+            write!(self, ");")?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript bindings for the element segments.
+    ///
+    /// Segment `N` is an array bound to `$eN`, emptied once the segment is
+    /// dropped.
+    fn elems(&mut self, elems: &[ElemSegment]) -> Result<(), CompileError> {
+        for elem in elems {
+            write!(self, "let $e{}=[", elem.index)?;
+            for item in elem.live_items() {
+                self.const_value(*item)?;
+                write!(self, ",")?;
             }
-            Frame::Loop(blockty) => {
-                let param_count = match blockty {
-                    portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                    portal_solutions_blitz_common::wasm_encoder::BlockType::Result(_) => 0,
-                    portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => {
-                        sigs[*f as usize].params().len()
-                    }
-                };
-                if let Some(o) = state.opt() {
-                    let mut o = o.lock();
-                    let d = param_count;
-                    let s = o.depth - d;
-                    // BUG FIX: Loop branch is a back-edge; emit continue, not break.
+            write!(self, "];")?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript bindings for the data segments.
+    ///
+    /// Segment `N` is a `Uint8Array` bound to `$dN`, emptied once the
+    /// segment is dropped.
+    fn data(&mut self, data: &[DataSegment<'_>]) -> Result<(), CompileError> {
+        for segment in data {
+            write!(self, "let $d{}=new Uint8Array([", segment.index)?;
+            self.bytes(segment.live_bytes())?;
+            write!(self, "]);")?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript code copying the active element and data
+    /// segments into their tables and memories, in that order, trapping
+    /// when a segment does not fit.
+    fn init(
+        &mut self,
+        elems: &[ElemSegment],
+        data: &[DataSegment<'_>],
+    ) -> Result<(), CompileError> {
+        for elem in elems {
+            let ElemMode::Active { table, offset } = elem.mode else {
+                continue;
+            };
+            write!(
+                self,
+                "((o)=>{{if(o+{}>$t{table}.length){{",
+                elem.items.len()
+            )?;
+            self.trap("out of bounds table access")?;
+            write!(self, "}}$t{table}.splice(o,{},", elem.items.len())?;
+            for item in &elem.items {
+                self.const_value(*item)?;
+                write!(self, ",")?;
+            }
+            write!(self, ")}})(Number(")?;
+            self.const_value(offset)?;
+            write!(self, "));")?;
+        }
+        for segment in data {
+            let DataMode::Active { memory, offset } = segment.mode else {
+                continue;
+            };
+            write!(
+                self,
+                "((o)=>{{if(o+{}>$mem{memory}.byteLength){{",
+                segment.bytes.len()
+            )?;
+            self.trap("out of bounds memory access")?;
+            write!(
+                self,
+                "}}new Uint8Array($mem{memory}.buffer,$mem{memory}.byteOffset).set(["
+            )?;
+            self.bytes(segment.bytes)?;
+            write!(self, "],o)}})(Number(")?;
+            self.const_value(offset)?;
+            write!(self, "));")?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript bindings for the imported functions of `module`,
+    /// as bound by `imports`.
+    ///
+    /// Import `N` is a function bound to `$N` with a signature like the
+    /// module's own. A host function is imported from its ES module, takes
+    /// the same arguments as the module's functions and returns its results
+    /// as an array, or as a single value when there is one; an unbound
+    /// import traps.
+    fn imports(&mut self, module: &Module<'_>, imports: &Imports) -> Result<(), CompileError> {
+        for import in module.func_import_items() {
+            let i = import.index;
+            match imports.bind(&import) {
+                Binding::Host { module, name } => {
+                    write!(self, "import{{")?;
+                    self.string(&name)?;
+                    write!(self, " as $h{i}}}from")?;
+                    self.string(&module)?;
                     write!(
                         self,
-                        "{{stack=[{}];continue l{idx};}}",
-                        DisplayFn(&|f| {
-                            for n in s..o.depth {
-                                write!(f, "stack[{n}]")?;
-                                if n + 1 != o.depth {
-                                    write!(f, ",")?;
-                                }
-                            }
-                            Ok(())
-                        })
+                        ";function ${i}(...args){{const r=$h{i}(...args);return Array.isArray(r)?r:r===undefined?[]:[r];}}"
                     )?;
-                } else if param_count == 0 {
-                    write!(self, "{{stack=[];continue l{idx};}}")?;
-                } else {
-                    write!(self, "{{stack=stack.slice(-{param_count});continue l{idx};}}")?;
+                }
+                _ => {
+                    write!(self, "function ${i}(){{")?;
+                    self.trap("unbound import")?;
+                    write!(self, "}}")?;
                 }
             }
-            _ => todo!(),
+            let ty = module.func_type(i);
+            write!(
+                self,
+                "Object.defineProperty(${i},'__sig',{{value:Object.freeze({{params:{},rets:{}}})}});",
+                ty.params().len(),
+                ty.results().len()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Generates a JavaScript call to the start function `start`, if any,
+    /// followed by an ES module `export` of every export of `module` under
+    /// its name.
+    ///
+    /// Both run once every function has been declared.
+    fn exports(&mut self, start: Option<u32>, module: &Module<'_>) -> Result<(), CompileError> {
+        if let Some(start) = start {
+            write!(self, "${start}();")?;
+        }
+        if module.exports.is_empty() {
+            return Ok(());
+        }
+        write!(self, "export{{")?;
+        for export in module.export_items() {
+            let export = export?;
+            match export.item {
+                Exported::Func(i) => write!(self, "${i}")?,
+                Exported::Table(i) => write!(self, "$t{i}")?,
+                Exported::Memory(i) => write!(self, "$mem{i}")?,
+                Exported::Global(i) => write!(self, "$g{i}")?,
+            }
+            write!(self, " as ")?;
+            self.string(export.name)?;
+            write!(self, ",")?;
+        }
+        write!(self, "}};")?;
+        Ok(())
+    }
+
+    /// Generates a JavaScript string literal of `s`.
+    fn string(&mut self, s: &str) -> core::fmt::Result {
+        write!(self, "\"")?;
+        for c in s.chars() {
+            match c {
+                '"' | '\\' => write!(self, "\\{c}")?,
+                c if c.is_control() => write!(self, "\\u{{{:x}}}", c as u32)?,
+                c => write!(self, "{c}")?,
+            }
+        }
+        write!(self, "\"")
+    }
+
+    /// Generates the elements of a JavaScript array literal of `bytes`.
+    fn bytes(&mut self, bytes: &[u8]) -> core::fmt::Result {
+        for byte in bytes {
+            write!(self, "{byte},")?;
+        }
+        Ok(())
+    }
+
+    /// Generates a JavaScript literal for a constant value.
+    fn const_value(&mut self, value: ConstValue) -> core::fmt::Result {
+        match value {
+            ConstValue::I32(v) => write!(self, "{}n", v as u32),
+            ConstValue::I64(v) => write!(self, "{}n", v as u64),
+            ConstValue::F32(bits) => write!(self, "{}", Float(f32::from_bits(bits) as f64)),
+            ConstValue::F64(bits) => write!(self, "{}", Float(f64::from_bits(bits))),
+            ConstValue::RefNull => write!(self, "null"),
+            ConstValue::RefFunc(f) => write!(self, "${f}"),
+            ConstValue::GlobalGet(g) => write!(self, "$g{g}"),
+        }
+    }
+
+    /// Generates JavaScript code copying `n` references from `src` into the
+    /// table `dst`, trapping when either range is out of bounds.
+    ///
+    /// Pops `n`, then the source index, then the destination index.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn table_copy(
+        &mut self,
+        state: &State,
+        dst: &(dyn Display + '_),
+        src: &(dyn Display + '_),
+    ) -> core::fmt::Result
+    where
+        Self: Sized,
+    {
+        write!(self, "((n,s,d)=>{{if(s+n>{src}.length||d+n>{dst}.length){{")?;
+        self.trap("out of bounds table access")?;
+        write!(
+            self,
+            "}}{dst}.splice(d,n,...{src}.slice(s,s+n))}})(Number({}),Number({}),Number({}))",
+            pop!(state),
+            pop!(state),
+            pop!(state)
+        )?;
+        Ok(())
+    }
+
+    /// Generates JavaScript code computing the effective address of an
+    /// access of `size` bytes into `ea`, trapping when it is out of bounds.
+    ///
+    /// Pops the address, which is truncated to 32 bits for 32-bit memories.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn address(
+        &mut self,
+        memories: &[LinearMemory],
+        state: &State,
+        memarg: &MemArg,
+        size: u64,
+    ) -> Result<(), CompileError>
+    where
+        Self: Sized,
+    {
+        let mem = memory::lookup(memories, memarg.memory_index)?;
+        let mask = if mem.memory64 { "mask64" } else { "mask32" };
+        write!(
+            self,
+            "ea=({}&{mask})+{}n;if(ea+{size}n>BigInt($mem{}.byteLength)){{",
+            pop!(state),
+            memarg.offset,
+            mem.index
+        )?;
+        self.trap("out of bounds memory access")?;
+        write!(self, "}};")?;
+        Ok(())
+    }
+
+    /// Generates JavaScript code for the load or store `access`.
+    ///
+    /// Integers narrower than their value are read with the signedness of
+    /// the access and stored as their low bytes.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn access(
+        &mut self,
+        memories: &[LinearMemory],
+        state: &State,
+        access: &Access,
+    ) -> Result<(), CompileError>
+    where
+        Self: Sized,
+    {
+        let i = access.memarg.memory_index;
+        let bits = access.size * 8;
+        let kind = match (access.float, access.signed, bits) {
+            (true, _, _) => "Float",
+            (false, _, 64) => "BigUint",
+            (false, true, _) => "Int",
+            (false, false, _) => "Uint",
+        };
+        if access.store {
+            write!(self, "val={};", pop!(state))?;
+            self.address(memories, state, &access.memarg, access.size)?;
+            if access.float || bits == 64 {
+                write!(self, "$mem{i}.set{kind}{bits}(Number(ea),val,true)")?;
+            } else {
+                let mask = (1u64 << bits) - 1;
+                write!(
+                    self,
+                    "$mem{i}.set{kind}{bits}(Number(ea),Number(val&{mask:#x}n),true)"
+                )?;
+            }
+            return Ok(());
+        }
+        self.address(memories, state, &access.memarg, access.size)?;
+        let value = format_args!("$mem{i}.get{kind}{bits}(Number(ea),true)");
+        if access.float || bits == 64 {
+            push(state, self, &value)?;
+        } else if access.signed {
+            push(
+                state,
+                self,
+                &format_args!("toUint(BigInt({value}),{})", access.bits()),
+            )?;
+        } else {
+            push(state, self, &format_args!("BigInt({value})"))?;
+        }
+        Ok(())
+    }
+
+    /// Generates JavaScript code for the floating-point operator `op`,
+    /// described by `float`.
+    ///
+    /// f32 results are rounded with `Math.fround`. Conversions to integers
+    /// trap on NaN and out-of-range values, except the saturating ones.
+    // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    fn float(
+        &mut self,
+        state: &State,
+        op: &Instruction<'_>,
+        float: &FloatOp,
+    ) -> Result<(), CompileError>
+    where
+        Self: Sized,
+    {
+        let (open, close) = if float.result == ValType::F32 && float.kind == FloatKind::Arith {
+            ("Math.fround(", ")")
+        } else {
+            ("", "")
         };
+        let unary = |w: &mut Self, f: &str| {
+            push(state, w, &format_args!("{open}{f}({}){close}", pop!(state)))
+        };
+        let binary = |w: &mut Self, f: &str| {
+            push(
+                state,
+                w,
+                &format_args!(
+                    "{open}((a={},b={})=>{f})(){close}",
+                    pop!(state),
+                    pop!(state)
+                ),
+            )
+        };
+        let compare = |w: &mut Self, f: &str| {
+            push(
+                state,
+                w,
+                &format_args!("((a={},b={})=>b{f}a?1n:0n)()", pop!(state), pop!(state)),
+            )
+        };
+        match op {
+            Instruction::F32Abs | Instruction::F64Abs => unary(self, "Math.abs"),
+            Instruction::F32Neg | Instruction::F64Neg => unary(self, "-"),
+            Instruction::F32Copysign | Instruction::F64Copysign => {
+                binary(self, "a<0||Object.is(a,-0)?-Math.abs(b):Math.abs(b)")
+            }
+            Instruction::F32Ceil | Instruction::F64Ceil => unary(self, "Math.ceil"),
+            Instruction::F32Floor | Instruction::F64Floor => unary(self, "Math.floor"),
+            Instruction::F32Trunc | Instruction::F64Trunc => unary(self, "Math.trunc"),
+            // `Math.round` rounds ties up; move them to the even neighbour.
+            Instruction::F32Nearest | Instruction::F64Nearest => {
+                unary(self, "((a,r=Math.round(a))=>r-a===0.5&&r%2!==0?r-1:r)")
+            }
+            Instruction::F32Sqrt | Instruction::F64Sqrt => unary(self, "Math.sqrt"),
+            Instruction::F32Add | Instruction::F64Add => binary(self, "b+a"),
+            Instruction::F32Sub | Instruction::F64Sub => binary(self, "b-a"),
+            Instruction::F32Mul | Instruction::F64Mul => binary(self, "b*a"),
+            Instruction::F32Div | Instruction::F64Div => binary(self, "b/a"),
+            // `Math.min` and `Math.max` already propagate NaN and order -0
+            // below 0.
+            Instruction::F32Min | Instruction::F64Min => binary(self, "Math.min(b,a)"),
+            Instruction::F32Max | Instruction::F64Max => binary(self, "Math.max(b,a)"),
+            Instruction::F32Eq | Instruction::F64Eq => compare(self, "==="),
+            Instruction::F32Ne | Instruction::F64Ne => compare(self, "!=="),
+            Instruction::F32Lt | Instruction::F64Lt => compare(self, "<"),
+            Instruction::F32Gt | Instruction::F64Gt => compare(self, ">"),
+            Instruction::F32Le | Instruction::F64Le => compare(self, "<="),
+            Instruction::F32Ge | Instruction::F64Ge => compare(self, ">="),
+            Instruction::I32TruncF32S
+            | Instruction::I32TruncF64S
+            | Instruction::I32TruncF32U
+            | Instruction::I32TruncF64U
+            | Instruction::I64TruncF32S
+            | Instruction::I64TruncF64S
+            | Instruction::I64TruncF32U
+            | Instruction::I64TruncF64U => {
+                let bits = if float.result == ValType::I64 { 64 } else { 32 };
+                // Bounds exclusive of the truncated range, which are exact
+                // doubles except for -2^63, where the bound is inclusive.
+                let in_range = match (bits, float.name.ends_with("_s")) {
+                    (32, true) => "val>-2147483649&&val<2147483648",
+                    (32, false) => "val>-1&&val<4294967296",
+                    (_, true) => "val>=-9223372036854775808&&val<9223372036854775808",
+                    (_, false) => "val>-1&&val<18446744073709551616",
+                };
+                write!(self, "val={};if(val!==val){{", pop!(state))?;
+                self.trap("invalid conversion to integer")?;
+                write!(self, "}}if(!({in_range})){{")?;
+                self.trap("integer overflow")?;
+                write!(self, "}}")?;
+                push(
+                    state,
+                    self,
+                    &format_args!("toUint(BigInt(Math.trunc(val)),{bits})"),
+                )
+            }
+            Instruction::I32TruncSatF32S
+            | Instruction::I32TruncSatF64S
+            | Instruction::I32TruncSatF32U
+            | Instruction::I32TruncSatF64U
+            | Instruction::I64TruncSatF32S
+            | Instruction::I64TruncSatF64S
+            | Instruction::I64TruncSatF32U
+            | Instruction::I64TruncSatF64U => {
+                let bits = if float.result == ValType::I64 { 64 } else { 32 };
+                let (lo, hi) = if float.name.ends_with("_s") {
+                    (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+                } else {
+                    (0, (1i128 << bits) - 1)
+                };
+                // Clamp to a range every integer result lies in before
+                // converting, as `BigInt` rejects infinities.
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "((a)=>{{if(a!==a)return 0n;const b=BigInt(Math.trunc(Math.min(Math.max(a,-1e20),1e20)));return toUint(b<{lo}n?{lo}n:b>{hi}n?{hi}n:b,{bits})}})({})",
+                        pop!(state)
+                    ),
+                )
+            }
+            Instruction::F32ConvertI32S | Instruction::F64ConvertI32S => push(
+                state,
+                self,
+                &format_args!("{open}Number(toInt({},32)){close}", pop!(state)),
+            ),
+            Instruction::F64ConvertI64S => push(
+                state,
+                self,
+                &format_args!("Number(toInt({},64))", pop!(state)),
+            ),
+            Instruction::F32ConvertI32U
+            | Instruction::F64ConvertI32U
+            | Instruction::F64ConvertI64U => unary(self, "Number"),
+            // Rounding an i64 to a double then to an f32 can round twice;
+            // shift it below 2^53 keeping a sticky bit so that only
+            // `Math.fround` rounds.
+            Instruction::F32ConvertI64S | Instruction::F32ConvertI64U => {
+                let signed = matches!(op, Instruction::F32ConvertI64S);
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "((x)=>{{let m=x<0n?-x:x,s=0;while(m>=1n<<53n){{m=(m>>1n)|(m&1n);s++}}return Math.fround((x<0n?-1:1)*Number(m)*2**s)}})({})",
+                        DisplayFn(&|f| if signed {
+                            write!(f, "toInt({},64)", pop!(state))
+                        } else {
+                            write!(f, "{}", pop!(state))
+                        })
+                    ),
+                )
+            }
+            Instruction::F32DemoteF64 => unary(self, "Math.fround"),
+            Instruction::F64PromoteF32 => push(state, self, &pop!(state)),
+            _ => return Err(CompileError::unsupported(op)),
+        }?;
         Ok(())
     }
 
@@ -345,25 +957,75 @@ pub trait JsWrite: Write {
     /// * `sigs` - Array of function type signatures
     /// * `fsigs` - Function signature indices
     /// * `func_imports` - Information about imported functions
+    /// * `memories` - Every linear memory, imports included
+    /// * `module` - The module being compiled, for its globals, tables and
+    ///   segments
     /// * `state` - The current compilation state
     /// * `op` - The instruction to convert
     // TODO: Remove the Sized bound once push/pop can work with ?Sized types
+    #[allow(clippy::too_many_arguments)]
     fn on_op(
         &mut self,
         sigs: &[FuncType],
         fsigs: &[u32],
         func_imports: &[(&str, &str)],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         state: &mut State,
         op: &Instruction<'_>,
-    ) -> core::fmt::Result
+    ) -> Result<(), CompileError>
     where
         Self: Sized,
     {
+        if let Some(access) = Access::of(op) {
+            return self.access(memories, state, &access);
+        }
+        if let Some(float) = FloatOp::of(op) {
+            return self.float(state, op, &float);
+        }
         match op {
             Instruction::I64Const(value) => push(state, self, &format_args!("{}n", *value as u64)),
             Instruction::I32Const(value) => {
                 push(state, self, &format_args!("{}n", *value as u32 as u64))
             }
+            Instruction::F32Const(value) => {
+                push(state, self, &Float(f32::from_bits(value.bits()) as f64))
+            }
+            Instruction::F64Const(value) => push(state, self, &Float(f64::from_bits(value.bits()))),
+            // Floats are numbers, so reinterpreting goes through memory.
+            // NaN payloads are not preserved.
+            Instruction::I32ReinterpretF32 => push(
+                state,
+                self,
+                &format_args!(
+                    "((d=new DataView(new ArrayBuffer(8)))=>(d.setFloat32(0,{}),BigInt(d.getUint32(0))))()",
+                    pop!(state)
+                ),
+            ),
+            Instruction::I64ReinterpretF64 => push(
+                state,
+                self,
+                &format_args!(
+                    "((d=new DataView(new ArrayBuffer(8)))=>(d.setFloat64(0,{}),d.getBigUint64(0)))()",
+                    pop!(state)
+                ),
+            ),
+            Instruction::F32ReinterpretI32 => push(
+                state,
+                self,
+                &format_args!(
+                    "((d=new DataView(new ArrayBuffer(8)))=>(d.setUint32(0,Number({})),d.getFloat32(0)))()",
+                    pop!(state)
+                ),
+            ),
+            Instruction::F64ReinterpretI64 => push(
+                state,
+                self,
+                &format_args!(
+                    "((d=new DataView(new ArrayBuffer(8)))=>(d.setBigUint64(0,{}),d.getFloat64(0)))()",
+                    pop!(state)
+                ),
+            ),
             Instruction::I64Eqz | Instruction::I32Eqz => {
                 push(state, self, &format_args!("({}===0n?1n:0n)", pop!(state)))
             }
@@ -391,22 +1053,33 @@ pub trait JsWrite: Write {
             Instruction::I32DivU => push(
                 state,
                 self,
-                &format_args!("((a={},b={})=>(b/a)&mask32)()", pop!(state), pop!(state)),
+                &format_args!(
+                    "((a={},b={})=>{{{}return(b/a)&mask32}})()",
+                    pop!(state),
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(None))
+                ),
             ),
             // BUG FIX: swap operands.
             Instruction::I32RemU => push(
                 state,
                 self,
-                &format_args!("((a={},b={})=>(b%a)&mask32)()", pop!(state), pop!(state)),
+                &format_args!(
+                    "((a={},b={})=>{{{}return(b%a)&mask32}})()",
+                    pop!(state),
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(None))
+                ),
             ),
             // BUG FIX: swap operands; also added missing ,32 to toUint.
             Instruction::I32DivS => push(
                 state,
                 self,
                 &format_args!(
-                    "((a=toInt({},32),b=toInt({},32))=>toUint((b/a)&mask32,32))()",
+                    "((a=toInt({},32),b=toInt({},32))=>{{{}return toUint((b/a)&mask32,32)}})()",
                     pop!(state),
-                    pop!(state)
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(Some("-2147483648n")))
                 ),
             ),
             // BUG FIX: swap operands; also added missing ,32 to toUint.
@@ -414,9 +1087,10 @@ pub trait JsWrite: Write {
                 state,
                 self,
                 &format_args!(
-                    "((a=toInt({},32),b=toInt({},32))=>toUint((b%a)&mask32,32))()",
+                    "((a=toInt({},32),b=toInt({},32))=>{{{}return toUint((b%a)&mask32,32)}})()",
                     pop!(state),
-                    pop!(state)
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(None))
                 ),
             ),
             // BUG FIX: a=shift-count (rhs, first pop) modulo 32; b=value (lhs, second pop).
@@ -495,22 +1169,33 @@ pub trait JsWrite: Write {
             Instruction::I64DivU => push(
                 state,
                 self,
-                &format_args!("((a={},b={})=>(b/a)&mask64)()", pop!(state), pop!(state)),
+                &format_args!(
+                    "((a={},b={})=>{{{}return(b/a)&mask64}})()",
+                    pop!(state),
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(None))
+                ),
             ),
             // BUG FIX: b%a = lhs%rhs.
             Instruction::I64RemU => push(
                 state,
                 self,
-                &format_args!("((a={},b={})=>(b%a)&mask64)()", pop!(state), pop!(state)),
+                &format_args!(
+                    "((a={},b={})=>{{{}return(b%a)&mask64}})()",
+                    pop!(state),
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(None))
+                ),
             ),
             // BUG FIX: swap operands; also add missing ,64 to toUint.
             Instruction::I64DivS => push(
                 state,
                 self,
                 &format_args!(
-                    "((a=toInt({},64),b=toInt({},64))=>toUint((b/a)&mask64,64))()",
+                    "((a=toInt({},64),b=toInt({},64))=>{{{}return toUint((b/a)&mask64,64)}})()",
                     pop!(state),
-                    pop!(state)
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(Some("-9223372036854775808n")))
                 ),
             ),
             // BUG FIX: swap operands; also add missing ,64 to toUint.
@@ -518,9 +1203,10 @@ pub trait JsWrite: Write {
                 state,
                 self,
                 &format_args!(
-                    "((a=toInt({},64),b=toInt({},64))=>toUint((b%a)&mask64,64))()",
+                    "((a=toInt({},64),b=toInt({},64))=>{{{}return toUint((b%a)&mask64,64)}})()",
                     pop!(state),
-                    pop!(state)
+                    pop!(state),
+                    DisplayFn(&|f| f.div_guard(None))
                 ),
             ),
             // BUG FIX: a=shift-count (rhs, first pop) modulo 64; b=value (lhs, second pop).
@@ -574,18 +1260,343 @@ pub trait JsWrite: Write {
                     pop!(state)
                 ),
             ),
-            //
-            Instruction::Return => {
-                write!(
+            // Both widths: operands are already in range, so bitwise
+            // results need no masking.
+            Instruction::I32And | Instruction::I64And => {
+                push(state, self, &format_args!("({}&{})", pop!(state), pop!(state)))
+            }
+            Instruction::I32Or | Instruction::I64Or => {
+                push(state, self, &format_args!("({}|{})", pop!(state), pop!(state)))
+            }
+            Instruction::I32Xor | Instruction::I64Xor => {
+                push(state, self, &format_args!("({}^{})", pop!(state), pop!(state)))
+            }
+            Instruction::I32Eq | Instruction::I64Eq => push(
+                state,
+                self,
+                &format_args!("({}==={}?1n:0n)", pop!(state), pop!(state)),
+            ),
+            Instruction::I32Ne | Instruction::I64Ne => push(
+                state,
+                self,
+                &format_args!("({}!=={}?1n:0n)", pop!(state), pop!(state)),
+            ),
+            // a=rhs (first pop), b=lhs (second pop); signed comparisons
+            // sign-extend both.
+            Instruction::I32LtS
+            | Instruction::I32LtU
+            | Instruction::I32GtS
+            | Instruction::I32GtU
+            | Instruction::I32LeS
+            | Instruction::I32LeU
+            | Instruction::I32GeS
+            | Instruction::I32GeU
+            | Instruction::I64LtS
+            | Instruction::I64LtU
+            | Instruction::I64GtS
+            | Instruction::I64GtU
+            | Instruction::I64LeS
+            | Instruction::I64LeU
+            | Instruction::I64GeS
+            | Instruction::I64GeU => {
+                let (cmp, signed, bits) = match op {
+                    Instruction::I32LtS => ("<", true, 32),
+                    Instruction::I32LtU => ("<", false, 32),
+                    Instruction::I32GtS => (">", true, 32),
+                    Instruction::I32GtU => (">", false, 32),
+                    Instruction::I32LeS => ("<=", true, 32),
+                    Instruction::I32LeU => ("<=", false, 32),
+                    Instruction::I32GeS => (">=", true, 32),
+                    Instruction::I32GeU => (">=", false, 32),
+                    Instruction::I64LtS => ("<", true, 64),
+                    Instruction::I64LtU => ("<", false, 64),
+                    Instruction::I64GtS => (">", true, 64),
+                    Instruction::I64GtU => (">", false, 64),
+                    Instruction::I64LeS => ("<=", true, 64),
+                    Instruction::I64LeU => ("<=", false, 64),
+                    Instruction::I64GeS => (">=", true, 64),
+                    _ => (">=", false, 64),
+                };
+                if signed {
+                    push(
+                        state,
+                        self,
+                        &format_args!(
+                            "((a=toInt({},{bits}),b=toInt({},{bits}))=>b{cmp}a?1n:0n)()",
+                            pop!(state),
+                            pop!(state)
+                        ),
+                    )
+                } else {
+                    push(
+                        state,
+                        self,
+                        &format_args!(
+                            "((a={},b={})=>b{cmp}a?1n:0n)()",
+                            pop!(state),
+                            pop!(state)
+                        ),
+                    )
+                }
+            }
+            Instruction::I32Clz | Instruction::I64Clz => {
+                let bits = if let Instruction::I32Clz = op { 32 } else { 64 };
+                push(
+                    state,
                     self,
-                    "if(stack.length===rets)return stack;tmp_locals=[];for(let i = 0; i < rets;i++)tmp_locals=[...{STACK_WEAVE}(tmp_locals),stack[stack.length-rets+i]];return tmp_locals;"
+                    &format_args!(
+                        "((a={})=>a===0n?{bits}n:BigInt({bits}-a.toString(2).length))()",
+                        pop!(state)
+                    ),
                 )
             }
+            // `a&-a` isolates the lowest set bit.
+            Instruction::I32Ctz | Instruction::I64Ctz => {
+                let bits = if let Instruction::I32Ctz = op { 32 } else { 64 };
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "((a={})=>a===0n?{bits}n:BigInt((a&-a).toString(2).length-1))()",
+                        pop!(state)
+                    ),
+                )
+            }
+            Instruction::I32Popcnt | Instruction::I64Popcnt => push(
+                state,
+                self,
+                &format_args!(
+                    "BigInt(({}).toString(2).replace(/0/g,'').length)",
+                    pop!(state)
+                ),
+            ),
+            Instruction::I32WrapI64 => push(state, self, &format_args!("({}&mask32)", pop!(state))),
+            // Values are stored unsigned, so zero-extension is free.
+            Instruction::I64ExtendI32U => Ok(()),
+            Instruction::I64ExtendI32S
+            | Instruction::I32Extend8S
+            | Instruction::I32Extend16S
+            | Instruction::I64Extend8S
+            | Instruction::I64Extend16S
+            | Instruction::I64Extend32S => {
+                let (from, to) = match op {
+                    Instruction::I32Extend8S => (8, 32),
+                    Instruction::I32Extend16S => (16, 32),
+                    Instruction::I64Extend8S => (8, 64),
+                    Instruction::I64Extend16S => (16, 64),
+                    _ => (32, 64),
+                };
+                push(
+                    state,
+                    self,
+                    &format_args!("toUint(toInt({},{from}),{to})", pop!(state)),
+                )
+            }
+            //
+            Instruction::Return => self.ret(),
             Instruction::Call(function_index) => self.call(
                 state,
                 &sigs[fsigs[*function_index as usize] as usize],
                 &format_args!("${function_index}"),
             ),
+            Instruction::CallIndirect {
+                type_index,
+                table_index,
+            } => {
+                table::lookup(&module.tables, *table_index)?;
+                let sig = sigs
+                    .get(*type_index as usize)
+                    .ok_or_else(|| CompileError::unknown("type", *type_index))?;
+                write!(self, "val=$t{table_index}[Number({})];", pop!(state))?;
+                write!(self, "if(val==null){{")?;
+                self.trap("uninitialized element")?;
+                write!(
+                    self,
+                    "}}if(val.__sig.params!={}||val.__sig.rets!={}){{",
+                    sig.params().len(),
+                    sig.results().len()
+                )?;
+                self.trap("indirect call type mismatch")?;
+                write!(self, "}};")?;
+                self.call(state, sig, &"val")?;
+                Ok(())
+            }
+            Instruction::RefNull(_) => push(state, self, &"null"),
+            Instruction::RefIsNull => {
+                push(state, self, &format_args!("({}===null?1n:0n)", pop!(state)))
+            }
+            Instruction::RefFunc(function_index) => {
+                if *function_index as usize >= fsigs.len() {
+                    return Err(CompileError::unknown("func", *function_index));
+                }
+                push(state, self, &format_args!("${function_index}"))
+            }
+            Instruction::TableGet(table) => {
+                table::lookup(&module.tables, *table)?;
+                write!(self, "val=$t{table}[Number({})];", pop!(state))?;
+                write!(self, "if(val===undefined){{")?;
+                self.trap("out of bounds table access")?;
+                write!(self, "}};")?;
+                push(state, self, &"val")
+            }
+            Instruction::TableSet(table) => {
+                table::lookup(&module.tables, *table)?;
+                write!(
+                    self,
+                    "val={};ea=Number({});if(ea>=$t{table}.length){{",
+                    pop!(state),
+                    pop!(state)
+                )?;
+                self.trap("out of bounds table access")?;
+                write!(self, "}}$t{table}[ea]=val")?;
+                Ok(())
+            }
+            Instruction::TableSize(table) => {
+                table::lookup(&module.tables, *table)?;
+                push(state, self, &format_args!("BigInt($t{table}.length)"))
+            }
+            Instruction::TableGrow(table) => {
+                let t = module.table(*table)?;
+                let fail = if t.table64 { "mask64" } else { "mask32" };
+                write!(self, "ea=Number({});val={};", pop!(state), pop!(state))?;
+                push(
+                    state,
+                    self,
+                    &format_args!(
+                        "($t{table}.length+ea>{}?{fail}:BigInt($t{table}.push(...new Array(ea).fill(val))-ea))",
+                        t.max_len()
+                    ),
+                )
+            }
+            Instruction::TableFill(table) => {
+                table::lookup(&module.tables, *table)?;
+                write!(self, "((n,v,i)=>{{if(i+n>$t{table}.length){{")?;
+                self.trap("out of bounds table access")?;
+                write!(
+                    self,
+                    "}}$t{table}.fill(v,i,i+n)}})(Number({}),{},Number({}))",
+                    pop!(state),
+                    pop!(state),
+                    pop!(state)
+                )?;
+                Ok(())
+            }
+            Instruction::TableCopy {
+                dst_table,
+                src_table,
+            } => {
+                table::lookup(&module.tables, *dst_table)?;
+                table::lookup(&module.tables, *src_table)?;
+                self.table_copy(
+                    state,
+                    &format_args!("$t{dst_table}"),
+                    &format_args!("$t{src_table}"),
+                )
+            }
+            Instruction::TableInit { elem_index, table } => {
+                table::lookup(&module.tables, *table)?;
+                table::lookup_elem(&module.elements, *elem_index)?;
+                self.table_copy(
+                    state,
+                    &format_args!("$t{table}"),
+                    &format_args!("$e{elem_index}"),
+                )
+            }
+            Instruction::MemoryInit { mem, data_index } => {
+                let mem = memory::lookup(memories, *mem)?;
+                memory::lookup_data(&module.data, *data_index)?;
+                write!(
+                    self,
+                    "((n,s,d)=>{{if(s+n>$d{data_index}.length||d+n>$mem{0}.byteLength){{",
+                    mem.index
+                )?;
+                self.trap("out of bounds memory access")?;
+                write!(
+                    self,
+                    "}}new Uint8Array($mem{0}.buffer,$mem{0}.byteOffset).set($d{data_index}.subarray(s,s+n),d)}})(Number({1}),Number({2}),Number({3}))",
+                    mem.index,
+                    pop!(state),
+                    pop!(state),
+                    pop!(state)
+                )?;
+                Ok(())
+            }
+            Instruction::MemorySize(mem) => {
+                let mem = memory::lookup(memories, *mem)?;
+                push(
+                    state,
+                    self,
+                    &format_args!("(BigInt($mem{}.byteLength)/{}n)", mem.index, mem.page_size()),
+                )
+            }
+            Instruction::MemoryGrow(mem) => {
+                let mem = memory::lookup(memories, *mem)?;
+                let i = mem.index;
+                let fail = if mem.memory64 { "mask64" } else { "mask32" };
+                let ctor = if mem.shared {
+                    "SharedArrayBuffer"
+                } else {
+                    "ArrayBuffer"
+                };
+                if mem.imported {
+                    // The embedder owns the buffer of an imported memory.
+                    write!(self, "{};", pop!(state))?;
+                    push(state, self, &fail)
+                } else {
+                    push(
+                        state,
+                        self,
+                        &format_args!(
+                            "((n)=>{{const o=$mem{i}.byteLength,p=BigInt(o)/{page}n;if(n>{max}n-p)return {fail};if(n===0n)return p;try{{const b=new {ctor}(o+Number(n)*{page});new Uint8Array(b).set(new Uint8Array($mem{i}.buffer,$mem{i}.byteOffset,o));$mem{i}=new DataView(b);}}catch{{return {fail};}}return p;}})({})",
+                            pop!(state),
+                            page = mem.page_size(),
+                            max = mem.max_size(),
+                        ),
+                    )
+                }
+            }
+            Instruction::MemoryFill(mem) => {
+                let i = memory::lookup(memories, *mem)?.index;
+                write!(self, "((n,v,d)=>{{if(d+n>BigInt($mem{i}.byteLength)){{")?;
+                self.trap("out of bounds memory access")?;
+                write!(
+                    self,
+                    "}}new Uint8Array($mem{i}.buffer,$mem{i}.byteOffset).fill(Number(v&255n),Number(d),Number(d+n))}})({},{},{})",
+                    pop!(state),
+                    pop!(state),
+                    pop!(state)
+                )?;
+                Ok(())
+            }
+            Instruction::MemoryCopy { dst_mem, src_mem } => {
+                let dst = memory::lookup(memories, *dst_mem)?.index;
+                let src = memory::lookup(memories, *src_mem)?.index;
+                write!(
+                    self,
+                    "((n,s,d)=>{{if(s+n>BigInt($mem{src}.byteLength)||d+n>BigInt($mem{dst}.byteLength)){{"
+                )?;
+                self.trap("out of bounds memory access")?;
+                // `set` copies as if through a temporary buffer, so
+                // overlapping ranges are handled.
+                write!(
+                    self,
+                    "}}new Uint8Array($mem{dst}.buffer,$mem{dst}.byteOffset).set(new Uint8Array($mem{src}.buffer,$mem{src}.byteOffset+Number(s),Number(n)),Number(d))}})({},{},{})",
+                    pop!(state),
+                    pop!(state),
+                    pop!(state)
+                )?;
+                Ok(())
+            }
+            Instruction::DataDrop(data_index) => {
+                memory::lookup_data(&module.data, *data_index)?;
+                write!(self, "$d{data_index}=new Uint8Array(0)")?;
+                Ok(())
+            }
+            Instruction::ElemDrop(elem_index) => {
+                table::lookup_elem(&module.elements, *elem_index)?;
+                write!(self, "$e{elem_index}=[]")?;
+                Ok(())
+            }
             Instruction::LocalGet(local_index) => {
                 push(state, self, &format_args!("locals[{local_index}]"))
             }
@@ -599,46 +1610,64 @@ pub trait JsWrite: Write {
                 self,
                 &format_args!("(locals[{local_index}]={})", pop!(state)),
             ),
+            Instruction::GlobalGet(global_index) => {
+                global::lookup(&module.globals, *global_index)?;
+                push(state, self, &format_args!("$g{global_index}"))
+            }
+            Instruction::GlobalSet(global_index) => {
+                global::lookup(&module.globals, *global_index)?;
+                write!(self, "$g{global_index}={}", pop!(state))
+            }
             Instruction::Block(blockty) => {
-                state.stack.push(Frame::Block(blockty.clone()));
-                if let Some(o) = state.opt() {
-                    let mut o = o.lock();
-                    o.depth = match blockty {
-                        portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                        portal_solutions_blitz_common::wasm_encoder::BlockType::Result(
-                            val_type,
-                        ) => 0,
-                        portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => {
-                            sigs[*f as usize].params().len()
-                        }
-                    };
+                let n = state.push_frame(FrameKind::Block, blockty, sigs, 0)?;
+                if state.opt().is_none() {
+                    write!(
+                        self,
+                        "heights[{n}]=stack.length-{};",
+                        state.stack[n - 1].arity.params
+                    )?;
                 }
-                write!(self, "l{}: for(;;){{", state.stack.len())
+                write!(self, "l{n}: for(;;){{")
             }
             Instruction::Loop(blockty) => {
-                state.stack.push(Frame::Loop(blockty.clone()));
-                if let Some(o) = state.opt() {
-                    let mut o = o.lock();
-                    o.depth = match blockty {
-                        portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                        portal_solutions_blitz_common::wasm_encoder::BlockType::Result(
-                            val_type,
-                        ) => 0,
-                        portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => {
-                            sigs[*f as usize].params().len()
-                        }
-                    };
+                let n = state.push_frame(FrameKind::Loop, blockty, sigs, 0)?;
+                if state.opt().is_none() {
+                    write!(
+                        self,
+                        "heights[{n}]=stack.length-{};",
+                        state.stack[n - 1].arity.params
+                    )?;
                 }
-                write!(self, "l{}: for(;;){{", state.stack.len())
+                write!(self, "l{n}: for(;;){{")
             }
             Instruction::If(blockty) => {
                 // Wrap in a labeled block so `br N` targeting this If frame can
                 // use `break l{n}` to exit it (JavaScript allows labeled breaks
                 // on any statement, not just loops).
-                state.stack.push(Frame::If(blockty.clone()));
-                write!(self, "l{}: {{if({}){{", state.stack.len(), pop!(state))
+                let n = state.push_frame(FrameKind::If, blockty, sigs, 1)?;
+                if state.opt().is_none() {
+                    // The condition is still on the stack at this point.
+                    write!(
+                        self,
+                        "heights[{n}]=stack.length-{};",
+                        state.stack[n - 1].arity.params + 1
+                    )?;
+                }
+                write!(self, "l{n}: {{if({}){{", pop!(state))
             }
             Instruction::Else => {
+                let Some(Frame {
+                    kind: FrameKind::If,
+                    arity,
+                    base,
+                }) = state.stack.last()
+                else {
+                    return Err(CompileError::malformed());
+                };
+                // The else arm starts from the if's parameters again.
+                if let Some(o) = state.opt() {
+                    o.lock().depth = base + arity.params;
+                }
                 write!(self, "}}else{{")
             }
             Instruction::End => {
@@ -647,54 +1676,34 @@ pub trait JsWrite: Write {
                     // Function-level end (implicit outer block) — no frame to close.
                     None => return Ok(()),
                 };
-                match s {
-                    Frame::Block(blockty) => {
-                        write!(self, "break;")?;
-                        if let Some(o) = state.opt() {
-                            let mut o = o.lock();
-                            o.depth = match blockty{
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::Result(val_type) => 1,
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => sigs[f as usize].results().len(),
-                            };
-                        }
-                        write!(self, "}}")?;
-                    }
-                    Frame::Loop(blockty) => {
-                        write!(self, "break;")?;
-                        if let Some(o) = state.opt() {
-                            let mut o = o.lock();
-                            o.depth = match blockty{
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::Result(val_type) => 1,
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => sigs[f as usize].results().len(),
-                            };
-                        }
-                        write!(self, "}}")?;
-                    }
-                    Frame::If(blockty) => {
-                        // Close if body, then close the labeled outer block wrapper.
-                        write!(self, "}}}}")?;
-                        if let Some(o) = state.opt() {
-                            let mut o = o.lock();
-                            o.depth = match blockty {
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::Empty => 0,
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::Result(_) => 1,
-                                portal_solutions_blitz_common::wasm_encoder::BlockType::FunctionType(f) => sigs[f as usize].results().len(),
-                            };
-                        }
-                    }
+                if let Some(o) = state.opt() {
+                    o.lock().depth = s.base + s.arity.results;
+                }
+                match s.kind {
+                    FrameKind::Block | FrameKind::Loop => write!(self, "break;}}"),
+                    // Close if body, then close the labeled outer block wrapper.
+                    FrameKind::If => write!(self, "}}}}"),
                 }
-                Ok(())
             }
-            Instruction::Br(relative_depth) => self.br(sigs, state, *relative_depth),
-            Instruction::BrIf(relative_depth) => write!(
-                self,
-                "if({}!==0n){}",
-                pop!(state),
-                DisplayFn(&|f| f.br(sigs, state, *relative_depth))
-            ),
+            Instruction::Unreachable => self.trap("unreachable"),
+            Instruction::Br(relative_depth) => {
+                state.check_depth(*relative_depth)?;
+                self.br(sigs, state, *relative_depth)
+            }
+            Instruction::BrIf(relative_depth) => {
+                state.check_depth(*relative_depth)?;
+                // Braced: a branch out of the body returns in several statements.
+                write!(
+                    self,
+                    "if({}!==0n){{{}}}",
+                    pop!(state),
+                    DisplayFn(&|f| f.br(sigs, state, *relative_depth))
+                )
+            }
             Instruction::BrTable(targets, default) => {
+                for t in targets.iter().chain([default]) {
+                    state.check_depth(*t)?;
+                }
                 // BUG FIX: was `write!(self, "{}", pop!(state))` which discarded the
                 // popped value — tmp was never assigned before the loop used it.
                 write!(self, "tmp={};", pop!(state))?;
@@ -708,7 +1717,7 @@ pub trait JsWrite: Write {
                 self.br(sigs, state, *default)?;
                 Ok(())
             }
-            _ => todo!(),
+            _ => return Err(CompileError::unsupported(op)),
         }?;
         Ok(())
     }
@@ -723,19 +1732,25 @@ pub trait JsWrite: Write {
     /// * `sigs` - Array of function type signatures
     /// * `fsigs` - Function signature indices
     /// * `func_imports` - Information about imported functions
+    /// * `memories` - Every linear memory, imports included
+    /// * `module` - The module being compiled, for its globals, tables and
+    ///   segments
     /// * `state` - The current compilation state
     /// * `m` - The machine operator to process
     /// * `r` - Re-encoder for converting between instruction formats
     // TODO: Remove the Sized bound once push/pop can work with ?Sized types
-    fn on_mach<Annot>(
+    #[allow(clippy::too_many_arguments)]
+    fn on_mach<Annot: ToWasmInfo>(
         &mut self,
         sigs: &[FuncType],
         fsigs: &[u32],
         func_imports: &[(&str, &str)],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         state: &mut State,
         m: &MachOperator<'_, Annot>,
         r: &mut impl Reencode,
-    ) -> core::fmt::Result
+    ) -> Result<(), CompileError>
     where
         Self: Sized,
     {
@@ -755,7 +1770,7 @@ pub trait JsWrite: Write {
                         writable:false
                     }});
                     function ${id}(...locals){{
-                    let stack=[],tmp,mask32=0xffff_ffffn,mask64=(mask32<<32n)|mask32,{{params,rets}}=${id}.__sig,tmp_locals=[],args=[];
+                    let stack=[],heights=[],tmp,ea,val,mask32=0xffff_ffffn,mask64=(mask32<<32n)|mask32,{{params,rets}}=${id}.__sig,tmp_locals=[],args=[];
                     if(locals.length!==params){{
                         for(let i = 0; i < params;i++)tmp_locals=[...{STACK_WEAVE}(tmp_locals),locals[locals.length - params + i]];locals=tmp_locals;
                     }};
@@ -763,7 +1778,8 @@ pub trait JsWrite: Write {
                     const toUint=(a,b)=>BigInt.asUintN(b,a);
                     ",
                     data.num_params, data.num_returns
-                )
+                )?;
+                Ok(())
             }
             MachOperator::Local { count, ty } => {
                 for _ in 0..*count {
@@ -772,6 +1788,7 @@ pub trait JsWrite: Write {
                         "locals=[...{STACK_WEAVE}(locals),{}];",
                         match ty {
                             ValType::F32 | ValType::F64 => "0",
+                            ValType::Ref(_) => "null",
                             _ => "0n",
                         }
                     )?
@@ -780,7 +1797,8 @@ pub trait JsWrite: Write {
             }
             MachOperator::StartBody => Ok(()),
             MachOperator::Instruction { op, annot } => {
-                self.on_op(sigs, fsigs, func_imports, state, op)?;
+                self.on_op(sigs, fsigs, func_imports, memories, module, state, op)
+                    .map_err(|e| e.at(annot))?;
                 write!(self, ";")?;
                 Ok(())
             }
@@ -789,17 +1807,104 @@ pub trait JsWrite: Write {
                     return Ok(());
                 };
                 let Ok(op) = r.instruction(op.clone()) else {
-                    return Ok(());
+                    return Err(CompileError::unsupported(op).at(annot));
                 };
-                self.on_op(sigs, fsigs, func_imports, state, &op)?;
+                self.on_op(sigs, fsigs, func_imports, memories, module, state, &op)
+                    .map_err(|e| e.at(annot))?;
                 write!(self, ";")?;
                 Ok(())
             }
-            MachOperator::EndBody => write!(self, "}}"),
-            _ => todo!(),
+            MachOperator::Trap { conditional, .. } => {
+                if *conditional {
+                    write!(self, "if({}!==0n){{", pop!(state))?;
+                    self.trap("trap")?;
+                    write!(self, "}}")?;
+                } else {
+                    self.trap("trap")?;
+                }
+                write!(self, ";")?;
+                Ok(())
+            }
+            MachOperator::EndBody => {
+                write!(self, "}}")?;
+                Ok(())
+            }
+            _ => Err(CompileError::unsupported_mach(m)),
         }
     }
 }
 
 /// Blanket implementation of JsWrite for all types that implement Write.
 impl<T: Write + ?Sized> JsWrite for T {}
+
+/// A [`Backend`] that writes JavaScript source to a writer.
+pub struct JsBackend<W> {
+    /// The writer receiving JavaScript source.
+    pub out: W,
+    /// Code generation state.
+    pub state: State,
+}
+
+impl<W: Write> JsBackend<W> {
+    /// Creates a backend writing to `out`.
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            state: State::default(),
+        }
+    }
+}
+
+impl<W: Write, R: Reencode, Annot: ToWasmInfo> Backend<R, Annot> for JsBackend<W> {
+    type Error = CompileError;
+    fn on_module(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        self.out.imports(cx.module, &self.state.imports)?;
+        self.out.memories(&cx.memories)?;
+        let globals = cx.module.global_vars().collect::<Result<Vec<_>, _>>()?;
+        self.out.globals(&globals)?;
+        let tables = cx.module.table_defs().collect::<Result<Vec<_>, _>>()?;
+        self.out.tables(&tables)?;
+        let elems = cx.module.elem_segments().collect::<Result<Vec<_>, _>>()?;
+        self.out.elems(&elems)?;
+        let data = cx.module.data_segments().collect::<Result<Vec<_>, _>>()?;
+        self.out.data(&data)?;
+        self.out.init(&elems, &data)
+    }
+    fn on_end(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        self.out.exports(cx.module.start_func()?, cx.module)
+    }
+    fn on_mach(
+        &mut self,
+        cx: &mut BackendContext<'_, R>,
+        op: &MachOperator<'_, Annot>,
+    ) -> Result<(), Self::Error> {
+        self.out.on_mach(
+            &cx.sigs,
+            cx.fsigs(),
+            cx.func_imports(),
+            &cx.memories,
+            cx.module,
+            &mut self.state,
+            op,
+            cx.rewriter,
+        )
+    }
+}
+
+impl<W: Write + Default + Display, R: Reencode, Annot: ToWasmInfo> ForkBackend<R, Annot>
+    for JsBackend<W>
+{
+    fn fork(&self) -> Self {
+        let mut fork = Self::new(W::default());
+        if self.state.opt().is_some() {
+            fork.state.enable_opt(OptState::default);
+        }
+        fork.state.imports = self.state.imports.clone();
+        fork
+    }
+
+    fn link(&mut self, fork: Self) -> Result<(), Self::Error> {
+        write!(self.out, "{}", fork.out)?;
+        Ok(())
+    }
+}
@@ -10,6 +10,7 @@
 //! This crate enables:
 //! - Converting blitz machine operators back to WASM bytecode
 //! - Applying optimizations before final WASM encoding
+//! - Dropping the dead functions, globals, types and data of a module
 //! - Integration with the wasm-encoder library for output generation
 //!
 //! # Example
@@ -35,6 +36,12 @@ use wax_core::build::InstructionSink;
 /// Provides state tracking for machine instructions during re-encoding.
 pub mod tracker;
 
+/// Whole-module dead code elimination.
+///
+/// Re-encodes modules without the functions, globals, types and data
+/// segments they cannot use.
+pub mod prune;
+
 /// Extension trait for re-encoding machine operators.
 ///
 /// This trait extends the `Reencode` trait with functionality specific to
@@ -0,0 +1,178 @@
+//! Whole-module dead code elimination.
+//!
+//! This module provides the `Pruner` re-encoder, which rewrites a module
+//! without the entities a [`Reach`] finds dead, renumbering every
+//! reference to the live ones. Its code section can be re-encoded from the
+//! original bodies or taken from a [`MachTracker`](crate::tracker::MachTracker)
+//! fed with a stream pruned by
+//! [`passes::prune`](portal_solutions_blitz_common::passes::prune).
+
+use crate::*;
+use portal_solutions_blitz_common::{CompileError, reach::Reach, wasmparser};
+use wasm_encoder::reencode::Error;
+
+/// Re-encodes a module without its dead entities.
+pub struct Pruner<'r> {
+    reach: &'r Reach,
+    code: Option<CodeSection>,
+    /// Number of types seen so far.
+    types: u32,
+}
+
+impl<'r> Pruner<'r> {
+    /// Creates a pruner removing what `reach` finds dead.
+    pub fn new(reach: &'r Reach) -> Self {
+        Pruner {
+            reach,
+            code: None,
+            types: 0,
+        }
+    }
+
+    /// Uses `code` as the code section instead of re-encoding the original
+    /// bodies.
+    ///
+    /// `code` must hold the live functions in order, such as the section
+    /// [`MachTracker::on_code_section`](crate::tracker::MachTracker::on_code_section)
+    /// writes after being fed with a stream pruned by
+    /// [`passes::prune`](portal_solutions_blitz_common::passes::prune).
+    pub fn with_code(mut self, code: CodeSection) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Re-encodes the module in `bytes`, which must be the module `reach`
+    /// was computed for.
+    ///
+    /// The `name` section is dropped, as it names entities by their
+    /// original indices.
+    pub fn module(&mut self, bytes: &[u8]) -> Result<wasm_encoder::Module, CompileError> {
+        let mut module = wasm_encoder::Module::new();
+        self.parse_core_module(&mut module, wasmparser::Parser::new(0), bytes)?;
+        Ok(module)
+    }
+
+    fn live(index: Option<u32>, kind: &'static str, old: u32) -> Result<u32, Error<CompileError>> {
+        index.ok_or(Error::UserError(CompileError::unknown(kind, old)))
+    }
+}
+
+impl Reencode for Pruner<'_> {
+    type Error = CompileError;
+
+    fn function_index(&mut self, func: u32) -> Result<u32, Error<CompileError>> {
+        Self::live(self.reach.func(func), "func", func)
+    }
+
+    fn global_index(&mut self, global: u32) -> Result<u32, Error<CompileError>> {
+        Self::live(self.reach.global(global), "global", global)
+    }
+
+    fn type_index(&mut self, ty: u32) -> Result<u32, Error<CompileError>> {
+        Self::live(self.reach.ty(ty), "type", ty)
+    }
+
+    fn data_index(&mut self, data: u32) -> Result<u32, Error<CompileError>> {
+        Self::live(self.reach.data(data), "data", data)
+    }
+
+    fn data_count(&mut self, count: u32) -> Result<u32, Error<CompileError>> {
+        Ok((0..count).filter(|&d| self.reach.data(d).is_some()).count() as u32)
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut wasm_encoder::TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), Error<CompileError>> {
+        for group in section {
+            let group = group?;
+            let start = self.types;
+            self.types += group.types().len() as u32;
+            let live = (start..self.types)
+                .filter(|&t| self.reach.ty(t).is_some())
+                .count() as u32;
+            if live == self.types - start {
+                self.parse_recursive_type_group(types.ty(), group)?;
+            } else if live != 0 {
+                return Err(Error::UserError(CompileError::feature(
+                    "partially live recursion groups",
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut wasm_encoder::FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), Error<CompileError>> {
+        for (id, ty) in section.into_iter().enumerate() {
+            let ty = ty?;
+            if self.reach.body(id as u32).is_some() {
+                functions.function(self.type_index(ty)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_global_section(
+        &mut self,
+        globals: &mut wasm_encoder::GlobalSection,
+        section: wasmparser::GlobalSectionReader<'_>,
+    ) -> Result<(), Error<CompileError>> {
+        for (id, global) in section.into_iter().enumerate() {
+            let global = global?;
+            let index = id as u32 + self.reach.num_global_imports();
+            if self.reach.global(index).is_some() {
+                self.parse_global(globals, global)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_data_section(
+        &mut self,
+        data: &mut wasm_encoder::DataSection,
+        section: wasmparser::DataSectionReader<'_>,
+    ) -> Result<(), Error<CompileError>> {
+        for (index, datum) in section.into_iter().enumerate() {
+            let datum = datum?;
+            if self.reach.data(index as u32).is_some() {
+                self.parse_data(data, datum)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Result<(), Error<CompileError>> {
+        if let Some(tracked) = self.code.take() {
+            *code = tracked;
+            return Ok(());
+        }
+        for (id, body) in section.into_iter().enumerate() {
+            let body = body?;
+            if self.reach.body(id as u32).is_some() {
+                self.parse_function_body(code, body)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_custom_section(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        section: wasmparser::CustomSectionReader<'_>,
+    ) -> Result<(), Error<CompileError>> {
+        if section.name() == "name" {
+            return Ok(());
+        }
+        module.section(&self.custom_section(section)?);
+        Ok(())
+    }
+}
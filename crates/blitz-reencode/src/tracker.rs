@@ -7,18 +7,31 @@
 use crate::*;
 use alloc::vec::Drain;
 use portal_solutions_blitz_common::dce::{DceStack, dce, dce_instr};
-use wasm_encoder::Function;
+use wasm_encoder::{Function, Instruction};
 use wax_core::build::InstructionSink;
 
 /// Tracks state during machine instruction re-encoding.
 ///
 /// Maintains collections of functions being generated, local variable
 /// declarations, and dead code elimination state.
-#[derive(Default)]
 pub struct MachTracker<S> {
     funcs: Vec<S>,
     locals: Vec<(u32, wasm_encoder::ValType)>,
     dce_stack: DceStack,
+    /// Control depth within the current body, or `None` past its final
+    /// `end`.
+    depth: Option<usize>,
+}
+
+impl<S> Default for MachTracker<S> {
+    fn default() -> Self {
+        MachTracker {
+            funcs: Vec::new(),
+            locals: Vec::new(),
+            dce_stack: DceStack::default(),
+            depth: None,
+        }
+    }
 }
 
 impl<S> MachTracker<S> {
@@ -28,13 +41,38 @@ impl<S> MachTracker<S> {
     pub fn current(&mut self) -> Option<&mut S> {
         return self.funcs.last_mut();
     }
+
+    /// Follows the control depth through `op`, returning whether `op` lies
+    /// past the final `end` of the current body, where the stream may still
+    /// carry a trailing `return`.
+    fn past_end(&mut self, op: &Instruction<'_>) -> bool {
+        let Some(depth) = &mut self.depth else {
+            return true;
+        };
+        match op {
+            Instruction::Block(_)
+            | Instruction::Loop(_)
+            | Instruction::If(_)
+            | Instruction::Try(_)
+            | Instruction::TryTable(..) => *depth += 1,
+            Instruction::End | Instruction::Delegate(_) => match depth.checked_sub(1) {
+                Some(d) => *depth = d,
+                None => self.depth = None,
+            },
+            _ => {}
+        }
+        false
+    }
 }
 
 impl MachTracker<Function> {
     /// Writes all tracked functions to a code section.
     ///
     /// Iterates through all accumulated functions and adds them to the
-    /// provided code section.
+    /// provided code section. After a stream pruned by
+    /// [`passes::prune`](portal_solutions_blitz_common::passes::prune),
+    /// this is the code section of the pruned module, which
+    /// [`Pruner::with_code`](crate::prune::Pruner::with_code) completes.
     ///
     /// # Arguments
     ///
@@ -75,6 +113,7 @@ pub fn do_mach_instruction<E, A, Context, S: InstructionSink<Context, E>>(
         }
         MachOperator::StartBody => {
             state.funcs.push(create(state.locals.drain(..)));
+            state.depth = Some(0);
         }
         MachOperator::EndBody => {
             state.dce_stack = Default::default();
@@ -83,20 +122,50 @@ pub fn do_mach_instruction<E, A, Context, S: InstructionSink<Context, E>>(
             let Some(o) = o.as_ref() else {
                 return Ok(());
             };
+            let op = r.instruction(o.clone())?;
+            if state.past_end(&op) {
+                return Ok(());
+            }
             let mut f = state.funcs.last_mut().unwrap();
             if !dce(&mut state.dce_stack, &o) {
-                f.instruction(ctx, &r.instruction(o.clone())?)
+                f.instruction(ctx, &op)
                     .map_err(|e| wasm_encoder::reencode::Error::UserError(e))?;
             }
         }
         MachOperator::Instruction { op, .. } => {
+            if state.past_end(op) {
+                return Ok(());
+            }
             let mut f = state.funcs.last_mut().unwrap();
             if !dce_instr(&mut state.dce_stack, op) {
                 f.instruction(ctx, op)
                     .map_err(|e| wasm_encoder::reencode::Error::UserError(e))?;
             }
         }
-        _ => todo!(),
+        MachOperator::Trap { conditional, .. } => {
+            if state.depth.is_none() {
+                return Ok(());
+            }
+            let f = state.funcs.last_mut().unwrap();
+            // A conditional trap consumes the condition and traps if it is non-zero.
+            let trap: &[Instruction<'static>] = if *conditional {
+                &[
+                    Instruction::If(wasm_encoder::BlockType::Empty),
+                    Instruction::Unreachable,
+                    Instruction::End,
+                ]
+            } else {
+                &[Instruction::Unreachable]
+            };
+            for op in trap {
+                if !dce_instr(&mut state.dce_stack, op) {
+                    f.instruction(ctx, op)
+                        .map_err(|e| wasm_encoder::reencode::Error::UserError(e))?;
+                }
+            }
+        }
+        // `MachOperator` is `#[non_exhaustive]`; every current variant is handled above.
+        _ => unreachable!(),
     };
     Ok(())
 }
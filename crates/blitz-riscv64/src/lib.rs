@@ -3,6 +3,44 @@
 //! This crate provides functionality to compile WebAssembly bytecode into native
 //! RISC-V 64-bit machine code. The backend targets the RV64 instruction set and
 //! reuses the asm-arch crate for instruction emission.
+//!
+//! Linear memory accesses are bounds-checked against a table of descriptors
+//! provided by the embedder and addressed by `gp`: three 64-bit words per
+//! memory, holding its base address, its length in bytes and the capacity
+//! in bytes reserved at that address. `memory.grow` succeeds while the new
+//! length fits in the capacity, which must be zero-filled past the length.
+//! Globals live in an area provided by the embedder and addressed by `tp`,
+//! global `N` in the 8 bytes at offset `8 * N`; `blitz_init` stores the
+//! initial value of every defined global and must be called first.
+//! Table `N` of a module with `M` memories is described by the pair at
+//! `24 * M + 16 * N` in the same descriptor table, holding the address of its
+//! slots followed by its length.
+//! `blitz_init` also copies every active element and data segment into its
+//! table or memory, jumping to the trap handler when one does not fit.
+//! Passive data segment `N` of a module with `M` memories and `T` tables is
+//! described by the pair at `24 * M + 16 * (T + N)`, holding the address of a
+//! buffer the size of the segment followed by its length; `blitz_init`
+//! fills the buffer and sets the length, which `data.drop` clears.
+//! Finally, `blitz_init` calls the start function, if any.
+//!
+//! Calls to imported functions jump to labels the embedder binds, listed by
+//! [`imports`].
+//! Float operators other than `abs`, `neg` and `copysign`, which are done
+//! on the bits, call routines `blitz_NAME`, where `NAME` is the operator's
+//! [`FloatOp::name`](portal_solutions_blitz_common::float::FloatOp::name).
+//! After the module's own functions, the backend generates every routine
+//! they call from the integer code of
+//! [`softfloat`](portal_solutions_blitz_common::softfloat), so neither the F
+//! nor the D extension is needed. The routines follow the calling
+//! convention of the module's functions, with floats as their bits, and
+//! conversions to integers jump to the trap handler themselves. NaN results
+//! are canonicalised when [`naive::State::nans`] asks for it.
+//! Every MVP integer operator and the sign-extension operators are lowered
+//! with RV64I and `mul` alone: division, `clz`, `ctz` and `popcnt` are done
+//! in software, and division by zero and signed overflow jump to the trap
+//! handler.
+//! Exports have no labels of their own: [`exports`] gives the symbol each is
+//! exposed under and where it lives, so embedders can define the symbols.
 
 #![no_std]
 use core::{
@@ -11,7 +49,13 @@ use core::{
 };
 extern crate alloc;
 
-use portal_solutions_blitz_common::asm::Reg;
+use portal_solutions_blitz_common::{
+    CompileError,
+    asm::Reg,
+    export::{Exported, Symbol},
+    import::{Binding, Imports},
+    module::Module,
+};
 
 pub use portal_solutions_asm_riscv64::*;
 
@@ -25,6 +69,15 @@ pub enum RiscvLabel {
     Indexed { idx: usize },
     /// A function entry point label.
     Func { r#fn: u32 },
+    /// The trap handler, provided by the embedder.
+    Trap,
+    /// The module's initialisation routine.
+    Init,
+    /// An imported function, provided by the embedder.
+    Import { index: u32 },
+    /// The runtime routine for a float operator, generated after the
+    /// module's functions.
+    Builtin { name: &'static str },
 }
 
 impl Display for RiscvLabel {
@@ -32,10 +85,89 @@ impl Display for RiscvLabel {
         match self {
             RiscvLabel::Indexed { idx } => write!(f, "_idx_{idx}"),
             RiscvLabel::Func { r#fn } => write!(f, "f{}", r#fn),
+            RiscvLabel::Trap => write!(f, "blitz_trap"),
+            RiscvLabel::Init => write!(f, "blitz_init"),
+            RiscvLabel::Import { index } => write!(f, "blitz_import_{index}"),
+            RiscvLabel::Builtin { name } => write!(f, "blitz_{name}"),
         }
     }
 }
 
+/// Where an export lives.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportLocation {
+    /// A function, at its entry point.
+    Func(RiscvLabel),
+    /// A table or memory, by the offset of its descriptor from `gp`.
+    Descriptor(i32),
+    /// A global, by the offset of its slot from `tp`.
+    Global(i32),
+}
+
+/// The symbol every export of `module` is exposed under, with where the
+/// entity it names lives.
+pub fn exports<'a>(
+    module: &Module<'a>,
+) -> impl Iterator<Item = Result<(Symbol<'a>, ExportLocation), CompileError>> {
+    let num_func_imports = module.num_func_imports();
+    let num_memories = module.memories.len();
+    module.export_items().map(move |export| {
+        let export = export?;
+        let location = match export.item {
+            Exported::Func(f) => ExportLocation::Func(callee(f, num_func_imports)),
+            Exported::Table(index) => ExportLocation::Descriptor(table_desc(num_memories, index)),
+            Exported::Memory(index) => ExportLocation::Descriptor(memory_desc(index)),
+            Exported::Global(index) => ExportLocation::Global(index as i32 * 8),
+        };
+        Ok((export.symbol(), location))
+    })
+}
+
+/// The label every imported function of `module` is called through, with
+/// what `imports` binds it to.
+///
+/// Embedders define each label as the host function's [`Symbol`], or as
+/// `blitz_trap` for unbound imports.
+pub fn imports<'a>(
+    module: &Module<'a>,
+    imports: &Imports,
+) -> impl Iterator<Item = (RiscvLabel, Binding<'a>)> {
+    module.func_import_items().map(move |import| {
+        (
+            RiscvLabel::Import {
+                index: import.index,
+            },
+            imports.bind(&import),
+        )
+    })
+}
+
+/// Offset from `gp` of the descriptor of memory `index`.
+fn memory_desc(index: u32) -> i32 {
+    index as i32 * 24
+}
+
+/// Offset from `gp` of the descriptor of table `index`, after those of the
+/// module's `num_memories` memories.
+fn table_desc(num_memories: usize, index: u32) -> i32 {
+    memory_desc(num_memories as u32) + index as i32 * 16
+}
+
+/// Offset from `gp` of the descriptor of passive data segment `index`,
+/// after those of the module's `num_memories` memories and `num_tables`
+/// tables.
+fn data_desc(num_memories: usize, num_tables: usize, index: u32) -> i32 {
+    table_desc(num_memories, num_tables as u32) + index as i32 * 16
+}
+
+/// The label calls of function `index` in the function index space jump to.
+fn callee(index: u32, num_func_imports: u32) -> RiscvLabel {
+    match index.checked_sub(num_func_imports) {
+        Some(r#fn) => RiscvLabel::Func { r#fn },
+        None => RiscvLabel::Import { index },
+    }
+}
+
 /// Label trait specialization for RISC-V.
 pub trait Label: portal_solutions_blitz_common::Label<RiscvLabel> {}
 impl<T: portal_solutions_blitz_common::Label<RiscvLabel> + ?Sized> Label for T {}
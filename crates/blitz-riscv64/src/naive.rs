@@ -8,10 +8,21 @@ use portal_solutions_asm_riscv64::ConditionCode;
 use portal_solutions_asm_riscv64::RiscV64Arch;
 use portal_solutions_asm_riscv64::out::Writer;
 
+use portal_solutions_blitz_common::CompileError;
 use portal_solutions_blitz_common::asm::Reg;
+use portal_solutions_blitz_common::backend::{Backend, BackendContext};
+use portal_solutions_blitz_common::coverage::Group;
+use portal_solutions_blitz_common::float::{self, FloatKind, FloatOp, Nans};
+use portal_solutions_blitz_common::global::{self, ConstValue};
+use portal_solutions_blitz_common::memory::{self, DataMode, LinearMemory};
+use portal_solutions_blitz_common::module::Module;
 use portal_solutions_blitz_common::ops::MachOperator;
+use portal_solutions_blitz_common::softfloat::Routines;
+use portal_solutions_blitz_common::table::{self, ElemMode, Table};
+use portal_solutions_blitz_common::typed::BlockArity;
 use portal_solutions_blitz_common::wasm_encoder;
 use portal_solutions_blitz_common::wasm_encoder::reencode::Reencode;
+use portal_solutions_blitz_common::wasmparser::ValType;
 
 use portal_pc_asm_common::types::mem::MemorySize;
 use portal_solutions_asm_riscv64::RegisterClass;
@@ -28,10 +39,19 @@ pub struct State {
     pub local_count: usize,
     pub num_returns: usize,
     pub control_depth: usize,
+    /// Frame-pointer offset of the control slots reserved in `StartBody`.
+    pub control_base: i32,
     pub if_stack: Vec<Endable>,
     pub regalloc: Option<regalloc::RegAlloc<riscv_regalloc::RegKind, 32, Frames>>,
     pub body: u32,
     pub body_labels: alloc::collections::BTreeMap<u32, usize>,
+    /// What NaNs produced by float arithmetic look like.
+    pub nans: Nans,
+    /// The float routines called so far, generated by `on_end`.
+    pub routines: Routines,
+    /// The float operator whose routine is being generated, labelling it
+    /// in place of its function index.
+    pub builtin: Option<&'static str>,
 }
 
 pub struct Frames(pub [[regalloc::RegAllocFrame<riscv_regalloc::RegKind>; 32]; 2]);
@@ -61,10 +81,72 @@ impl regalloc::Length for Frames {
     }
 }
 
+/// An open control structure.
+///
+/// Each one owns the control slot of its nesting depth, which holds the
+/// stack pointer below its parameters.
 pub enum Endable {
-    Block { idx: usize },
-    Loop { idx: usize },
-    If { idx: usize },
+    Block {
+        idx: usize,
+        arity: BlockArity,
+    },
+    Loop {
+        idx: usize,
+        arity: BlockArity,
+    },
+    If {
+        idx: usize,
+        arity: BlockArity,
+        has_else: bool,
+    },
+}
+
+impl Endable {
+    /// The label a branch to this structure jumps to.
+    fn label(&self) -> RiscvLabel {
+        match self {
+            Endable::Block { idx, .. } | Endable::Loop { idx, .. } => {
+                RiscvLabel::Indexed { idx: *idx }
+            }
+            Endable::If { idx, .. } => RiscvLabel::Indexed { idx: *idx + 2 },
+        }
+    }
+
+    /// Number of values a branch to this structure carries.
+    fn carried(&self) -> usize {
+        match self {
+            Endable::Block { arity, .. } | Endable::If { arity, .. } => arity.label(false),
+            Endable::Loop { arity, .. } => arity.label(true),
+        }
+    }
+}
+
+/// The byte at the address in `reg`.
+fn byte_at(reg: Reg) -> MemArgKind<ArgKind> {
+    MemArgKind::Mem {
+        base: ArgKind::Reg {
+            reg,
+            size: MemorySize::_64,
+        },
+        offset: None,
+        disp: 0,
+        size: MemorySize::_8,
+        reg_class: RegisterClass::Gpr,
+    }
+}
+
+/// A 64-bit memory operand at `disp` from `reg`.
+fn at(reg: Reg, disp: i32) -> MemArgKind<ArgKind> {
+    MemArgKind::Mem {
+        base: ArgKind::Reg {
+            reg,
+            size: MemorySize::_64,
+        },
+        offset: None,
+        disp,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    }
 }
 
 pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
@@ -77,49 +159,928 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
     ) -> Result<(), Self::Error>
     where
         Self: Sized,
+        Self::Error: From<CompileError>,
     {
         // flush regalloc before branching
         if let Some(ralloc) = state.regalloc.as_mut() {
             let it = ralloc.flush();
             emit_cmds(self, ctx, arch, it)?;
         }
-        let mut depth = relative_depth as usize;
-        for entry in state.if_stack.iter().rev() {
-            if depth == 0 {
-                match entry {
-                    Endable::Block { idx } => {
-                        let lbl = RiscvLabel::Indexed { idx: *idx };
-                        self.jal_label(
-                            ctx,
-                            arch,
-                            &portal_solutions_blitz_common::asm::Reg(0),
-                            lbl,
-                        )?;
-                        return Ok(());
+        let Some(depth) = state
+            .if_stack
+            .len()
+            .checked_sub(relative_depth as usize + 1)
+        else {
+            return if relative_depth as usize == state.if_stack.len() {
+                Err(CompileError::feature("branch to the function body").into())
+            } else {
+                Err(CompileError::malformed().into())
+            };
+        };
+        let entry = &state.if_stack[depth];
+        let carried = entry.carried() as i32;
+        // Carry the label's values down to the stack pointer recorded on
+        // entry, deepest value first so none is overwritten before it moves.
+        let base = Reg(11);
+        let tmp = Reg(10);
+        let sp = Reg(2);
+        let fp = Reg(8);
+        self.ld(
+            ctx,
+            arch,
+            &base,
+            &at(fp, state.control_base + depth as i32 * 16),
+        )?;
+        for i in (0..carried).rev() {
+            self.ld(ctx, arch, &tmp, &at(sp, i * 8))?;
+            self.sd(ctx, arch, &tmp, &at(base, (i - carried) * 8))?;
+        }
+        self.addi(ctx, arch, &sp, &base, -carried * 8)?;
+        self.jal_label(ctx, arch, &Reg(0), entry.label())?;
+        Ok(())
+    }
+    /// Opens a control structure, recording the stack pointer below its
+    /// `params` in the control slot of its nesting depth. The register
+    /// allocator must be flushed first.
+    fn enter(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        params: usize,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        let depth = state.if_stack.len() as i32 - 1;
+        // `Reg(10)` may still hold an `if` condition.
+        let tmp = Reg(11);
+        self.addi(ctx, arch, &tmp, &Reg(2), params as i32 * 8)?;
+        self.sd(
+            ctx,
+            arch,
+            &tmp,
+            &at(Reg(8), state.control_base + depth * 16),
+        )
+    }
+    /// Generates code for a trap, jumping to the embedder's trap handler.
+    ///
+    /// A conditional trap pops an `i32` and only jumps when it is non-zero.
+    fn trap(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        conditional: bool,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        if !conditional {
+            return self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap);
+        }
+        // flush regalloc so the condition is on the memory stack
+        if let Some(ralloc) = state.regalloc.as_mut() {
+            let it = ralloc.flush();
+            emit_cmds(self, ctx, arch, it)?;
+        }
+        let i = state.label_index;
+        state.label_index += 1;
+        let skip = RiscvLabel::Indexed { idx: i };
+        let tmp = Reg(10);
+        let spmem = MemArgKind::Mem {
+            base: ArgKind::Reg {
+                reg: Reg(2),
+                size: MemorySize::_64,
+            },
+            offset: None,
+            disp: 0,
+            size: MemorySize::_64,
+            reg_class: RegisterClass::Gpr,
+        };
+        self.ld(ctx, arch, &tmp, &spmem)?;
+        self.addi(ctx, arch, &Reg(2), &Reg(2), 8)?;
+        self.bcond_label(ctx, arch, ConditionCode::EQ, &tmp, &Reg(0), skip)?;
+        self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+        self.set_label(ctx, arch, skip)?;
+        Ok(())
+    }
+    /// Leaves in `Reg(10)` the host address of an access of `size` bytes at
+    /// static `offset` into `mem`, jumping to the trap handler when it is out
+    /// of bounds.
+    ///
+    /// The address is read `depth` slots down the memory stack and left
+    /// there, so the register allocator must be flushed first. Clobbers
+    /// `Reg(11)` and `Reg(12)`.
+    fn address(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        mem: &LinearMemory,
+        offset: u64,
+        size: u64,
+        depth: i32,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        let addr = Reg(10);
+        let end = Reg(11);
+        let tmp = Reg(12);
+        // descriptor table: (base, length, capacity), 24 bytes per memory
+        let gp = Reg(3);
+        let desc = crate::memory_desc(mem.index);
+        self.ld(ctx, arch, &addr, &at(Reg(2), depth * 8))?;
+        if !mem.memory64 {
+            self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+            self.and(ctx, arch, &addr, &addr, &tmp)?;
+        }
+        let Some(len) = mem.access_end(offset, size) else {
+            // No address can bring the access in bounds.
+            return self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap);
+        };
+        let i = state.label_index;
+        state.label_index += 2;
+        self.li(ctx, arch, &end, len)?;
+        self.add(ctx, arch, &end, &addr, &end)?;
+        if mem.memory64 {
+            // The end of the access wrapped around.
+            let skip = RiscvLabel::Indexed { idx: i };
+            self.bcond_label(ctx, arch, ConditionCode::GEU, &end, &addr, skip)?;
+            self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+            self.set_label(ctx, arch, skip)?;
+        }
+        let skip = RiscvLabel::Indexed { idx: i + 1 };
+        self.ld(ctx, arch, &tmp, &at(gp, desc + 8))?;
+        self.bcond_label(ctx, arch, ConditionCode::GEU, &tmp, &end, skip)?;
+        self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+        self.set_label(ctx, arch, skip)?;
+        self.ld(ctx, arch, &tmp, &at(gp, desc))?;
+        self.add(ctx, arch, &addr, &addr, &tmp)?;
+        self.li(ctx, arch, &tmp, offset)?;
+        self.add(ctx, arch, &addr, &addr, &tmp)
+    }
+    /// Generates the load or store `access`, with its operands on the
+    /// memory stack.
+    ///
+    /// Narrow loads are zero-extended, then sign-extended when signed;
+    /// narrow stores keep the low bytes of the value. The register
+    /// allocator must be flushed first.
+    fn access(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        memories: &[LinearMemory],
+        access: &memory::Access,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+        Self::Error: From<CompileError>,
+    {
+        let mem = memory::lookup(memories, access.memarg.memory_index)?;
+        let bytes = access.size;
+        let val = Reg(11);
+        let tmp = Reg(12);
+        let value = MemArgKind::Mem {
+            base: ArgKind::Reg {
+                reg: Reg(10),
+                size: MemorySize::_64,
+            },
+            offset: None,
+            disp: 0,
+            size: match bytes {
+                1 => MemorySize::_8,
+                2 => MemorySize::_16,
+                4 => MemorySize::_32,
+                _ => MemorySize::_64,
+            },
+            reg_class: RegisterClass::Gpr,
+        };
+        if access.store {
+            self.address(ctx, arch, state, mem, access.memarg.offset, bytes, 1)?;
+            self.ld(ctx, arch, &val, &at(Reg(2), 0))?;
+            self.sd(ctx, arch, &val, &value)?;
+            return self.addi(ctx, arch, &Reg(2), &Reg(2), 16);
+        }
+        self.address(ctx, arch, state, mem, access.memarg.offset, bytes, 0)?;
+        self.ld(ctx, arch, &val, &value)?;
+        if bytes < 8 {
+            self.li(ctx, arch, &tmp, (1 << (bytes * 8)) - 1)?;
+            self.and(ctx, arch, &val, &val, &tmp)?;
+        }
+        if access.signed {
+            self.sign_extend(ctx, arch, val, tmp, bytes as u32 * 8)?;
+            if !access.wide {
+                self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+                self.and(ctx, arch, &val, &val, &tmp)?;
+            }
+        }
+        self.sd(ctx, arch, &val, &at(Reg(2), 0))
+    }
+    /// Sign-extends the low `bits` bits of `reg`, whose other bits must be
+    /// clear, to 64 bits. Clobbers `scratch`.
+    fn sign_extend(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        reg: Reg,
+        scratch: Reg,
+        bits: u32,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        // `(v ^ sign) - sign`, where `sign` is the top bit.
+        self.li(ctx, arch, &scratch, 1 << (bits - 1))?;
+        self.xor(ctx, arch, &reg, &reg, &scratch)?;
+        self.sub(ctx, arch, &reg, &reg, &scratch)
+    }
+    /// Replaces `reg` with the number of bits set in it. Clobbers `Reg(10)`,
+    /// `Reg(13)` and `Reg(14)`.
+    fn popcnt(&mut self, ctx: &mut Context, arch: RiscV64Arch, reg: Reg) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        let count = Reg(10);
+        let part = Reg(13);
+        let mask = Reg(14);
+        // Count in pairs, nibbles and bytes, then add the bytes up with a
+        // multiplication.
+        self.li(ctx, arch, &count, 1)?;
+        self.srl(ctx, arch, &part, &reg, &count)?;
+        self.li(ctx, arch, &mask, 0x5555_5555_5555_5555)?;
+        self.and(ctx, arch, &part, &part, &mask)?;
+        self.sub(ctx, arch, &reg, &reg, &part)?;
+        self.li(ctx, arch, &count, 2)?;
+        self.srl(ctx, arch, &part, &reg, &count)?;
+        self.li(ctx, arch, &mask, 0x3333_3333_3333_3333)?;
+        self.and(ctx, arch, &part, &part, &mask)?;
+        self.and(ctx, arch, &reg, &reg, &mask)?;
+        self.add(ctx, arch, &reg, &reg, &part)?;
+        self.li(ctx, arch, &count, 4)?;
+        self.srl(ctx, arch, &part, &reg, &count)?;
+        self.add(ctx, arch, &reg, &reg, &part)?;
+        self.li(ctx, arch, &mask, 0x0f0f_0f0f_0f0f_0f0f)?;
+        self.and(ctx, arch, &reg, &reg, &mask)?;
+        self.li(ctx, arch, &mask, 0x0101_0101_0101_0101)?;
+        self.mul(ctx, arch, &reg, &reg, &mask)?;
+        self.li(ctx, arch, &count, 56)?;
+        self.srl(ctx, arch, &reg, &reg, &count)
+    }
+    /// Generates the integer or sign-extension operator `op`, with its
+    /// operands on the memory stack.
+    ///
+    /// i32 values are kept zero-extended. Division, `clz`, `ctz` and
+    /// `popcnt` are done in software, and division by zero and signed
+    /// overflow jump to the trap handler. The register allocator must be
+    /// flushed first.
+    fn int(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        op: &wasm_encoder::Instruction<'_>,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+        Self::Error: From<CompileError>,
+    {
+        use wasm_encoder::Instruction;
+        let sp = Reg(2);
+        let zero = Reg(0);
+        let tmp = Reg(10);
+        let val = Reg(11);
+        let rhs = Reg(12);
+        let narrow = matches!(
+            op,
+            Instruction::I32Eq
+                | Instruction::I32Ne
+                | Instruction::I32LtS
+                | Instruction::I32LtU
+                | Instruction::I32GtS
+                | Instruction::I32GtU
+                | Instruction::I32LeS
+                | Instruction::I32LeU
+                | Instruction::I32GeS
+                | Instruction::I32GeU
+                | Instruction::I32Clz
+                | Instruction::I32Ctz
+                | Instruction::I32Add
+                | Instruction::I32Sub
+                | Instruction::I32Mul
+                | Instruction::I32DivS
+                | Instruction::I32DivU
+                | Instruction::I32RemS
+                | Instruction::I32RemU
+                | Instruction::I32And
+                | Instruction::I32Or
+                | Instruction::I32Xor
+                | Instruction::I32Shl
+                | Instruction::I32ShrS
+                | Instruction::I32ShrU
+                | Instruction::I32Rotl
+                | Instruction::I32Rotr
+                | Instruction::I32WrapI64
+                | Instruction::I64ExtendI32U
+                | Instruction::I32Extend8S
+                | Instruction::I32Extend16S
+        );
+        let bits: u64 = if narrow { 32 } else { 64 };
+        let sign: u64 = 1 << (bits - 1);
+        match op {
+            Instruction::I32Const(_) | Instruction::I64Const(_) => {
+                let value = match op {
+                    Instruction::I32Const(v) => *v as u32 as u64,
+                    Instruction::I64Const(v) => *v as u64,
+                    _ => unreachable!(),
+                };
+                self.addi(ctx, arch, &sp, &sp, -8)?;
+                self.li(ctx, arch, &val, value)?;
+                return self.sd(ctx, arch, &val, &at(sp, 0));
+            }
+            Instruction::I32Eqz
+            | Instruction::I64Eqz
+            | Instruction::I32Clz
+            | Instruction::I64Clz
+            | Instruction::I32Ctz
+            | Instruction::I64Ctz
+            | Instruction::I32Popcnt
+            | Instruction::I64Popcnt
+            | Instruction::I32WrapI64
+            | Instruction::I64ExtendI32U
+            | Instruction::I64ExtendI32S
+            | Instruction::I32Extend8S
+            | Instruction::I32Extend16S
+            | Instruction::I64Extend8S
+            | Instruction::I64Extend16S
+            | Instruction::I64Extend32S => {
+                self.ld(ctx, arch, &val, &at(sp, 0))?;
+                match op {
+                    Instruction::I32Eqz | Instruction::I64Eqz => {
+                        let end = RiscvLabel::Indexed {
+                            idx: state.label_index,
+                        };
+                        state.label_index += 1;
+                        self.li(ctx, arch, &tmp, 1)?;
+                        self.bcond_label(ctx, arch, ConditionCode::EQ, &val, &zero, end)?;
+                        self.li(ctx, arch, &tmp, 0)?;
+                        self.set_label(ctx, arch, end)?;
+                        self.mv(ctx, arch, &val, &tmp)?;
                     }
-                    Endable::Loop { idx } => {
-                        let lbl = RiscvLabel::Indexed { idx: *idx };
-                        self.jal_label(
-                            ctx,
-                            arch,
-                            &portal_solutions_blitz_common::asm::Reg(0),
-                            lbl,
-                        )?;
-                        return Ok(());
+                    // Smear the highest set bit rightwards, then count the
+                    // bits below and including it.
+                    Instruction::I32Clz | Instruction::I64Clz => {
+                        for count in [1, 2, 4, 8, 16, 32] {
+                            self.li(ctx, arch, &tmp, count)?;
+                            self.srl(ctx, arch, &rhs, &val, &tmp)?;
+                            self.or(ctx, arch, &val, &val, &rhs)?;
+                        }
+                        self.popcnt(ctx, arch, val)?;
+                        self.li(ctx, arch, &tmp, bits)?;
+                        self.sub(ctx, arch, &val, &tmp, &val)?;
                     }
-                    Endable::If { idx } => {
-                        let lbl = RiscvLabel::Indexed { idx: *idx + 2 };
-                        self.jal_label(
-                            ctx,
-                            arch,
-                            &portal_solutions_blitz_common::asm::Reg(0),
-                            lbl,
-                        )?;
-                        return Ok(());
+                    // Count the bits below the lowest set one, as those set
+                    // in `(v & -v) - 1`.
+                    Instruction::I32Ctz | Instruction::I64Ctz => {
+                        self.sub(ctx, arch, &rhs, &zero, &val)?;
+                        self.and(ctx, arch, &val, &val, &rhs)?;
+                        self.addi(ctx, arch, &val, &val, -1)?;
+                        if narrow {
+                            self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+                            self.and(ctx, arch, &val, &val, &tmp)?;
+                        }
+                        self.popcnt(ctx, arch, val)?;
+                    }
+                    Instruction::I32Popcnt | Instruction::I64Popcnt => {
+                        self.popcnt(ctx, arch, val)?;
+                    }
+                    Instruction::I32WrapI64 | Instruction::I64ExtendI32U => {
+                        self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+                        self.and(ctx, arch, &val, &val, &tmp)?;
                     }
+                    _ => {
+                        let from = match op {
+                            Instruction::I32Extend8S | Instruction::I64Extend8S => 8,
+                            Instruction::I32Extend16S | Instruction::I64Extend16S => 16,
+                            _ => 32,
+                        };
+                        self.li(ctx, arch, &tmp, (1 << from) - 1)?;
+                        self.and(ctx, arch, &val, &val, &tmp)?;
+                        self.sign_extend(ctx, arch, val, tmp, from)?;
+                        if narrow {
+                            self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+                            self.and(ctx, arch, &val, &val, &tmp)?;
+                        }
+                    }
+                }
+                return self.sd(ctx, arch, &val, &at(sp, 0));
+            }
+            _ => {}
+        }
+        // Binary operators: the right operand in `rhs` and the left in `val`.
+        self.ld(ctx, arch, &rhs, &at(sp, 0))?;
+        self.ld(ctx, arch, &val, &at(sp, 8))?;
+        self.addi(ctx, arch, &sp, &sp, 8)?;
+        // RV64 shifts only mask the count to 6 bits.
+        if narrow
+            && matches!(
+                op,
+                Instruction::I32Shl
+                    | Instruction::I32ShrS
+                    | Instruction::I32ShrU
+                    | Instruction::I32Rotl
+                    | Instruction::I32Rotr
+            )
+        {
+            self.li(ctx, arch, &tmp, 31)?;
+            self.and(ctx, arch, &rhs, &rhs, &tmp)?;
+        }
+        match op {
+            Instruction::I32Add | Instruction::I64Add => self.add(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32Sub | Instruction::I64Sub => self.sub(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32Mul | Instruction::I64Mul => self.mul(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32And | Instruction::I64And => self.and(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32Or | Instruction::I64Or => self.or(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32Xor | Instruction::I64Xor => self.xor(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32Shl | Instruction::I64Shl => self.sll(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32ShrU | Instruction::I64ShrU => self.srl(ctx, arch, &val, &val, &rhs)?,
+            Instruction::I32ShrS | Instruction::I64ShrS => {
+                if narrow {
+                    self.sign_extend(ctx, arch, val, tmp, 32)?;
+                }
+                self.sra(ctx, arch, &val, &val, &rhs)?;
+            }
+            // Shift one way by `n` and the other by `bits - n`; a count of
+            // `bits` shifts a zero-extended i32 out entirely and leaves an
+            // i64 in place, either of which is right.
+            Instruction::I32Rotl | Instruction::I64Rotl => {
+                self.li(ctx, arch, &tmp, bits)?;
+                self.sub(ctx, arch, &tmp, &tmp, &rhs)?;
+                self.sll(ctx, arch, &Reg(13), &val, &rhs)?;
+                self.srl(ctx, arch, &val, &val, &tmp)?;
+                self.or(ctx, arch, &val, &val, &Reg(13))?;
+            }
+            Instruction::I32Rotr | Instruction::I64Rotr => {
+                self.li(ctx, arch, &tmp, bits)?;
+                self.sub(ctx, arch, &tmp, &tmp, &rhs)?;
+                self.srl(ctx, arch, &Reg(13), &val, &rhs)?;
+                self.sll(ctx, arch, &val, &val, &tmp)?;
+                self.or(ctx, arch, &val, &val, &Reg(13))?;
+            }
+            Instruction::I32DivU
+            | Instruction::I64DivU
+            | Instruction::I32RemU
+            | Instruction::I64RemU
+            | Instruction::I32DivS
+            | Instruction::I64DivS
+            | Instruction::I32RemS
+            | Instruction::I64RemS => {
+                let signed = matches!(
+                    op,
+                    Instruction::I32DivS
+                        | Instruction::I64DivS
+                        | Instruction::I32RemS
+                        | Instruction::I64RemS
+                );
+                let rem = matches!(
+                    op,
+                    Instruction::I32RemU
+                        | Instruction::I64RemU
+                        | Instruction::I32RemS
+                        | Instruction::I64RemS
+                );
+                let quotient = Reg(13);
+                let remainder = Reg(14);
+                let count = Reg(15);
+                let carry = Reg(16);
+                let top = Reg(17);
+                let quotient_sign = Reg(5);
+                let remainder_sign = Reg(6);
+                let i = state.label_index;
+                state.label_index += 5;
+                self.bcond_label(
+                    ctx,
+                    arch,
+                    ConditionCode::NE,
+                    &rhs,
+                    &zero,
+                    RiscvLabel::Indexed { idx: i },
+                )?;
+                self.jal_label(ctx, arch, &zero, RiscvLabel::Trap)?;
+                self.set_label(ctx, arch, RiscvLabel::Indexed { idx: i })?;
+                self.li(ctx, arch, &top, 63)?;
+                if signed {
+                    if narrow {
+                        self.sign_extend(ctx, arch, val, tmp, 32)?;
+                        self.sign_extend(ctx, arch, rhs, tmp, 32)?;
+                    }
+                    if !rem {
+                        // Trap when dividing the minimum by -1.
+                        let skip = RiscvLabel::Indexed { idx: i + 1 };
+                        self.li(ctx, arch, &tmp, u64::MAX)?;
+                        self.bcond_label(ctx, arch, ConditionCode::NE, &rhs, &tmp, skip)?;
+                        self.li(ctx, arch, &tmp, 0u64.wrapping_sub(sign))?;
+                        self.bcond_label(ctx, arch, ConditionCode::NE, &val, &tmp, skip)?;
+                        self.jal_label(ctx, arch, &zero, RiscvLabel::Trap)?;
+                        self.set_label(ctx, arch, skip)?;
+                    }
+                    // Divide the magnitudes, remembering the signs of the
+                    // quotient and the remainder.
+                    self.xor(ctx, arch, &quotient_sign, &val, &rhs)?;
+                    self.mv(ctx, arch, &remainder_sign, &val)?;
+                    for reg in [val, rhs] {
+                        self.sra(ctx, arch, &tmp, &reg, &top)?;
+                        self.xor(ctx, arch, &reg, &reg, &tmp)?;
+                        self.sub(ctx, arch, &reg, &reg, &tmp)?;
+                    }
+                }
+                // Restoring division, one bit of the dividend at a time.
+                // The partial remainder can carry out of 64 bits only when
+                // the divisor is above 2^63, in which case it must be
+                // subtracted.
+                let head = RiscvLabel::Indexed { idx: i + 2 };
+                let subtract = RiscvLabel::Indexed { idx: i + 3 };
+                let next = RiscvLabel::Indexed { idx: i + 4 };
+                self.li(ctx, arch, &quotient, 0)?;
+                self.li(ctx, arch, &remainder, 0)?;
+                self.li(ctx, arch, &count, 64)?;
+                self.set_label(ctx, arch, head)?;
+                self.srl(ctx, arch, &carry, &remainder, &top)?;
+                self.add(ctx, arch, &remainder, &remainder, &remainder)?;
+                self.srl(ctx, arch, &tmp, &val, &top)?;
+                self.or(ctx, arch, &remainder, &remainder, &tmp)?;
+                self.add(ctx, arch, &val, &val, &val)?;
+                self.add(ctx, arch, &quotient, &quotient, &quotient)?;
+                self.bcond_label(ctx, arch, ConditionCode::NE, &carry, &zero, subtract)?;
+                self.bcond_label(ctx, arch, ConditionCode::LTU, &remainder, &rhs, next)?;
+                self.set_label(ctx, arch, subtract)?;
+                self.sub(ctx, arch, &remainder, &remainder, &rhs)?;
+                self.addi(ctx, arch, &quotient, &quotient, 1)?;
+                self.set_label(ctx, arch, next)?;
+                self.addi(ctx, arch, &count, &count, -1)?;
+                self.bcond_label(ctx, arch, ConditionCode::NE, &count, &zero, head)?;
+                let (result, result_sign) = if rem {
+                    (remainder, remainder_sign)
+                } else {
+                    (quotient, quotient_sign)
+                };
+                self.mv(ctx, arch, &val, &result)?;
+                if signed {
+                    self.sra(ctx, arch, &tmp, &result_sign, &top)?;
+                    self.xor(ctx, arch, &val, &val, &tmp)?;
+                    self.sub(ctx, arch, &val, &val, &tmp)?;
+                }
+            }
+            _ => {
+                // Comparisons set the result when the branch is taken.
+                let (signed, cond, set) = match op {
+                    Instruction::I32Eq | Instruction::I64Eq => (false, ConditionCode::EQ, 1),
+                    Instruction::I32Ne | Instruction::I64Ne => (false, ConditionCode::NE, 1),
+                    Instruction::I32LtS | Instruction::I64LtS => (true, ConditionCode::LT, 1),
+                    Instruction::I32LtU | Instruction::I64LtU => (false, ConditionCode::LTU, 1),
+                    Instruction::I32GtS | Instruction::I64GtS => (true, ConditionCode::GT, 1),
+                    Instruction::I32GtU | Instruction::I64GtU => (false, ConditionCode::GTU, 1),
+                    Instruction::I32LeS | Instruction::I64LeS => (true, ConditionCode::GT, 0),
+                    Instruction::I32LeU | Instruction::I64LeU => (false, ConditionCode::GTU, 0),
+                    Instruction::I32GeS | Instruction::I64GeS => (true, ConditionCode::LT, 0),
+                    Instruction::I32GeU | Instruction::I64GeU => (false, ConditionCode::LTU, 0),
+                    _ => return Err(CompileError::unsupported(op).into()),
+                };
+                if signed && narrow {
+                    self.sign_extend(ctx, arch, val, tmp, 32)?;
+                    self.sign_extend(ctx, arch, rhs, tmp, 32)?;
+                }
+                let end = RiscvLabel::Indexed {
+                    idx: state.label_index,
+                };
+                state.label_index += 1;
+                self.li(ctx, arch, &tmp, set)?;
+                self.bcond_label(ctx, arch, cond, &val, &rhs, end)?;
+                self.li(ctx, arch, &tmp, 1 - set)?;
+                self.set_label(ctx, arch, end)?;
+                self.mv(ctx, arch, &val, &tmp)?;
+            }
+        }
+        if narrow {
+            self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+            self.and(ctx, arch, &val, &val, &tmp)?;
+        }
+        self.sd(ctx, arch, &val, &at(sp, 0))
+    }
+    /// Generates the float operator `float`, with its operands on the
+    /// memory stack.
+    ///
+    /// Sign operations are done on the bits; every other operator calls the
+    /// routine `blitz_NAME` for its [`FloatOp::name`], recorded in `state`
+    /// for `on_end` to generate, whose result is then canonicalised when
+    /// `state` asks for it. The register allocator must be flushed first.
+    fn float(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        float: &FloatOp,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        let sp = Reg(2);
+        let val = Reg(11);
+        let tmp = Reg(12);
+        let wide = float.params[0] == ValType::F64;
+        let sign: u64 = if wide { 1 << 63 } else { 1 << 31 };
+        if float.kind == FloatKind::Sign {
+            self.ld(ctx, arch, &val, &at(sp, 0))?;
+            match float.params.len() {
+                // copysign: the sign comes from the top operand.
+                2 => {
+                    self.li(ctx, arch, &tmp, sign)?;
+                    self.and(ctx, arch, &val, &val, &tmp)?;
+                    self.ld(ctx, arch, &Reg(13), &at(sp, 8))?;
+                    self.li(ctx, arch, &tmp, sign - 1)?;
+                    self.and(ctx, arch, &Reg(13), &Reg(13), &tmp)?;
+                    self.or(ctx, arch, &val, &val, &Reg(13))?;
+                    self.addi(ctx, arch, &sp, &sp, 8)?;
+                }
+                _ if float.name.ends_with("abs") => {
+                    self.li(ctx, arch, &tmp, sign - 1)?;
+                    self.and(ctx, arch, &val, &val, &tmp)?;
+                }
+                _ => {
+                    self.li(ctx, arch, &tmp, sign)?;
+                    self.xor(ctx, arch, &val, &val, &tmp)?;
+                }
+            }
+            return self.sd(ctx, arch, &val, &at(sp, 0));
+        }
+        state.routines.call(float);
+        self.jal_label(
+            ctx,
+            arch,
+            &Reg(10),
+            RiscvLabel::Builtin { name: float.name },
+        )?;
+        self.call(ctx, arch, &Reg(10))?;
+        if !float.canonicalises(state.nans) {
+            return Ok(());
+        }
+        // A float is a NaN when its bits without the sign are above those of
+        // infinity.
+        let (abs, infinity, nan) = if float.result == ValType::F64 {
+            (
+                u64::MAX >> 1,
+                f64::INFINITY.to_bits(),
+                float::CANONICAL_NAN_F64,
+            )
+        } else {
+            (
+                u32::MAX as u64 >> 1,
+                f32::INFINITY.to_bits() as u64,
+                float::CANONICAL_NAN_F32 as u64,
+            )
+        };
+        let skip = RiscvLabel::Indexed {
+            idx: state.label_index,
+        };
+        state.label_index += 1;
+        self.ld(ctx, arch, &val, &at(sp, 0))?;
+        self.li(ctx, arch, &tmp, abs)?;
+        self.and(ctx, arch, &tmp, &val, &tmp)?;
+        self.li(ctx, arch, &Reg(13), infinity)?;
+        self.bcond_label(ctx, arch, ConditionCode::GEU, &Reg(13), &tmp, skip)?;
+        self.li(ctx, arch, &val, nan)?;
+        self.sd(ctx, arch, &val, &at(sp, 0))?;
+        self.set_label(ctx, arch, skip)
+    }
+    /// Leaves in `Reg(10)` the host address of the slot of `table` indexed
+    /// by the value `depth` slots down the memory stack, jumping to the trap
+    /// handler when it is out of bounds.
+    ///
+    /// The index is left on the stack, so the register allocator must be
+    /// flushed first. `num_memories` locates the table's descriptor after
+    /// those of the memories. Clobbers `Reg(11)` and `Reg(12)`.
+    fn table_slot(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        table: &Table,
+        num_memories: usize,
+        depth: i32,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        let idx = Reg(10);
+        let len = Reg(11);
+        let tmp = Reg(12);
+        let gp = Reg(3);
+        let desc = crate::table_desc(num_memories, table.index);
+        self.ld(ctx, arch, &idx, &at(Reg(2), depth * 8))?;
+        if !table.table64 {
+            self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+            self.and(ctx, arch, &idx, &idx, &tmp)?;
+        }
+        let skip = RiscvLabel::Indexed {
+            idx: state.label_index,
+        };
+        state.label_index += 1;
+        self.ld(ctx, arch, &len, &at(gp, desc + 8))?;
+        self.bcond_label(ctx, arch, ConditionCode::LTU, &idx, &len, skip)?;
+        self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+        self.set_label(ctx, arch, skip)?;
+        // Scale the index by the size of a slot.
+        for _ in 0..3 {
+            self.add(ctx, arch, &idx, &idx, &idx)?;
+        }
+        self.ld(ctx, arch, &tmp, &at(gp, desc))?;
+        self.add(ctx, arch, &idx, &idx, &tmp)
+    }
+    /// Generates `blitz_init`, which stores the initial value of every
+    /// defined global of `module` into the globals area, copies its active
+    /// element and data segments into their tables and memories and its
+    /// passive data segments into their buffers, then calls its start
+    /// function.
+    fn init(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        module: &Module<'_>,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+        Self::Error: From<CompileError>,
+    {
+        let tmp = Reg(10);
+        let tp = Reg(4);
+        let gp = Reg(3);
+        self.set_label(ctx, arch, RiscvLabel::Init)?;
+        for global in module.global_vars() {
+            let global = global?;
+            let Some(init) = global.init else {
+                continue;
+            };
+            self.const_value(ctx, arch, &tmp, init)?;
+            self.sd(ctx, arch, &tmp, &at(tp, global.index as i32 * 8))?;
+        }
+        let num_memories = module.linear_memories().count();
+        for elem in module.elem_segments() {
+            let elem = elem?;
+            let ElemMode::Active { table, offset } = elem.mode else {
+                continue;
+            };
+            let table = module.table(table)?;
+            let desc = crate::table_desc(num_memories, table.index);
+            let len = elem.items.len() as u64;
+            self.segment_base(ctx, arch, state, offset, table.table64, len, desc, 3)?;
+            for item in &elem.items {
+                self.const_value(ctx, arch, &Reg(11), *item)?;
+                self.sd(ctx, arch, &Reg(11), &at(tmp, 0))?;
+                self.addi(ctx, arch, &tmp, &tmp, 8)?;
+            }
+        }
+        for segment in module.data_segments() {
+            let segment = segment?;
+            let len = segment.bytes.len() as u64;
+            match segment.mode {
+                DataMode::Active { memory, offset } => {
+                    let mem = module
+                        .memory(memory)
+                        .ok_or_else(|| CompileError::unknown("memory", memory))?;
+                    let desc = crate::memory_desc(memory);
+                    self.segment_base(ctx, arch, state, offset, mem.memory64, len, desc, 0)?;
+                    self.store_bytes(ctx, arch, segment.bytes)?;
+                }
+                DataMode::Passive => {
+                    let desc = crate::data_desc(num_memories, module.tables.len(), segment.index);
+                    self.ld(ctx, arch, &tmp, &at(gp, desc))?;
+                    self.store_bytes(ctx, arch, segment.bytes)?;
+                    self.li(ctx, arch, &Reg(11), len)?;
+                    self.sd(ctx, arch, &Reg(11), &at(gp, desc + 8))?;
                 }
             }
-            depth -= 1;
+        }
+        if let Some(start) = module.start_func()? {
+            let callee = crate::callee(start, module.num_func_imports());
+            // The call clobbers the return address.
+            push(self, ctx, arch, Reg(1))?;
+            self.jal_label(ctx, arch, &Reg(1), callee)?;
+            pop(self, ctx, arch, &Reg(1))?;
+        }
+        self.ret(ctx, arch)
+    }
+    /// Loads the constant `value` into `reg`, reading globals from the
+    /// globals area.
+    fn const_value(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        reg: &Reg,
+        value: ConstValue,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+        Self::Error: From<CompileError>,
+    {
+        let tp = Reg(4);
+        match value {
+            ConstValue::I32(v) => self.li(ctx, arch, reg, v as u32 as u64),
+            ConstValue::I64(v) => self.li(ctx, arch, reg, v as u64),
+            ConstValue::F32(bits) => self.li(ctx, arch, reg, bits as u64),
+            ConstValue::F64(bits) => self.li(ctx, arch, reg, bits),
+            ConstValue::RefNull => self.li(ctx, arch, reg, 0),
+            ConstValue::RefFunc(_) => Err(CompileError::feature("function references").into()),
+            ConstValue::GlobalGet(g) => self.ld(ctx, arch, reg, &at(tp, g as i32 * 8)),
+        }
+    }
+    /// Leaves in `Reg(10)` the host address of the `len` elements at
+    /// `offset` into the table or memory whose descriptor is at `desc` in
+    /// the descriptor table, each `1 << shift` bytes, jumping to the trap
+    /// handler when they do not fit.
+    ///
+    /// Offsets into 32-bit tables and memories are truncated to 32 bits.
+    /// Clobbers `Reg(11)` and `Reg(12)`.
+    #[allow(clippy::too_many_arguments)]
+    fn segment_base(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        state: &mut State,
+        offset: ConstValue,
+        wide: bool,
+        len: u64,
+        desc: i32,
+        shift: usize,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+        Self::Error: From<CompileError>,
+    {
+        let addr = Reg(10);
+        let end = Reg(11);
+        let tmp = Reg(12);
+        let gp = Reg(3);
+        match offset {
+            ConstValue::I32(_) | ConstValue::I64(_) | ConstValue::GlobalGet(_) => {
+                self.const_value(ctx, arch, &addr, offset)?
+            }
+            _ => return Err(CompileError::malformed().into()),
+        }
+        if !wide {
+            self.li(ctx, arch, &tmp, 0xffff_ffff)?;
+            self.and(ctx, arch, &addr, &addr, &tmp)?;
+        }
+        let i = state.label_index;
+        state.label_index += 2;
+        self.li(ctx, arch, &end, len)?;
+        self.add(ctx, arch, &end, &addr, &end)?;
+        if wide {
+            // The end of the segment wrapped around.
+            let skip = RiscvLabel::Indexed { idx: i };
+            self.bcond_label(ctx, arch, ConditionCode::GEU, &end, &addr, skip)?;
+            self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+            self.set_label(ctx, arch, skip)?;
+        }
+        let skip = RiscvLabel::Indexed { idx: i + 1 };
+        self.ld(ctx, arch, &tmp, &at(gp, desc + 8))?;
+        self.bcond_label(ctx, arch, ConditionCode::GEU, &tmp, &end, skip)?;
+        self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+        self.set_label(ctx, arch, skip)?;
+        // Scale the offset by the size of an element.
+        for _ in 0..shift {
+            self.add(ctx, arch, &addr, &addr, &addr)?;
+        }
+        self.ld(ctx, arch, &tmp, &at(gp, desc))?;
+        self.add(ctx, arch, &addr, &addr, &tmp)
+    }
+    /// Stores `bytes` at the host address in `Reg(10)`, eight at a time,
+    /// advancing it past them. Clobbers `Reg(11)`.
+    fn store_bytes(
+        &mut self,
+        ctx: &mut Context,
+        arch: RiscV64Arch,
+        bytes: &[u8],
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        let addr = Reg(10);
+        let val = Reg(11);
+        let chunks = bytes.chunks_exact(8);
+        let tail = chunks.remainder();
+        for chunk in chunks {
+            let value = u64::from_le_bytes(chunk.try_into().unwrap());
+            self.li(ctx, arch, &val, value)?;
+            self.sd(ctx, arch, &val, &at(addr, 0))?;
+            self.addi(ctx, arch, &addr, &addr, 8)?;
+        }
+        for byte in tail {
+            self.li(ctx, arch, &val, *byte as u64)?;
+            self.sd(ctx, arch, &val, &byte_at(addr))?;
+            self.addi(ctx, arch, &addr, &addr, 1)?;
         }
         Ok(())
     }
@@ -128,6 +1089,9 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
         ctx: &mut Context,
         arch: RiscV64Arch,
         state: &mut State,
+        sigs: &[wasm_encoder::FuncType],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         func_imports: &[(&str, &str)],
         op: &portal_solutions_blitz_common::wasm_encoder::Instruction<'_>,
         _rewriter: &mut (dyn Reencode<Error = E> + '_),
@@ -135,7 +1099,7 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
     ) -> Result<(), Self::Error>
     where
         wasm_encoder::reencode::Error<E>: Into<Self::Error>,
-        Self::Error: From<core::fmt::Error>,
+        Self::Error: From<core::fmt::Error> + From<CompileError>,
         Self: Sized,
     {
         if target != state.body {
@@ -156,6 +1120,22 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
             }
         }
         use portal_solutions_blitz_common::wasm_encoder::Instruction;
+        if let Some(access) = memory::Access::of(op) {
+            // bounds checks need the address on the memory stack
+            if let Some(ralloc) = state.regalloc.as_mut() {
+                let it = ralloc.flush();
+                emit_cmds(self, ctx, arch, it)?;
+            }
+            return self.access(ctx, arch, state, memories, &access);
+        }
+        if let Some(float) = FloatOp::of(op) {
+            // runtime routines take their operands on the memory stack
+            if let Some(ralloc) = state.regalloc.as_mut() {
+                let it = ralloc.flush();
+                emit_cmds(self, ctx, arch, it)?;
+            }
+            return self.float(ctx, arch, state, &float);
+        }
         match op {
             Instruction::I32Const(v) => {
                 // Use regalloc to push an int value
@@ -175,7 +1155,7 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                     .map_err(|_| core::fmt::Error)?;
                 emit_cmds(self, ctx, arch, cmds)?;
                 let phys = Reg(ridx as u8);
-                self.li(ctx, arch, &phys, *v as u64)?;
+                self.li(ctx, arch, &phys, *v as u32 as u64)?;
             }
             Instruction::I64Const(v) => {
                 if state.regalloc.is_none() {
@@ -196,6 +1176,45 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 let phys = Reg(ridx as u8);
                 self.li(ctx, arch, &phys, *v as u64)?;
             }
+            Instruction::F32Const(v) => {
+                // floats are kept as their bits
+                if state.regalloc.is_none() {
+                    let r = riscv_regalloc::init_regalloc::<32>(arch);
+                    let new = regalloc::RegAlloc {
+                        frames: Frames(r.frames),
+                        tos: r.tos,
+                    };
+                    state.regalloc = Some(new);
+                }
+                let (ridx, cmds) = state
+                    .regalloc
+                    .as_mut()
+                    .unwrap()
+                    .push(riscv_regalloc::RegKind::Int)
+                    .map_err(|_| core::fmt::Error)?;
+                emit_cmds(self, ctx, arch, cmds)?;
+                let phys = Reg(ridx as u8);
+                self.li(ctx, arch, &phys, v.bits() as u64)?;
+            }
+            Instruction::F64Const(v) => {
+                if state.regalloc.is_none() {
+                    let r = riscv_regalloc::init_regalloc::<32>(arch);
+                    let new = regalloc::RegAlloc {
+                        frames: Frames(r.frames),
+                        tos: r.tos,
+                    };
+                    state.regalloc = Some(new);
+                }
+                let (ridx, cmds) = state
+                    .regalloc
+                    .as_mut()
+                    .unwrap()
+                    .push(riscv_regalloc::RegKind::Int)
+                    .map_err(|_| core::fmt::Error)?;
+                emit_cmds(self, ctx, arch, cmds)?;
+                let phys = Reg(ridx as u8);
+                self.li(ctx, arch, &phys, v.bits())?;
+            }
             Instruction::LocalGet(local_index) => {
                 if state.regalloc.is_none() {
                     let r = riscv_regalloc::init_regalloc::<32>(arch);
@@ -276,89 +1295,385 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 };
                 self.sd(ctx, arch, &tmp, &spmem2)?;
             }
-            Instruction::I64Load(memarg) => {
-                if state.regalloc.is_none() {
-                    let r = riscv_regalloc::init_regalloc::<32>(arch);
-                    let new = regalloc::RegAlloc {
-                        frames: Frames(r.frames),
-                        tos: r.tos,
-                    };
-                    state.regalloc = Some(new);
+            Instruction::GlobalGet(global_index) | Instruction::GlobalSet(global_index) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
                 }
-                // pop address
-                let (addr_t, cmds1) = state
-                    .regalloc
-                    .as_mut()
-                    .unwrap()
-                    .pop(riscv_regalloc::RegKind::Int);
-                emit_cmds(self, ctx, arch, cmds1)?;
-                // allocate dest reg for loaded value
-                let (didx, cmds2) = state
-                    .regalloc
-                    .as_mut()
-                    .unwrap()
-                    .push(riscv_regalloc::RegKind::Int)
-                    .map_err(|_| core::fmt::Error)?;
-                emit_cmds(self, ctx, arch, cmds2)?;
-                let addr = Reg(addr_t.reg);
-                let dest = Reg(didx as u8);
-                let mem = MemArgKind::Mem {
-                    base: ArgKind::Reg {
-                        reg: addr,
-                        size: MemorySize::_64,
-                    },
-                    offset: None,
-                    disp: memarg.offset as i32,
-                    size: MemorySize::_64,
-                    reg_class: RegisterClass::Gpr,
+                global::lookup(&module.globals, *global_index)?;
+                let tmp = Reg(10);
+                let sp = Reg(2);
+                let slot = at(Reg(4), *global_index as i32 * 8);
+                if let Instruction::GlobalGet(_) = op {
+                    self.ld(ctx, arch, &tmp, &slot)?;
+                    self.addi(ctx, arch, &sp, &sp, -8)?;
+                    self.sd(ctx, arch, &tmp, &at(sp, 0))?;
+                } else {
+                    self.ld(ctx, arch, &tmp, &at(sp, 0))?;
+                    self.addi(ctx, arch, &sp, &sp, 8)?;
+                    self.sd(ctx, arch, &tmp, &slot)?;
+                }
+            }
+            Instruction::RefNull(_) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                push(self, ctx, arch, Reg(0))?;
+            }
+            Instruction::RefIsNull => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let val = Reg(10);
+                let tmp = Reg(11);
+                let skip = RiscvLabel::Indexed {
+                    idx: state.label_index,
                 };
-                self.ld(ctx, arch, &dest, &mem)?;
-                // push the dest register as the value
-                let mut it = state
-                    .regalloc
-                    .as_mut()
-                    .unwrap()
-                    .push_existing(regalloc::Target {
-                        reg: didx as u8,
-                        kind: riscv_regalloc::RegKind::Int,
-                    });
-                emit_cmds(self, ctx, arch, it)?;
+                state.label_index += 1;
+                pop(self, ctx, arch, &val)?;
+                self.li(ctx, arch, &tmp, 1)?;
+                self.bcond_label(ctx, arch, ConditionCode::EQ, &val, &Reg(0), skip)?;
+                self.li(ctx, arch, &tmp, 0)?;
+                self.set_label(ctx, arch, skip)?;
+                push(self, ctx, arch, tmp)?;
             }
-            Instruction::I64Store(memarg) => {
-                if state.regalloc.is_none() {
-                    let r = riscv_regalloc::init_regalloc::<32>(arch);
-                    let new = regalloc::RegAlloc {
-                        frames: Frames(r.frames),
-                        tos: r.tos,
-                    };
-                    state.regalloc = Some(new);
+            Instruction::TableGet(table) => {
+                // table accesses need the index on the memory stack
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
                 }
-                // pop value then pop address
-                let (val_t, cmds1) = state
-                    .regalloc
-                    .as_mut()
-                    .unwrap()
-                    .pop(riscv_regalloc::RegKind::Int);
-                emit_cmds(self, ctx, arch, cmds1)?;
-                let (addr_t, cmds2) = state
-                    .regalloc
-                    .as_mut()
-                    .unwrap()
-                    .pop(riscv_regalloc::RegKind::Int);
-                emit_cmds(self, ctx, arch, cmds2)?;
-                let val = Reg(val_t.reg);
-                let addr = Reg(addr_t.reg);
-                let mem = MemArgKind::Mem {
-                    base: ArgKind::Reg {
-                        reg: addr,
-                        size: MemorySize::_64,
-                    },
-                    offset: None,
-                    disp: memarg.offset as i32,
-                    size: MemorySize::_64,
-                    reg_class: RegisterClass::Gpr,
+                let table = module.table(*table)?;
+                let val = Reg(10);
+                self.table_slot(ctx, arch, state, &table, memories.len(), 0)?;
+                self.ld(ctx, arch, &val, &at(val, 0))?;
+                self.sd(ctx, arch, &val, &at(Reg(2), 0))?;
+            }
+            Instruction::TableSet(table) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let table = module.table(*table)?;
+                let slot = Reg(10);
+                let tmp = Reg(11);
+                let sp = Reg(2);
+                self.table_slot(ctx, arch, state, &table, memories.len(), 1)?;
+                pop(self, ctx, arch, &tmp)?;
+                self.addi(ctx, arch, &sp, &sp, 8)?;
+                self.sd(ctx, arch, &tmp, &at(slot, 0))?;
+            }
+            Instruction::TableSize(table) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                table::lookup(&module.tables, *table)?;
+                let len = Reg(10);
+                let desc = crate::table_desc(memories.len(), *table);
+                self.ld(ctx, arch, &len, &at(Reg(3), desc + 8))?;
+                push(self, ctx, arch, len)?;
+            }
+            Instruction::CallIndirect { .. } | Instruction::RefFunc(_) => {
+                return Err(CompileError::feature("function references").into());
+            }
+            Instruction::TableGrow(_)
+            | Instruction::TableFill(_)
+            | Instruction::TableCopy { .. }
+            | Instruction::TableInit { .. }
+            | Instruction::ElemDrop(_) => {
+                return Err(CompileError::feature("bulk table operations").into());
+            }
+            Instruction::MemoryInit { mem, data_index } => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let mem = memory::lookup(memories, *mem)?;
+                let segment = module.data_segment(*data_index)?;
+                let a = Reg(10);
+                let b = Reg(11);
+                let c = Reg(12);
+                let sp = Reg(2);
+                let gp = Reg(3);
+                let mdesc = crate::memory_desc(mem.index);
+                let ddesc = crate::data_desc(memories.len(), module.tables.len(), *data_index);
+                let i = state.label_index;
+                state.label_index += 5;
+                // The source range must lie in the segment; an active
+                // segment has already been dropped.
+                self.ld(ctx, arch, &a, &at(sp, 8))?;
+                self.li(ctx, arch, &c, 0xffff_ffff)?;
+                self.and(ctx, arch, &a, &a, &c)?;
+                self.sd(ctx, arch, &a, &at(sp, 8))?;
+                self.ld(ctx, arch, &b, &at(sp, 0))?;
+                self.and(ctx, arch, &b, &b, &c)?;
+                self.sd(ctx, arch, &b, &at(sp, 0))?;
+                self.add(ctx, arch, &b, &a, &b)?;
+                let skip = RiscvLabel::Indexed { idx: i };
+                if let DataMode::Passive = segment.mode {
+                    self.ld(ctx, arch, &c, &at(gp, ddesc + 8))?;
+                    self.bcond_label(ctx, arch, ConditionCode::GEU, &c, &b, skip)?;
+                } else {
+                    self.bcond_label(ctx, arch, ConditionCode::EQ, &b, &Reg(0), skip)?;
+                }
+                self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                self.set_label(ctx, arch, skip)?;
+                // The destination range must lie in the memory.
+                self.ld(ctx, arch, &a, &at(sp, 16))?;
+                if !mem.memory64 {
+                    self.li(ctx, arch, &c, 0xffff_ffff)?;
+                    self.and(ctx, arch, &a, &a, &c)?;
+                }
+                self.ld(ctx, arch, &b, &at(sp, 0))?;
+                self.add(ctx, arch, &b, &a, &b)?;
+                if mem.memory64 {
+                    // The end of the range wrapped around.
+                    let skip = RiscvLabel::Indexed { idx: i + 1 };
+                    self.bcond_label(ctx, arch, ConditionCode::GEU, &b, &a, skip)?;
+                    self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                    self.set_label(ctx, arch, skip)?;
+                }
+                let skip = RiscvLabel::Indexed { idx: i + 2 };
+                self.ld(ctx, arch, &c, &at(gp, mdesc + 8))?;
+                self.bcond_label(ctx, arch, ConditionCode::GEU, &c, &b, skip)?;
+                self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                self.set_label(ctx, arch, skip)?;
+                if let DataMode::Passive = segment.mode {
+                    self.ld(ctx, arch, &c, &at(gp, mdesc))?;
+                    self.add(ctx, arch, &a, &a, &c)?;
+                    self.ld(ctx, arch, &b, &at(sp, 8))?;
+                    self.ld(ctx, arch, &c, &at(gp, ddesc))?;
+                    self.add(ctx, arch, &b, &b, &c)?;
+                    self.ld(ctx, arch, &c, &at(sp, 0))?;
+                    self.add(ctx, arch, &c, &b, &c)?;
+                    self.sd(ctx, arch, &c, &at(sp, 0))?;
+                    // Copy a byte at a time until the source reaches the end
+                    // of the range, kept in place of the count.
+                    let head = RiscvLabel::Indexed { idx: i + 3 };
+                    let done = RiscvLabel::Indexed { idx: i + 4 };
+                    self.set_label(ctx, arch, head)?;
+                    self.ld(ctx, arch, &c, &at(sp, 0))?;
+                    self.bcond_label(ctx, arch, ConditionCode::EQ, &b, &c, done)?;
+                    self.ld(ctx, arch, &c, &byte_at(b))?;
+                    self.sd(ctx, arch, &c, &byte_at(a))?;
+                    self.addi(ctx, arch, &b, &b, 1)?;
+                    self.addi(ctx, arch, &a, &a, 1)?;
+                    self.jal_label(ctx, arch, &Reg(0), head)?;
+                    self.set_label(ctx, arch, done)?;
+                }
+                self.addi(ctx, arch, &sp, &sp, 24)?;
+            }
+            Instruction::DataDrop(data_index) => {
+                // Active segments are dropped by `blitz_init`.
+                if let DataMode::Passive = module.data_segment(*data_index)?.mode {
+                    let desc = crate::data_desc(memories.len(), module.tables.len(), *data_index);
+                    self.sd(ctx, arch, &Reg(0), &at(Reg(3), desc + 8))?;
+                }
+            }
+            Instruction::MemorySize(mem) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let mem = memory::lookup(memories, *mem)?;
+                let desc = crate::memory_desc(mem.index);
+                let a = Reg(10);
+                let b = Reg(11);
+                self.ld(ctx, arch, &a, &at(Reg(3), desc + 8))?;
+                self.li(ctx, arch, &b, mem.page_size_log2 as u64)?;
+                self.srl(ctx, arch, &a, &a, &b)?;
+                push(self, ctx, arch, a)?;
+            }
+            Instruction::MemoryGrow(mem) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let mem = memory::lookup(memories, *mem)?;
+                let desc = crate::memory_desc(mem.index);
+                let a = Reg(10);
+                let b = Reg(11);
+                let c = Reg(12);
+                let gp = Reg(3);
+                let fail = RiscvLabel::Indexed {
+                    idx: state.label_index,
+                };
+                let done = RiscvLabel::Indexed {
+                    idx: state.label_index + 1,
                 };
-                self.sd(ctx, arch, &val, &mem)?;
+                state.label_index += 2;
+                pop(self, ctx, arch, &a)?;
+                if !mem.memory64 {
+                    self.li(ctx, arch, &c, 0xffff_ffff)?;
+                    self.and(ctx, arch, &a, &a, &c)?;
+                }
+                self.ld(ctx, arch, &b, &at(gp, desc + 8))?;
+                self.li(ctx, arch, &c, mem.page_size_log2 as u64)?;
+                self.srl(ctx, arch, &b, &b, &c)?;
+                self.add(ctx, arch, &c, &b, &a)?;
+                // Fail when the new size wraps around, exceeds the maximum
+                // or does not fit in the capacity the embedder reserved.
+                self.bcond_label(ctx, arch, ConditionCode::LTU, &c, &b, fail)?;
+                self.li(ctx, arch, &a, mem.max_size())?;
+                self.bcond_label(ctx, arch, ConditionCode::LTU, &a, &c, fail)?;
+                self.li(ctx, arch, &a, mem.page_size_log2 as u64)?;
+                self.sll(ctx, arch, &c, &c, &a)?;
+                self.ld(ctx, arch, &a, &at(gp, desc + 16))?;
+                self.bcond_label(ctx, arch, ConditionCode::LTU, &a, &c, fail)?;
+                self.sd(ctx, arch, &c, &at(gp, desc + 8))?;
+                push(self, ctx, arch, b)?;
+                self.jal_label(ctx, arch, &Reg(0), done)?;
+                self.set_label(ctx, arch, fail)?;
+                let value = if mem.memory64 {
+                    u64::MAX
+                } else {
+                    u32::MAX as u64
+                };
+                self.li(ctx, arch, &a, value)?;
+                push(self, ctx, arch, a)?;
+                self.set_label(ctx, arch, done)?;
+            }
+            Instruction::MemoryFill(mem) => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let mem = memory::lookup(memories, *mem)?;
+                let desc = crate::memory_desc(mem.index);
+                let a = Reg(10);
+                let b = Reg(11);
+                let c = Reg(12);
+                let sp = Reg(2);
+                let gp = Reg(3);
+                let i = state.label_index;
+                state.label_index += 4;
+                // The range must lie in the memory.
+                self.ld(ctx, arch, &a, &at(sp, 16))?;
+                self.ld(ctx, arch, &b, &at(sp, 0))?;
+                if !mem.memory64 {
+                    self.li(ctx, arch, &c, 0xffff_ffff)?;
+                    self.and(ctx, arch, &a, &a, &c)?;
+                    self.and(ctx, arch, &b, &b, &c)?;
+                }
+                self.add(ctx, arch, &b, &a, &b)?;
+                if mem.memory64 {
+                    // The end of the range wrapped around.
+                    let skip = RiscvLabel::Indexed { idx: i };
+                    self.bcond_label(ctx, arch, ConditionCode::GEU, &b, &a, skip)?;
+                    self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                    self.set_label(ctx, arch, skip)?;
+                }
+                let skip = RiscvLabel::Indexed { idx: i + 1 };
+                self.ld(ctx, arch, &c, &at(gp, desc + 8))?;
+                self.bcond_label(ctx, arch, ConditionCode::GEU, &c, &b, skip)?;
+                self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                self.set_label(ctx, arch, skip)?;
+                self.ld(ctx, arch, &c, &at(gp, desc))?;
+                self.add(ctx, arch, &a, &a, &c)?;
+                self.add(ctx, arch, &b, &b, &c)?;
+                self.ld(ctx, arch, &c, &at(sp, 8))?;
+                // Store a byte at a time until the end of the range.
+                let head = RiscvLabel::Indexed { idx: i + 2 };
+                let done = RiscvLabel::Indexed { idx: i + 3 };
+                self.set_label(ctx, arch, head)?;
+                self.bcond_label(ctx, arch, ConditionCode::EQ, &a, &b, done)?;
+                self.sd(ctx, arch, &c, &byte_at(a))?;
+                self.addi(ctx, arch, &a, &a, 1)?;
+                self.jal_label(ctx, arch, &Reg(0), head)?;
+                self.set_label(ctx, arch, done)?;
+                self.addi(ctx, arch, &sp, &sp, 24)?;
+            }
+            Instruction::MemoryCopy { dst_mem, src_mem } => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                let dst = memory::lookup(memories, *dst_mem)?;
+                let src = memory::lookup(memories, *src_mem)?;
+                // The length is only 64-bit when both memories are.
+                let wide = dst.memory64 && src.memory64;
+                let a = Reg(10);
+                let b = Reg(11);
+                let c = Reg(12);
+                let sp = Reg(2);
+                let gp = Reg(3);
+                let i = state.label_index;
+                state.label_index += 8;
+                if !wide {
+                    self.ld(ctx, arch, &b, &at(sp, 0))?;
+                    self.li(ctx, arch, &c, 0xffff_ffff)?;
+                    self.and(ctx, arch, &b, &b, &c)?;
+                    self.sd(ctx, arch, &b, &at(sp, 0))?;
+                }
+                // Both ranges must lie in their memories.
+                for (n, (mem, disp)) in [(src, 8), (dst, 16)].into_iter().enumerate() {
+                    let desc = crate::memory_desc(mem.index);
+                    self.ld(ctx, arch, &a, &at(sp, disp))?;
+                    if !mem.memory64 {
+                        self.li(ctx, arch, &c, 0xffff_ffff)?;
+                        self.and(ctx, arch, &a, &a, &c)?;
+                    }
+                    self.ld(ctx, arch, &b, &at(sp, 0))?;
+                    self.add(ctx, arch, &b, &a, &b)?;
+                    if mem.memory64 {
+                        // The end of the range wrapped around.
+                        let skip = RiscvLabel::Indexed { idx: i + 2 * n };
+                        self.bcond_label(ctx, arch, ConditionCode::GEU, &b, &a, skip)?;
+                        self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                        self.set_label(ctx, arch, skip)?;
+                    }
+                    let skip = RiscvLabel::Indexed { idx: i + 2 * n + 1 };
+                    self.ld(ctx, arch, &c, &at(gp, desc + 8))?;
+                    self.bcond_label(ctx, arch, ConditionCode::GEU, &c, &b, skip)?;
+                    self.jal_label(ctx, arch, &Reg(0), RiscvLabel::Trap)?;
+                    self.set_label(ctx, arch, skip)?;
+                }
+                for (mem, disp, reg) in [(dst, 16, a), (src, 8, b)] {
+                    self.ld(ctx, arch, &reg, &at(sp, disp))?;
+                    if !mem.memory64 {
+                        self.li(ctx, arch, &c, 0xffff_ffff)?;
+                        self.and(ctx, arch, &reg, &reg, &c)?;
+                    }
+                    self.ld(ctx, arch, &c, &at(gp, crate::memory_desc(mem.index)))?;
+                    self.add(ctx, arch, &reg, &reg, &c)?;
+                }
+                self.ld(ctx, arch, &c, &at(sp, 0))?;
+                // Copy a byte at a time until the source reaches the end of
+                // the range, kept in place of the count. Copy backwards when
+                // the destination starts above the source, so overlapping
+                // ranges are not overwritten before being read.
+                let backward = RiscvLabel::Indexed { idx: i + 4 };
+                let done = RiscvLabel::Indexed { idx: i + 7 };
+                self.bcond_label(ctx, arch, ConditionCode::LTU, &b, &a, backward)?;
+                self.add(ctx, arch, &c, &b, &c)?;
+                self.sd(ctx, arch, &c, &at(sp, 0))?;
+                let head = RiscvLabel::Indexed { idx: i + 5 };
+                self.set_label(ctx, arch, head)?;
+                self.ld(ctx, arch, &c, &at(sp, 0))?;
+                self.bcond_label(ctx, arch, ConditionCode::EQ, &b, &c, done)?;
+                self.ld(ctx, arch, &c, &byte_at(b))?;
+                self.sd(ctx, arch, &c, &byte_at(a))?;
+                self.addi(ctx, arch, &b, &b, 1)?;
+                self.addi(ctx, arch, &a, &a, 1)?;
+                self.jal_label(ctx, arch, &Reg(0), head)?;
+                self.set_label(ctx, arch, backward)?;
+                self.sd(ctx, arch, &b, &at(sp, 0))?;
+                self.add(ctx, arch, &a, &a, &c)?;
+                self.add(ctx, arch, &b, &b, &c)?;
+                let head = RiscvLabel::Indexed { idx: i + 6 };
+                self.set_label(ctx, arch, head)?;
+                self.ld(ctx, arch, &c, &at(sp, 0))?;
+                self.bcond_label(ctx, arch, ConditionCode::EQ, &b, &c, done)?;
+                self.addi(ctx, arch, &b, &b, -1)?;
+                self.addi(ctx, arch, &a, &a, -1)?;
+                self.ld(ctx, arch, &c, &byte_at(b))?;
+                self.sd(ctx, arch, &c, &byte_at(a))?;
+                self.jal_label(ctx, arch, &Reg(0), head)?;
+                self.set_label(ctx, arch, done)?;
+                self.addi(ctx, arch, &sp, &sp, 24)?;
             }
             Instruction::I64Add => {
                 if state.regalloc.is_none() {
@@ -1145,16 +2460,34 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 }
                 self.set_label(ctx, arch, default_label)?;
             }
-            Instruction::Block(_blockty) => {
+            Instruction::Block(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                // flush so the stack pointer covers the block's parameters
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
                 let i = state.label_index;
                 state.label_index += 1;
-                state.if_stack.push(Endable::Block { idx: i });
-                self.set_label(ctx, arch, RiscvLabel::Indexed { idx: i })?;
+                state.if_stack.push(Endable::Block { idx: i, arity });
+                self.enter(ctx, arch, state, arity.params)?;
             }
-            Instruction::If(_blockty) => {
+            Instruction::If(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                // flush so the condition is on the memory stack
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
                 let i = state.label_index;
                 state.label_index += 3;
-                state.if_stack.push(Endable::If { idx: i });
+                state.if_stack.push(Endable::If {
+                    idx: i,
+                    arity,
+                    has_else: false,
+                });
                 let tmp = Reg(10);
                 let spmem = MemArgKind::Mem {
                     base: ArgKind::Reg {
@@ -1168,6 +2501,7 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 };
                 self.ld(ctx, arch, &tmp, &spmem)?;
                 self.addi(ctx, arch, &Reg(2), &Reg(2), 8)?;
+                self.enter(ctx, arch, state, arity.params)?;
                 let lbl_else = RiscvLabel::Indexed { idx: i + 1 };
                 self.bcond_label(
                     ctx,
@@ -1185,11 +2519,11 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                     let it = ralloc.flush();
                     emit_cmds(self, ctx, arch, it)?;
                 }
-                let endable = state.if_stack.last().unwrap();
-                let idx = match endable {
-                    Endable::If { idx } => *idx,
-                    _ => panic!("Else without If"),
+                let Some(Endable::If { idx, has_else, .. }) = state.if_stack.last_mut() else {
+                    return Err(CompileError::malformed().into());
                 };
+                *has_else = true;
+                let idx = *idx;
                 let lbl_end = RiscvLabel::Indexed { idx: idx + 2 };
                 self.jal_label(
                     ctx,
@@ -1199,11 +2533,19 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 )?;
                 self.set_label(ctx, arch, RiscvLabel::Indexed { idx: idx + 1 })?;
             }
-            Instruction::Loop(_blockty) => {
+            Instruction::Loop(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                // flush so the stack pointer covers the loop's parameters
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
                 let i = state.label_index;
                 state.label_index += 1;
-                state.if_stack.push(Endable::Loop { idx: i });
+                state.if_stack.push(Endable::Loop { idx: i, arity });
                 self.set_label(ctx, arch, RiscvLabel::Indexed { idx: i })?;
+                self.enter(ctx, arch, state, arity.params)?;
             }
             Instruction::End => {
                 // flush regalloc on end boundary
@@ -1211,17 +2553,25 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                     let it = ralloc.flush();
                     emit_cmds(self, ctx, arch, it)?;
                 }
-                match state.if_stack.pop().unwrap() {
-                    Endable::Block { idx } => {
+                match state.if_stack.pop() {
+                    Some(Endable::Block { idx, .. }) => {
                         self.set_label(ctx, arch, RiscvLabel::Indexed { idx })?;
+                        return Ok(());
                     }
-                    Endable::Loop { idx } => {
+                    Some(Endable::Loop { .. }) => {
                         // no-op; loop already has label at start
                         //  self.set_label(ctx,arch, RiscvLabel::Indexed { idx })?;
+                        return Ok(());
                     }
-                    Endable::If { idx } => {
+                    Some(Endable::If { idx, has_else, .. }) => {
+                        if !has_else {
+                            self.set_label(ctx, arch, RiscvLabel::Indexed { idx: idx + 1 })?;
+                        }
                         self.set_label(ctx, arch, RiscvLabel::Indexed { idx: idx + 2 })?;
+                        return Ok(());
                     }
+                    // The function-level `End` tears down the frame set up in `StartBody`.
+                    None => {}
                 }
                 // restore control stack space if reserved
                 let control_space = (state.control_depth as i32) * 16;
@@ -1254,14 +2604,12 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                         // not implemented: hypercall path
                     }
                     _ => {
-                        let function_index = *function_index - func_imports.len() as u32;
+                        let callee = crate::callee(*function_index, func_imports.len() as u32);
                         self.jal_label(
                             ctx,
                             arch,
                             &portal_solutions_blitz_common::asm::Reg(10),
-                            RiscvLabel::Func {
-                                r#fn: function_index,
-                            },
+                            callee,
                         )?;
                         self.call(ctx, arch, &portal_solutions_blitz_common::asm::Reg(10))?;
                     }
@@ -1294,7 +2642,27 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 self.mv(ctx, arch, &fp, &saved_fp)?;
                 self.ret(ctx, arch)?;
             }
-            _ => {}
+            Instruction::I64ReinterpretF64
+            | Instruction::F64ReinterpretI64
+            | Instruction::I32ReinterpretF32
+            | Instruction::F32ReinterpretI32
+            | Instruction::Nop => {}
+            Instruction::Unreachable => self.trap(ctx, arch, state, false)?,
+            op if matches!(Group::of(op), Some(Group::Int | Group::SignExt)) => {
+                // the remaining integer operators work on the memory stack
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let it = ralloc.flush();
+                    emit_cmds(self, ctx, arch, it)?;
+                }
+                self.int(ctx, arch, state, op)?;
+            }
+            Instruction::Drop => {
+                if let Some(ralloc) = state.regalloc.as_mut() {
+                    let (_, cmds) = ralloc.pop(riscv_regalloc::RegKind::Int);
+                    emit_cmds(self, ctx, arch, cmds)?;
+                }
+            }
+            _ => return Err(CompileError::unsupported(op).into()),
         }
         Ok(())
     }
@@ -1303,6 +2671,9 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
         ctx: &mut Context,
         arch: RiscV64Arch,
         state: &mut State,
+        sigs: &[wasm_encoder::FuncType],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         func_imports: &[(&str, &str)],
         op: &MachOperator<'_>,
         _rewriter: &mut (dyn Reencode<Error = E> + '_),
@@ -1310,7 +2681,7 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
     ) -> Result<(), Self::Error>
     where
         wasm_encoder::reencode::Error<E>: Into<Self::Error>,
-        Self::Error: From<core::fmt::Error>,
+        Self::Error: From<core::fmt::Error> + From<CompileError>,
         Self: Sized,
     {
         if target != state.body {
@@ -1336,7 +2707,11 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 state.num_returns = data.num_returns;
                 state.control_depth = data.control_depth;
 
-                self.set_label(ctx, arch, RiscvLabel::Func { r#fn: *id })?;
+                let label = match state.builtin {
+                    Some(name) => RiscvLabel::Builtin { name },
+                    None => RiscvLabel::Func { r#fn: *id },
+                };
+                self.set_label(ctx, arch, label)?;
 
                 let sp = Reg(2);
                 let fp = Reg(8);
@@ -1435,17 +2810,35 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                 if control_space > 0 {
                     self.addi(ctx, arch, &sp, &sp, -control_space)?;
                 }
+                // locals and the slots `StartFn` allocated, the marker, then
+                // the control slots
+                state.control_base =
+                    -((state.local_count as i32 + state.control_depth as i32 * 2 + 4) * 8
+                        + 8
+                        + control_space);
                 Ok(())
             }
-            MachOperator::Instruction { op, .. } => {
-                self.handle_op_(ctx, arch, state, func_imports, op, _rewriter, target)
-            }
+            MachOperator::Instruction { op, .. } => self.handle_op_(
+                ctx,
+                arch,
+                state,
+                sigs,
+                memories,
+                module,
+                func_imports,
+                op,
+                _rewriter,
+                target,
+            ),
             MachOperator::Operator { op, .. } => {
                 if let Some(op) = op {
                     self.handle_op_(
                         ctx,
                         arch,
                         state,
+                        sigs,
+                        memories,
+                        module,
                         func_imports,
                         &_rewriter.instruction(op.clone()).map_err(|e| e.into())?,
                         _rewriter,
@@ -1455,13 +2848,108 @@ pub trait WriterExt<Context>: Writer<RiscvLabel, Context> {
                     Ok(())
                 }
             }
-            _ => Ok(()),
+            MachOperator::Trap { conditional, .. } => self.trap(ctx, arch, state, *conditional),
+            MachOperator::EndBody => Ok(()),
+            _ => Err(CompileError::unsupported_mach(op).into()),
         }
     }
 }
 
 impl<T: Writer<RiscvLabel, Context> + ?Sized, Context> WriterExt<Context> for T {}
 
+/// A [`Backend`] emitting RISC-V code with the naive strategy.
+///
+/// Bundles a writer with its context, target architecture and `State` so
+/// that the naive generator can be driven like any other backend.
+pub struct NaiveBackend<W, Context> {
+    /// The writer receiving machine code.
+    pub writer: W,
+    /// The writer's context.
+    pub ctx: Context,
+    /// The RISC-V architecture variant.
+    pub arch: RiscV64Arch,
+    /// Code generation state.
+    pub state: State,
+    /// Body target passed to `handle_op`.
+    pub target: u32,
+}
+
+impl<W, Context> NaiveBackend<W, Context> {
+    /// Creates a backend writing to `writer`.
+    pub fn new(writer: W, ctx: Context, arch: RiscV64Arch) -> Self {
+        Self {
+            writer,
+            ctx,
+            arch,
+            state: State::default(),
+            target: 0,
+        }
+    }
+}
+
+impl<W: WriterExt<Context>, Context, R: Reencode, Annot> Backend<R, Annot>
+    for NaiveBackend<W, Context>
+where
+    W::Error: From<core::fmt::Error> + From<CompileError>,
+    wasm_encoder::reencode::Error<R::Error>: Into<W::Error>,
+{
+    type Error = W::Error;
+    fn on_module(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        self.writer
+            .init(&mut self.ctx, self.arch, &mut self.state, cx.module)
+    }
+    fn on_mach(
+        &mut self,
+        cx: &mut BackendContext<'_, R>,
+        op: &MachOperator<'_, Annot>,
+    ) -> Result<(), Self::Error> {
+        let Ok(op) = op
+            .as_ref()
+            .map(&mut |_| Ok::<_, core::convert::Infallible>(()));
+        self.writer.handle_op(
+            &mut self.ctx,
+            self.arch,
+            &mut self.state,
+            &cx.sigs,
+            &cx.memories,
+            cx.module,
+            cx.func_imports(),
+            &op,
+            cx.rewriter,
+            self.target,
+        )
+    }
+    fn on_end(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        // The float routines the module calls, generated like its own
+        // functions.
+        let routines = core::mem::take(&mut self.state.routines);
+        let Self {
+            writer,
+            ctx,
+            arch,
+            state,
+            target,
+        } = self;
+        routines.compile(|rcx, float, op| {
+            state.builtin = Some(float.name);
+            writer.handle_op(
+                ctx,
+                *arch,
+                state,
+                &rcx.sigs,
+                &rcx.memories,
+                rcx.module,
+                rcx.func_imports(),
+                op,
+                &mut *cx.rewriter,
+                *target,
+            )
+        })?;
+        state.builtin = None;
+        Ok(())
+    }
+}
+
 fn emit_cmds<
     E: core::error::Error,
     Context,
@@ -6,8 +6,8 @@
 //!
 //! # Pipeline
 //! ```text
-//! wasm-encoder  →  raw bytes  →  wasmparser (FunctionBody, FuncType)
-//!   →  mach_operators  →  dce_pass!  →  on_mach  →  String output
+//! wasm-encoder  →  raw bytes  →  blitz Module (types, functions, exports, ...)
+//!   →  Module::mach_operators  →  dce_pass!  →  Module::drive  →  String output
 //!   →  node / clang   →  execute  →  numeric result
 //! ```
 //!
@@ -15,21 +15,37 @@
 //! Each test is annotated with the bug(s) it exercises.
 
 use std::borrow::Cow;
+use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use std::error::Error;
+
+use portal_solutions_blitz_c::{CBackend, CWrite};
 use portal_solutions_blitz_common::{
+    CompileError,
+    backend::Backend,
     dce_pass,
-    ops::mach_operators,
-    wasmparser::{self, FuncType as WpFuncType},
+    float::{FloatKind, FloatOp, Nans},
+    import::{Binding, FuncImport, Imports, Resolve},
+    module::Module as BlitzModule,
+    ops::{InstructionOrOperator, MachOperator, RewriteCx, WasmInfo},
+    passes::{InlineBudget, coalesce_locals, const_fold, explicit_traps, inline, prune},
+    peephole::{Rules, peephole},
+    peephole_rule,
+    reach::Reach,
+    softfloat,
     wasm_encoder::{
-        self,
-        reencode::RoundtripReencoder,
-        CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, Module,
-        TypeSection, ValType,
+        self, CodeSection, ConstExpr, DataCountSection, DataSection, ElementSection, Elements,
+        EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection,
+        GlobalType, HeapType, ImportSection, Instruction, MemArg, MemorySection, MemoryType,
+        Module, RefType, StartSection, TableSection, TableType, TypeSection, ValType,
     },
+    wasmparser,
 };
-use portal_solutions_blitz_c::{CWrite, State as CState};
-use portal_solutions_blitz_js::{JsWrite, State as JsState};
+use portal_solutions_blitz_js::JsBackend;
+use portal_solutions_blitz_opt::OptState;
+use portal_solutions_blitz_reencode::{ReencodeExt, prune::Pruner, tracker::MachTracker};
+use wasm_encoder::reencode::RoundtripReencoder;
 
 /// Global counter for unique temp-file names (needed for parallel test runs).
 static TEMP_SEQ: AtomicU64 = AtomicU64::new(0);
@@ -42,124 +58,291 @@ static TEMP_SEQ: AtomicU64 = AtomicU64::new(0);
 /// and instruction sequence. Always finishes with `Return; End` so that DCE
 /// can prune the implicit function-level `End` operator.
 fn make_module(params: &[ValType], results: &[ValType], instrs: &[Instruction<'_>]) -> Vec<u8> {
+    build_module(&Sections::default(), params, results, instrs)
+}
+
+/// Build a module like [`make_module`] that also defines `memories`.
+fn make_memory_module(
+    memories: &[MemoryType],
+    params: &[ValType],
+    results: &[ValType],
+    instrs: &[Instruction<'_>],
+) -> Vec<u8> {
+    let sections = Sections {
+        memories,
+        ..Sections::default()
+    };
+    build_module(&sections, params, results, instrs)
+}
+
+/// Module-level definitions added by [`build_module`].
+#[derive(Default)]
+struct Sections<'a> {
+    memories: &'a [MemoryType],
+    globals: &'a [(GlobalType, ConstExpr)],
+    /// Functions after the exported one, each with a type of its own:
+    /// parameters, results and body.
+    funcs: &'a [(&'a [ValType], &'a [ValType], &'a [Instruction<'a>])],
+    tables: &'a [TableType],
+    /// Passive element segments, by the functions they reference.
+    elements: &'a [&'a [u32]],
+    /// Active element segments of table 0, after the passive ones, by their
+    /// offset and the functions they reference.
+    active_elements: &'a [(i32, &'a [u32])],
+    /// Data segments, by the offset they initialise memory 0 at if they
+    /// are active, and their bytes.
+    data: &'a [(Option<i32>, &'a [u8])],
+    /// Exports after function 0, which is always exported as `f`.
+    exports: &'a [(&'a str, ExportKind, u32)],
+    /// The start function, if any.
+    start: Option<u32>,
+    /// Imported functions, each with a type of its own: module, name,
+    /// parameters and results. They come first in the function index
+    /// space.
+    imports: &'a [(&'a str, &'a str, &'a [ValType], &'a [ValType])],
+}
+
+/// Build a module like [`make_module`] that also defines `sections`.
+fn build_module(
+    sections: &Sections<'_>,
+    params: &[ValType],
+    results: &[ValType],
+    instrs: &[Instruction<'_>],
+) -> Vec<u8> {
     let mut module = Module::new();
 
     let mut types = TypeSection::new();
-    types.ty().function(params.iter().cloned(), results.iter().cloned());
+    types
+        .ty()
+        .function(params.iter().cloned(), results.iter().cloned());
+    for (params, results, _) in sections.funcs {
+        types
+            .ty()
+            .function(params.iter().cloned(), results.iter().cloned());
+    }
+    for (_, _, params, results) in sections.imports {
+        types
+            .ty()
+            .function(params.iter().cloned(), results.iter().cloned());
+    }
     module.section(&types);
 
+    if !sections.imports.is_empty() {
+        let mut section = ImportSection::new();
+        for (i, (module, name, _, _)) in sections.imports.iter().enumerate() {
+            let ty = (sections.funcs.len() + 1 + i) as u32;
+            section.import(module, name, EntityType::Function(ty));
+        }
+        module.section(&section);
+    }
+
     let mut functions = FunctionSection::new();
-    functions.function(0);
+    for ty in 0..=sections.funcs.len() as u32 {
+        functions.function(ty);
+    }
     module.section(&functions);
 
+    if !sections.tables.is_empty() {
+        let mut section = TableSection::new();
+        for table in sections.tables {
+            section.table(*table);
+        }
+        module.section(&section);
+    }
+
+    if !sections.memories.is_empty() {
+        let mut section = MemorySection::new();
+        for memory in sections.memories {
+            section.memory(*memory);
+        }
+        module.section(&section);
+    }
+
+    if !sections.globals.is_empty() {
+        let mut section = GlobalSection::new();
+        for (ty, init) in sections.globals {
+            section.global(*ty, init);
+        }
+        module.section(&section);
+    }
+
     let mut exports = ExportSection::new();
-    exports.export("f", ExportKind::Func, 0);
+    exports.export("f", ExportKind::Func, sections.imports.len() as u32);
+    for (name, kind, index) in sections.exports {
+        exports.export(name, *kind, *index);
+    }
     module.section(&exports);
 
+    if let Some(function_index) = sections.start {
+        module.section(&StartSection { function_index });
+    }
+
+    if !sections.elements.is_empty() || !sections.active_elements.is_empty() {
+        let mut section = ElementSection::new();
+        for funcs in sections.elements {
+            section.passive(Elements::Functions(Cow::Borrowed(funcs)));
+        }
+        for (offset, funcs) in sections.active_elements {
+            section.active(
+                None,
+                &ConstExpr::i32_const(*offset),
+                Elements::Functions(Cow::Borrowed(funcs)),
+            );
+        }
+        module.section(&section);
+    }
+
+    if !sections.data.is_empty() {
+        module.section(&DataCountSection {
+            count: sections.data.len() as u32,
+        });
+    }
+
     let mut code = CodeSection::new();
-    let mut func = Function::new([]);
-    for instr in instrs {
-        func.instruction(instr);
+    let bodies = std::iter::once(instrs).chain(sections.funcs.iter().map(|(_, _, body)| *body));
+    for instrs in bodies {
+        let mut func = Function::new([]);
+        for instr in instrs {
+            func.instruction(instr);
+        }
+        // Explicit return so DCE removes the dead function-level `End`.
+        func.instruction(&Instruction::Return);
+        func.instruction(&Instruction::End);
+        code.function(&func);
     }
-    // Explicit return so DCE removes the dead function-level `End`.
-    func.instruction(&Instruction::Return);
-    func.instruction(&Instruction::End);
-    code.function(&func);
     module.section(&code);
 
-    module.finish()
-}
-
-/// Parse `wasm` bytes, collect the `FunctionBody` items (borrowing the
-/// bytes) and the two parallel signature slices required by the pipeline:
-/// `wasmparser::FuncType` for `mach_operators`, and `wasm_encoder::FuncType`
-/// for the backends' `on_mach`.
-fn parse_sigs(wasm: &[u8]) -> (Vec<WpFuncType>, Vec<wasm_encoder::FuncType>, Vec<u32>) {
-    let mut sigs_wp: Vec<WpFuncType> = Vec::new();
-    let mut fsigs: Vec<u32> = Vec::new();
-
-    for payload in wasmparser::Parser::new(0).parse_all(wasm).flatten() {
-        match payload {
-            wasmparser::Payload::TypeSection(reader) => {
-                for group in reader.into_iter().flatten() {
-                    for subtype in group.into_types() {
-                        if let wasmparser::CompositeInnerType::Func(ft) =
-                            subtype.composite_type.inner
-                        {
-                            sigs_wp.push(ft);
-                        }
-                    }
+    if !sections.data.is_empty() {
+        let mut section = DataSection::new();
+        for (offset, bytes) in sections.data {
+            match offset {
+                Some(offset) => {
+                    section.active(0, &ConstExpr::i32_const(*offset), bytes.iter().copied())
                 }
-            }
-            wasmparser::Payload::FunctionSection(reader) => {
-                fsigs.extend(reader.into_iter().flatten());
-            }
-            _ => {}
+                None => section.passive(bytes.iter().copied()),
+            };
         }
+        module.section(&section);
     }
 
-    // Convert wasmparser::FuncType → wasm_encoder::FuncType.
-    let sigs_enc: Vec<wasm_encoder::FuncType> = sigs_wp
-        .iter()
-        .cloned()
-        .map(|ft| wasm_encoder::FuncType::try_from(ft).unwrap())
-        .collect();
-
-    (sigs_wp, sigs_enc, fsigs)
+    module.finish()
 }
 
-/// Compile `wasm` bytes to JavaScript source using the JS backend.
+/// Compile `wasm` bytes with any backend.
 /// Applies DCE so the dead function-level `End` after explicit `Return` is
 /// removed before reaching the backend.
-fn compile_js(wasm: &[u8]) -> String {
-    let (sigs_wp, sigs_enc, fsigs) = parse_sigs(wasm);
+fn compile_with<B: Backend>(wasm: &[u8], backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(module.mach_operators::<(), Box<dyn Error>>());
+    module.drive(ops, backend).unwrap();
+}
 
-    let mut bodies: Vec<wasmparser::FunctionBody<'_>> = Vec::new();
-    for payload in wasmparser::Parser::new(0).parse_all(wasm).flatten() {
-        if let wasmparser::Payload::CodeSectionEntry(body) = payload {
-            bodies.push(body);
-        }
-    }
+/// Compile `wasm` bytes with any backend after making traps explicit with
+/// `explicit_traps`, then applying DCE as `compile_with` does.
+fn compile_traps_with<B: Backend>(wasm: &[u8], backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(explicit_traps(
+        module.mach_operators::<(), Box<dyn Error>>()
+    ));
+    module.drive(ops, backend).unwrap();
+}
+
+/// Compile `wasm` bytes with any backend after making traps explicit and
+/// folding constants with `const_fold`, then applying DCE.
+fn compile_fold_with<B: Backend>(wasm: &[u8], backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(const_fold(explicit_traps(
+        module.mach_operators::<(), Box<dyn Error>>()
+    )));
+    module.drive(ops, backend).unwrap();
+}
 
-    let raw_ops = mach_operators::<(), wasmparser::BinaryReaderError>(&bodies, &fsigs, &sigs_wp, 0);
-    let ops = dce_pass!(raw_ops);
+/// Compile `wasm` bytes with any backend after rewriting it with `rules`
+/// by `peephole`, then applying DCE.
+fn compile_peephole_with<B: Backend>(wasm: &[u8], rules: &Rules, backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(peephole(
+        module.mach_operators::<(), Box<dyn Error>>(),
+        rules
+    ));
+    module.drive(ops, backend).unwrap();
+}
 
-    let mut out = String::new();
-    let mut state = JsState::default();
-    let mut reencoder = RoundtripReencoder;
+/// Compile `wasm` bytes with any backend after inlining calls with
+/// `inline`, then applying DCE.
+fn compile_inline_with<B: Backend>(wasm: &[u8], backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(inline(
+        module.mach_operators::<(), Box<dyn Error>>(),
+        module.num_func_imports(),
+        InlineBudget::default(),
+    ));
+    module.drive(ops, backend).unwrap();
+}
 
-    for op in ops {
-        let op = op.unwrap();
-        JsWrite::on_mach(&mut out, &sigs_enc, &fsigs, &[], &mut state, &op, &mut reencoder)
+/// Drop what `wasm` cannot reach: prune its stream, re-encode it through a
+/// `MachTracker` and write the module around the tracked code.
+fn prune_wasm(wasm: &[u8]) -> Vec<u8> {
+    let module = BlitzModule::new(wasm).unwrap();
+    let reach = Reach::new(&module).unwrap();
+    let mut tracker = MachTracker::default();
+    for o in prune(module.mach_operators::<(), CompileError>(), &reach) {
+        RoundtripReencoder
+            .mach_instruction(&mut (), &o.unwrap(), &mut tracker, &mut |locals| {
+                Function::new(locals)
+            })
             .unwrap();
     }
-    out
+    let mut code = CodeSection::new();
+    tracker.on_code_section(&mut code);
+    Pruner::new(&reach)
+        .with_code(code)
+        .module(wasm)
+        .unwrap()
+        .finish()
+}
+
+/// Compile `wasm` bytes to JavaScript source using the JS backend.
+fn compile_js(wasm: &[u8]) -> String {
+    let mut backend = JsBackend::new(String::new());
+    compile_with(wasm, &mut backend);
+    backend.out
 }
 
 /// Compile `wasm` bytes to C source using the C backend.
 fn compile_c(wasm: &[u8]) -> String {
-    let (sigs_wp, sigs_enc, fsigs) = parse_sigs(wasm);
-
-    let mut bodies: Vec<wasmparser::FunctionBody<'_>> = Vec::new();
-    for payload in wasmparser::Parser::new(0).parse_all(wasm).flatten() {
-        if let wasmparser::Payload::CodeSectionEntry(body) = payload {
-            bodies.push(body);
-        }
-    }
-
-    let raw_ops = mach_operators::<(), wasmparser::BinaryReaderError>(&bodies, &fsigs, &sigs_wp, 0);
-    let ops = dce_pass!(raw_ops);
-
-    let mut out = String::new();
-    let mut state = CState::default();
-    let mut reencoder = RoundtripReencoder;
+    let mut backend = CBackend::new(String::new());
+    compile_with(wasm, &mut backend);
+    backend.out
+}
 
-    for op in ops {
-        let op = op.unwrap();
-        CWrite::on_mach(&mut out, &sigs_enc, &fsigs, &[], &mut state, &op, &mut reencoder)
-            .unwrap();
-    }
-    out
+/// Compile `wasm` bytes with any backend, annotating operators with their
+/// offsets, and return the error the backend reports.
+fn compile_err<B: Backend<wasm_encoder::reencode::RoundtripReencoder, WasmInfo>>(
+    wasm: &[u8],
+    backend: &mut B,
+) -> CompileError
+where
+    CompileError: From<B::Error>,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(module.mach_operators::<WasmInfo, CompileError>());
+    module.drive(ops, backend).unwrap_err()
 }
 
 // ---------------------------------------------------------------------------
@@ -168,10 +351,11 @@ fn compile_c(wasm: &[u8]) -> String {
 
 /// Run the generated JavaScript source code using `node`, passing `bigint_args`
 /// as the function arguments (each is emitted as a BigInt literal `{n}n`).
-/// Returns all return values as `i64` (interpreting the BigInt as signed).
+/// Returns the code that was run and the process output.
 ///
-/// The JS backend names function 0 as `$0`.
-fn run_js(js_src: &str, bigint_args: &[i64]) -> Vec<i64> {
+/// The code runs as an ES module, since the JS backend emits one. The JS
+/// backend names function 0 as `$0`.
+fn exec_js(js_src: &str, bigint_args: &[i64]) -> (String, std::process::Output) {
     let args: Vec<String> = bigint_args.iter().map(|v| format!("{v}n")).collect();
     let harness = format!(
         "\nconst __r=$0({args});const __n=Array.isArray(__r)?__r:[__r];for(const v of __n)console.log(String(v));",
@@ -180,11 +364,18 @@ fn run_js(js_src: &str, bigint_args: &[i64]) -> Vec<i64> {
     let code = format!("{js_src}{harness}");
 
     let out = std::process::Command::new("node")
+        .arg("--input-type=module")
         .arg("-e")
         .arg(&code)
         .output()
         .expect("node not found in PATH");
+    (code, out)
+}
 
+/// Run the generated JavaScript like [`exec_js`] and return all return values
+/// as `i64` (interpreting the BigInt as signed).
+fn run_js(js_src: &str, bigint_args: &[i64]) -> Vec<i64> {
+    let (code, out) = exec_js(js_src, bigint_args);
     assert!(
         out.status.success(),
         "node exited non-zero.\nstderr: {}\ncode: {}",
@@ -196,16 +387,20 @@ fn run_js(js_src: &str, bigint_args: &[i64]) -> Vec<i64> {
         .unwrap()
         .lines()
         .filter(|l| !l.is_empty())
-        .map(|l| l.trim().parse::<i64>().expect("expected integer line from node"))
+        .map(|l| {
+            l.trim()
+                .parse::<i64>()
+                .expect("expected integer line from node")
+        })
         .collect()
 }
 
 /// Compile the generated C source (function `fn_{fn_id}`) with clang/gcc,
-/// run the resulting binary, and return all printed `uint64_t` return values.
+/// run the resulting binary, and return its output.
 ///
 /// `args` are the raw `uint64_t` arguments to pass to the function.
-/// `rets` is how many return values to read.
-fn run_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> Vec<u64> {
+/// `rets` is how many return values to print.
+fn exec_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> std::process::Output {
     use std::io::Write as _;
 
     let seq = TEMP_SEQ.fetch_add(1, Ordering::Relaxed);
@@ -216,19 +411,18 @@ fn run_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> Vec<u64> {
 
     // Build main(): declare a zero-padded arg array so 0-param functions still
     // receive a valid (non-null) pointer.
-    let mut main_body = format!(
-        "int main(){{uint64_t _args[{n}]={{",
-        n = args.len().max(1)
-    );
+    let mut main_body = format!("int main(){{uint64_t _args[{n}]={{", n = args.len().max(1));
     for (i, &a) in args.iter().enumerate() {
-        if i > 0 { main_body.push(','); }
+        if i > 0 {
+            main_body.push(',');
+        }
         main_body.push_str(&format!("{a}ull"));
     }
     // Pad to at least 1 element so the pointer is non-null.
     if args.is_empty() {
         main_body.push('0');
     }
-    main_body.push_str(&format!("}};uint64_t*_r=fn_{fn_id}(_args);"));
+    main_body.push_str(&format!("}};blitz_init();uint64_t*_r=fn_{fn_id}(_args);"));
     for i in 0..rets {
         main_body.push_str(&format!("printf(\"%llu\\n\",_r[{i}]);"));
     }
@@ -242,9 +436,10 @@ fn run_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> Vec<u64> {
 
     let compile = std::process::Command::new("cc")
         .arg(&src_path)
-        .arg("-Wno-unsequenced")   // C backend may use sp in single expression
+        .arg("-Wno-unsequenced") // C backend may use sp in single expression
         .arg("-o")
         .arg(&bin_path)
+        .arg("-lm")
         .output()
         .expect("cc not found in PATH");
 
@@ -259,12 +454,24 @@ fn run_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> Vec<u64> {
         .output()
         .expect("failed to run compiled binary");
 
-    assert!(run.status.success(), "binary exited non-zero: {}", String::from_utf8_lossy(&run.stderr));
-
     // Clean up.
     let _ = std::fs::remove_file(&src_path);
     let _ = std::fs::remove_file(&bin_path);
 
+    run
+}
+
+/// Run the generated C like [`exec_c`] and return all printed `uint64_t`
+/// return values.
+fn run_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> Vec<u64> {
+    let run = exec_c(c_src, fn_id, args, rets);
+
+    assert!(
+        run.status.success(),
+        "binary exited non-zero: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+
     String::from_utf8(run.stdout)
         .unwrap()
         .lines()
@@ -273,7 +480,100 @@ fn run_c(c_src: &str, fn_id: u32, args: &[u64], rets: usize) -> Vec<u64> {
         .collect()
 }
 
+/// Write the generated JavaScript to a module file, run `script` in another
+/// module that imports it as `m`, and return the lines it prints.
+fn run_js_importer(js_src: &str, script: &str) -> Vec<String> {
+    let seq = TEMP_SEQ.fetch_add(1, Ordering::Relaxed);
+    let pid = std::process::id();
+    let path = std::env::temp_dir().join(format!("blitz_e2e_{pid}_{seq}.mjs"));
+    std::fs::write(&path, js_src).unwrap();
+
+    let code = format!(
+        "const m=await import({url:?});{script}",
+        url = format!("file://{}", path.display())
+    );
+    let out = std::process::Command::new("node")
+        .arg("--input-type=module")
+        .arg("-e")
+        .arg(&code)
+        .output()
+        .expect("node not found in PATH");
+    let _ = std::fs::remove_file(&path);
+
+    assert!(
+        out.status.success(),
+        "node exited non-zero.\nstderr: {}\nmodule: {}",
+        String::from_utf8_lossy(&out.stderr),
+        js_src
+    );
+    String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+/// Compile the generated C source and a separate host program that includes
+/// its `header`, defines `host` and runs `main_body` from `main`, link them,
+/// and return the lines the binary prints.
+fn run_c_linked(c_src: &str, header: &str, host: &str, main_body: &str) -> Vec<String> {
+    let seq = TEMP_SEQ.fetch_add(1, Ordering::Relaxed);
+    let pid = std::process::id();
+    let dir = std::env::temp_dir();
+    let src_path = dir.join(format!("blitz_e2e_{pid}_{seq}.c"));
+    let header_path = dir.join(format!("blitz_e2e_{pid}_{seq}.h"));
+    let main_path = dir.join(format!("blitz_e2e_{pid}_{seq}_main.c"));
+    let bin_path = dir.join(format!("blitz_e2e_{pid}_{seq}"));
+
+    std::fs::write(
+        &src_path,
+        format!(
+            "#include<stdint.h>\n#include<string.h>\n#include<stdlib.h>\n#define WASM_STACK_SIZE 512\n{c_src}\n"
+        ),
+    )
+    .unwrap();
+    std::fs::write(&header_path, header).unwrap();
+    std::fs::write(
+        &main_path,
+        format!(
+            "#include<stdio.h>\n#include<string.h>\n#include {header_path:?}\n{host}\nint main(){{{main_body}return 0;}}\n"
+        ),
+    )
+    .unwrap();
 
+    let compile = std::process::Command::new("cc")
+        .arg(&src_path)
+        .arg(&main_path)
+        .arg("-Wno-unsequenced")
+        .arg("-o")
+        .arg(&bin_path)
+        .arg("-lm")
+        .output()
+        .expect("cc not found in PATH");
+    assert!(
+        compile.status.success(),
+        "C compile failed:\n{}\nheader:\n{header}\nsource:\n{c_src}",
+        String::from_utf8_lossy(&compile.stderr)
+    );
+
+    let run = std::process::Command::new(&bin_path)
+        .output()
+        .expect("failed to run compiled binary");
+    for path in [&src_path, &header_path, &main_path, &bin_path] {
+        let _ = std::fs::remove_file(path);
+    }
+
+    assert!(
+        run.status.success(),
+        "binary exited non-zero: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    String::from_utf8(run.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
 
 /// A function that returns an i32 constant should emit a BigInt literal in JS
 /// and a uint64_t cast in C.
@@ -282,7 +582,10 @@ fn test_const_js() {
     let wasm = make_module(&[], &[ValType::I32], &[Instruction::I32Const(42)]);
     let js = compile_js(&wasm);
     assert!(js.contains("42n"), "expected BigInt literal 42n in: {js}");
-    assert!(js.contains("$0"), "expected function identifier $0 in: {js}");
+    assert!(
+        js.contains("$0"),
+        "expected function identifier $0 in: {js}"
+    );
 }
 
 #[test]
@@ -290,7 +593,10 @@ fn test_const_c() {
     let wasm = make_module(&[], &[ValType::I32], &[Instruction::I32Const(42)]);
     let c = compile_c(&wasm);
     assert!(c.contains("42u"), "expected 42u in C output: {c}");
-    assert!(c.contains("fn_0"), "expected function identifier fn_0 in: {c}");
+    assert!(
+        c.contains("fn_0"),
+        "expected function identifier fn_0 in: {c}"
+    );
     assert!(c.contains("uint64_t"), "expected uint64_t in: {c}");
 }
 
@@ -300,7 +606,11 @@ fn test_add_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Add],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+        ],
     );
     let js = compile_js(&wasm);
     assert!(js.contains("locals[0]"), "expected locals[0] in: {js}");
@@ -314,7 +624,11 @@ fn test_add_c() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Add],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+        ],
     );
     let c = compile_c(&wasm);
     assert!(c.contains("locals[0]"), "expected locals[0] in: {c}");
@@ -334,12 +648,19 @@ fn test_i32sub_operand_order_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Sub,
+        ],
     );
     let js = compile_js(&wasm);
     // After the fix, the lambda body must compute b-a (lhs minus rhs).
     assert!(js.contains("b-a"), "expected b-a (lhs-rhs) in: {js}");
-    assert!(!js.contains("a-b"), "must NOT contain a-b (rhs-lhs) in: {js}");
+    assert!(
+        !js.contains("a-b"),
+        "must NOT contain a-b (rhs-lhs) in: {js}"
+    );
 }
 
 #[test]
@@ -347,7 +668,11 @@ fn test_i32sub_operand_order_c() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Sub,
+        ],
     );
     let c = compile_c(&wasm);
     // C backend emits casts: (uint32_t)tmp2-(uint32_t)tmp = lhs-rhs.
@@ -367,7 +692,11 @@ fn test_i32divu_operand_order_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32DivU],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivU,
+        ],
     );
     let js = compile_js(&wasm);
     assert!(js.contains("b/a"), "expected b/a in: {js}");
@@ -381,7 +710,11 @@ fn test_i32shl_operand_order_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Shl],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Shl,
+        ],
     );
     let js = compile_js(&wasm);
     assert!(js.contains("b<<a"), "expected b<<a in: {js}");
@@ -394,7 +727,11 @@ fn test_i64sub_operand_order_js() {
     let wasm = make_module(
         &[ValType::I64, ValType::I64],
         &[ValType::I64],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I64Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I64Sub,
+        ],
     );
     let js = compile_js(&wasm);
     assert!(js.contains("b-a"), "expected b-a (lhs-rhs) in: {js}");
@@ -412,12 +749,19 @@ fn test_i32shrs_paren_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32ShrS],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32ShrS,
+        ],
     );
     let js = compile_js(&wasm);
     // Must contain `toUint(` followed eventually by `,32)` where 32 is INSIDE.
     assert!(js.contains("toUint("), "expected toUint( in: {js}");
-    assert!(js.contains(",32)"), "expected ,32) (bit-width inside toUint) in: {js}");
+    assert!(
+        js.contains(",32)"),
+        "expected ,32) (bit-width inside toUint) in: {js}"
+    );
     // The bad pattern was `mask32),32)` — the mask close-paren before the 32 arg.
     assert!(
         !js.contains("mask32),32)"),
@@ -431,7 +775,11 @@ fn test_i64shrs_paren_js() {
     let wasm = make_module(
         &[ValType::I64, ValType::I64],
         &[ValType::I64],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I64ShrS],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I64ShrS,
+        ],
     );
     let js = compile_js(&wasm);
     assert!(js.contains("toUint("), "expected toUint( in: {js}");
@@ -460,7 +808,10 @@ fn test_localset_syntax_js() {
     );
     let js = compile_js(&wasm);
     assert!(js.contains("locals[0]="), "expected locals[0]= in: {js}");
-    assert!(!js.contains("locals[0="), "must NOT contain locals[0= in: {js}");
+    assert!(
+        !js.contains("locals[0="),
+        "must NOT contain locals[0= in: {js}"
+    );
 }
 
 #[test]
@@ -476,7 +827,10 @@ fn test_localset_syntax_c() {
     );
     let c = compile_c(&wasm);
     assert!(c.contains("locals[0]="), "expected locals[0]= in: {c}");
-    assert!(!c.contains("locals[0="), "must NOT contain locals[0= in: {c}");
+    assert!(
+        !c.contains("locals[0="),
+        "must NOT contain locals[0= in: {c}"
+    );
 }
 
 /// LocalTee: must emit `(locals[N]=…)` not `locals[N=…`.
@@ -489,7 +843,10 @@ fn test_localtee_syntax_js() {
     );
     let js = compile_js(&wasm);
     assert!(js.contains("locals[0]="), "expected locals[0]= in: {js}");
-    assert!(!js.contains("locals[0="), "must NOT contain locals[0= in: {js}");
+    assert!(
+        !js.contains("locals[0="),
+        "must NOT contain locals[0= in: {js}"
+    );
 }
 
 #[test]
@@ -501,7 +858,10 @@ fn test_localtee_syntax_c() {
     );
     let c = compile_c(&wasm);
     assert!(c.contains("locals[0]="), "expected locals[0]= in: {c}");
-    assert!(!c.contains("locals[0="), "must NOT contain locals[0= in: {c}");
+    assert!(
+        !c.contains("locals[0="),
+        "must NOT contain locals[0= in: {c}"
+    );
 }
 
 // ---------------------------------------------------------------------------
@@ -537,7 +897,10 @@ fn test_brtable_tmp_assign_js() {
     );
     let js = compile_js(&wasm);
     // The fix emits `tmp=<pop>;` before the comparison loop.
-    assert!(js.contains("tmp="), "expected tmp= assignment from BrTable in: {js}");
+    assert!(
+        js.contains("tmp="),
+        "expected tmp= assignment from BrTable in: {js}"
+    );
 }
 
 #[test]
@@ -560,7 +923,10 @@ fn test_brtable_tmp_assign_c() {
         ],
     );
     let c = compile_c(&wasm);
-    assert!(c.contains("tmp="), "expected tmp= assignment from BrTable in: {c}");
+    assert!(
+        c.contains("tmp="),
+        "expected tmp= assignment from BrTable in: {c}"
+    );
 }
 
 // ---------------------------------------------------------------------------
@@ -585,7 +951,10 @@ fn test_loop_continue_js() {
     );
     let js = compile_js(&wasm);
     // Non-opt mode: `continue l{n}`, never `break l{n}` for a loop target.
-    assert!(js.contains("continue l"), "expected `continue l` for loop back-edge in: {js}");
+    assert!(
+        js.contains("continue l"),
+        "expected `continue l` for loop back-edge in: {js}"
+    );
 }
 
 #[test]
@@ -601,77 +970,239 @@ fn test_loop_continue_c() {
     );
     let c = compile_c(&wasm);
     // C backend uses goto lp_s_{n} for loop back-edges.
-    assert!(c.contains("goto lp_s_"), "expected `goto lp_s_` for loop back-edge in: {c}");
+    assert!(
+        c.contains("goto lp_s_"),
+        "expected `goto lp_s_` for loop back-edge in: {c}"
+    );
 }
 
 // ---------------------------------------------------------------------------
-// Tests — function signature metadata
+// Tests — br_if out of the function body
 // ---------------------------------------------------------------------------
 
-/// The JS backend must emit `__sig` property with correct param/result counts.
+/// A `br_if` out of the function body returns in several statements, all
+/// of which must only run when the branch is taken.
+fn br_if_return_module() -> Vec<u8> {
+    make_module(
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::I32Const(7),
+            Instruction::LocalGet(0),
+            Instruction::BrIf(0),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+        ],
+    )
+}
+
 #[test]
-fn test_js_function_signature() {
-    let wasm = make_module(&[ValType::I32, ValType::I32], &[ValType::I32], &[
-        Instruction::LocalGet(0),
-        Instruction::LocalGet(1),
-        Instruction::I32Add,
-    ]);
-    let js = compile_js(&wasm);
-    assert!(js.contains("params:2"), "expected params:2 in: {js}");
-    assert!(js.contains("rets:1"), "expected rets:1 in: {js}");
+fn test_exec_br_if_return_js() {
+    let js = compile_js(&br_if_return_module());
+    assert_eq!(run_js(&js, &[1]), vec![7]);
+    assert_eq!(run_js(&js, &[0]), vec![8]);
 }
 
-/// The C backend must emit the signature struct with correct values.
 #[test]
-fn test_c_function_signature() {
-    let wasm = make_module(&[ValType::I32, ValType::I32], &[ValType::I32], &[
-        Instruction::LocalGet(0),
-        Instruction::LocalGet(1),
-        Instruction::I32Add,
-    ]);
-    let c = compile_c(&wasm);
-    assert!(c.contains(".params=2"), "expected .params=2 in: {c}");
-    assert!(c.contains(".rets=1"), "expected .rets=1 in: {c}");
+fn test_exec_br_if_return_c() {
+    let c = compile_c(&br_if_return_module());
+    assert_eq!(run_c(&c, 0, &[1], 1), vec![7]);
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![8]);
 }
 
 // ---------------------------------------------------------------------------
-// Tests — i64 constants
+// Tests — sibling block labels
 // ---------------------------------------------------------------------------
 
+/// Two sibling blocks at the same depth, each left early by a `br_if`.
+/// Labels have function scope in C, so the blocks need distinct ones.
+fn sibling_blocks_module() -> Vec<u8> {
+    use Instruction::*;
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    module.section(&functions);
+    let mut exports = ExportSection::new();
+    exports.export("f", ExportKind::Func, 0);
+    module.section(&exports);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([(1u32, ValType::I32)]);
+    for op in [
+        Block(wasm_encoder::BlockType::Empty),
+        LocalGet(0),
+        I32Eqz,
+        BrIf(0),
+        I32Const(10),
+        LocalSet(1),
+        End,
+        Block(wasm_encoder::BlockType::Empty),
+        LocalGet(0),
+        I32Const(2),
+        I32LtU,
+        BrIf(0),
+        LocalGet(1),
+        I32Const(1),
+        I32Add,
+        LocalSet(1),
+        End,
+        LocalGet(1),
+        Return,
+        End,
+    ] {
+        func.instruction(&op);
+    }
+    code.function(&func);
+    module.section(&code);
+    module.finish()
+}
+
 #[test]
-fn test_i64const_js() {
-    let wasm = make_module(&[], &[ValType::I64], &[Instruction::I64Const(0xDEAD_BEEF_u64 as i64)]);
-    let js = compile_js(&wasm);
-    assert!(js.contains("n"), "expected BigInt suffix n in: {js}");
+fn test_exec_sibling_blocks_js() {
+    let js = compile_js(&sibling_blocks_module());
+    assert_eq!(run_js(&js, &[0]), vec![0]);
+    assert_eq!(run_js(&js, &[1]), vec![10]);
+    assert_eq!(run_js(&js, &[5]), vec![11]);
 }
 
 #[test]
-fn test_i64const_c() {
-    let wasm = make_module(&[], &[ValType::I64], &[Instruction::I64Const(0xDEAD_BEEF_u64 as i64)]);
-    let c = compile_c(&wasm);
-    assert!(c.contains("ull"), "expected ull suffix in: {c}");
+fn test_exec_sibling_blocks_c() {
+    let c = compile_c(&sibling_blocks_module());
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![0]);
+    assert_eq!(run_c(&c, 0, &[1], 1), vec![10]);
+    assert_eq!(run_c(&c, 0, &[5], 1), vec![11]);
 }
 
 // ---------------------------------------------------------------------------
-// Execution tests — run the generated code and verify numeric results
+// Tests — function signature metadata
 // ---------------------------------------------------------------------------
 
-/// A constant function returns the right value when executed.
+/// The JS backend must emit `__sig` property with correct param/result counts.
 #[test]
-fn test_exec_const_js() {
-    let wasm = make_module(&[], &[ValType::I32], &[Instruction::I32Const(42)]);
+fn test_js_function_signature() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+        ],
+    );
     let js = compile_js(&wasm);
-    let result = run_js(&js, &[]);
-    assert_eq!(result, vec![42], "I32Const(42) should return 42");
+    assert!(js.contains("params:2"), "expected params:2 in: {js}");
+    assert!(js.contains("rets:1"), "expected rets:1 in: {js}");
 }
 
+/// The C backend must emit the signature struct with correct values.
 #[test]
-fn test_exec_const_c() {
-    let wasm = make_module(&[], &[ValType::I32], &[Instruction::I32Const(42)]);
-    let c = compile_c(&wasm);
-    let result = run_c(&c, 0, &[], 1);
-    assert_eq!(result, vec![42], "I32Const(42) should return 42");
-}
+fn test_c_function_signature() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+        ],
+    );
+    let c = compile_c(&wasm);
+    assert!(c.contains(".params=2"), "expected .params=2 in: {c}");
+    assert!(c.contains(".rets=1"), "expected .rets=1 in: {c}");
+}
+
+/// The C backend sizes each function's operand stack from its
+/// `FnData::max_stack`, which `explicit_traps` raises for its checks.
+#[test]
+fn test_c_stack_size() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivS,
+        ],
+    );
+    let c = compile_c(&wasm);
+    assert!(
+        c.contains("uint64_t stack[2];"),
+        "expected stack[2] in: {c}"
+    );
+    assert!(
+        !c.contains("WASM_STACK_SIZE"),
+        "unexpected WASM_STACK_SIZE in: {c}"
+    );
+
+    let mut backend = CBackend::new(String::new());
+    compile_traps_with(&wasm, &mut backend);
+    let c = backend.out;
+    assert!(
+        c.contains("uint64_t stack[5];"),
+        "expected stack[5] in: {c}"
+    );
+    assert_eq!(run_c(&c, 0, &[7, 2], 1), vec![3]);
+
+    // Optimized mode indexes the stack from 1.
+    let mut backend = CBackend::new(String::new());
+    backend.state.enable_opt(OptState::default);
+    compile_with(&wasm, &mut backend);
+    let c = backend.out;
+    assert!(
+        c.contains("uint64_t stack[3];"),
+        "expected stack[3] in: {c}"
+    );
+    assert_eq!(run_c(&c, 0, &[7, 2], 1), vec![3]);
+}
+
+// ---------------------------------------------------------------------------
+// Tests — i64 constants
+// ---------------------------------------------------------------------------
+
+#[test]
+fn test_i64const_js() {
+    let wasm = make_module(
+        &[],
+        &[ValType::I64],
+        &[Instruction::I64Const(0xDEAD_BEEF_u64 as i64)],
+    );
+    let js = compile_js(&wasm);
+    assert!(js.contains("n"), "expected BigInt suffix n in: {js}");
+}
+
+#[test]
+fn test_i64const_c() {
+    let wasm = make_module(
+        &[],
+        &[ValType::I64],
+        &[Instruction::I64Const(0xDEAD_BEEF_u64 as i64)],
+    );
+    let c = compile_c(&wasm);
+    assert!(c.contains("ull"), "expected ull suffix in: {c}");
+}
+
+// ---------------------------------------------------------------------------
+// Execution tests — run the generated code and verify numeric results
+// ---------------------------------------------------------------------------
+
+/// A constant function returns the right value when executed.
+#[test]
+fn test_exec_const_js() {
+    let wasm = make_module(&[], &[ValType::I32], &[Instruction::I32Const(42)]);
+    let js = compile_js(&wasm);
+    let result = run_js(&js, &[]);
+    assert_eq!(result, vec![42], "I32Const(42) should return 42");
+}
+
+#[test]
+fn test_exec_const_c() {
+    let wasm = make_module(&[], &[ValType::I32], &[Instruction::I32Const(42)]);
+    let c = compile_c(&wasm);
+    let result = run_c(&c, 0, &[], 1);
+    assert_eq!(result, vec![42], "I32Const(42) should return 42");
+}
 
 /// Addition returns the correct sum.
 #[test]
@@ -679,7 +1210,11 @@ fn test_exec_add_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Add],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+        ],
     );
     let js = compile_js(&wasm);
     assert_eq!(run_js(&js, &[5, 3]), vec![8]);
@@ -691,7 +1226,11 @@ fn test_exec_add_c() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Add],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+        ],
     );
     let c = compile_c(&wasm);
     assert_eq!(run_c(&c, 0, &[5, 3], 1), vec![8]);
@@ -705,7 +1244,11 @@ fn test_exec_sub_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Sub,
+        ],
     );
     let js = compile_js(&wasm);
     // 10 - 3 = 7, NOT 3 - 10 = -7
@@ -721,7 +1264,11 @@ fn test_exec_sub_c() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Sub,
+        ],
     );
     let c = compile_c(&wasm);
     assert_eq!(run_c(&c, 0, &[10, 3], 1), vec![7]);
@@ -735,7 +1282,11 @@ fn test_exec_divu_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32DivU],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivU,
+        ],
     );
     let js = compile_js(&wasm);
     // 10 / 2 = 5, NOT 2 / 10 = 0
@@ -747,7 +1298,11 @@ fn test_exec_divu_c() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32DivU],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivU,
+        ],
     );
     let c = compile_c(&wasm);
     assert_eq!(run_c(&c, 0, &[10, 2], 1), vec![5]);
@@ -809,7 +1364,11 @@ fn test_exec_i64sub_js() {
     let wasm = make_module(
         &[ValType::I64, ValType::I64],
         &[ValType::I64],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I64Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I64Sub,
+        ],
     );
     let js = compile_js(&wasm);
     assert_eq!(run_js(&js, &[100, 37]), vec![63]);
@@ -820,7 +1379,11 @@ fn test_exec_i64sub_c() {
     let wasm = make_module(
         &[ValType::I64, ValType::I64],
         &[ValType::I64],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I64Sub],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I64Sub,
+        ],
     );
     let c = compile_c(&wasm);
     assert_eq!(run_c(&c, 0, &[100, 37], 1), vec![63]);
@@ -832,7 +1395,11 @@ fn test_exec_shl_js() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Shl],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Shl,
+        ],
     );
     let js = compile_js(&wasm);
     // 3 << 4 = 48
@@ -844,7 +1411,11 @@ fn test_exec_shl_c() {
     let wasm = make_module(
         &[ValType::I32, ValType::I32],
         &[ValType::I32],
-        &[Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::I32Shl],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Shl,
+        ],
     );
     let c = compile_c(&wasm);
     assert_eq!(run_c(&c, 0, &[3, 4], 1), vec![48]);
@@ -883,8 +1454,16 @@ fn test_exec_brtable_js() {
         ],
     );
     let js = compile_js(&wasm);
-    assert_eq!(run_js(&js, &[0]), vec![20], "selector 0 → target 0 (inner block) → falls to i32.const 20, br 1 → 20");
-    assert_eq!(run_js(&js, &[1]), vec![10], "selector 1 → default (middle block) → falls to i32.const 10 → 10");
+    assert_eq!(
+        run_js(&js, &[0]),
+        vec![20],
+        "selector 0 → target 0 (inner block) → falls to i32.const 20, br 1 → 20"
+    );
+    assert_eq!(
+        run_js(&js, &[1]),
+        vec![10],
+        "selector 1 → default (middle block) → falls to i32.const 10 → 10"
+    );
 }
 
 #[test]
@@ -907,8 +1486,16 @@ fn test_exec_brtable_c() {
         ],
     );
     let c = compile_c(&wasm);
-    assert_eq!(run_c(&c, 0, &[0], 1), vec![20], "selector 0 → target 0 (inner block) → i32.const 20, br 1 → 20");
-    assert_eq!(run_c(&c, 0, &[1], 1), vec![10], "selector 1 → default (middle block) → i32.const 10 → 10");
+    assert_eq!(
+        run_c(&c, 0, &[0], 1),
+        vec![20],
+        "selector 0 → target 0 (inner block) → i32.const 20, br 1 → 20"
+    );
+    assert_eq!(
+        run_c(&c, 0, &[1], 1),
+        vec![10],
+        "selector 1 → default (middle block) → i32.const 10 → 10"
+    );
 }
 
 /// A loop with a counter: counts down from N to 0, returns N total iterations.
@@ -961,18 +1548,18 @@ fn test_exec_loop_counter_js() {
         func.instruction(&Instruction::I32Sub);
         func.instruction(&Instruction::LocalSet(0));
         func.instruction(&Instruction::Br(1)); // br $lp (depth 1 from if = loop)
-        func.instruction(&Instruction::End);   // end if
-        func.instruction(&Instruction::End);   // end loop
+        func.instruction(&Instruction::End); // end if
+        func.instruction(&Instruction::End); // end loop
         func.instruction(&Instruction::LocalGet(1)); // acc
         func.instruction(&Instruction::Return);
-        func.instruction(&Instruction::End);   // end func
+        func.instruction(&Instruction::End); // end func
         code.function(&func);
         module.section(&code);
         module.finish()
     };
     let js = compile_js(&wasm);
-    assert_eq!(run_js(&js, &[0]), vec![0],  "loop(0) → 0 iterations");
-    assert_eq!(run_js(&js, &[5]), vec![5],  "loop(5) → 5 iterations");
+    assert_eq!(run_js(&js, &[0]), vec![0], "loop(0) → 0 iterations");
+    assert_eq!(run_js(&js, &[5]), vec![5], "loop(5) → 5 iterations");
     assert_eq!(run_js(&js, &[10]), vec![10], "loop(10) → 10 iterations");
 }
 
@@ -1019,3 +1606,2730 @@ fn test_exec_loop_counter_c() {
     assert_eq!(run_c(&c, 0, &[10], 1), vec![10]);
 }
 
+// ---------------------------------------------------------------------------
+// Traps
+// ---------------------------------------------------------------------------
+
+/// Assert that running the generated JS threw a `WebAssembly.RuntimeError`.
+fn assert_js_trap((code, out): (String, std::process::Output)) {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        !out.status.success() && stderr.contains("RuntimeError"),
+        "expected a RuntimeError.\nstderr: {stderr}\ncode: {code}"
+    );
+}
+
+/// `unreachable` throws in JS and aborts in C.
+#[test]
+fn test_exec_unreachable_js() {
+    let wasm = make_module(&[], &[ValType::I32], &[Instruction::Unreachable]);
+    assert_js_trap(exec_js(&compile_js(&wasm), &[]));
+}
+
+#[test]
+fn test_exec_unreachable_c() {
+    let wasm = make_module(&[], &[ValType::I32], &[Instruction::Unreachable]);
+    assert!(!exec_c(&compile_c(&wasm), 0, &[], 1).status.success());
+}
+
+/// `explicit_traps` guards integer division against a zero divisor.
+#[test]
+fn test_exec_div_by_zero_js() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivU,
+        ],
+    );
+    let mut backend = JsBackend::new(String::new());
+    compile_traps_with(&wasm, &mut backend);
+    assert_eq!(run_js(&backend.out, &[7, 2]), vec![3]);
+    assert_js_trap(exec_js(&backend.out, &[7, 0]));
+}
+
+#[test]
+fn test_exec_div_by_zero_c() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivU,
+        ],
+    );
+    let mut backend = CBackend::new(String::new());
+    compile_traps_with(&wasm, &mut backend);
+    assert_eq!(run_c(&backend.out, 0, &[7, 2], 1), vec![3]);
+    assert!(!exec_c(&backend.out, 0, &[7, 0], 1).status.success());
+}
+
+/// `explicit_traps` guards signed division against `MIN / -1`.
+#[test]
+fn test_exec_div_overflow_js() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivS,
+        ],
+    );
+    let mut backend = JsBackend::new(String::new());
+    compile_traps_with(&wasm, &mut backend);
+    assert_eq!(run_js(&backend.out, &[9, 2]), vec![4]);
+    assert_js_trap(exec_js(
+        &backend.out,
+        &[i32::MIN as u32 as i64, u32::MAX as i64],
+    ));
+}
+
+#[test]
+fn test_exec_div_overflow_c() {
+    let wasm = make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32DivS,
+        ],
+    );
+    let mut backend = CBackend::new(String::new());
+    compile_traps_with(&wasm, &mut backend);
+    assert_eq!(run_c(&backend.out, 0, &[9, 2], 1), vec![4]);
+    let run = exec_c(
+        &backend.out,
+        0,
+        &[i32::MIN as u32 as u64, u32::MAX as u64],
+        1,
+    );
+    assert!(!run.status.success());
+}
+
+/// Every division and remainder traps on a zero divisor, and signed
+/// division on `MIN / -1`, without `explicit_traps`; the signed remainder
+/// of `MIN` by -1 is zero.
+fn div_cases() -> Vec<(Vec<u8>, [i64; 2], Option<i64>)> {
+    use Instruction::*;
+    let mut cases = Vec::new();
+    for (ty, min, ops) in [
+        (
+            ValType::I32,
+            i32::MIN as u32 as i64,
+            [I32DivU, I32RemU, I32DivS, I32RemS],
+        ),
+        (ValType::I64, i64::MIN, [I64DivU, I64RemU, I64DivS, I64RemS]),
+    ] {
+        let minus_one = if ty == ValType::I32 {
+            u32::MAX as i64
+        } else {
+            -1
+        };
+        for op in ops {
+            let signed = matches!(op, I32DivS | I32RemS | I64DivS | I64RemS);
+            let rem = matches!(op, I32RemU | I32RemS | I64RemU | I64RemS);
+            let wasm = make_module(&[ty, ty], &[ty], &[LocalGet(0), LocalGet(1), op]);
+            cases.push((wasm.clone(), [7, 0], None));
+            if signed {
+                cases.push((wasm, [min, minus_one], rem.then_some(0)));
+            }
+        }
+    }
+    cases
+}
+
+#[test]
+fn test_exec_div_guards_js() {
+    for (wasm, args, result) in div_cases() {
+        let js = compile_js(&wasm);
+        match result {
+            Some(r) => assert_eq!(run_js(&js, &args), vec![r]),
+            None => assert_js_trap(exec_js(&js, &args)),
+        }
+    }
+}
+
+#[test]
+fn test_exec_div_guards_c() {
+    for (wasm, args, result) in div_cases() {
+        let mut backend = CBackend::new(String::new());
+        backend.state.trap_handler = Some("on_trap".into());
+        compile_with(&wasm, &mut backend);
+        let c = format!("static void on_trap(void){{exit(42);}}\n{}", backend.out);
+        let args = args.map(|a| a as u64);
+        match result {
+            Some(r) => assert_eq!(run_c(&c, 0, &args, 1), vec![r as u64]),
+            None => assert_eq!(exec_c(&c, 0, &args, 1).status.code(), Some(42)),
+        }
+    }
+}
+
+/// The C backend calls the configured trap handler instead of `abort()`.
+#[test]
+fn test_c_trap_handler() {
+    let wasm = make_module(&[], &[ValType::I32], &[Instruction::Unreachable]);
+    let mut backend = CBackend::new(String::new());
+    backend.state.trap_handler = Some("on_trap".into());
+    compile_with(&wasm, &mut backend);
+    assert!(backend.out.contains("on_trap()"));
+    let src = format!("static void on_trap(void){{exit(42);}}\n{}", backend.out);
+    assert_eq!(exec_c(&src, 0, &[], 1).status.code(), Some(42));
+}
+
+// ---------------------------------------------------------------------------
+// Parallel compilation
+// ---------------------------------------------------------------------------
+
+/// A module of `n` functions `() -> i32`, function `i` returning `i`.
+fn many_functions(n: u32) -> Vec<u8> {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    for _ in 0..n {
+        functions.function(0);
+    }
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    for i in 0..n {
+        let mut func = Function::new([]);
+        func.instruction(&Instruction::I32Const(i as i32));
+        func.instruction(&Instruction::Return);
+        func.instruction(&Instruction::End);
+        code.function(&func);
+    }
+    module.section(&code);
+    module.finish()
+}
+
+/// Compiling on several threads links the forks back in function order, so
+/// the output matches serial compilation.
+#[test]
+fn test_compile_parallel_js() {
+    let wasm = many_functions(5);
+    let module = BlitzModule::new(&wasm).unwrap();
+    let mut serial = JsBackend::new(String::new());
+    module.compile::<CompileError, _>(&mut serial).unwrap();
+    for threads in [1, 2, 3, 8] {
+        let mut parallel = JsBackend::new(String::new());
+        module
+            .compile_parallel::<CompileError, _>(&mut parallel, NonZeroUsize::new(threads).unwrap())
+            .unwrap();
+        assert_eq!(parallel.out, serial.out, "{threads} threads");
+    }
+    assert_eq!(run_js(&serial.out, &[]), vec![0]);
+}
+
+#[test]
+fn test_compile_parallel_c() {
+    let wasm = many_functions(5);
+    let module = BlitzModule::new(&wasm).unwrap();
+    let mut serial = CBackend::new(String::new());
+    serial.state.trap_handler = Some("on_trap".into());
+    module.compile::<CompileError, _>(&mut serial).unwrap();
+    for threads in [1, 2, 3, 8] {
+        let mut parallel = CBackend::new(String::new());
+        parallel.state.trap_handler = Some("on_trap".into());
+        module
+            .compile_parallel::<CompileError, _>(&mut parallel, NonZeroUsize::new(threads).unwrap())
+            .unwrap();
+        assert_eq!(parallel.out, serial.out, "{threads} threads");
+    }
+}
+
+/// `fn_mach_operators` yields the slice of `mach_operators` belonging to one
+/// function.
+#[test]
+fn test_fn_mach_operators() {
+    let wasm = many_functions(3);
+    let module = BlitzModule::new(&wasm).unwrap();
+    let all = module
+        .mach_operators::<WasmInfo, CompileError>()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let split = (0..3)
+        .flat_map(|i| {
+            module
+                .fn_mach_operators::<WasmInfo, CompileError>(i)
+                .unwrap()
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(format!("{split:?}"), format!("{all:?}"));
+}
+
+// ---------------------------------------------------------------------------
+// Rewriting hooks
+// ---------------------------------------------------------------------------
+
+/// Lowers `i32.sub` to `a + b * -1`, mixing encoded instructions and
+/// operators in the replacement.
+fn lower_sub<'a>(
+    _: &RewriteCx<'_>,
+    op: wasmparser::Operator<'a>,
+) -> Result<Vec<InstructionOrOperator<'a>>, Box<dyn Error>> {
+    Ok(match op {
+        wasmparser::Operator::I32Sub => vec![
+            InstructionOrOperator::Instruction(Instruction::I32Const(-1)),
+            InstructionOrOperator::Operator(wasmparser::Operator::I32Mul),
+            InstructionOrOperator::Instruction(Instruction::I32Add),
+        ],
+        op => vec![InstructionOrOperator::Operator(op)],
+    })
+}
+
+/// Compile `wasm` bytes with any backend, lowering `i32.sub` while the
+/// operator stream is produced, then applying DCE as `compile_with` does.
+fn compile_lowered_with<B: Backend>(wasm: &[u8], backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(module.mach_operators_with::<(), Box<dyn Error>, _>(lower_sub));
+    module.drive(ops, backend).unwrap();
+}
+
+fn sub_module() -> Vec<u8> {
+    make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Sub,
+        ],
+    )
+}
+
+#[test]
+fn test_rewriter_lowering_js() {
+    let mut backend = JsBackend::new(String::new());
+    compile_lowered_with(&sub_module(), &mut backend);
+    assert_eq!(run_js(&backend.out, &[10, 3]), vec![7]);
+    assert_eq!(run_js(&backend.out, &[3, 10]), vec![(-7i32) as u32 as i64]);
+}
+
+#[test]
+fn test_rewriter_lowering_c() {
+    let mut backend = CBackend::new(String::new());
+    compile_lowered_with(&sub_module(), &mut backend);
+    assert_eq!(run_c(&backend.out, 0, &[10, 3], 1), vec![7]);
+    assert_eq!(
+        run_c(&backend.out, 0, &[3, 10], 1)[0] as u32,
+        (-7i32) as u32
+    );
+}
+
+/// The rewriter sees every operator, the trailing `Return` included, with
+/// the function it belongs to and the locals declared before it. The
+/// rewritten stream makes no claims about the rewritten bodies.
+#[test]
+fn test_rewriter_context() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], []);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    functions.function(0);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    for locals in [0, 2] {
+        let mut func = Function::new([(locals, ValType::I64)]);
+        func.instruction(&Instruction::Nop);
+        func.instruction(&Instruction::End);
+        code.function(&func);
+    }
+    module.section(&code);
+    let wasm = module.finish();
+
+    let module = BlitzModule::new(&wasm).unwrap();
+    let mut seen = Vec::new();
+    let ops = module
+        .mach_operators_with::<WasmInfo, CompileError, _>(|cx: &RewriteCx<'_>, op| {
+            assert_eq!(cx.data.max_stack, Some(0));
+            seen.push((cx.id, cx.num_locals, cx.data.params.clone(), op.clone()));
+            Ok(vec![InstructionOrOperator::Operator(op)])
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    for op in &ops {
+        if let MachOperator::StartFn { data, .. } = op {
+            assert_eq!(data.max_stack, None);
+            assert!(data.has_calls && data.has_loops && data.uses_memory);
+        }
+    }
+    let seen = seen
+        .into_iter()
+        .map(|(id, n, params, op)| (id, n, params, format!("{op:?}")))
+        .collect::<Vec<_>>();
+    let i32 = vec![wasmparser::ValType::I32];
+    assert_eq!(
+        seen,
+        [
+            (0, 1, i32.clone(), "Nop".to_string()),
+            (0, 1, i32.clone(), "End".to_string()),
+            (0, 1, i32.clone(), "Return".to_string()),
+            (1, 3, i32.clone(), "Nop".to_string()),
+            (1, 3, i32.clone(), "End".to_string()),
+            (1, 3, i32, "Return".to_string()),
+        ]
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Multi-value blocks
+// ---------------------------------------------------------------------------
+
+/// `(func (param i64 i64) (result i64 i64))` passing both parameters into a
+/// block of the same type, which branches out with them swapped over a
+/// value of its own. The value pushed before the block must survive.
+fn multi_value_block() -> Vec<u8> {
+    make_module(
+        &[ValType::I64, ValType::I64],
+        &[ValType::I64, ValType::I64],
+        &[
+            Instruction::I64Const(100),
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::Block(wasm_encoder::BlockType::FunctionType(0)),
+            Instruction::I64Const(1),
+            Instruction::LocalGet(1),
+            Instruction::LocalGet(0),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::I64Sub,
+        ],
+    )
+}
+
+/// `(func (param $n i64) (result i64))` summing `n..1` in a loop that
+/// carries the accumulator as its parameter, on top of a constant 1000.
+fn loop_params() -> Vec<u8> {
+    use wasm_encoder::BlockType;
+    make_module(
+        &[ValType::I64],
+        &[ValType::I64],
+        &[
+            Instruction::I64Const(1000),
+            Instruction::I64Const(0),
+            Instruction::Block(BlockType::FunctionType(0)),
+            Instruction::Loop(BlockType::FunctionType(0)),
+            Instruction::LocalGet(0),
+            Instruction::I64Eqz,
+            Instruction::BrIf(1), // exit with the accumulator
+            Instruction::LocalGet(0),
+            Instruction::I64Add,
+            Instruction::LocalGet(0),
+            Instruction::I64Const(1),
+            Instruction::I64Sub,
+            Instruction::LocalSet(0),
+            Instruction::Br(0), // back-edge with the accumulator
+            Instruction::End,
+            Instruction::End,
+            Instruction::I64Add,
+        ],
+    )
+}
+
+/// `(func (param $a i64) (result i64))` with an `if` taking a parameter:
+/// `100 + (a == 0 ? 1 : 5 - 2)`, where the `then` arm branches out over
+/// its parameter and a value of its own.
+fn if_params() -> Vec<u8> {
+    make_module(
+        &[ValType::I64],
+        &[ValType::I64],
+        &[
+            Instruction::I64Const(100),
+            Instruction::I64Const(5),
+            Instruction::LocalGet(0),
+            Instruction::I64Eqz,
+            Instruction::If(wasm_encoder::BlockType::FunctionType(0)),
+            Instruction::I64Const(77),
+            Instruction::I64Const(1),
+            Instruction::Br(0),
+            Instruction::Else,
+            Instruction::I64Const(2),
+            Instruction::I64Sub,
+            Instruction::End,
+            Instruction::I64Add,
+        ],
+    )
+}
+
+#[test]
+fn test_exec_multi_value_block_js() {
+    let js = compile_js(&multi_value_block());
+    assert_eq!(run_js(&js, &[3, 10]), vec![100, 7]);
+}
+
+#[test]
+fn test_exec_multi_value_block_c() {
+    let c = compile_c(&multi_value_block());
+    assert_eq!(run_c(&c, 0, &[3, 10], 2), vec![100, 7]);
+}
+
+#[test]
+fn test_exec_loop_params_js() {
+    let js = compile_js(&loop_params());
+    assert_eq!(run_js(&js, &[0]), vec![1000]);
+    assert_eq!(run_js(&js, &[4]), vec![1010]);
+}
+
+#[test]
+fn test_exec_loop_params_c() {
+    let c = compile_c(&loop_params());
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![1000]);
+    assert_eq!(run_c(&c, 0, &[4], 1), vec![1010]);
+}
+
+#[test]
+fn test_exec_if_params_js() {
+    let js = compile_js(&if_params());
+    assert_eq!(run_js(&js, &[0]), vec![101]);
+    assert_eq!(run_js(&js, &[7]), vec![103]);
+}
+
+#[test]
+fn test_exec_if_params_c() {
+    let c = compile_c(&if_params());
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![101]);
+    assert_eq!(run_c(&c, 0, &[7], 1), vec![103]);
+}
+
+// ---------------------------------------------------------------------------
+// Linear memory
+// ---------------------------------------------------------------------------
+
+/// A 32-bit memory followed by a 64-bit one, one page each.
+const TWO_MEMORIES: [MemoryType; 2] = [
+    MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    },
+    MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: true,
+        shared: false,
+        page_size_log2: None,
+    },
+];
+
+fn memarg(memory_index: u32, offset: u64) -> MemArg {
+    MemArg {
+        offset,
+        align: 3,
+        memory_index,
+    }
+}
+
+/// Stores its two arguments at the same address of each memory and
+/// returns their difference, then copies an `i32` -1 from one memory to
+/// the other and returns the 64-bit value around it.
+fn multi_memory() -> Vec<u8> {
+    make_memory_module(
+        &TWO_MEMORIES,
+        &[ValType::I64, ValType::I64],
+        &[ValType::I64, ValType::I64],
+        &[
+            Instruction::I32Const(16),
+            Instruction::LocalGet(0),
+            Instruction::I64Store(memarg(0, 8)),
+            Instruction::I64Const(24),
+            Instruction::LocalGet(1),
+            Instruction::I64Store(memarg(1, 0)),
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 24)),
+            Instruction::I64Const(0),
+            Instruction::I64Load(memarg(1, 24)),
+            Instruction::I64Sub,
+            Instruction::I64Const(40),
+            Instruction::I32Const(-1),
+            Instruction::I32Store(memarg(1, 0)),
+            Instruction::I32Const(48),
+            Instruction::I64Const(40),
+            Instruction::I32Load(memarg(1, 0)),
+            Instruction::I32Store(memarg(0, 0)),
+            Instruction::I32Const(48),
+            Instruction::I64Load(memarg(0, 0)),
+        ],
+    )
+}
+
+/// Loads through a 64-bit address from memory 1 and a 32-bit address at
+/// offset 65528 from memory 0, returning their sum.
+fn memory_bounds() -> Vec<u8> {
+    make_memory_module(
+        &TWO_MEMORIES,
+        &[ValType::I64, ValType::I32],
+        &[ValType::I64],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::I64Load(memarg(1, 0)),
+            Instruction::LocalGet(1),
+            Instruction::I64Load(memarg(0, 65528)),
+            Instruction::I64Add,
+        ],
+    )
+}
+
+/// Stores `0x0123_4567_89ab_cdef` at 0 and reads back its low bytes with
+/// every narrow load, halving the signed 64-bit ones so they fit an `i64`
+/// line of output; then stores narrow values at 8, 9 and 11, and copies
+/// floats through 16 and 24, reading each back as an integer.
+fn narrow_access() -> Vec<u8> {
+    let halve = [Instruction::I64Const(1), Instruction::I64ShrU];
+    let load =
+        |op: fn(MemArg) -> Instruction<'static>| [Instruction::I32Const(0), op(memarg(0, 0))];
+    let mut instrs = vec![
+        Instruction::I32Const(0),
+        Instruction::I64Const(0x0123_4567_89ab_cdef),
+        Instruction::I64Store(memarg(0, 0)),
+    ];
+    instrs.extend(load(Instruction::I32Load8S));
+    instrs.extend(load(Instruction::I32Load8U));
+    instrs.extend(load(Instruction::I32Load16S));
+    instrs.extend(load(Instruction::I32Load16U));
+    instrs.extend(load(Instruction::I64Load8S));
+    instrs.extend(halve.clone());
+    instrs.extend(load(Instruction::I64Load16U));
+    instrs.extend(load(Instruction::I64Load32S));
+    instrs.extend(halve);
+    instrs.extend(load(Instruction::I64Load32U));
+    instrs.extend([
+        Instruction::I32Const(8),
+        Instruction::I32Const(0x1234),
+        Instruction::I32Store8(memarg(0, 0)),
+        Instruction::I32Const(9),
+        Instruction::I64Const(0x5678_9abc),
+        Instruction::I64Store16(memarg(0, 0)),
+        Instruction::I32Const(11),
+        Instruction::I64Const(0x1_dead_beef),
+        Instruction::I64Store32(memarg(0, 0)),
+        Instruction::I32Const(0),
+        Instruction::I64Load(memarg(0, 8)),
+        Instruction::I32Const(16),
+        Instruction::I32Const(0),
+        Instruction::F32Load(memarg(0, 4)),
+        Instruction::F32Store(memarg(0, 0)),
+        Instruction::I32Const(0),
+        Instruction::I32Load(memarg(0, 16)),
+        Instruction::I32Const(24),
+        Instruction::I32Const(0),
+        Instruction::F64Load(memarg(0, 0)),
+        Instruction::F64Store(memarg(0, 0)),
+        Instruction::I32Const(0),
+        Instruction::I64Load(memarg(0, 24)),
+    ]);
+    let results = [
+        [ValType::I32; 4].as_slice(),
+        &[ValType::I64; 4],
+        &[ValType::I64, ValType::I32, ValType::I64],
+    ]
+    .concat();
+    build_module(
+        &Sections {
+            memories: &TWO_MEMORIES[..1],
+            ..Sections::default()
+        },
+        &[],
+        &results,
+        &instrs,
+    )
+}
+
+const NARROW_ACCESS: [u64; 11] = [
+    0xffff_ffef,
+    0xef,
+    0xffff_cdef,
+    0xcdef,
+    0x7fff_ffff_ffff_fff7,
+    0xcdef,
+    0x7fff_ffff_c4d5_e6f7,
+    0x89ab_cdef,
+    0x00de_adbe_ef9a_bc34,
+    0x0123_4567,
+    0x0123_4567_89ab_cdef,
+];
+
+/// Narrow loads extend with their signedness, narrow stores keep the low
+/// bytes, and floats are copied bit for bit.
+#[test]
+fn test_exec_narrow_access_js() {
+    let js = compile_js(&narrow_access());
+    let expected: Vec<i64> = NARROW_ACCESS.iter().map(|&v| v as i64).collect();
+    assert_eq!(run_js(&js, &[]), expected);
+}
+
+#[test]
+fn test_exec_narrow_access_c() {
+    let c = compile_c(&narrow_access());
+    assert_eq!(run_c(&c, 0, &[], 11), NARROW_ACCESS);
+}
+
+/// Memories are separate, and accesses honour their width.
+#[test]
+fn test_exec_multi_memory_js() {
+    let js = compile_js(&multi_memory());
+    assert_eq!(run_js(&js, &[10, 3]), vec![7, 0xffff_ffff]);
+}
+
+#[test]
+fn test_exec_multi_memory_c() {
+    let c = compile_c(&multi_memory());
+    assert_eq!(run_c(&c, 0, &[10, 3], 2), vec![7, 0xffff_ffff]);
+}
+
+/// Accesses past the end of a memory trap, whatever the address width.
+#[test]
+fn test_exec_memory_bounds_js() {
+    let js = compile_js(&memory_bounds());
+    assert_eq!(run_js(&js, &[65528, 0]), vec![0]);
+    assert_js_trap(exec_js(&js, &[65529, 0]));
+    assert_js_trap(exec_js(&js, &[-8, 0]));
+    assert_js_trap(exec_js(&js, &[0, 1]));
+}
+
+#[test]
+fn test_exec_memory_bounds_c() {
+    let c = compile_c(&memory_bounds());
+    assert_eq!(run_c(&c, 0, &[65528, 0], 1), vec![0]);
+    for args in [[65529, 0], [u64::MAX - 7, 0], [0, 1]] {
+        assert!(!exec_c(&c, 0, &args, 1).status.success(), "{args:?}");
+    }
+}
+
+/// A memory index past the end of the memory index space is reported.
+#[test]
+fn test_unknown_memory() {
+    let wasm = make_module(
+        &[],
+        &[ValType::I64],
+        &[Instruction::I32Const(0), Instruction::I64Load(memarg(0, 0))],
+    );
+    for err in [
+        compile_err(&wasm, &mut JsBackend::new(String::new())),
+        compile_err(&wasm, &mut CBackend::new(String::new())),
+    ] {
+        assert!(
+            matches!(
+                err,
+                CompileError::UnknownEntity {
+                    kind: "memory",
+                    index: 0,
+                    info: Some(_)
+                }
+            ),
+            "expected UnknownEntity, got {err:?}"
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Globals
+// ---------------------------------------------------------------------------
+
+/// A mutable `i32` stack pointer, an immutable `i64` initialised with an
+/// extended constant expression, and an immutable `f64`.
+fn globals() -> [(GlobalType, ConstExpr); 3] {
+    let ty = |val_type, mutable| GlobalType {
+        val_type,
+        mutable,
+        shared: false,
+    };
+    [
+        (ty(ValType::I32, true), ConstExpr::i32_const(1024)),
+        (
+            ty(ValType::I64, false),
+            ConstExpr::i64_const(5).with_i64_const(3).with_i64_mul(),
+        ),
+        (ty(ValType::F64, false), ConstExpr::f64_const(1.5.into())),
+    ]
+}
+
+/// Moves the stack pointer down by its argument twice, returning the new
+/// stack pointer and the `i64` global.
+fn stack_pointer() -> Vec<u8> {
+    let globals = globals();
+    let sections = Sections {
+        globals: &globals,
+        ..Sections::default()
+    };
+    let bump = [
+        Instruction::GlobalGet(0),
+        Instruction::LocalGet(0),
+        Instruction::I32Sub,
+        Instruction::GlobalSet(0),
+    ];
+    let instrs = [
+        &bump[..],
+        &bump[..],
+        &[Instruction::GlobalGet(0), Instruction::GlobalGet(1)],
+    ]
+    .concat();
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32, ValType::I64],
+        &instrs,
+    )
+}
+
+/// Globals keep the values they are set to.
+#[test]
+fn test_exec_globals_js() {
+    let js = compile_js(&stack_pointer());
+    assert!(js.contains("const $g2=1.5;"), "{js}");
+    assert_eq!(run_js(&js, &[16]), vec![992, 15]);
+}
+
+#[test]
+fn test_exec_globals_c() {
+    let c = compile_c(&stack_pointer());
+    assert_eq!(run_c(&c, 0, &[16], 2), vec![992, 15]);
+}
+
+/// A global index past the end of the global index space is reported.
+#[test]
+fn test_unknown_global() {
+    let wasm = make_module(&[], &[ValType::I64], &[Instruction::GlobalGet(0)]);
+    for err in [
+        compile_err(&wasm, &mut JsBackend::new(String::new())),
+        compile_err(&wasm, &mut CBackend::new(String::new())),
+    ] {
+        assert!(
+            matches!(
+                err,
+                CompileError::UnknownEntity {
+                    kind: "global",
+                    index: 0,
+                    info: Some(_)
+                }
+            ),
+            "expected UnknownEntity, got {err:?}"
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tables
+// ---------------------------------------------------------------------------
+
+/// A table of `min` function references, growable to `max`.
+fn funcref_table(min: u64, max: Option<u64>) -> TableType {
+    TableType {
+        element_type: RefType::FUNCREF,
+        table64: false,
+        minimum: min,
+        maximum: max,
+        shared: false,
+    }
+}
+
+/// Copies a passive segment of three functions into a table of five, then
+/// calls the slot given by its argument with `21`: slot 0 adds one, slot 1
+/// doubles, slot 2 has the wrong signature, slot 3 is null and slot 5 is
+/// out of bounds.
+fn dispatch() -> Vec<u8> {
+    let tables = [funcref_table(5, None)];
+    let sections = Sections {
+        funcs: &[
+            (
+                &[ValType::I32],
+                &[ValType::I32],
+                &[
+                    Instruction::LocalGet(0),
+                    Instruction::I32Const(1),
+                    Instruction::I32Add,
+                ],
+            ),
+            (
+                &[ValType::I32],
+                &[ValType::I32],
+                &[
+                    Instruction::LocalGet(0),
+                    Instruction::I32Const(2),
+                    Instruction::I32Mul,
+                ],
+            ),
+            (&[], &[ValType::I32], &[Instruction::I32Const(0)]),
+        ],
+        tables: &tables,
+        elements: &[&[1, 2, 3]],
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::I32Const(3),
+            Instruction::TableInit {
+                elem_index: 0,
+                table: 0,
+            },
+            Instruction::I32Const(21),
+            Instruction::LocalGet(0),
+            Instruction::CallIndirect {
+                type_index: 1,
+                table_index: 0,
+            },
+        ],
+    )
+}
+
+/// Grows a table of one slot, growable to four, by its argument, fills slot
+/// 0 with a function, and returns the result of the growth, the new size,
+/// and whether the first and last slots are null.
+fn table_ops() -> Vec<u8> {
+    let tables = [funcref_table(1, Some(4))];
+    let sections = Sections {
+        funcs: &[(&[], &[], &[])],
+        tables: &tables,
+        elements: &[&[1]],
+        ..Sections::default()
+    };
+    let is_null = |slot: &[Instruction<'static>]| {
+        [
+            slot,
+            &[Instruction::TableGet(0), Instruction::RefIsNull][..],
+        ]
+        .concat()
+    };
+    let instrs = [
+        &[
+            Instruction::RefNull(HeapType::FUNC),
+            Instruction::LocalGet(0),
+            Instruction::TableGrow(0),
+            Instruction::TableSize(0),
+            Instruction::I32Const(0),
+            Instruction::RefFunc(1),
+            Instruction::I32Const(1),
+            Instruction::TableFill(0),
+        ][..],
+        &is_null(&[Instruction::I32Const(0)]),
+        &is_null(&[
+            Instruction::TableSize(0),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+        ]),
+    ]
+    .concat();
+    build_module(&sections, &[ValType::I32], &[ValType::I32; 4], &instrs)
+}
+
+/// Initialises a slot from a passive segment after dropping it, which
+/// traps unless no slot is initialised.
+fn elem_drop() -> Vec<u8> {
+    let tables = [funcref_table(1, None)];
+    let sections = Sections {
+        funcs: &[(&[], &[], &[])],
+        tables: &tables,
+        elements: &[&[1]],
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::ElemDrop(0),
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::LocalGet(0),
+            Instruction::TableInit {
+                elem_index: 0,
+                table: 0,
+            },
+            Instruction::I32Const(7),
+        ],
+    )
+}
+
+/// `call_indirect` calls the function in the slot, whatever its type index,
+/// as long as the signature matches.
+#[test]
+fn test_exec_call_indirect_js() {
+    let js = compile_js(&dispatch());
+    assert_eq!(run_js(&js, &[0]), vec![22]);
+    assert_eq!(run_js(&js, &[1]), vec![42]);
+}
+
+#[test]
+fn test_exec_call_indirect_c() {
+    let c = compile_c(&dispatch());
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![22]);
+    assert_eq!(run_c(&c, 0, &[1], 1), vec![42]);
+}
+
+/// `call_indirect` traps on a signature mismatch, a null slot and an index
+/// out of bounds.
+#[test]
+fn test_exec_call_indirect_traps_js() {
+    let js = compile_js(&dispatch());
+    for slot in [2, 3, 5] {
+        assert_js_trap(exec_js(&js, &[slot]));
+    }
+}
+
+#[test]
+fn test_exec_call_indirect_traps_c() {
+    let c = compile_c(&dispatch());
+    for slot in [2, 3, 5] {
+        assert!(!exec_c(&c, 0, &[slot], 1).status.success(), "{slot}");
+    }
+}
+
+/// Tables grow up to their maximum and are filled, read and sized.
+#[test]
+fn test_exec_table_ops_js() {
+    let js = compile_js(&table_ops());
+    assert_eq!(run_js(&js, &[2]), vec![1, 3, 0, 1]);
+    assert_eq!(run_js(&js, &[4]), vec![0xffff_ffff, 1, 0, 0]);
+}
+
+#[test]
+fn test_exec_table_ops_c() {
+    let c = compile_c(&table_ops());
+    assert_eq!(run_c(&c, 0, &[2], 4), vec![1, 3, 0, 1]);
+    assert_eq!(run_c(&c, 0, &[4], 4), vec![0xffff_ffff, 1, 0, 0]);
+}
+
+/// A dropped segment has no items left.
+#[test]
+fn test_exec_elem_drop_js() {
+    let js = compile_js(&elem_drop());
+    assert_eq!(run_js(&js, &[0]), vec![7]);
+    assert_js_trap(exec_js(&js, &[1]));
+}
+
+#[test]
+fn test_exec_elem_drop_c() {
+    let c = compile_c(&elem_drop());
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![7]);
+    assert!(!exec_c(&c, 0, &[1], 1).status.success());
+}
+
+/// A table index past the end of the table index space is reported.
+#[test]
+fn test_unknown_table() {
+    let wasm = make_module(&[], &[ValType::I32], &[Instruction::TableSize(0)]);
+    for err in [
+        compile_err(&wasm, &mut JsBackend::new(String::new())),
+        compile_err(&wasm, &mut CBackend::new(String::new())),
+    ] {
+        assert!(
+            matches!(
+                err,
+                CompileError::UnknownEntity {
+                    kind: "table",
+                    index: 0,
+                    info: Some(_)
+                }
+            ),
+            "expected UnknownEntity, got {err:?}"
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Segments
+// ---------------------------------------------------------------------------
+
+/// Reads back the eight bytes at 8 and at 16 of a memory initialised by an
+/// active segment of ten bytes at 8, then copies as many bytes as its
+/// argument from offset 1 of a passive segment of three to address 100 and
+/// returns the eight bytes there.
+fn data_init() -> Vec<u8> {
+    let sections = Sections {
+        memories: &TWO_MEMORIES[..1],
+        data: &[
+            (Some(8), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]),
+            (None, &[0xaa, 0xbb, 0xcc]),
+        ],
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I64; 3],
+        &[
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 8)),
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 16)),
+            Instruction::I32Const(100),
+            Instruction::I32Const(1),
+            Instruction::LocalGet(0),
+            Instruction::MemoryInit {
+                mem: 0,
+                data_index: 1,
+            },
+            Instruction::I32Const(100),
+            Instruction::I64Load(memarg(0, 0)),
+        ],
+    )
+}
+
+/// Copies as many bytes as its argument from a passive segment after
+/// dropping it, and none from an active one, which traps unless no byte is
+/// copied.
+fn data_drop() -> Vec<u8> {
+    let sections = Sections {
+        memories: &TWO_MEMORIES[..1],
+        data: &[(Some(0), &[1]), (None, &[2])],
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::DataDrop(1),
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::LocalGet(0),
+            Instruction::MemoryInit {
+                mem: 0,
+                data_index: 1,
+            },
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::MemoryInit {
+                mem: 0,
+                data_index: 0,
+            },
+            Instruction::I32Const(7),
+        ],
+    )
+}
+
+/// Calls the slot of a table of three given by its argument with `21`,
+/// slots 1 and 2 being initialised by an active segment to functions that
+/// add one and double.
+fn active_elements() -> Vec<u8> {
+    let tables = [funcref_table(3, None)];
+    let sections = Sections {
+        funcs: &[
+            (
+                &[ValType::I32],
+                &[ValType::I32],
+                &[
+                    Instruction::LocalGet(0),
+                    Instruction::I32Const(1),
+                    Instruction::I32Add,
+                ],
+            ),
+            (
+                &[ValType::I32],
+                &[ValType::I32],
+                &[
+                    Instruction::LocalGet(0),
+                    Instruction::I32Const(2),
+                    Instruction::I32Mul,
+                ],
+            ),
+        ],
+        tables: &tables,
+        active_elements: &[(1, &[1, 2])],
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::I32Const(21),
+            Instruction::LocalGet(0),
+            Instruction::CallIndirect {
+                type_index: 1,
+                table_index: 0,
+            },
+        ],
+    )
+}
+
+/// Initialises the last byte of a page and the one past it.
+fn data_out_of_bounds() -> Vec<u8> {
+    let sections = Sections {
+        memories: &TWO_MEMORIES[..1],
+        data: &[(Some(65535), &[1, 2])],
+        ..Sections::default()
+    };
+    build_module(&sections, &[], &[ValType::I32], &[Instruction::I32Const(0)])
+}
+
+/// Active segments initialise memory, and `memory.init` copies from passive
+/// ones within their bounds.
+#[test]
+fn test_exec_data_init_js() {
+    let js = compile_js(&data_init());
+    assert_eq!(
+        run_js(&js, &[2]),
+        vec![0x0807_0605_0403_0201, 0x0a09, 0xccbb]
+    );
+    assert_js_trap(exec_js(&js, &[3]));
+}
+
+#[test]
+fn test_exec_data_init_c() {
+    let c = compile_c(&data_init());
+    assert_eq!(
+        run_c(&c, 0, &[2], 3),
+        vec![0x0807_0605_0403_0201, 0x0a09, 0xccbb]
+    );
+    assert!(!exec_c(&c, 0, &[3], 3).status.success());
+}
+
+/// Dropped segments, active ones included, have no bytes left.
+#[test]
+fn test_exec_data_drop_js() {
+    let js = compile_js(&data_drop());
+    assert_eq!(run_js(&js, &[0]), vec![7]);
+    assert_js_trap(exec_js(&js, &[1]));
+}
+
+#[test]
+fn test_exec_data_drop_c() {
+    let c = compile_c(&data_drop());
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![7]);
+    assert!(!exec_c(&c, 0, &[1], 1).status.success());
+}
+
+/// Active element segments initialise tables.
+#[test]
+fn test_exec_active_elements_js() {
+    let js = compile_js(&active_elements());
+    assert_eq!(run_js(&js, &[1]), vec![22]);
+    assert_eq!(run_js(&js, &[2]), vec![42]);
+    assert_js_trap(exec_js(&js, &[0]));
+}
+
+#[test]
+fn test_exec_active_elements_c() {
+    let c = compile_c(&active_elements());
+    assert_eq!(run_c(&c, 0, &[1], 1), vec![22]);
+    assert_eq!(run_c(&c, 0, &[2], 1), vec![42]);
+    assert!(!exec_c(&c, 0, &[0], 1).status.success());
+}
+
+/// A segment that does not fit traps on instantiation.
+#[test]
+fn test_exec_data_out_of_bounds_js() {
+    let js = compile_js(&data_out_of_bounds());
+    assert_js_trap(exec_js(&js, &[]));
+}
+
+#[test]
+fn test_exec_data_out_of_bounds_c() {
+    let c = compile_c(&data_out_of_bounds());
+    assert!(!exec_c(&c, 0, &[], 1).status.success());
+}
+
+// ---------------------------------------------------------------------------
+// Bulk memory
+// ---------------------------------------------------------------------------
+
+/// Stores 42 at 0 of a memory of one page that may grow to three, grows it
+/// by its argument and returns the result, the new size, the last eight
+/// bytes of the memory and the eight bytes at 0.
+fn memory_grow() -> Vec<u8> {
+    let memories = [MemoryType {
+        maximum: Some(3),
+        ..TWO_MEMORIES[0]
+    }];
+    let sections = Sections {
+        memories: &memories,
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32, ValType::I32, ValType::I64, ValType::I64],
+        &[
+            Instruction::I32Const(0),
+            Instruction::I64Const(42),
+            Instruction::I64Store(memarg(0, 0)),
+            Instruction::LocalGet(0),
+            Instruction::MemoryGrow(0),
+            Instruction::MemorySize(0),
+            Instruction::MemorySize(0),
+            Instruction::I32Const(16),
+            Instruction::I32Shl,
+            Instruction::I32Const(8),
+            Instruction::I32Sub,
+            Instruction::I64Load(memarg(0, 0)),
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 0)),
+        ],
+    )
+}
+
+/// Fills as many bytes as its third argument at its first with its second,
+/// returning the eight bytes at 0.
+fn memory_fill() -> Vec<u8> {
+    build_module(
+        &Sections {
+            memories: &TWO_MEMORIES[..1],
+            ..Sections::default()
+        },
+        &[ValType::I32; 3],
+        &[ValType::I64],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::LocalGet(2),
+            Instruction::MemoryFill(0),
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 0)),
+        ],
+    )
+}
+
+/// Stores the bytes 1 to 8 at 0, copies as many bytes as its third
+/// argument from its second to its first, and returns the sixteen bytes at
+/// 0.
+fn memory_copy() -> Vec<u8> {
+    build_module(
+        &Sections {
+            memories: &TWO_MEMORIES[..1],
+            ..Sections::default()
+        },
+        &[ValType::I32; 3],
+        &[ValType::I64, ValType::I64],
+        &[
+            Instruction::I32Const(0),
+            Instruction::I64Const(0x0807_0605_0403_0201),
+            Instruction::I64Store(memarg(0, 0)),
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::LocalGet(2),
+            Instruction::MemoryCopy {
+                src_mem: 0,
+                dst_mem: 0,
+            },
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 0)),
+            Instruction::I32Const(0),
+            Instruction::I64Load(memarg(0, 8)),
+        ],
+    )
+}
+
+/// `memory.grow` keeps the contents, zeroes the new pages and fails past
+/// the maximum.
+#[test]
+fn test_exec_memory_grow_js() {
+    let js = compile_js(&memory_grow());
+    assert_eq!(run_js(&js, &[0]), vec![1, 1, 0, 42]);
+    assert_eq!(run_js(&js, &[2]), vec![1, 3, 0, 42]);
+    assert_eq!(run_js(&js, &[3]), vec![0xffff_ffff, 1, 0, 42]);
+}
+
+#[test]
+fn test_exec_memory_grow_c() {
+    let c = compile_c(&memory_grow());
+    assert_eq!(run_c(&c, 0, &[0], 4), vec![1, 1, 0, 42]);
+    assert_eq!(run_c(&c, 0, &[2], 4), vec![1, 3, 0, 42]);
+    assert_eq!(run_c(&c, 0, &[3], 4), vec![0xffff_ffff, 1, 0, 42]);
+}
+
+/// `memory.fill` stores the low byte of its value, and traps when the range
+/// does not fit even if it is empty.
+#[test]
+fn test_exec_memory_fill_js() {
+    let js = compile_js(&memory_fill());
+    assert_eq!(run_js(&js, &[1, 0x1ab, 3]), vec![0xabab_ab00]);
+    assert_eq!(run_js(&js, &[65536, 1, 0]), vec![0]);
+    assert_js_trap(exec_js(&js, &[65535, 1, 2]));
+    assert_js_trap(exec_js(&js, &[65537, 1, 0]));
+}
+
+#[test]
+fn test_exec_memory_fill_c() {
+    let c = compile_c(&memory_fill());
+    assert_eq!(run_c(&c, 0, &[1, 0x1ab, 3], 1), vec![0xabab_ab00]);
+    assert_eq!(run_c(&c, 0, &[65536, 1, 0], 1), vec![0]);
+    for args in [[65535, 1, 2], [65537, 1, 0]] {
+        assert!(!exec_c(&c, 0, &args, 1).status.success(), "{args:?}");
+    }
+}
+
+/// `memory.copy` behaves as if through a temporary buffer when the ranges
+/// overlap in either direction, and traps when either does not fit.
+#[test]
+fn test_exec_memory_copy_js() {
+    let js = compile_js(&memory_copy());
+    assert_eq!(run_js(&js, &[2, 0, 4]), vec![0x0807_0403_0201_0201, 0]);
+    assert_eq!(run_js(&js, &[0, 2, 4]), vec![0x0807_0605_0605_0403, 0]);
+    assert_eq!(
+        run_js(&js, &[5, 0, 8]),
+        vec![0x0302_0105_0403_0201, 0x08_0706_0504]
+    );
+    assert_js_trap(exec_js(&js, &[65535, 0, 2]));
+    assert_js_trap(exec_js(&js, &[0, 65535, 2]));
+}
+
+#[test]
+fn test_exec_memory_copy_c() {
+    let c = compile_c(&memory_copy());
+    assert_eq!(run_c(&c, 0, &[2, 0, 4], 2), vec![0x0807_0403_0201_0201, 0]);
+    assert_eq!(run_c(&c, 0, &[0, 2, 4], 2), vec![0x0807_0605_0605_0403, 0]);
+    assert_eq!(
+        run_c(&c, 0, &[5, 0, 8], 2),
+        vec![0x0302_0105_0403_0201, 0x08_0706_0504]
+    );
+    for args in [[65535, 0, 2], [0, 65535, 2]] {
+        assert!(!exec_c(&c, 0, &args, 2).status.success(), "{args:?}");
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Integers
+// ---------------------------------------------------------------------------
+
+/// Applies `op` to its arguments and returns its result.
+fn int_op(operands: &[ValType], result: ValType, op: Instruction<'static>) -> Vec<u8> {
+    let mut instrs: Vec<Instruction<'static>> = (0..operands.len() as u32)
+        .map(Instruction::LocalGet)
+        .collect();
+    instrs.push(op);
+    make_module(operands, &[result], &instrs)
+}
+
+/// An operator, its operand and result types, its arguments and the result
+/// expected from them.
+type IntCase = (
+    Instruction<'static>,
+    &'static [ValType],
+    ValType,
+    Vec<u64>,
+    u64,
+);
+
+/// Cases whose arguments and results stay below 2^63, as `run_js` reads
+/// results as `i64`.
+fn int_cases() -> Vec<IntCase> {
+    use Instruction::*;
+    use ValType::{I32, I64};
+    const I32_2: &[ValType] = &[I32, I32];
+    const I64_2: &[ValType] = &[I64, I64];
+    vec![
+        (I32Sub, I32_2, I32, vec![1, 2], 0xffff_ffff),
+        (
+            I32And,
+            I32_2,
+            I32,
+            vec![0xff00_ff00, 0x0ff0_0ff0],
+            0x0f00_0f00,
+        ),
+        (I32Or, I32_2, I32, vec![0xff00_0000, 0x00ff], 0xff00_00ff),
+        (
+            I32Xor,
+            I32_2,
+            I32,
+            vec![0xffff_0000, 0xff00_ff00],
+            0x00ff_ff00,
+        ),
+        (I32Eq, I32_2, I32, vec![5, 5], 1),
+        (I32Ne, I32_2, I32, vec![5, 5], 0),
+        (I32LtS, I32_2, I32, vec![0xffff_ffff, 1], 1),
+        (I32LtU, I32_2, I32, vec![0xffff_ffff, 1], 0),
+        (I32GtS, I32_2, I32, vec![1, 0xffff_ffff], 1),
+        (I32GtU, I32_2, I32, vec![1, 0xffff_ffff], 0),
+        (I32LeS, I32_2, I32, vec![0x8000_0000, 0x7fff_ffff], 1),
+        (I32LeU, I32_2, I32, vec![0x8000_0000, 0x7fff_ffff], 0),
+        (I32GeS, I32_2, I32, vec![3, 3], 1),
+        (I32GeU, I32_2, I32, vec![2, 3], 0),
+        (I32Clz, &[I32], I32, vec![1], 31),
+        (I32Clz, &[I32], I32, vec![0], 32),
+        (I32Ctz, &[I32], I32, vec![0x8000_0000], 31),
+        (I32Ctz, &[I32], I32, vec![0], 32),
+        (I32Popcnt, &[I32], I32, vec![0xffff_ffff], 32),
+        (I32Popcnt, &[I32], I32, vec![0x0f0f], 8),
+        (I32Shl, I32_2, I32, vec![1, 33], 2),
+        (I32ShrU, I32_2, I32, vec![0x8000_0000, 35], 0x1000_0000),
+        (I32ShrS, I32_2, I32, vec![0x8000_0000, 4], 0xf800_0000),
+        (I32ShrS, I32_2, I32, vec![0x4000_0000, 36], 0x0400_0000),
+        (I32Rotl, I32_2, I32, vec![0x8000_0001, 1], 3),
+        (I32Rotr, I32_2, I32, vec![1, 1], 0x8000_0000),
+        (I32Rotl, I32_2, I32, vec![0x1234_5678, 32], 0x1234_5678),
+        (I32Rotr, I32_2, I32, vec![0x1234_5678, 0], 0x1234_5678),
+        (I32DivU, I32_2, I32, vec![7, 2], 3),
+        (I32RemU, I32_2, I32, vec![7, 4], 3),
+        (I32DivS, I32_2, I32, vec![0xffff_fff9, 2], 0xffff_fffd),
+        (I32RemS, I32_2, I32, vec![0xffff_fff9, 2], 0xffff_ffff),
+        (I32RemS, I32_2, I32, vec![0x8000_0000, 0xffff_ffff], 0),
+        (I32WrapI64, &[I64], I32, vec![0x1_2345_6789], 0x2345_6789),
+        (I64ExtendI32U, &[I32], I64, vec![0xffff_ffff], 0xffff_ffff),
+        (I64ExtendI32S, &[I32], I64, vec![0x7fff_ffff], 0x7fff_ffff),
+        (I32Extend8S, &[I32], I32, vec![0x80], 0xffff_ff80),
+        (I32Extend8S, &[I32], I32, vec![0x1ff], 0xffff_ffff),
+        (I32Extend16S, &[I32], I32, vec![0x1_7fff], 0x7fff),
+        (I64Extend8S, &[I64], I64, vec![0x17f], 0x7f),
+        (I64Extend16S, &[I64], I64, vec![0x1_7fff], 0x7fff),
+        (I64Extend32S, &[I64], I64, vec![0x1_7fff_ffff], 0x7fff_ffff),
+        (
+            I64And,
+            I64_2,
+            I64,
+            vec![0x0ff0_0000_0000, 0xff00_0000_0000],
+            0x0f00_0000_0000,
+        ),
+        (I64Or, I64_2, I64, vec![0x1_0000_0000, 1], 0x1_0000_0001),
+        (
+            I64Xor,
+            I64_2,
+            I64,
+            vec![0x3_0000_0000, 0x1_0000_0000],
+            0x2_0000_0000,
+        ),
+        (I64Eq, I64_2, I32, vec![0x1_0000_0000, 0], 0),
+        (I64LtS, I64_2, I32, vec![1, 2], 1),
+        (I64GtU, I64_2, I32, vec![1, 2], 0),
+        (I64LeS, I64_2, I32, vec![5, 5], 1),
+        (I64GeU, I64_2, I32, vec![0x1_0000_0000, 1], 1),
+        (I64Clz, &[I64], I64, vec![1], 63),
+        (I64Clz, &[I64], I64, vec![0], 64),
+        (I64Ctz, &[I64], I64, vec![0x1_0000_0000], 32),
+        (I64Ctz, &[I64], I64, vec![0], 64),
+        (I64Popcnt, &[I64], I64, vec![i64::MAX as u64], 63),
+        (I64ShrS, I64_2, I64, vec![0x4000_0000_0000_0000, 62], 1),
+        (I64Rotl, I64_2, I64, vec![0x4000_0000_0000_0000, 2], 1),
+        (I64Rotr, I64_2, I64, vec![2, 65], 1),
+        (I64Rotl, I64_2, I64, vec![0x1234_5678, 64], 0x1234_5678),
+        (I64Rotr, I64_2, I64, vec![0x1234_5678, 0], 0x1234_5678),
+        (I64DivS, I64_2, I64, vec![7, 2], 3),
+        (I64RemU, I64_2, I64, vec![7, 4], 3),
+    ]
+}
+
+/// Cases with arguments or results past 2^63, which only `run_c` can pass.
+fn wide_int_cases() -> Vec<IntCase> {
+    use Instruction::*;
+    use ValType::{I32, I64};
+    const I64_2: &[ValType] = &[I64, I64];
+    let neg = |v: i64| v as u64;
+    vec![
+        (
+            I64ExtendI32S,
+            &[I32],
+            I64,
+            vec![0x8000_0000],
+            neg(-0x8000_0000),
+        ),
+        (I64Extend8S, &[I64], I64, vec![0x80], neg(-0x80)),
+        (I64LtS, I64_2, I32, vec![u64::MAX, 0], 1),
+        (I64LtU, I64_2, I32, vec![u64::MAX, 0], 0),
+        (I64GeS, I64_2, I32, vec![u64::MAX, 0], 0),
+        (I64ShrS, I64_2, I64, vec![1 << 63, 63], u64::MAX),
+        (I64Rotl, I64_2, I64, vec![1 << 63, 1], 1),
+        (I64Clz, &[I64], I64, vec![u64::MAX], 0),
+        (I64Popcnt, &[I64], I64, vec![u64::MAX], 64),
+        (I64DivS, I64_2, I64, vec![neg(-7), 2], neg(-3)),
+        (I64RemS, I64_2, I64, vec![neg(-7), 2], neg(-1)),
+        (I64RemS, I64_2, I64, vec![1 << 63, u64::MAX], 0),
+        (I64DivU, I64_2, I64, vec![u64::MAX, 1 << 63], 1),
+    ]
+}
+
+/// Integer and sign-extension operators wrap, compare, count and extend as
+/// WebAssembly does.
+#[test]
+fn test_exec_ints_js() {
+    for (op, operands, result, args, expected) in int_cases() {
+        let js = compile_js(&int_op(operands, result, op.clone()));
+        let args: Vec<i64> = args.iter().map(|&a| a as i64).collect();
+        assert_eq!(run_js(&js, &args), vec![expected as i64], "{op:?} {args:?}");
+    }
+}
+
+#[test]
+fn test_exec_ints_c() {
+    for (op, operands, result, args, expected) in int_cases().into_iter().chain(wide_int_cases()) {
+        let c = compile_c(&int_op(operands, result, op.clone()));
+        assert_eq!(run_c(&c, 0, &args, 1), vec![expected], "{op:?} {args:?}");
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Floats
+// ---------------------------------------------------------------------------
+
+/// Applies `op` to its arguments, read as the bits of a float where
+/// `operands` has one, and returns its result, as bits if it is a float.
+fn float_op(operands: &[ValType], result: ValType, op: Instruction<'static>) -> Vec<u8> {
+    let bits = |ty: ValType| match ty {
+        ValType::F32 => ValType::I32,
+        ValType::F64 => ValType::I64,
+        ty => ty,
+    };
+    let params: Vec<ValType> = operands.iter().map(|&ty| bits(ty)).collect();
+    let mut instrs = Vec::new();
+    for (i, &ty) in operands.iter().enumerate() {
+        instrs.push(Instruction::LocalGet(i as u32));
+        match ty {
+            ValType::F32 => instrs.push(Instruction::F32ReinterpretI32),
+            ValType::F64 => instrs.push(Instruction::F64ReinterpretI64),
+            _ => {}
+        }
+    }
+    instrs.push(op);
+    match result {
+        ValType::F32 => instrs.push(Instruction::I32ReinterpretF32),
+        ValType::F64 => instrs.push(Instruction::I64ReinterpretF64),
+        _ => {}
+    }
+    make_module(&params, &[bits(result)], &instrs)
+}
+
+/// Bits of an f32 argument or result.
+fn f32_bits(v: f32) -> u64 {
+    v.to_bits() as u64
+}
+
+/// An operator, its operand and result types, its arguments and the result
+/// expected from them.
+type FloatCase = (
+    Instruction<'static>,
+    &'static [ValType],
+    ValType,
+    Vec<u64>,
+    u64,
+);
+
+/// Cases whose arguments and results stay below 2^63, as `run_js` reads
+/// results as `i64`.
+fn float_cases() -> Vec<FloatCase> {
+    use Instruction::*;
+    use ValType::{F32, F64, I32, I64};
+    let f = f32_bits;
+    let d = f64::to_bits;
+    let nan = f(f32::NAN);
+    vec![
+        (F32Add, &[F32, F32], F32, vec![f(1.5), f(2.25)], f(3.75)),
+        (F32Sub, &[F32, F32], F32, vec![f(1.0), f(3.0)], f(-2.0)),
+        (
+            F32Mul,
+            &[F32, F32],
+            F32,
+            vec![f(1e30), f(1e30)],
+            f(f32::INFINITY),
+        ),
+        (F32Div, &[F32, F32], F32, vec![f(1.0), f(3.0)], f(1.0 / 3.0)),
+        (F32Sqrt, &[F32], F32, vec![f(2.0)], f(2f32.sqrt())),
+        (F32Min, &[F32, F32], F32, vec![f(-0.0), f(0.0)], f(-0.0)),
+        (F32Min, &[F32, F32], F32, vec![f(0.0), f(-0.0)], f(-0.0)),
+        (F32Max, &[F32, F32], F32, vec![f(-0.0), f(0.0)], f(0.0)),
+        (F32Min, &[F32, F32], F32, vec![f(1.0), nan], nan),
+        (F32Max, &[F32, F32], F32, vec![nan, f(1.0)], nan),
+        (F32Nearest, &[F32], F32, vec![f(2.5)], f(2.0)),
+        (F32Nearest, &[F32], F32, vec![f(3.5)], f(4.0)),
+        (F32Nearest, &[F32], F32, vec![f(-0.5)], f(-0.0)),
+        (F32Nearest, &[F32], F32, vec![f(-1.5)], f(-2.0)),
+        (F32Ceil, &[F32], F32, vec![f(-0.5)], f(-0.0)),
+        (F32Floor, &[F32], F32, vec![f(-0.5)], f(-1.0)),
+        (F32Trunc, &[F32], F32, vec![f(-1.7)], f(-1.0)),
+        (F32Abs, &[F32], F32, vec![f(-3.0)], f(3.0)),
+        (F32Neg, &[F32], F32, vec![f(3.0)], f(-3.0)),
+        (
+            F32Copysign,
+            &[F32, F32],
+            F32,
+            vec![f(1.0), f(-2.0)],
+            f(-1.0),
+        ),
+        (F64Add, &[F64, F64], F64, vec![d(0.1), d(0.2)], d(0.1 + 0.2)),
+        (F64Div, &[F64, F64], F64, vec![d(1.0), d(3.0)], d(1.0 / 3.0)),
+        (F64Nearest, &[F64], F64, vec![d(2.5)], d(2.0)),
+        (F64Abs, &[F64], F64, vec![d(-0.5)], d(0.5)),
+        (F32Lt, &[F32, F32], I32, vec![f(1.0), f(2.0)], 1),
+        (F32Lt, &[F32, F32], I32, vec![f(2.0), f(1.0)], 0),
+        (F32Ge, &[F32, F32], I32, vec![f(2.0), f(1.0)], 1),
+        (F32Eq, &[F32, F32], I32, vec![nan, nan], 0),
+        (F32Ne, &[F32, F32], I32, vec![nan, nan], 1),
+        (F32Eq, &[F32, F32], I32, vec![f(0.0), f(-0.0)], 1),
+        (F64Le, &[F64, F64], I32, vec![d(1.0), d(1.0)], 1),
+        (F64Gt, &[F64, F64], I32, vec![d(f64::NAN), d(1.0)], 0),
+        (I32TruncF32S, &[F32], I32, vec![f(-1.5)], 0xffff_ffff),
+        (I32TruncF32U, &[F32], I32, vec![f(-0.5)], 0),
+        (
+            I32TruncF64S,
+            &[F64],
+            I32,
+            vec![d(-2147483648.9)],
+            0x8000_0000,
+        ),
+        (
+            I32TruncF64U,
+            &[F64],
+            I32,
+            vec![d(4294967295.9)],
+            0xffff_ffff,
+        ),
+        (
+            I64TruncF64S,
+            &[F64],
+            I64,
+            vec![d(1e15)],
+            1_000_000_000_000_000,
+        ),
+        (I32TruncSatF32S, &[F32], I32, vec![f(3e9)], 0x7fff_ffff),
+        (I32TruncSatF32S, &[F32], I32, vec![f(-3e9)], 0x8000_0000),
+        (I32TruncSatF32S, &[F32], I32, vec![nan], 0),
+        (I32TruncSatF32U, &[F32], I32, vec![f(-5.0)], 0),
+        (
+            I32TruncSatF64U,
+            &[F64],
+            I32,
+            vec![d(f64::INFINITY)],
+            0xffff_ffff,
+        ),
+        (I64TruncSatF64S, &[F64], I64, vec![d(1e19)], i64::MAX as u64),
+        (F32ConvertI32S, &[I32], F32, vec![0xffff_ffff], f(-1.0)),
+        (
+            F32ConvertI32U,
+            &[I32],
+            F32,
+            vec![0xffff_ffff],
+            f(4294967296.0),
+        ),
+        // 2^53 + 2^29 + 1 rounds up; rounding to f64 first would round down.
+        (
+            F32ConvertI64S,
+            &[I64],
+            F32,
+            vec![0x0020_0000_2000_0001],
+            0x5a00_0001,
+        ),
+        (
+            F64ConvertI32U,
+            &[I32],
+            F64,
+            vec![0xffff_ffff],
+            d(4294967295.0),
+        ),
+        (F64PromoteF32, &[F32], F64, vec![f(1.5)], d(1.5)),
+        (F32DemoteF64, &[F64], F32, vec![d(0.1)], f(0.1)),
+    ]
+}
+
+/// Conversions to integers that trap: on NaN and past either bound.
+fn float_traps() -> Vec<FloatCase> {
+    use Instruction::*;
+    use ValType::{F32, F64, I32, I64};
+    let f = f32_bits;
+    let d = f64::to_bits;
+    vec![
+        (I32TruncF32S, &[F32], I32, vec![f(f32::NAN)], 0),
+        (I32TruncF32S, &[F32], I32, vec![f(2147483648.0)], 0),
+        (I32TruncF32U, &[F32], I32, vec![f(-1.0)], 0),
+        (I32TruncF64S, &[F64], I32, vec![d(-2147483649.0)], 0),
+        (I64TruncF64S, &[F64], I64, vec![d(9223372036854775808.0)], 0),
+        (I64TruncF32U, &[F32], I64, vec![f(f32::INFINITY)], 0),
+    ]
+}
+
+/// Float arithmetic, comparisons and conversions round, order zeroes and
+/// propagate NaN as WebAssembly does.
+#[test]
+fn test_exec_floats_js() {
+    for (op, operands, result, args, expected) in float_cases() {
+        let js = compile_js(&float_op(operands, result, op.clone()));
+        let args: Vec<i64> = args.iter().map(|&a| a as i64).collect();
+        assert_eq!(run_js(&js, &args), vec![expected as i64], "{op:?} {args:?}");
+    }
+}
+
+#[test]
+fn test_exec_floats_c() {
+    for (op, operands, result, args, expected) in float_cases() {
+        let c = compile_c(&float_op(operands, result, op.clone()));
+        assert_eq!(run_c(&c, 0, &args, 1), vec![expected], "{op:?} {args:?}");
+    }
+}
+
+/// Conversions to integers trap on NaN and out-of-range values.
+#[test]
+fn test_exec_float_traps_js() {
+    for (op, operands, result, args, _) in float_traps() {
+        let js = compile_js(&float_op(operands, result, op));
+        let args: Vec<i64> = args.iter().map(|&a| a as i64).collect();
+        assert_js_trap(exec_js(&js, &args));
+    }
+}
+
+#[test]
+fn test_exec_float_traps_c() {
+    for (op, operands, result, args, _) in float_traps() {
+        let c = compile_c(&float_op(operands, result, op.clone()));
+        assert!(!exec_c(&c, 0, &args, 1).status.success(), "{op:?}");
+    }
+}
+
+/// With canonical NaNs, arithmetic producing a NaN produces the canonical
+/// one, whatever its operands.
+#[test]
+fn test_exec_canonical_nans_c() {
+    let wasm = float_op(
+        &[ValType::F32, ValType::F32],
+        ValType::F32,
+        Instruction::F32Add,
+    );
+    let mut backend = CBackend::new(String::new());
+    backend.state.nans = Nans::Canonical;
+    compile_with(&wasm, &mut backend);
+    let args = [0xffa0_0000, f32_bits(1.0)];
+    assert_eq!(run_c(&backend.out, 0, &args, 1), vec![0x7fc0_0000]);
+}
+
+/// Forks canonicalise NaNs like the backend they come from, so parallel
+/// compilation matches serial compilation.
+#[test]
+fn test_compile_parallel_canonical_nans_c() {
+    let wasm = float_op(
+        &[ValType::F32, ValType::F32],
+        ValType::F32,
+        Instruction::F32Add,
+    );
+    let module = BlitzModule::new(&wasm).unwrap();
+    let mut serial = CBackend::new(String::new());
+    serial.state.nans = Nans::Canonical;
+    module.compile::<CompileError, _>(&mut serial).unwrap();
+    for threads in [1, 2] {
+        let mut parallel = CBackend::new(String::new());
+        parallel.state.nans = Nans::Canonical;
+        module
+            .compile_parallel::<CompileError, _>(&mut parallel, NonZeroUsize::new(threads).unwrap())
+            .unwrap();
+        assert_eq!(parallel.out, serial.out, "{threads} threads");
+    }
+}
+
+/// Every float operator other than a constant, load, store or
+/// reinterpretation.
+fn routine_ops() -> Vec<FloatOp> {
+    use Instruction::*;
+    [
+        F32Abs,
+        F32Neg,
+        F32Copysign,
+        F64Abs,
+        F64Neg,
+        F64Copysign,
+        F32Ceil,
+        F32Floor,
+        F32Trunc,
+        F32Nearest,
+        F32Sqrt,
+        F32Add,
+        F32Sub,
+        F32Mul,
+        F32Div,
+        F32Min,
+        F32Max,
+        F64Ceil,
+        F64Floor,
+        F64Trunc,
+        F64Nearest,
+        F64Sqrt,
+        F64Add,
+        F64Sub,
+        F64Mul,
+        F64Div,
+        F64Min,
+        F64Max,
+        F32Eq,
+        F32Ne,
+        F32Lt,
+        F32Gt,
+        F32Le,
+        F32Ge,
+        F64Eq,
+        F64Ne,
+        F64Lt,
+        F64Gt,
+        F64Le,
+        F64Ge,
+        I32TruncF32S,
+        I32TruncF32U,
+        I32TruncF64S,
+        I32TruncF64U,
+        I64TruncF32S,
+        I64TruncF32U,
+        I64TruncF64S,
+        I64TruncF64U,
+        I32TruncSatF32S,
+        I32TruncSatF32U,
+        I32TruncSatF64S,
+        I32TruncSatF64U,
+        I64TruncSatF32S,
+        I64TruncSatF32U,
+        I64TruncSatF64S,
+        I64TruncSatF64U,
+        F32ConvertI32S,
+        F32ConvertI32U,
+        F32ConvertI64S,
+        F32ConvertI64U,
+        F64ConvertI32S,
+        F64ConvertI32U,
+        F64ConvertI64S,
+        F64ConvertI64U,
+        F32DemoteF64,
+        F64PromoteF32,
+    ]
+    .iter()
+    .map(|op| FloatOp::of(op).unwrap())
+    .collect()
+}
+
+/// What `float` produces from `args` on the host, with NaN results
+/// canonical, or `None` if it traps.
+fn host_float(float: &FloatOp, args: &[u64]) -> Option<u64> {
+    use wasmparser::ValType::{F32, I32, I64};
+    let s = |i: usize| f32::from_bits(args[i] as u32);
+    let d = |i: usize| f64::from_bits(args[i]);
+    let canon_s = |v: f32| match v.is_nan() {
+        true => 0x7fc0_0000,
+        false => v.to_bits() as u64,
+    };
+    let canon_d = |v: f64| match v.is_nan() {
+        true => 0x7ff8_0000_0000_0000,
+        false => v.to_bits(),
+    };
+    // WebAssembly orders -0 below +0 and propagates NaN
+    let min_max =
+        |x: f64, y: f64, xb: u64, yb: u64, max: bool| match (x.is_nan() || y.is_nan(), x == y) {
+            (true, _) => None,
+            (false, true) => Some(if max { xb & yb } else { xb | yb }),
+            (false, false) => Some(if (x < y) != max { xb } else { yb }),
+        };
+    let op = float.name.split_once('_').unwrap().1;
+    let bits = match (float.kind, float.params[0]) {
+        (FloatKind::Sign, F32) => match op {
+            "abs" => args[0] & 0x7fff_ffff,
+            "neg" => args[0] ^ 0x8000_0000,
+            _ => args[0] & 0x7fff_ffff | args[1] & 0x8000_0000,
+        },
+        (FloatKind::Sign, _) => match op {
+            "abs" => args[0] & !(1 << 63),
+            "neg" => args[0] ^ 1 << 63,
+            _ => args[0] & !(1 << 63) | args[1] & 1 << 63,
+        },
+        (FloatKind::Compare, ty) => {
+            let (x, y) = match ty {
+                F32 => (s(0) as f64, s(1) as f64),
+                _ => (d(0), d(1)),
+            };
+            (match op {
+                "eq" => x == y,
+                "ne" => x != y,
+                "lt" => x < y,
+                "gt" => x > y,
+                "le" => x <= y,
+                _ => x >= y,
+            }) as u64
+        }
+        (FloatKind::Trunc | FloatKind::TruncSat, ty) => {
+            let x = match ty {
+                F32 => s(0) as f64,
+                _ => d(0),
+            };
+            let signed = op.ends_with("_s");
+            let width = if float.result == I64 { 64 } else { 32 };
+            let (lo, hi) = match signed {
+                true => (-(2f64.powi(width - 1)), 2f64.powi(width - 1)),
+                false => (0.0, 2f64.powi(width)),
+            };
+            if float.kind == FloatKind::Trunc && (x.is_nan() || !(lo..hi).contains(&x.trunc())) {
+                return None;
+            }
+            match (width, signed) {
+                (32, true) => x as i32 as u32 as u64,
+                (32, false) => x as u32 as u64,
+                (_, true) => x as i64 as u64,
+                (_, false) => x as u64,
+            }
+        }
+        (_, I32 | I64) => {
+            let signed = op.ends_with("_s");
+            let x = match (float.params[0], signed) {
+                (I32, true) => args[0] as i32 as f64,
+                (I32, false) => args[0] as u32 as f64,
+                (_, true) => {
+                    return Some(match float.result {
+                        F32 => canon_s(args[0] as i64 as f32),
+                        _ => canon_d(args[0] as i64 as f64),
+                    });
+                }
+                (_, false) => {
+                    return Some(match float.result {
+                        F32 => canon_s(args[0] as f32),
+                        _ => canon_d(args[0] as f64),
+                    });
+                }
+            };
+            match float.result {
+                F32 => canon_s(x as f32),
+                _ => canon_d(x),
+            }
+        }
+        (_, F32) => match op {
+            "promote_f32" => canon_d(s(0) as f64),
+            "min" | "max" => min_max(s(0) as f64, s(1) as f64, args[0], args[1], op == "max")
+                .unwrap_or(0x7fc0_0000),
+            _ => canon_s(match op {
+                "add" => s(0) + s(1),
+                "sub" => s(0) - s(1),
+                "mul" => s(0) * s(1),
+                "div" => s(0) / s(1),
+                "sqrt" => s(0).sqrt(),
+                "ceil" => s(0).ceil(),
+                "floor" => s(0).floor(),
+                "trunc" => s(0).trunc(),
+                _ => s(0).round_ties_even(),
+            }),
+        },
+        (_, _) => match op {
+            "demote_f64" => canon_s(d(0) as f32),
+            "min" | "max" => {
+                min_max(d(0), d(1), args[0], args[1], op == "max").unwrap_or(0x7ff8_0000_0000_0000)
+            }
+            _ => canon_d(match op {
+                "add" => d(0) + d(1),
+                "sub" => d(0) - d(1),
+                "mul" => d(0) * d(1),
+                "div" => d(0) / d(1),
+                "sqrt" => d(0).sqrt(),
+                "ceil" => d(0).ceil(),
+                "floor" => d(0).floor(),
+                "trunc" => d(0).trunc(),
+                _ => d(0).round_ties_even(),
+            }),
+        },
+    };
+    Some(bits)
+}
+
+/// Arguments of type `ty` for the routine tests: edge cases, then `n`
+/// pseudo-random values drawn from `next`, half with exponents near those
+/// of integers in range of a conversion.
+fn float_args(ty: wasmparser::ValType, n: usize, next: &mut impl FnMut() -> u64) -> Vec<u64> {
+    use wasmparser::ValType::{F32, F64, I32};
+    let (mant, exp_bits) = match ty {
+        F32 => (23, 8),
+        F64 => (52, 11),
+        I32 => {
+            let mut args = vec![
+                0,
+                1,
+                0xffff_ffff,
+                0x8000_0000,
+                0x7fff_ffff,
+                0x0100_0001,
+                0xffff_ff81,
+            ];
+            args.extend((0..n).map(|_| next() >> (32 + next() % 32)));
+            return args;
+        }
+        _ => {
+            let mut args = vec![
+                0,
+                1,
+                u64::MAX,
+                1 << 63,
+                i64::MAX as u64,
+                0x0020_0000_2000_0001,
+                (1 << 53) + 1,
+                0xffff_ffff_ffff_ff81,
+            ];
+            args.extend((0..n).map(|_| next() >> (next() % 64)));
+            return args;
+        }
+    };
+    let sign = 1u64 << (mant + exp_bits);
+    let bias = (1u64 << (exp_bits - 1)) - 1;
+    let frac = (1u64 << mant) - 1;
+    let inf = ((1u64 << exp_bits) - 1) << mant;
+    let one = bias << mant;
+    let magnitudes = [
+        0,
+        1,
+        frac,
+        frac + 1,
+        one,
+        one | 1 << (mant - 1),
+        one - (1 << mant),
+        (one - (2 << mant)) | (1 << (mant - 1)),
+        (one + (1 << mant)) | (1 << (mant - 1)),
+        (one + (3 << mant)) | (1 << (mant - 1)),
+        (bias + mant as u64) << mant | 1,
+        (bias + 31) << mant,
+        (bias + 32) << mant,
+        (bias + 63) << mant,
+        (bias + 64) << mant,
+        inf - 1,
+        inf,
+        inf | 1 << (mant - 1),
+        inf | 1,
+    ];
+    let mut args: Vec<u64> = magnitudes.iter().flat_map(|&m| [m, m | sign]).collect();
+    for i in 0..n {
+        let r = next();
+        args.push(match i % 2 {
+            0 => r & (sign | inf | frac),
+            _ => r & (sign | frac) | (bias - 40 + r % 110) << mant,
+        });
+    }
+    args
+}
+
+/// The routines software floating point builds from integer operators agree
+/// bit for bit with the host, rounding, ordering zeroes and producing the
+/// canonical NaN as WebAssembly does; compiled by the C backend.
+#[test]
+fn test_exec_softfloat_c() {
+    let ops = routine_ops();
+    let wasm = softfloat::module(&ops);
+    wasmparser::Validator::new().validate_all(&wasm).unwrap();
+    let c = compile_c(&wasm);
+    let mut header = String::new();
+    header.header(&BlitzModule::new(&wasm).unwrap()).unwrap();
+
+    let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let (mut host, mut main_body, mut expected) = (String::new(), String::new(), Vec::new());
+    for (i, float) in ops.iter().enumerate() {
+        let first = float_args(float.params[0], 400, &mut next);
+        let cases: Vec<Vec<u64>> = match float.params.len() {
+            1 => first.iter().map(|&x| vec![x]).collect(),
+            _ => {
+                let second = float_args(float.params[1], 400, &mut next);
+                let mut cases: Vec<Vec<u64>> = first[..38]
+                    .iter()
+                    .flat_map(|&x| second[..38].iter().map(move |&y| vec![x, y]))
+                    .collect();
+                cases.extend(
+                    first[38..]
+                        .iter()
+                        .zip(&second[38..])
+                        .map(|(&x, &y)| vec![x, y]),
+                );
+                // nearly cancelling operands
+                let sign = match float.params[0] {
+                    wasmparser::ValType::F32 => 1 << 31,
+                    _ => 1 << 63,
+                };
+                cases.extend(first[38..].iter().flat_map(|&x| {
+                    let y = x.wrapping_add(next() % 5).wrapping_sub(2);
+                    [vec![x, y], vec![x, y ^ sign]]
+                }));
+                cases
+            }
+        };
+        let cases: Vec<Vec<u64>> = cases
+            .into_iter()
+            .filter_map(|args| Some((host_float(float, &args)?, args)))
+            .map(|(result, args)| {
+                expected.push(format!("{} {result}", float.name));
+                args
+            })
+            .collect();
+        let n = float.params.len();
+        host.push_str(&format!("static const uint64_t v{i}[][{n}]={{"));
+        for args in &cases {
+            let args: Vec<String> = args.iter().map(|a| format!("{a}ull")).collect();
+            host.push_str(&format!("{{{}}},", args.join(",")));
+        }
+        host.push_str("};\n");
+        main_body.push_str(&format!(
+            "for(size_t i=0;i<sizeof v{i}/sizeof*v{i};i++){{uint64_t a[{n}];memcpy(a,v{i}[i],sizeof a);\
+             printf(\"{name} %llu\\n\",(unsigned long long){name}(a)[0]);}}",
+            name = float.name
+        ));
+    }
+    let lines = run_c_linked(&c, &header, &host, &format!("blitz_init();{main_body}"));
+    assert_eq!(lines.len(), expected.len());
+    for (i, (line, expected)) in lines.iter().zip(&expected).enumerate() {
+        assert_eq!(line, expected, "case {i}");
+    }
+}
+
+/// The trapping conversions among the routines trap on NaN and past either
+/// bound.
+#[test]
+fn test_exec_softfloat_traps_c() {
+    for (op, _, _, args, _) in float_traps() {
+        let float = FloatOp::of(&op).unwrap();
+        let c = compile_c(&softfloat::module(&[float]));
+        assert!(!exec_c(&c, 0, &args, 1).status.success(), "{op:?} {args:?}");
+    }
+}
+
+/// Exports a memory, a global and a function whose name is not an
+/// identifier; the start function stores to the memory and the global.
+fn exported() -> Vec<u8> {
+    let sections = Sections {
+        memories: &TWO_MEMORIES[..1],
+        globals: &[(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            },
+            ConstExpr::i32_const(0),
+        )],
+        funcs: &[
+            (
+                &[],
+                &[],
+                &[
+                    Instruction::I32Const(0),
+                    Instruction::I64Const(7),
+                    Instruction::I64Store(memarg(0, 0)),
+                    Instruction::I32Const(42),
+                    Instruction::GlobalSet(0),
+                ],
+            ),
+            (
+                &[ValType::I32],
+                &[ValType::I32],
+                &[
+                    Instruction::LocalGet(0),
+                    Instruction::I32Const(1),
+                    Instruction::I32Add,
+                ],
+            ),
+        ],
+        exports: &[
+            ("memory", ExportKind::Memory, 0),
+            ("counter", ExportKind::Global, 0),
+            ("add-one", ExportKind::Func, 2),
+        ],
+        start: Some(1),
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[],
+        &[ValType::I32],
+        &[Instruction::GlobalGet(0)],
+    )
+}
+
+/// The start function runs when the module is instantiated, and exports are
+/// exported from the ES module under their own names.
+#[test]
+fn test_exec_exports_js() {
+    let js = compile_js(&exported());
+    let lines = run_js_importer(
+        &js,
+        "for(const v of [m.f(),m.counter,m.memory.getBigUint64(0,true),m[\"add-one\"](41n)])console.log(String(v));",
+    );
+    assert_eq!(lines, ["42", "42", "7", "42"]);
+}
+
+/// The start function runs in `blitz_init`, and exports are reachable
+/// through the header under their symbols.
+#[test]
+fn test_exec_exports_c() {
+    let wasm = exported();
+    let c = compile_c(&wasm);
+    let mut header = String::new();
+    header.header(&BlitzModule::new(&wasm).unwrap()).unwrap();
+    assert!(header.contains("blitz_xadd_2done"), "in: {header}");
+
+    let lines = run_c_linked(
+        &c,
+        &header,
+        "",
+        "blitz_init();uint64_t a[1]={41};uint64_t v;memcpy(&v,memory->data,8);\
+         printf(\"%llu %llu %llu %llu\\n\",(unsigned long long)f(a)[0],(unsigned long long)*counter,\
+         (unsigned long long)v,(unsigned long long)blitz_xadd_2done(a)[0]);",
+    );
+    assert_eq!(lines, ["42 42 7 42"]);
+}
+
+/// Imports `env.add` and `env.missing`; `f` adds one to its argument with
+/// `add`, and the exported `boom` calls `missing`.
+fn importing() -> Vec<u8> {
+    let sections = Sections {
+        imports: &[
+            ("env", "add", &[ValType::I32, ValType::I32], &[ValType::I32]),
+            ("env", "missing", &[], &[]),
+        ],
+        funcs: &[(&[], &[], &[Instruction::Call(1)])],
+        exports: &[("boom", ExportKind::Func, 3)],
+        ..Sections::default()
+    };
+    build_module(
+        &sections,
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            Instruction::LocalGet(0),
+            Instruction::I32Const(1),
+            Instruction::Call(0),
+        ],
+    )
+}
+
+/// Leaves imports named `missing` unbound and binds the others to the host
+/// function of the same name provided under the module it holds.
+struct HostModule(String);
+
+impl Resolve for HostModule {
+    fn resolve<'a>(&self, import: &FuncImport<'a>) -> Binding<'a> {
+        match import.name {
+            "missing" => Binding::Trap,
+            name => Binding::Host {
+                module: Cow::Owned(self.0.clone()),
+                name: Cow::Borrowed(name),
+            },
+        }
+    }
+}
+
+/// Calls to imports reach the host function they are bound to, and calls to
+/// unbound imports trap.
+#[test]
+fn test_exec_imports_js() {
+    let seq = TEMP_SEQ.fetch_add(1, Ordering::Relaxed);
+    let host =
+        std::env::temp_dir().join(format!("blitz_e2e_{}_{seq}_host.mjs", std::process::id()));
+    std::fs::write(&host, "export const add=(a,b)=>a+b;").unwrap();
+
+    let mut backend = JsBackend::new(String::new());
+    backend.state.imports = Imports::new(HostModule(format!("file://{}", host.display())));
+    compile_with(&importing(), &mut backend);
+    let lines = run_js_importer(
+        &backend.out,
+        "console.log(String(m.f(41n)));try{m.boom();}catch(e){console.log(e instanceof WebAssembly.RuntimeError);}",
+    );
+    let _ = std::fs::remove_file(&host);
+    assert_eq!(lines, ["42", "true"]);
+}
+
+#[test]
+fn test_exec_imports_c() {
+    let wasm = importing();
+    let mut backend = CBackend::new(String::new());
+    backend.state.imports = Imports::new(HostModule(String::new()));
+    compile_with(&wasm, &mut backend);
+    let c = backend.out;
+    assert!(!c.contains("missing"), "unbound import declared in: {c}");
+    let mut header = String::new();
+    header.header(&BlitzModule::new(&wasm).unwrap()).unwrap();
+
+    let lines = run_c_linked(
+        &c,
+        &header,
+        "uint64_t*add(uint64_t*restrict args){static uint64_t r[1];r[0]=(uint32_t)(args[0]+args[1]);return r;}",
+        "blitz_init();uint64_t a[1]={41};printf(\"%llu\\n\",(unsigned long long)f(a)[0]);",
+    );
+    assert_eq!(lines, ["42"]);
+}
+
+// ---------------------------------------------------------------------------
+// Constant folding
+// ---------------------------------------------------------------------------
+
+/// `(42 + x) / 3`, computing 42 through a local, arms and branches on
+/// constant conditions, then counting the local down to zero in a loop and
+/// adding it, so a constant kept past the loop would show.
+fn const_fold_module() -> Vec<u8> {
+    use Instruction::*;
+    make_module(
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+        &[
+            I32Const(6),
+            I32Const(7),
+            I32Mul,
+            LocalSet(1),
+            I32Const(0),
+            If(wasm_encoder::BlockType::Empty),
+            Unreachable,
+            End,
+            I32Const(1),
+            If(wasm_encoder::BlockType::Result(ValType::I32)),
+            LocalGet(1),
+            Else,
+            Unreachable,
+            End,
+            LocalGet(0),
+            I32Add,
+            I32Const(3),
+            I32DivU,
+            LocalGet(1),
+            LocalGet(1),
+            I32Ne,
+            BrIf(0),
+            Block(wasm_encoder::BlockType::Empty),
+            Block(wasm_encoder::BlockType::Empty),
+            I32Const(1),
+            BrTable(Cow::Borrowed(&[0]), 1),
+            End,
+            Unreachable,
+            End,
+            Loop(wasm_encoder::BlockType::Empty),
+            LocalGet(1),
+            I32Const(1),
+            I32Sub,
+            LocalTee(1),
+            BrIf(0),
+            End,
+            LocalGet(1),
+            I32Add,
+        ],
+    )
+}
+
+#[test]
+fn test_exec_const_fold_js() {
+    let mut backend = JsBackend::new(String::new());
+    compile_fold_with(&const_fold_module(), &mut backend);
+    assert_eq!(run_js(&backend.out, &[9, 0]), vec![17]);
+    assert_eq!(run_js(&backend.out, &[0, 5]), vec![14]);
+}
+
+#[test]
+fn test_exec_const_fold_c() {
+    let mut backend = CBackend::new(String::new());
+    compile_fold_with(&const_fold_module(), &mut backend);
+    assert_eq!(run_c(&backend.out, 0, &[9, 0], 1), vec![17]);
+    assert_eq!(run_c(&backend.out, 0, &[0, 5], 1), vec![14]);
+}
+
+/// Folding keeps the operators that would trap, and their traps.
+#[test]
+fn test_exec_const_fold_traps_js() {
+    use Instruction::*;
+    for op in [I32DivU, I32DivS, I32RemU] {
+        let wasm = make_module(&[], &[ValType::I32], &[I32Const(1), I32Const(0), op]);
+        let mut backend = JsBackend::new(String::new());
+        compile_fold_with(&wasm, &mut backend);
+        assert_js_trap(exec_js(&backend.out, &[]));
+    }
+}
+
+#[test]
+fn test_exec_const_fold_traps_c() {
+    use Instruction::*;
+    for op in [I32DivU, I32DivS, I32RemU] {
+        let wasm = make_module(&[], &[ValType::I32], &[I32Const(1), I32Const(0), op]);
+        let mut backend = CBackend::new(String::new());
+        compile_fold_with(&wasm, &mut backend);
+        assert!(!exec_c(&backend.out, 0, &[], 1).status.success());
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Peephole rules
+// ---------------------------------------------------------------------------
+
+/// `x * 2`, through identities, a store and load of the same local and a
+/// double negation of a branch condition, or 7 if that is zero.
+fn peephole_module() -> Vec<u8> {
+    use Instruction::*;
+    make_module(
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            LocalGet(0),
+            I32Const(2),
+            I32Mul,
+            I32Const(0),
+            I32Add,
+            I32Const(-1),
+            I32And,
+            LocalSet(0),
+            LocalGet(0),
+            LocalGet(0),
+            I32Eqz,
+            I32Eqz,
+            BrIf(0),
+            I32Const(7),
+        ],
+    )
+}
+
+/// Rules registered on top of the default ones.
+fn peephole_rules() -> Rules {
+    let mut rules = Rules::default();
+    rules.push(peephole_rule!("double": [I32Const(2), I32Mul] => [I32Const(1), I32Shl]));
+    rules
+}
+
+#[test]
+fn test_exec_peephole_js() {
+    let mut backend = JsBackend::new(String::new());
+    compile_peephole_with(&peephole_module(), &peephole_rules(), &mut backend);
+    assert_eq!(run_js(&backend.out, &[5]), vec![10]);
+    assert_eq!(run_js(&backend.out, &[0]), vec![7]);
+}
+
+#[test]
+fn test_exec_peephole_c() {
+    let mut backend = CBackend::new(String::new());
+    compile_peephole_with(&peephole_module(), &peephole_rules(), &mut backend);
+    assert_eq!(run_c(&backend.out, 0, &[5], 1), vec![10]);
+    assert_eq!(run_c(&backend.out, 0, &[0], 1), vec![7]);
+}
+
+// ---------------------------------------------------------------------------
+// Inlining
+// ---------------------------------------------------------------------------
+
+/// `|x - 3| + |x - 4|`, through two inlined calls of a function returning
+/// from within an `if`.
+fn inline_module() -> Vec<u8> {
+    use Instruction::*;
+    let dist: &[Instruction<'_>] = &[
+        LocalGet(0),
+        LocalGet(1),
+        I32GtU,
+        If(wasm_encoder::BlockType::Empty),
+        LocalGet(0),
+        LocalGet(1),
+        I32Sub,
+        Return,
+        End,
+        LocalGet(1),
+        LocalGet(0),
+        I32Sub,
+    ];
+    build_module(
+        &Sections {
+            funcs: &[(&[ValType::I32, ValType::I32], &[ValType::I32], dist)],
+            ..Sections::default()
+        },
+        &[ValType::I32],
+        &[ValType::I32],
+        &[
+            LocalGet(0),
+            I32Const(3),
+            Call(1),
+            LocalGet(0),
+            I32Const(4),
+            Call(1),
+            I32Add,
+        ],
+    )
+}
+
+#[test]
+fn test_exec_inline_js() {
+    let mut backend = JsBackend::new(String::new());
+    compile_inline_with(&inline_module(), &mut backend);
+    assert_eq!(run_js(&backend.out, &[10]), vec![13]);
+    assert_eq!(run_js(&backend.out, &[0]), vec![7]);
+    assert_eq!(run_js(&backend.out, &[3]), vec![1]);
+}
+
+#[test]
+fn test_exec_inline_c() {
+    let mut backend = CBackend::new(String::new());
+    compile_inline_with(&inline_module(), &mut backend);
+    assert_eq!(run_c(&backend.out, 0, &[10], 1), vec![13]);
+    assert_eq!(run_c(&backend.out, 0, &[0], 1), vec![7]);
+    assert_eq!(run_c(&backend.out, 0, &[3], 1), vec![1]);
+}
+
+/// `f(x) = x + 5` and `g(x) = 2x`, with a function and a global that
+/// nothing reaches: the dead function calls `g` and stores to the dead
+/// global, which comes before the one `f` reads.
+fn prune_module() -> Vec<u8> {
+    use Instruction::*;
+    let ty = GlobalType {
+        val_type: ValType::I32,
+        mutable: true,
+        shared: false,
+    };
+    build_module(
+        &Sections {
+            globals: &[
+                (ty, ConstExpr::i32_const(100)),
+                (ty, ConstExpr::i32_const(5)),
+            ],
+            funcs: &[
+                (&[ValType::I32], &[], &[LocalGet(0), Call(2), GlobalSet(0)]),
+                (
+                    &[ValType::I32],
+                    &[ValType::I32],
+                    &[LocalGet(0), LocalGet(0), I32Add],
+                ),
+            ],
+            exports: &[("g", ExportKind::Func, 2)],
+            ..Sections::default()
+        },
+        &[ValType::I32],
+        &[ValType::I32],
+        &[LocalGet(0), GlobalGet(1), I32Add],
+    )
+}
+
+#[test]
+fn test_exec_prune_js() {
+    let wasm = prune_wasm(&prune_module());
+    let module = BlitzModule::new(&wasm).unwrap();
+    assert_eq!((module.funcs.len(), module.globals.len()), (2, 1));
+    let lines = run_js_importer(
+        &compile_js(&wasm),
+        "for(const v of [m.f(10n),m.f(0n),m.g(10n)])console.log(String(v));",
+    );
+    assert_eq!(lines, ["15", "5", "20"]);
+}
+
+#[test]
+fn test_exec_prune_c() {
+    let c = compile_c(&prune_wasm(&prune_module()));
+    assert_eq!(run_c(&c, 0, &[10], 1), vec![15]);
+    assert_eq!(run_c(&c, 0, &[0], 1), vec![5]);
+    assert_eq!(run_c(&c, 1, &[10], 1), vec![20]);
+}
+
+/// Compile `wasm` bytes with any backend after coalescing locals with
+/// `coalesce_locals`, then applying DCE.
+fn compile_coalesce_with<B: Backend>(wasm: &[u8], backend: &mut B)
+where
+    B::Error: Error + 'static,
+{
+    let module = BlitzModule::new(wasm).unwrap();
+    let ops = dce_pass!(coalesce_locals(
+        module.mach_operators::<(), Box<dyn Error>>()
+    ));
+    module.drive(ops, backend).unwrap();
+}
+
+/// `2 * (n + (n - 1) + ... + 1)`, with a counter that can reuse the slot
+/// of `n`, two temporaries that can reuse it after the loop, and a dead
+/// store. Only the parameter and the sum need a slot.
+fn coalesce_module() -> Vec<u8> {
+    use Instruction::*;
+    use wasm_encoder::BlockType;
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    module.section(&functions);
+    let mut exports = ExportSection::new();
+    exports.export("f", ExportKind::Func, 0);
+    module.section(&exports);
+    let mut code = CodeSection::new();
+    // Locals: the sum (1), the counter (2) and two temporaries (3, 4).
+    let mut func = Function::new([(4u32, ValType::I32)]);
+    for op in [
+        LocalGet(0),
+        LocalSet(2),
+        Block(BlockType::Empty),
+        Loop(BlockType::Empty),
+        LocalGet(2),
+        I32Eqz,
+        BrIf(1),
+        LocalGet(1),
+        LocalGet(2),
+        I32Add,
+        LocalSet(1),
+        LocalGet(2),
+        I32Const(1),
+        I32Sub,
+        LocalSet(2),
+        Br(0),
+        End,
+        End,
+        LocalGet(1),
+        LocalSet(3),
+        LocalGet(3),
+        I32Const(2),
+        I32Mul,
+        LocalSet(4),
+        I32Const(9),
+        LocalSet(2),
+        LocalGet(4),
+        Return,
+        End,
+    ] {
+        func.instruction(&op);
+    }
+    code.function(&func);
+    module.section(&code);
+    module.finish()
+}
+
+#[test]
+fn test_exec_coalesce_js() {
+    let mut backend = JsBackend::new(String::new());
+    compile_coalesce_with(&coalesce_module(), &mut backend);
+    assert!(!backend.out.contains("locals[2]"), "in: {}", backend.out);
+    assert_eq!(run_js(&backend.out, &[4]), vec![20]);
+    assert_eq!(run_js(&backend.out, &[0]), vec![0]);
+    assert_eq!(run_js(&backend.out, &[10]), vec![110]);
+}
+
+#[test]
+fn test_exec_coalesce_c() {
+    let mut backend = CBackend::new(String::new());
+    compile_coalesce_with(&coalesce_module(), &mut backend);
+    assert!(!backend.out.contains("locals[2]"), "in: {}", backend.out);
+    assert_eq!(run_c(&backend.out, 0, &[4], 1), vec![20]);
+    assert_eq!(run_c(&backend.out, 0, &[0], 1), vec![0]);
+    assert_eq!(run_c(&backend.out, 0, &[10], 1), vec![110]);
+}
+
+// ---------------------------------------------------------------------------
+// Error reporting
+// ---------------------------------------------------------------------------
+
+/// An operator the backends do not implement is reported by name, with the
+/// offset of the operator in the input.
+#[test]
+fn test_unsupported_operator() {
+    let wasm = make_module(&[], &[], &[Instruction::AtomicFence]);
+    for err in [
+        compile_err(&wasm, &mut JsBackend::new(String::new())),
+        compile_err(&wasm, &mut CBackend::new(String::new())),
+    ] {
+        let CompileError::UnsupportedOperator { name, info } = &err else {
+            panic!("expected UnsupportedOperator, got {err:?}");
+        };
+        assert_eq!(name, "AtomicFence");
+        assert!(info.is_some(), "expected an offset in: {err}");
+    }
+}
+
+/// A branch deeper than the control stack is a malformed control stack
+/// rather than a panic.
+#[test]
+fn test_malformed_br() {
+    let wasm = make_module(&[], &[], &[Instruction::Br(3)]);
+    for err in [
+        compile_err(&wasm, &mut JsBackend::new(String::new())),
+        compile_err(&wasm, &mut CBackend::new(String::new())),
+    ] {
+        assert!(
+            matches!(err, CompileError::MalformedControl { info: Some(_) }),
+            "expected MalformedControl, got {err:?}"
+        );
+    }
+}
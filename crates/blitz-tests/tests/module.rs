@@ -0,0 +1,1359 @@
+//! Tests for the whole-module front end in `blitz-common`.
+//!
+//! Each test assembles a module with `wasm-encoder`, parses it with
+//! `Module::new` and checks the exposed index spaces and operator streams.
+
+use portal_solutions_blitz_common::{
+    CompileError, MachOperator,
+    coverage::{COVERAGE, Coverage, Group, Support},
+    export::{Exported, Symbol},
+    global::ConstValue,
+    import::{Binding, FuncImport, Imports},
+    liveness::Liveness,
+    memory::{DataMode, LinearMemory},
+    module::Module as BlitzModule,
+    ops::WasmInfo,
+    passes::{InlineBudget, coalesce_locals, const_fold, explicit_traps, inline, prune},
+    peephole::{Rules, peephole},
+    peephole_rule,
+    reach::Reach,
+    table::ElemMode,
+    typed::{BlockArity, Typed},
+    validate::Validated,
+    wasm_encoder::{
+        self, BlockType, CodeSection, ConstExpr, DataCountSection, DataSection, ElementSection,
+        Elements, EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection,
+        GlobalType, HeapType, ImportSection, Instruction, MemArg, MemorySection, MemoryType,
+        Module, RefType, StartSection, TableSection, TableType, TagKind, TagSection, TagType,
+        TypeSection, ValType,
+    },
+    wasmparser::{self, BinaryReaderError, ExternalKind, WasmFeatures},
+};
+use portal_solutions_blitz_reencode::{ReencodeExt, prune::Pruner, tracker::MachTracker};
+use wasm_encoder::reencode::RoundtripReencoder;
+
+/// A module touching every section the front end models: one imported
+/// function, two defined functions, a memory, a global, a data segment, an
+/// export and a start function.
+fn full_module() -> Vec<u8> {
+    let mut module = Module::new();
+
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], []);
+    types.ty().function([], [ValType::I32]);
+    types.ty().function([], []);
+    module.section(&types);
+
+    let mut imports = ImportSection::new();
+    imports.import("env", "log", EntityType::Function(0));
+    module.section(&imports);
+
+    let mut functions = FunctionSection::new();
+    functions.function(1);
+    functions.function(2);
+    module.section(&functions);
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: Some(2),
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    module.section(&memories);
+
+    let mut globals = GlobalSection::new();
+    globals.global(
+        GlobalType {
+            val_type: ValType::I32,
+            mutable: true,
+            shared: false,
+        },
+        &ConstExpr::i32_const(7),
+    );
+    module.section(&globals);
+
+    let mut exports = ExportSection::new();
+    exports.export("get", ExportKind::Func, 1);
+    module.section(&exports);
+
+    module.section(&StartSection { function_index: 2 });
+
+    let mut code = CodeSection::new();
+    let mut func = Function::new([(1, ValType::I64)]);
+    func.instruction(&Instruction::GlobalGet(0));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    let mut start = Function::new([]);
+    start.instruction(&Instruction::Call(1));
+    start.instruction(&Instruction::Drop);
+    start.instruction(&Instruction::End);
+    code.function(&start);
+    module.section(&code);
+
+    let mut data = DataSection::new();
+    data.active(0, &ConstExpr::i32_const(16), b"hi".iter().copied());
+    module.section(&data);
+
+    module.finish()
+}
+
+#[test]
+fn test_module_index_spaces() {
+    let wasm = full_module();
+    let m = BlitzModule::new(&wasm).unwrap();
+
+    assert_eq!(m.types.len(), 3);
+    assert_eq!(m.func_imports, [("env", "log")]);
+    assert_eq!(m.num_func_imports(), 1);
+    // Imports come first in the function index space.
+    assert_eq!(m.funcs, [0, 1, 2]);
+    assert_eq!(m.bodies.len(), 2);
+    assert_eq!(m.func_type(1).results(), [wasmparser::ValType::I32]);
+
+    assert_eq!(m.memories.len(), 1);
+    assert_eq!(m.memories[0].initial, 1);
+    assert_eq!(m.memories[0].maximum, Some(2));
+
+    assert_eq!(m.globals.len(), 1);
+    assert!(m.globals[0].mutable);
+    assert_eq!(m.global_inits.len(), 1);
+
+    assert_eq!(m.data.len(), 1);
+    assert_eq!(m.data[0].data, b"hi");
+
+    assert_eq!(m.exports.len(), 1);
+    assert_eq!(m.exports[0].name, "get");
+    assert_eq!(m.exports[0].kind, ExternalKind::Func);
+    assert_eq!(m.start, Some(2));
+}
+
+/// `FnData` carries the signature, the flattened locals, the operand-stack
+/// bound and what the body contains; `explicit_traps` keeps it accurate.
+#[test]
+fn test_module_fn_data() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], []);
+    types
+        .ty()
+        .function([ValType::I32, ValType::I64], [ValType::I32]);
+    module.section(&types);
+    let mut imports = ImportSection::new();
+    imports.import("env", "f", EntityType::Function(0));
+    module.section(&imports);
+    let mut functions = FunctionSection::new();
+    functions.function(1);
+    module.section(&functions);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    module.section(&memories);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([(2, ValType::F32), (1, ValType::I64)]);
+    func.instruction(&Instruction::I32Const(0));
+    func.instruction(&Instruction::I32Load(MemArg {
+        offset: 0,
+        align: 2,
+        memory_index: 0,
+    }));
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::I32Const(1));
+    func.instruction(&Instruction::I32Add);
+    func.instruction(&Instruction::I32Add);
+    func.instruction(&Instruction::Call(0));
+    func.instruction(&Instruction::Loop(BlockType::Empty));
+    func.instruction(&Instruction::End);
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let fn_data = |ops: Vec<MachOperator<'_, ()>>| match &ops[0] {
+        MachOperator::StartFn { data, .. } => data.clone(),
+        op => panic!("expected StartFn, got {op:?}"),
+    };
+    use wasmparser::ValType::{F32, F64, I32, I64};
+
+    let data = fn_data(
+        m.mach_operators::<(), BinaryReaderError>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap(),
+    );
+    assert_eq!(data.params, [I32, I64]);
+    assert_eq!(data.results, [I32]);
+    assert_eq!(data.locals, [F32, F32, I64]);
+    assert_eq!(data.max_stack, Some(3));
+    assert!(data.has_calls);
+    assert!(data.has_loops);
+    assert!(data.uses_memory);
+
+    let data = fn_data(
+        explicit_traps(m.mach_operators::<(), BinaryReaderError>())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap(),
+    );
+    assert_eq!(data.locals, [F32, F32, I64, I32, I32, I64, I64, F32, F64]);
+    // Room for the three values a truncation check pushes above its operand.
+    assert_eq!(data.max_stack, Some(6));
+}
+
+/// The operator stream numbers defined functions from zero and carries the
+/// signature of the right type, skipping the imported function.
+#[test]
+fn test_module_mach_operators() {
+    let wasm = full_module();
+    let m = BlitzModule::new(&wasm).unwrap();
+    let ops = m
+        .mach_operators::<(), BinaryReaderError>()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let MachOperator::StartFn { id, data } = &ops[0] else {
+        panic!("expected StartFn, got {:?}", ops[0]);
+    };
+    assert_eq!(*id, 0);
+    assert_eq!(data.num_params, 0);
+    assert_eq!(data.num_returns, 1);
+    assert!(matches!(
+        ops[1],
+        MachOperator::Local {
+            count: 1,
+            ty: wasmparser::ValType::I64
+        }
+    ));
+    assert!(matches!(ops.last(), Some(MachOperator::EndBody)));
+}
+
+/// The validating front end annotates every operator with the operand stack
+/// it leaves behind.
+#[test]
+fn test_module_validated_stack_types() {
+    let wasm = full_module();
+    let m = BlitzModule::new(&wasm).unwrap();
+    let ops = m
+        .validated_mach_operators::<WasmInfo, BinaryReaderError>(WasmFeatures::default())
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let stacks = ops
+        .iter()
+        .filter_map(|op| match op {
+            MachOperator::Operator {
+                annot: Validated { stack, .. },
+                ..
+            } => Some(stack.as_slice()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let i32 = Some(wasmparser::ValType::I32);
+    assert_eq!(
+        stacks,
+        [
+            // global.get 0; end (leaves the results); trailing return
+            &[i32][..],
+            &[i32],
+            &[i32],
+            // call 1; drop; end; trailing return
+            &[i32],
+            &[],
+            &[],
+            &[],
+        ]
+    );
+}
+
+/// An ill-typed body is rejected before any operator is produced, with the
+/// offset of the offending operator.
+#[test]
+fn test_module_validate_rejects() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::I64Const(1));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let Err(err) =
+        m.validated_mach_operators::<WasmInfo, BinaryReaderError>(WasmFeatures::default())
+    else {
+        panic!("expected a validation error");
+    };
+    assert!(matches!(err, CompileError::Reader(_)), "got {err:?}");
+    assert!(err.info().is_some(), "expected an offset in: {err}");
+}
+
+/// Type tracking records what every operator consumes and produces, through
+/// locals, `select` and nested blocks.
+#[test]
+fn test_module_typed_mach_operators() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types
+        .ty()
+        .function([ValType::I32, ValType::F64], [ValType::F64]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::LocalGet(1));
+    func.instruction(&Instruction::LocalGet(1));
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::Select);
+    func.instruction(&Instruction::Block(BlockType::Result(ValType::F64)));
+    func.instruction(&Instruction::F64Const(1.0.into()));
+    func.instruction(&Instruction::End);
+    func.instruction(&Instruction::F64Add);
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let ops = m
+        .typed_mach_operators::<WasmInfo, CompileError>()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let typed = ops
+        .iter()
+        .filter_map(|op| match op {
+            MachOperator::Operator {
+                annot:
+                    Typed {
+                        params,
+                        results,
+                        height,
+                        ..
+                    },
+                ..
+            } => Some((params.as_slice(), results.as_slice(), *height)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let i32 = Some(wasmparser::ValType::I32);
+    let f64 = Some(wasmparser::ValType::F64);
+    assert_eq!(
+        typed,
+        [
+            (&[][..], &[f64][..], 0),
+            (&[], &[f64], 1),
+            (&[], &[i32], 2),
+            (&[f64, f64, i32], &[f64], 3),
+            (&[], &[], 1),
+            (&[], &[f64], 1),
+            (&[f64], &[f64], 2),
+            (&[f64, f64], &[f64], 2),
+            // function-level end; trailing return
+            (&[f64], &[f64], 1),
+            (&[f64], &[], 1),
+        ]
+    );
+}
+
+/// Block types resolve to their arities against the type section, both as
+/// parsed and as re-encoded for backends.
+#[test]
+fn test_module_block_arity() {
+    let wasm = full_module();
+    let m = BlitzModule::new(&wasm).unwrap();
+    let arity = |params, results| BlockArity { params, results };
+
+    assert_eq!(
+        BlockArity::of(wasmparser::BlockType::Empty, &m),
+        Some(arity(0, 0))
+    );
+    assert_eq!(
+        BlockArity::of(wasmparser::BlockType::Type(wasmparser::ValType::I64), &m),
+        Some(arity(0, 1))
+    );
+    assert_eq!(
+        BlockArity::of(wasmparser::BlockType::FuncType(0), &m),
+        Some(arity(1, 0))
+    );
+    assert_eq!(BlockArity::of(wasmparser::BlockType::FuncType(3), &m), None);
+
+    let sigs = [wasm_encoder::FuncType::new(
+        [ValType::I32, ValType::I64],
+        [ValType::F32, ValType::F64, ValType::I32],
+    )];
+    assert_eq!(
+        BlockArity::of_encoded(&BlockType::FunctionType(0), &sigs),
+        Some(arity(2, 3))
+    );
+    assert_eq!(
+        BlockArity::of_encoded(&BlockType::FunctionType(1), &sigs),
+        None
+    );
+    assert_eq!(arity(2, 3).label(true), 2);
+    assert_eq!(arity(2, 3).label(false), 3);
+}
+
+/// Imported memories come first in the memory index space, and each memory
+/// reports its address width, limits and sharing.
+#[test]
+fn test_module_linear_memories() {
+    let mut module = Module::new();
+    let mut imports = ImportSection::new();
+    imports.import(
+        "env",
+        "shared",
+        EntityType::Memory(MemoryType {
+            minimum: 2,
+            maximum: Some(4),
+            memory64: true,
+            shared: true,
+            page_size_log2: None,
+        }),
+    );
+    module.section(&imports);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 3,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: Some(0),
+    });
+    module.section(&memories);
+    let wasm = module.finish();
+    let m = BlitzModule::new(&wasm).unwrap();
+
+    let mems = m.linear_memories().collect::<Vec<LinearMemory>>();
+    assert_eq!(mems.len(), 2);
+    assert_eq!(m.memory(2), None);
+
+    let imported = mems[0];
+    assert_eq!(imported.index, 0);
+    assert!(imported.imported && imported.memory64 && imported.shared);
+    assert_eq!(imported.addr_type(), wasmparser::ValType::I64);
+    assert_eq!(imported.min_bytes(), Some(2 << 16));
+    assert_eq!(imported.max_bytes(), 4 << 16);
+
+    let defined = mems[1];
+    assert_eq!(defined.index, 1);
+    assert!(!defined.imported && !defined.memory64 && !defined.shared);
+    assert_eq!(defined.addr_type(), wasmparser::ValType::I32);
+    assert_eq!(defined.page_size(), 1);
+    assert_eq!(defined.min_bytes(), Some(3));
+    // Unbounded 32-bit memories are capped by their address space.
+    assert_eq!(defined.max_bytes(), 1 << 32);
+    assert_eq!(defined.access_end(8, 8), Some(16));
+    assert_eq!(defined.access_end(u32::MAX as u64, 8), None);
+    assert_eq!(imported.access_end(u64::MAX, 1), None);
+}
+
+/// Globals are numbered imports first, and their initialisers are
+/// evaluated, folding extended constant arithmetic.
+#[test]
+fn test_module_globals() {
+    let mut module = Module::new();
+    let mut imports = ImportSection::new();
+    imports.import(
+        "env",
+        "base",
+        EntityType::Global(GlobalType {
+            val_type: ValType::I32,
+            mutable: false,
+            shared: false,
+        }),
+    );
+    module.section(&imports);
+    let mut globals = GlobalSection::new();
+    globals.global(
+        GlobalType {
+            val_type: ValType::I32,
+            mutable: true,
+            shared: false,
+        },
+        &ConstExpr::global_get(0),
+    );
+    globals.global(
+        GlobalType {
+            val_type: ValType::I64,
+            mutable: false,
+            shared: false,
+        },
+        &ConstExpr::i64_const(2).with_i64_const(7).with_i64_sub(),
+    );
+    module.section(&globals);
+    let wasm = module.finish();
+    let m = BlitzModule::new(&wasm).unwrap();
+
+    let globals = m.global_vars().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(globals.len(), 3);
+    assert!(globals[0].imported && !globals[0].mutable);
+    assert_eq!(globals[0].init, None);
+    assert_eq!(globals[1].index, 1);
+    assert!(!globals[1].imported && globals[1].mutable);
+    assert_eq!(globals[1].ty, wasmparser::ValType::I32);
+    assert_eq!(globals[1].init, Some(ConstValue::GlobalGet(0)));
+    assert_eq!(globals[2].init, Some(ConstValue::I64(-5)));
+    assert!(matches!(
+        m.global(3),
+        Err(CompileError::UnknownEntity {
+            kind: "global",
+            index: 3,
+            ..
+        })
+    ));
+}
+
+/// Tables, imported and defined, and element segments of every mode are
+/// described with their items evaluated.
+#[test]
+fn test_module_tables() {
+    let table = |minimum, maximum| TableType {
+        element_type: RefType::FUNCREF,
+        table64: false,
+        minimum,
+        maximum,
+        shared: false,
+    };
+    let mut module = Module::new();
+    let mut imports = ImportSection::new();
+    imports.import("env", "table", EntityType::Table(table(1, None)));
+    module.section(&imports);
+    let mut tables = TableSection::new();
+    tables.table(table(2, Some(8)));
+    module.section(&tables);
+    let mut elements = ElementSection::new();
+    elements.active(
+        Some(1),
+        &ConstExpr::i32_const(1),
+        Elements::Functions([0, 0].as_slice().into()),
+    );
+    elements.passive(Elements::Expressions(
+        RefType::FUNCREF,
+        [ConstExpr::ref_null(wasm_encoder::HeapType::FUNC)]
+            .as_slice()
+            .into(),
+    ));
+    elements.declared(Elements::Functions([0].as_slice().into()));
+    module.section(&elements);
+    let wasm = module.finish();
+    let m = BlitzModule::new(&wasm).unwrap();
+
+    let tables = m.table_defs().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tables.len(), 2);
+    assert!(tables[0].imported);
+    assert_eq!(tables[0].init, None);
+    assert!(!tables[1].imported);
+    assert_eq!((tables[1].min, tables[1].max), (2, Some(8)));
+    assert_eq!(tables[1].init, Some(ConstValue::RefNull));
+    assert_eq!(tables[1].index_type(), wasmparser::ValType::I32);
+
+    let elems = m.elem_segments().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        elems[0].mode,
+        ElemMode::Active {
+            table: 1,
+            offset: ConstValue::I32(1)
+        }
+    );
+    assert_eq!(elems[0].items, [ConstValue::RefFunc(0); 2]);
+    assert!(elems[0].live_items().is_empty());
+    assert_eq!(elems[1].mode, ElemMode::Passive);
+    assert_eq!(elems[1].live_items(), [ConstValue::RefNull]);
+    assert_eq!(elems[2].mode, ElemMode::Declared);
+    assert!(matches!(
+        m.table(2),
+        Err(CompileError::UnknownEntity {
+            kind: "table",
+            index: 2,
+            ..
+        })
+    ));
+}
+
+/// Data segments of both modes are described with their offsets evaluated.
+#[test]
+fn test_module_data_segments() {
+    let mut module = Module::new();
+    let mut globals = GlobalSection::new();
+    globals.global(
+        GlobalType {
+            val_type: ValType::I32,
+            mutable: false,
+            shared: false,
+        },
+        &ConstExpr::i32_const(8),
+    );
+    module.section(&globals);
+    let mut data = DataSection::new();
+    data.active(0, &ConstExpr::global_get(0), b"hi".iter().copied());
+    data.passive(b"there".iter().copied());
+    module.section(&data);
+    let wasm = module.finish();
+    let m = BlitzModule::new(&wasm).unwrap();
+
+    let data = m.data_segments().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        data[0].mode,
+        DataMode::Active {
+            memory: 0,
+            offset: ConstValue::GlobalGet(0)
+        }
+    );
+    assert_eq!(data[0].bytes, b"hi");
+    assert!(data[0].live_bytes().is_empty());
+    assert_eq!(data[1].mode, DataMode::Passive);
+    assert_eq!(data[1].live_bytes(), b"there");
+    assert!(matches!(
+        m.data_segment(2),
+        Err(CompileError::UnknownEntity {
+            kind: "data",
+            index: 2,
+            ..
+        })
+    ));
+}
+
+/// Exports resolve to the entity they name, and the start function is
+/// checked against the function index space.
+#[test]
+fn test_module_exports() {
+    let wasm = full_module();
+    let m = BlitzModule::new(&wasm).unwrap();
+    let exports = m.export_items().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(exports.len(), 1);
+    assert_eq!(exports[0].name, "get");
+    assert_eq!(exports[0].item, Exported::Func(1));
+    assert_eq!(m.start_func().unwrap(), Some(2));
+
+    let mut module = Module::new();
+    let mut exports = ExportSection::new();
+    exports.export("missing", ExportKind::Memory, 0);
+    module.section(&exports);
+    module.section(&StartSection { function_index: 0 });
+    let wasm = module.finish();
+    let m = BlitzModule::new(&wasm).unwrap();
+    assert!(matches!(
+        m.export_items().next(),
+        Some(Err(CompileError::UnknownEntity {
+            kind: "memory",
+            index: 0,
+            ..
+        }))
+    ));
+    assert!(matches!(
+        m.start_func(),
+        Err(CompileError::UnknownEntity { kind: "func", .. })
+    ));
+}
+
+/// Export names that are identifiers are their own symbols; others are
+/// escaped so distinct names stay distinct.
+#[test]
+fn test_export_symbols() {
+    for (name, symbol) in [
+        ("memory", "memory"),
+        ("_start", "_start"),
+        ("add-one", "blitz_xadd_2done"),
+        ("add_one!", "blitz_xadd__one_21"),
+        ("1st", "blitz_x1st"),
+        ("", "blitz_x"),
+    ] {
+        assert_eq!(Symbol(name).to_string(), symbol);
+    }
+}
+
+/// Function imports are described in the function index space, and bound by
+/// name unless a resolver says otherwise.
+#[test]
+fn test_module_func_imports() {
+    let wasm = full_module();
+    let m = BlitzModule::new(&wasm).unwrap();
+    let imports = m.func_import_items().collect::<Vec<_>>();
+    assert_eq!(imports.len(), 1);
+    assert_eq!(
+        (
+            imports[0].index,
+            imports[0].module,
+            imports[0].name,
+            imports[0].ty
+        ),
+        (0, "env", "log", 0)
+    );
+
+    assert_eq!(
+        Imports::default().bind(&imports[0]),
+        Binding::Host {
+            module: "env".into(),
+            name: "log".into()
+        }
+    );
+    fn unbound<'a>(_: &FuncImport<'a>) -> Binding<'a> {
+        Binding::Trap
+    }
+    assert_eq!(Imports::new(unbound).bind(&imports[0]), Binding::Trap);
+}
+
+/// Operators are sorted into their groups, and every backend lowers every
+/// integer and sign-extension operator.
+#[test]
+fn test_coverage() {
+    let load = Instruction::I64Load8S(MemArg {
+        offset: 0,
+        align: 0,
+        memory_index: 0,
+    });
+    assert_eq!(Group::of(&load), Some(Group::Memory));
+    assert_eq!(Group::of(&Instruction::I32Clz), Some(Group::Int));
+    assert_eq!(Group::of(&Instruction::I64Extend32S), Some(Group::SignExt));
+    assert_eq!(
+        Group::of(&Instruction::F32ReinterpretI32),
+        Some(Group::Float)
+    );
+    assert_eq!(
+        Group::of(&Instruction::MemoryFill(0)),
+        Some(Group::BulkMemory)
+    );
+    assert_eq!(Group::of(&Instruction::RefIsNull), Some(Group::Table));
+    assert_eq!(Group::of(&Instruction::Select), Some(Group::Parametric));
+    assert_eq!(Group::of(&Instruction::AtomicFence), None);
+
+    for coverage in COVERAGE {
+        assert_eq!(
+            coverage.of(Group::Int),
+            Support::Full,
+            "{}",
+            coverage.backend
+        );
+        assert_eq!(
+            coverage.of(Group::SignExt),
+            Support::Full,
+            "{}",
+            coverage.backend
+        );
+    }
+    let c = Coverage::lookup("c").unwrap();
+    assert_eq!(c.op(&Instruction::I32Extend8S), Support::Full);
+    assert_eq!(c.of(Group::Parametric), Support::Missing);
+    for backend in ["c", "x86-64 naive", "x86-64 fast", "riscv64 naive"] {
+        let coverage = Coverage::lookup(backend).unwrap();
+        assert_eq!(coverage.of(Group::Float), Support::Full, "{backend}");
+    }
+    assert!(Coverage::lookup("wasm").is_none());
+}
+
+/// `const_fold` evaluates constant arithmetic, propagates a constant through
+/// a local and resolves branches on constant conditions.
+#[test]
+fn test_const_fold() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([(1, ValType::I32)]);
+    for instr in [
+        Instruction::I32Const(6),
+        Instruction::I32Const(7),
+        Instruction::I32Mul,
+        Instruction::LocalSet(0),
+        Instruction::I32Const(0),
+        Instruction::If(BlockType::Empty),
+        Instruction::Unreachable,
+        Instruction::End,
+        Instruction::LocalGet(0),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::LocalGet(0),
+        Instruction::BrIf(0),
+        Instruction::End,
+    ] {
+        func.instruction(&instr);
+    }
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let ops = const_fold(m.mach_operators::<(), BinaryReaderError>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    use wasmparser::Operator;
+    assert!(
+        matches!(
+            &ops[3..],
+            [
+                MachOperator::Instruction {
+                    op: Instruction::I32Const(42),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::LocalSet { local_index: 0 }),
+                    ..
+                },
+                MachOperator::Instruction {
+                    op: Instruction::I32Const(43),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::Br { relative_depth: 0 }),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::End),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::Return),
+                    ..
+                },
+                MachOperator::EndBody,
+            ]
+        ),
+        "{ops:#?}"
+    );
+}
+
+/// `peephole` applies the default rules, and rules registered on top of
+/// them, to runs of operators, rewriting the result of one rule with
+/// another.
+#[test]
+fn test_peephole() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([]);
+    for instr in [
+        Instruction::LocalGet(0),
+        Instruction::I32Const(2),
+        Instruction::I32Mul,
+        Instruction::I32Const(0),
+        Instruction::I32Add,
+        Instruction::LocalSet(0),
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(0),
+        Instruction::I32Eqz,
+        Instruction::I32Eqz,
+        Instruction::BrIf(0),
+        Instruction::Nop,
+        Instruction::End,
+    ] {
+        func.instruction(&instr);
+    }
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let mut rules = Rules::default();
+    rules.push(peephole_rule!("double": [I32Const(2), I32Mul] => [LocalGet(0), I32Add]));
+    let ops = peephole(m.mach_operators::<(), BinaryReaderError>(), &rules)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    use wasmparser::Operator;
+    assert!(
+        matches!(
+            &ops[2..],
+            [
+                MachOperator::Operator {
+                    op: Some(Operator::LocalGet { local_index: 0 }),
+                    ..
+                },
+                MachOperator::Instruction {
+                    op: Instruction::LocalGet(0),
+                    ..
+                },
+                MachOperator::Instruction {
+                    op: Instruction::I32Add,
+                    ..
+                },
+                MachOperator::Instruction {
+                    op: Instruction::LocalTee(0),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::LocalGet { local_index: 0 }),
+                    ..
+                },
+                MachOperator::Instruction {
+                    op: Instruction::BrIf(0),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::End),
+                    ..
+                },
+                MachOperator::Operator {
+                    op: Some(Operator::Return),
+                    ..
+                },
+                MachOperator::EndBody,
+            ]
+        ),
+        "{ops:#?}"
+    );
+}
+
+/// `inline` replaces calls of small functions with their bodies, giving
+/// their parameters and locals fresh locals of the caller, unless the
+/// budget rules it out.
+#[test]
+fn test_inline() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I32]);
+    types.ty().function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut imports = ImportSection::new();
+    imports.import("env", "f", EntityType::Function(0));
+    module.section(&imports);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    functions.function(1);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::I32Const(1));
+    func.instruction(&Instruction::Call(2));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    let mut func = Function::new([(1, ValType::I64)]);
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::Return);
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let caller = |budget: InlineBudget| {
+        let ops = inline(
+            m.mach_operators::<(), BinaryReaderError>(),
+            m.num_func_imports(),
+            budget,
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        let end = ops
+            .iter()
+            .position(|o| matches!(o, MachOperator::EndBody))
+            .unwrap();
+        ops[..=end].to_vec()
+    };
+    use wasmparser::Operator;
+    let ops = caller(InlineBudget::default());
+    let MachOperator::StartFn { data, .. } = &ops[0] else {
+        panic!("expected StartFn, got {:?}", ops[0]);
+    };
+    assert_eq!(
+        data.locals,
+        [wasmparser::ValType::I32, wasmparser::ValType::I64]
+    );
+    assert_eq!(data.control_depth, 1);
+    let body = ops
+        .iter()
+        .filter_map(|o| match o {
+            MachOperator::Operator { op: Some(op), .. } => Some(op.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        body,
+        [
+            Operator::I32Const { value: 1 },
+            Operator::LocalSet { local_index: 0 },
+            Operator::I64Const { value: 0 },
+            Operator::LocalSet { local_index: 1 },
+            Operator::Block {
+                blockty: wasmparser::BlockType::Type(wasmparser::ValType::I32)
+            },
+            Operator::LocalGet { local_index: 0 },
+            Operator::Br { relative_depth: 0 },
+            Operator::End,
+            Operator::End,
+            Operator::Return,
+        ]
+    );
+
+    let mut budget = InlineBudget::default();
+    budget.max_size = 0;
+    budget.max_single_site_size = 0;
+    let ops = caller(budget);
+    assert!(ops.iter().any(|o| matches!(
+        o,
+        MachOperator::Operator {
+            op: Some(Operator::Call { function_index: 2 }),
+            ..
+        }
+    )));
+}
+
+/// `Reach` keeps what the exports reach and renumbers it; `prune` drops
+/// the dead bodies from the stream, and `Pruner` writes the module without
+/// them, whether its code comes from the stream or from the input.
+#[test]
+fn test_prune() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I32]);
+    types.ty().function([ValType::I32], []);
+    types.ty().function([], []);
+    module.section(&types);
+    let mut imports = ImportSection::new();
+    imports.import("env", "f", EntityType::Function(0));
+    module.section(&imports);
+    let mut functions = FunctionSection::new();
+    functions.function(1);
+    functions.function(0);
+    functions.function(0);
+    module.section(&functions);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    module.section(&memories);
+    let mut globals = GlobalSection::new();
+    let ty = GlobalType {
+        val_type: ValType::I32,
+        mutable: true,
+        shared: false,
+    };
+    globals.global(ty, &ConstExpr::i32_const(0));
+    globals.global(ty, &ConstExpr::i32_const(5));
+    module.section(&globals);
+    let mut exports = ExportSection::new();
+    exports.export("main", ExportKind::Func, 2);
+    module.section(&exports);
+    module.section(&DataCountSection { count: 3 });
+    let mut code = CodeSection::new();
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::GlobalSet(0));
+    func.instruction(&Instruction::DataDrop(0));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::Call(3));
+    func.instruction(&Instruction::GlobalGet(1));
+    func.instruction(&Instruction::I32Add);
+    func.instruction(&Instruction::DataDrop(2));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::Call(0));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    module.section(&code);
+    let mut data = DataSection::new();
+    data.passive([1]);
+    data.active(0, &ConstExpr::i32_const(0), [2]);
+    data.passive([3]);
+    module.section(&data);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let reach = Reach::new(&m).unwrap();
+    assert_eq!(
+        (0..4).map(|f| reach.func(f)).collect::<Vec<_>>(),
+        [Some(0), None, Some(1), Some(2)]
+    );
+    assert_eq!(reach.body(0), None);
+    assert_eq!(reach.body(2), Some(1));
+    assert_eq!((reach.global(0), reach.global(1)), (None, Some(0)));
+    assert_eq!(
+        (0..3).map(|t| reach.ty(t)).collect::<Vec<_>>(),
+        [Some(0), None, None]
+    );
+    assert_eq!(
+        (0..3).map(|d| reach.data(d)).collect::<Vec<_>>(),
+        [None, Some(0), Some(1)]
+    );
+
+    let ops = prune(m.mach_operators::<(), BinaryReaderError>(), &reach)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let ids = ops
+        .iter()
+        .filter_map(|o| match o {
+            MachOperator::StartFn { id, .. } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [0, 1]);
+    use wasmparser::Operator;
+    let end = ops
+        .iter()
+        .position(|o| matches!(o, MachOperator::EndBody))
+        .unwrap();
+    let body = ops[..end]
+        .iter()
+        .filter_map(|o| match o {
+            MachOperator::Operator { op: Some(op), .. } => Some(op.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        body,
+        [
+            Operator::Call { function_index: 2 },
+            Operator::GlobalGet { global_index: 0 },
+            Operator::I32Add,
+            Operator::DataDrop { data_index: 1 },
+            Operator::End,
+            Operator::Return,
+        ]
+    );
+
+    let mut tracker = MachTracker::default();
+    for o in &ops {
+        RoundtripReencoder
+            .mach_instruction(&mut (), o, &mut tracker, &mut |locals| {
+                Function::new(locals)
+            })
+            .unwrap();
+    }
+    let mut code = CodeSection::new();
+    tracker.on_code_section(&mut code);
+    let tracked = Pruner::new(&reach)
+        .with_code(code)
+        .module(&wasm)
+        .unwrap()
+        .finish();
+    let pruned = Pruner::new(&reach).module(&wasm).unwrap().finish();
+    assert_eq!(tracked, pruned);
+    wasmparser::Validator::new().validate_all(&pruned).unwrap();
+    let p = BlitzModule::new(&pruned).unwrap();
+    assert_eq!(p.types.len(), 1);
+    assert_eq!(p.funcs, [0, 0, 0]);
+    assert_eq!(p.global_inits.len(), 1);
+    assert_eq!(p.data.len(), 2);
+    assert_eq!((p.exports[0].name, p.exports[0].index), ("main", 1));
+}
+
+/// `Reach` keeps every type once a typed-reference operator names one,
+/// since the stream is not renumbered for it.
+#[test]
+fn test_prune_ref_test() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], []);
+    types.ty().function([ValType::I32], []);
+    types.ty().function([], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(2);
+    module.section(&functions);
+    let mut exports = ExportSection::new();
+    exports.export("main", ExportKind::Func, 0);
+    module.section(&exports);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([]);
+    func.instruction(&Instruction::RefNull(HeapType::FUNC));
+    func.instruction(&Instruction::RefTestNullable(HeapType::Concrete(1)));
+    func.instruction(&Instruction::End);
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let reach = Reach::new(&m).unwrap();
+    assert_eq!(
+        (0..3).map(|t| reach.ty(t)).collect::<Vec<_>>(),
+        [Some(0), Some(1), Some(2)]
+    );
+    let pruned = Pruner::new(&reach).module(&wasm).unwrap().finish();
+    wasmparser::Validator::new().validate_all(&pruned).unwrap();
+}
+
+/// Modules defining tags are rejected up front rather than pruned into
+/// modules whose tags name removed types.
+#[test]
+fn test_module_rejects_tags() {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([], []);
+    types.ty().function([ValType::I32], []);
+    module.section(&types);
+    let mut tags = TagSection::new();
+    tags.tag(TagType {
+        kind: TagKind::Exception,
+        func_type_idx: 1,
+    });
+    module.section(&tags);
+    let wasm = module.finish();
+
+    assert!(matches!(
+        BlitzModule::new(&wasm),
+        Err(CompileError::UnsupportedFeature {
+            feature: "tags",
+            ..
+        })
+    ));
+}
+
+/// `coalesce_locals` removes dead stores and unused locals, and gives a
+/// local the slot of one that is no longer live; `Liveness` keeps locals
+/// read around a loop live through it.
+#[test]
+fn test_coalesce_locals() {
+    use Instruction::*;
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    functions.function(0);
+    module.section(&functions);
+    let mut code = CodeSection::new();
+    let mut func = Function::new([(4, ValType::I32), (1, ValType::I64)]);
+    for op in [
+        I32Const(7),
+        LocalSet(3),
+        LocalGet(0),
+        I32Const(1),
+        I32Add,
+        LocalSet(1),
+        LocalGet(1),
+        LocalGet(1),
+        I32Mul,
+        LocalTee(4),
+        LocalSet(2),
+        LocalGet(2),
+        End,
+    ] {
+        func.instruction(&op);
+    }
+    code.function(&func);
+    // Sums `n` down to 1 in local 1, which starts out zeroed.
+    let mut func = Function::new([(2, ValType::I32)]);
+    for op in [
+        LocalGet(0),
+        LocalSet(2),
+        Block(BlockType::Empty),
+        Loop(BlockType::Empty),
+        LocalGet(2),
+        I32Eqz,
+        BrIf(1),
+        LocalGet(1),
+        LocalGet(2),
+        I32Add,
+        LocalSet(1),
+        LocalGet(2),
+        I32Const(1),
+        I32Sub,
+        LocalSet(2),
+        Br(0),
+        End,
+        End,
+        LocalGet(1),
+        End,
+    ] {
+        func.instruction(&op);
+    }
+    code.function(&func);
+    module.section(&code);
+    let wasm = module.finish();
+
+    let m = BlitzModule::new(&wasm).unwrap();
+    let ops = coalesce_locals(m.mach_operators::<(), BinaryReaderError>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let end = ops
+        .iter()
+        .position(|o| matches!(o, MachOperator::EndBody))
+        .unwrap();
+    let MachOperator::StartFn { data, .. } = &ops[0] else {
+        panic!("expected StartFn, got {:?}", ops[0]);
+    };
+    assert_eq!(data.locals, []);
+    assert!(
+        !ops[..end]
+            .iter()
+            .any(|o| matches!(o, MachOperator::Local { .. }))
+    );
+    use wasmparser::Operator;
+    let body = ops[..end]
+        .iter()
+        .filter_map(|o| match o {
+            MachOperator::Operator { op: Some(op), .. } => Some(op.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        body,
+        [
+            Operator::LocalGet { local_index: 0 },
+            Operator::I32Const { value: 1 },
+            Operator::I32Add,
+            Operator::LocalSet { local_index: 0 },
+            Operator::LocalGet { local_index: 0 },
+            Operator::LocalGet { local_index: 0 },
+            Operator::I32Mul,
+            Operator::LocalSet { local_index: 0 },
+            Operator::LocalGet { local_index: 0 },
+            Operator::End,
+            Operator::Return,
+        ]
+    );
+
+    let ops = &ops[end + 1..];
+    let start = ops
+        .iter()
+        .position(|o| matches!(o, MachOperator::StartBody))
+        .unwrap();
+    let end = ops
+        .iter()
+        .position(|o| matches!(o, MachOperator::EndBody))
+        .unwrap();
+    let body = &ops[start + 1..end];
+    let live = Liveness::new(body, 2).unwrap();
+    assert!(live.live_at_entry(0));
+    // The sum is read before it is first written; the counter took the
+    // parameter's slot.
+    assert!(live.live_at_entry(1));
+    assert_eq!(live.live_at_entry_locals().collect::<Vec<_>>(), [0, 1]);
+    let br = body
+        .iter()
+        .position(|o| {
+            matches!(
+                o,
+                MachOperator::Operator {
+                    op: Some(Operator::Br { .. }),
+                    ..
+                }
+            )
+        })
+        .unwrap();
+    assert!(live.live_out(br, 0) && live.live_out(br, 1));
+    let MachOperator::StartFn { data, .. } = &ops[0] else {
+        panic!("expected StartFn, got {:?}", ops[0]);
+    };
+    assert_eq!(data.locals, [wasmparser::ValType::I32]);
+}
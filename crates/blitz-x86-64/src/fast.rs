@@ -4,7 +4,23 @@ use core::fmt::Display;
 
 pub use portal_solutions_asm_x86_64::*;
 
-use portal_solutions_blitz_common::{asm::Reg, ops::MachOperator};
+use portal_solutions_blitz_common::{
+    CompileError,
+    asm::Reg,
+    backend::{Backend, BackendContext},
+    coverage::Group,
+    float::{FloatKind, FloatOp, Nans},
+    global,
+    memory::{self, LinearMemory},
+    module::Module,
+    ops::MachOperator,
+    softfloat::Routines,
+    typed::BlockArity,
+    wasm_encoder::{
+        self,
+        reencode::{self, Reencode},
+    },
+};
 
 /// The stack pointer register (RSP).
 const RSP: Reg = Reg(4);
@@ -76,11 +92,42 @@ pub struct State {
     pub stack_manager: StackManager,
     pub body: u32,
     pub body_labels: alloc::collections::BTreeMap<u32, usize>,
+    /// What NaNs produced by float arithmetic look like.
+    pub nans: Nans,
+    /// The float routines called so far, generated by `on_end`.
+    pub routines: Routines,
+    /// The float operator whose routine is being generated, labelling it
+    /// in place of its function index.
+    pub builtin: Option<&'static str>,
 }
 
+/// A control flow structure that needs an end marker.
+///
+/// Every structure keeps an entry on the control stack holding the address
+/// of its label and the stack pointer below its parameters.
 enum Endable {
-    Br,
-    If { idx: usize },
+    Block {
+        idx: usize,
+        arity: BlockArity,
+    },
+    Loop {
+        arity: BlockArity,
+    },
+    If {
+        idx: usize,
+        arity: BlockArity,
+        has_else: bool,
+    },
+}
+
+impl Endable {
+    /// Number of values a branch to this structure carries.
+    fn carried(&self) -> usize {
+        match self {
+            Endable::Block { arity, .. } | Endable::If { arity, .. } => arity.label(false),
+            Endable::Loop { arity } => arity.label(true),
+        }
+    }
 }
 
 impl Default for State {
@@ -95,6 +142,9 @@ impl Default for State {
             stack_manager: StackManager::new(),
             body: 0,
             body_labels: alloc::collections::BTreeMap::new(),
+            nans: Nans::default(),
+            routines: Routines::default(),
+            builtin: None,
         }
     }
 }
@@ -116,55 +166,264 @@ fn emit_cmds<
     Ok(())
 }
 
+/// Ensures the register allocator is initialized for the current function.
+fn ensure_regalloc(state: &mut State, arch: asm_x86::X64Arch) {
+    if state.regalloc.is_none() {
+        let r = x86_regalloc::init_regalloc::<32>(arch);
+        let new = regalloc::RegAlloc {
+            frames: Frames(r.frames),
+            tos: r.tos,
+        };
+        state.regalloc = Some(new);
+    }
+}
+
 pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
+    /// Generates code for a branch, carrying the values the target label
+    /// takes. The register allocator must be flushed first.
     fn br(
         &mut self,
         ctx: &mut Context,
         arch: asm_x86::X64Arch,
-        _state: &mut State,
+        state: &mut State,
         relative_depth: u32,
     ) -> Result<(), Self::Error>
     where
         Self: Sized,
     {
-        // match naive backend br sequence
-        let rsp_mem = asm_x86::out::arg::MemArgKind::Mem {
-            base: RSP,
-            offset: None,
-            disp: 8u32,
-            size: MemorySize::_64,
-            reg_class: asm_x86::RegisterClass::Gpr,
+        // A branch out of the function body is a return.
+        let Some(target) = state.if_stack.iter().rev().nth(relative_depth as usize) else {
+            return self.wasm_return(ctx, arch, state);
         };
-        self.xchg(ctx, arch, &rsp_mem, &Reg::CTX)?;
+        let carried = target.carried();
+        // match naive backend br sequence
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
         for _ in 0..=relative_depth {
             self.pop(ctx, arch, &Reg(0))?;
             self.pop(ctx, arch, &Reg(1))?;
         }
-        let rsp_mem = asm_x86::out::arg::MemArgKind::Mem {
-            base: RSP,
-            offset: None,
-            disp: 8u32,
-            size: MemorySize::_64,
-            reg_class: asm_x86::RegisterClass::Gpr,
-        };
-        self.xchg(ctx, arch, &rsp_mem, &Reg::CTX)?;
-        self.mov(ctx, arch, &RSP, &Reg(1))?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        crate::carry(self, ctx, arch, carried)?;
         self.jmp(ctx, arch, &Reg(0))?;
         Ok(())
     }
 
+    /// Generates code for a return from the current function. The register
+    /// allocator must be flushed first.
+    fn wasm_return(
+        &mut self,
+        ctx: &mut Context,
+        arch: asm_x86::X64Arch,
+        state: &mut State,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.mov(ctx, arch, &Reg(1), &Reg(4))?;
+        self.mov(ctx, arch, &Reg(0), &Reg::CTX)?;
+        let mut tmp = Reg(0);
+        self.lea(
+            ctx,
+            arch,
+            &tmp,
+            &asm_x86::out::arg::MemArgKind::Mem {
+                base: tmp,
+                offset: None,
+                disp: (state.local_count + 3 * 8) as u32,
+                size: MemorySize::_8,
+                reg_class: asm_x86::RegisterClass::Gpr,
+            },
+        )?;
+        self.mov(ctx, arch, &Reg(4), &tmp)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        for a in 0..state.num_returns {
+            self.mov(ctx, arch, &Reg(2), &Reg(1))?;
+            self.push(ctx, arch, &Reg(2))?;
+        }
+        self.push(ctx, arch, &Reg(0))?;
+        self.ret(ctx, arch)?;
+        Ok(())
+    }
+
+    /// Opens a control structure: records the address of `label` and the
+    /// stack pointer below the structure's `params` on the control stack.
+    /// The register allocator must be flushed first.
+    fn enter(
+        &mut self,
+        ctx: &mut Context,
+        arch: asm_x86::X64Arch,
+        label: X64FastLabel,
+        params: usize,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.lea_label(ctx, arch, &Reg(0), label)?;
+        self.lea(
+            ctx,
+            arch,
+            &Reg(1),
+            &asm_x86::out::arg::MemArgKind::Mem {
+                base: RSP,
+                offset: None,
+                disp: (params * 8) as u32,
+                size: MemorySize::_64,
+                reg_class: asm_x86::RegisterClass::Gpr,
+            },
+        )?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        self.push(ctx, arch, &Reg(1))?;
+        self.push(ctx, arch, &Reg(0))?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        Ok(())
+    }
+
+    /// Closes a control structure, dropping its control stack entry.
+    fn leave(&mut self, ctx: &mut Context, arch: asm_x86::X64Arch) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.pop(ctx, arch, &Reg(1))?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        Ok(())
+    }
+
+    /// Generates code for a trap, jumping to the embedder's trap handler.
+    ///
+    /// A conditional trap pops an `i32` and only jumps when it is non-zero.
+    fn trap(
+        &mut self,
+        ctx: &mut Context,
+        arch: asm_x86::X64Arch,
+        state: &mut State,
+        conditional: bool,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+    {
+        if !conditional {
+            return self.jmp_label(ctx, arch, X64FastLabel::Trap);
+        }
+        ensure_regalloc(state, arch);
+        let i = state.label_index;
+        state.label_index += 1;
+        self.lea_label(ctx, arch, &Reg(1), X64FastLabel::Indexed { idx: i })?;
+        let (t, cmds) = state
+            .regalloc
+            .as_mut()
+            .unwrap()
+            .pop(x86_regalloc::RegKind::Int);
+        emit_cmds(self, ctx, arch, cmds, &mut state.stack_manager)?;
+        self.cmp0(ctx, arch, &Reg(t.reg))?;
+        self.jcc(
+            ctx,
+            arch,
+            portal_solutions_asm_x86_64::ConditionCode::E,
+            &Reg(1),
+        )?;
+        self.jmp_label(ctx, arch, X64FastLabel::Trap)?;
+        self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
+        Ok(())
+    }
+
+    /// Generates code for a machine operator.
+    ///
+    /// Handles function boundaries and local declarations, resetting the
+    /// register allocator for every function, and defers to `handle_op`
+    /// for instructions.
+    fn handle_mach<E>(
+        &mut self,
+        ctx: &mut Context,
+        arch: asm_x86::X64Arch,
+        state: &mut State,
+        sigs: &[wasm_encoder::FuncType],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
+        func_imports: &[(&str, &str)],
+        op: &MachOperator<'_>,
+        rewriter: &mut (dyn Reencode<Error = E> + '_),
+        target: u32,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized,
+        Self::Error: From<core::fmt::Error> + From<CompileError>,
+        reencode::Error<E>: Into<Self::Error>,
+    {
+        match op {
+            MachOperator::StartFn { id, data } => {
+                state.local_count = data.num_params;
+                state.num_returns = data.num_returns;
+                state.control_depth = data.control_depth;
+                state.if_stack.clear();
+                state.regalloc = None;
+                let label = match state.builtin {
+                    Some(name) => X64FastLabel::Builtin { name },
+                    None => X64FastLabel::Func { r#fn: *id },
+                };
+                self.set_label(ctx, arch, label)?;
+            }
+            MachOperator::Local { count, .. } => {
+                state.local_count += *count as usize;
+            }
+            MachOperator::Instruction { op, .. } => {
+                self.handle_op(
+                    ctx,
+                    arch,
+                    state,
+                    sigs,
+                    memories,
+                    module,
+                    func_imports,
+                    op,
+                    target,
+                )?;
+            }
+            MachOperator::Operator { op: Some(op), .. } => {
+                let op = rewriter.instruction(op.clone()).map_err(|e| e.into())?;
+                self.handle_op(
+                    ctx,
+                    arch,
+                    state,
+                    sigs,
+                    memories,
+                    module,
+                    func_imports,
+                    &op,
+                    target,
+                )?;
+            }
+            MachOperator::Trap { conditional, .. } => {
+                self.trap(ctx, arch, state, *conditional)?;
+            }
+            MachOperator::Operator { op: None, .. }
+            | MachOperator::StartBody
+            | MachOperator::EndBody => {}
+            _ => return Err(CompileError::unsupported_mach(op).into()),
+        }
+        Ok(())
+    }
+
     fn handle_op(
         &mut self,
         ctx: &mut Context,
         arch: asm_x86::X64Arch,
         state: &mut State,
+        sigs: &[wasm_encoder::FuncType],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         func_imports: &[(&str, &str)],
         op: &portal_solutions_blitz_common::wasm_encoder::Instruction<'_>,
         target: u32,
     ) -> Result<(), Self::Error>
     where
         Self: Sized,
-        Self::Error: From<core::fmt::Error>,
+        Self::Error: From<core::fmt::Error> + From<CompileError>,
     {
         if target != state.body {
             self.jmp_label(
@@ -182,16 +441,23 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                 self.set_label(ctx, arch, X64FastLabel::Indexed { idx })?;
             }
         }
-        // Ensure regalloc is initialized per function
-        if state.regalloc.is_none() {
-            let r = x86_regalloc::init_regalloc::<32>(arch);
-            let new = regalloc::RegAlloc {
-                frames: Frames(r.frames),
-                tos: r.tos,
-            };
-            state.regalloc = Some(new);
-        }
+        ensure_regalloc(state, arch);
         use portal_solutions_blitz_common::wasm_encoder::Instruction;
+        if let Some(access) = memory::Access::of(op) {
+            // bounds checks need the address on the stack
+            let flush = state.regalloc.as_mut().unwrap().flush();
+            emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
+            return crate::access_op(self, ctx, arch, memories, &access);
+        }
+        if let Some(float) = FloatOp::of(op) {
+            // runtime routines take their operands on the stack
+            let flush = state.regalloc.as_mut().unwrap().flush();
+            emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
+            if float.kind != FloatKind::Sign {
+                state.routines.call(&float);
+            }
+            return crate::float_op(self, ctx, arch, state.nans, &float);
+        }
         match op {
             Instruction::I32Const(value) => {
                 {
@@ -277,6 +543,9 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                         reg_class: asm_x86::RegisterClass::Gpr,
                     },
                 )?;
+                if let Instruction::I32Add = op {
+                    self.u32(ctx, arch, &r1)?;
+                }
                 // push existing
                 {
                     let iter = state
@@ -323,86 +592,67 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                     emit_cmds(self, ctx, arch, it, &mut state.stack_manager)?;
                 }
             }
-            Instruction::I64Load(memarg) => {
-                // address calculation: pop addr, add offset, mov
-                let t;
+            Instruction::GlobalGet(global_index) | Instruction::GlobalSet(global_index) => {
                 {
-                    let (tt, cmds) = state
-                        .regalloc
-                        .as_mut()
-                        .unwrap()
-                        .pop(x86_regalloc::RegKind::Int);
-                    emit_cmds(self, ctx, arch, cmds, &mut state.stack_manager)?;
-                    t = tt;
+                    let flush = state.regalloc.as_mut().unwrap().flush();
+                    emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
-                let addr = Reg(t.reg);
-                let imm = Reg(0); // temporary immediate reg
-                self.mov64(ctx, arch, &imm, memarg.offset)?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &addr,
-                    &asm_x86::out::arg::MemArgKind::Mem {
-                        base: addr,
-                        offset: Some((imm, 0)),
-                        disp: 0,
-                        size: MemorySize::_64,
-                        reg_class: asm_x86::RegisterClass::Gpr,
-                    },
-                )?;
-                self.mov(ctx, arch, &addr, &addr)?;
-                // push existing
-                {
-                    let mut it = state
-                        .regalloc
-                        .as_mut()
-                        .unwrap()
-                        .push_existing(regalloc::Target {
-                            reg: t.reg,
-                            kind: t.kind,
-                        });
-                    emit_cmds(self, ctx, arch, it, &mut state.stack_manager)?;
+                global::lookup(&module.globals, *global_index)?;
+                let slot = asm_x86::out::arg::MemArgKind::Mem {
+                    base: Reg(1),
+                    offset: None,
+                    disp: *global_index * 8,
+                    size: MemorySize::_64,
+                    reg_class: asm_x86::RegisterClass::Gpr,
+                };
+                self.lea_label(ctx, arch, &Reg(1), X64FastLabel::Globals)?;
+                if let Instruction::GlobalGet(_) = op {
+                    self.mov(ctx, arch, &Reg(0), &slot)?;
+                    self.push(ctx, arch, &Reg(0))?;
+                } else {
+                    self.pop(ctx, arch, &Reg(0))?;
+                    self.mov(ctx, arch, &slot, &Reg(0))?;
                 }
             }
-            Instruction::I64Store(memarg) => {
-                // pop value then address
-                let val;
-                let addr;
+            op @ (Instruction::CallIndirect { .. }
+            | Instruction::RefNull(_)
+            | Instruction::RefIsNull
+            | Instruction::RefFunc(_)
+            | Instruction::TableGet(_)
+            | Instruction::TableSet(_)
+            | Instruction::TableSize(_)
+            | Instruction::TableGrow(_)
+            | Instruction::TableFill(_)
+            | Instruction::TableCopy { .. }
+            | Instruction::TableInit { .. }
+            | Instruction::ElemDrop(_)) => {
                 {
-                    let (vv, cmds_val) = state
-                        .regalloc
-                        .as_mut()
-                        .unwrap()
-                        .pop(x86_regalloc::RegKind::Int);
-                    emit_cmds(self, ctx, arch, cmds_val, &mut state.stack_manager)?;
-                    val = vv;
+                    let flush = state.regalloc.as_mut().unwrap().flush();
+                    emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
+                crate::table_op(self, ctx, arch, sigs, module, op)?;
+            }
+            op @ (Instruction::MemorySize(_)
+            | Instruction::MemoryGrow(_)
+            | Instruction::MemoryFill(_)
+            | Instruction::MemoryCopy { .. }
+            | Instruction::MemoryInit { .. }
+            | Instruction::DataDrop(_)) => {
                 {
-                    let (aa, cmds_addr) = state
-                        .regalloc
-                        .as_mut()
-                        .unwrap()
-                        .pop(x86_regalloc::RegKind::Int);
-                    emit_cmds(self, ctx, arch, cmds_addr, &mut state.stack_manager)?;
-                    addr = aa;
+                    let flush = state.regalloc.as_mut().unwrap().flush();
+                    emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
-                let imm = Reg(0);
-                self.mov64(ctx, arch, &imm, memarg.offset)?;
-                let base = Reg(addr.reg);
-                self.lea(
+                crate::data_op(
+                    self,
                     ctx,
                     arch,
-                    &base,
-                    &asm_x86::out::arg::MemArgKind::Mem {
-                        base,
-                        offset: Some((imm, 0)),
-                        disp: 0,
-                        size: MemorySize::_64,
-                        reg_class: asm_x86::RegisterClass::Gpr,
-                    },
+                    memories,
+                    module,
+                    op,
+                    &mut state.label_index,
                 )?;
-                self.xchg(ctx, arch, &Reg(val.reg), &base)?;
             }
+            Instruction::Unreachable => self.trap(ctx, arch, state, false)?,
             Instruction::Br(relative_depth) => {
                 // flush regalloc before control transfer
                 {
@@ -534,24 +784,32 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                 }
                 self.br(ctx, arch, state, *default)?;
             }
-            Instruction::Block(_blockty) => {
-                state.if_stack.push(Endable::Br);
+            Instruction::Block(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
                 let i = state.label_index;
                 state.label_index += 1;
-                self.lea_label(ctx, arch, &Reg(0), X64FastLabel::Indexed { idx: i })?;
-                // flush to resume with current stack pointer saved
+                state.if_stack.push(Endable::Block { idx: i, arity });
+                // flush so the stack pointer covers the block's parameters
                 {
                     let flush = state.regalloc.as_mut().unwrap().flush();
                     emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
-                self.push(ctx, arch, &Reg(1))?;
-                self.push(ctx, arch, &Reg(0))?;
-                self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
+                self.enter(ctx, arch, X64FastLabel::Indexed { idx: i }, arity.params)?;
             }
-            Instruction::If(_blockty) => {
+            Instruction::If(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                // `i` starts the then arm, `i + 1` the else arm, `i + 3`
+                // closes the if and `i + 2`, after its control stack entry
+                // is dropped, is the branch target.
                 let i = state.label_index;
-                state.label_index += 3;
-                state.if_stack.push(Endable::If { idx: i });
+                state.label_index += 4;
+                state.if_stack.push(Endable::If {
+                    idx: i,
+                    arity,
+                    has_else: false,
+                });
                 let t;
                 {
                     let (tt, cmds) = state
@@ -562,7 +820,19 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                     emit_cmds(self, ctx, arch, cmds, &mut state.stack_manager)?;
                     t = tt;
                 }
-                let cond = Reg(t.reg);
+                // park the condition out of the way of `enter`
+                let cond = Reg(2);
+                self.mov(ctx, arch, &cond, &Reg(t.reg))?;
+                {
+                    let flush = state.regalloc.as_mut().unwrap().flush();
+                    emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
+                }
+                self.enter(
+                    ctx,
+                    arch,
+                    X64FastLabel::Indexed { idx: i + 2 },
+                    arity.params,
+                )?;
                 self.lea_label(ctx, arch, &Reg(0), X64FastLabel::Indexed { idx: i })?;
                 self.lea_label(ctx, arch, &Reg(1), X64FastLabel::Indexed { idx: i + 1 })?;
                 self.cmp0(ctx, arch, &cond)?;
@@ -575,38 +845,78 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                 self.jmp(ctx, arch, &Reg(0))?;
                 self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
             }
+            Instruction::I64ReinterpretF64
+            | Instruction::F64ReinterpretI64
+            | Instruction::I32ReinterpretF32
+            | Instruction::F32ReinterpretI32 => {
+                // the bits stay the same, but move to the other register kind
+                let flush = state.regalloc.as_mut().unwrap().flush();
+                emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
+            }
+            Instruction::Nop => {}
+            Instruction::Drop => {
+                let (_, cmds) = state
+                    .regalloc
+                    .as_mut()
+                    .unwrap()
+                    .pop(x86_regalloc::RegKind::Int);
+                emit_cmds(self, ctx, arch, cmds, &mut state.stack_manager)?;
+            }
             Instruction::Else => {
-                let Endable::If { idx: i } = state.if_stack.last().unwrap() else {
-                    todo!()
+                {
+                    let flush = state.regalloc.as_mut().unwrap().flush();
+                    emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
+                }
+                let Some(Endable::If {
+                    idx: i, has_else, ..
+                }) = state.if_stack.last_mut()
+                else {
+                    return Err(CompileError::malformed().into());
                 };
-                self.lea_label(ctx, arch, &Reg(0), X64FastLabel::Indexed { idx: i + 2 })?;
+                *has_else = true;
+                let i = *i;
+                self.lea_label(ctx, arch, &Reg(0), X64FastLabel::Indexed { idx: i + 3 })?;
                 self.jmp(ctx, arch, &Reg(0))?;
                 self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i + 1 })?;
             }
-            Instruction::Loop(_blockty) => {
-                state.if_stack.push(Endable::Br);
+            Instruction::Loop(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                state.if_stack.push(Endable::Loop { arity });
                 let i = state.label_index;
                 state.label_index += 1;
-                self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
-                self.lea_label(ctx, arch, &Reg(0), X64FastLabel::Indexed { idx: i })?;
                 {
                     let flush = state.regalloc.as_mut().unwrap().flush();
                     emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
-                self.push(ctx, arch, &Reg(1))?;
-                self.push(ctx, arch, &Reg(0))?;
+                self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
+                self.enter(ctx, arch, X64FastLabel::Indexed { idx: i }, arity.params)?;
             }
             Instruction::End => {
                 {
                     let flush = state.regalloc.as_mut().unwrap().flush();
                     emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
-                match state.if_stack.pop().unwrap() {
-                    Endable::Br => {
-                        self.pop(ctx, arch, &Reg(0))?;
-                        self.pop(ctx, arch, &Reg(1))?;
+                // The function-level `End` is followed by the trailing `Return`.
+                let Some(endable) = state.if_stack.pop() else {
+                    return Ok(());
+                };
+                // Branches drop the control stack entry themselves, so labels
+                // they target follow `leave`.
+                match endable {
+                    Endable::Block { idx: i, .. } => {
+                        self.leave(ctx, arch)?;
+                        self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
                     }
-                    Endable::If { idx: i } => {
+                    Endable::Loop { .. } => self.leave(ctx, arch)?,
+                    Endable::If {
+                        idx: i, has_else, ..
+                    } => {
+                        if !has_else {
+                            self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i + 1 })?;
+                        }
+                        self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i + 3 })?;
+                        self.leave(ctx, arch)?;
                         self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i + 2 })?;
                     }
                 }
@@ -638,27 +948,13 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                         self.set_label(ctx, arch, X64FastLabel::Indexed { idx: i })?;
                     } else {
                         // normal call
-                        let function_index = *function_index - func_imports.len() as u32;
-                        self.lea_label(
-                            ctx,
-                            arch,
-                            &Reg(0),
-                            X64FastLabel::Func {
-                                r#fn: function_index,
-                            },
-                        )?;
+                        let callee = crate::callee(*function_index, func_imports.len() as u32);
+                        self.lea_label(ctx, arch, &Reg(0), callee)?;
                         self.call(ctx, arch, &Reg(0))?;
                     }
                 } else {
-                    let function_index = *function_index - func_imports.len() as u32;
-                    self.lea_label(
-                        ctx,
-                        arch,
-                        &Reg(0),
-                        X64FastLabel::Func {
-                            r#fn: function_index,
-                        },
-                    )?;
+                    let callee = crate::callee(*function_index, func_imports.len() as u32);
+                    self.lea_label(ctx, arch, &Reg(0), callee)?;
                     self.call(ctx, arch, &Reg(0))?;
                 }
             }
@@ -668,38 +964,117 @@ pub trait WriterExt<Context>: asm_x86::out::Writer<X64FastLabel, Context> {
                     let flush = state.regalloc.as_mut().unwrap().flush();
                     emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
                 }
-                self.mov(ctx, arch, &Reg(1), &Reg(4))?;
-                self.mov(ctx, arch, &Reg(0), &Reg::CTX)?;
-                let mut tmp = Reg(0);
-                self.lea(
-                    ctx,
-                    arch,
-                    &tmp,
-                    &asm_x86::out::arg::MemArgKind::Mem {
-                        base: tmp,
-                        offset: None,
-                        disp: (state.local_count + 3 * 8) as u32,
-                        size: MemorySize::_8,
-                        reg_class: asm_x86::RegisterClass::Gpr,
-                    },
-                )?;
-                self.mov(ctx, arch, &Reg(4), &tmp)?;
-                self.pop(ctx, arch, &Reg(0))?;
-                self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
-                self.pop(ctx, arch, &Reg(0))?;
-                self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
-                self.pop(ctx, arch, &Reg(0))?;
-                for a in 0..state.num_returns {
-                    self.mov(ctx, arch, &Reg(2), &Reg(1))?;
-                    self.push(ctx, arch, &Reg(2))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-                self.ret(ctx, arch)?;
+                self.wasm_return(ctx, arch, state)?;
+            }
+            op if matches!(Group::of(op), Some(Group::Int | Group::SignExt)) => {
+                // the remaining integer operators work on the stack
+                let flush = state.regalloc.as_mut().unwrap().flush();
+                emit_cmds(self, ctx, arch, flush, &mut state.stack_manager)?;
+                crate::int_op(self, ctx, arch, op)?;
             }
-            _ => {}
+            _ => return Err(CompileError::unsupported(op).into()),
         }
         Ok(())
     }
 }
 
 impl<T: asm_x86::out::Writer<X64FastLabel, Context> + ?Sized, Context> WriterExt<Context> for T {}
+
+/// A [`Backend`] emitting x86-64 code with the register-allocating strategy.
+///
+/// Bundles a writer with its context, target architecture and `State` so
+/// that the fast generator can be driven like any other backend.
+pub struct FastBackend<W, Context> {
+    /// The writer receiving machine code.
+    pub writer: W,
+    /// The writer's context.
+    pub ctx: Context,
+    /// The x86-64 architecture variant.
+    pub arch: asm_x86::X64Arch,
+    /// Code generation state.
+    pub state: State,
+    /// Body target passed to `handle_op`.
+    pub target: u32,
+}
+
+impl<W, Context> FastBackend<W, Context> {
+    /// Creates a backend writing to `writer`.
+    pub fn new(writer: W, ctx: Context, arch: asm_x86::X64Arch) -> Self {
+        Self {
+            writer,
+            ctx,
+            arch,
+            state: State::default(),
+            target: 0,
+        }
+    }
+}
+
+impl<W: WriterExt<Context>, Context, R: Reencode, Annot> Backend<R, Annot>
+    for FastBackend<W, Context>
+where
+    W::Error: From<core::fmt::Error> + From<CompileError>,
+    reencode::Error<R::Error>: Into<W::Error>,
+{
+    type Error = W::Error;
+    fn on_module(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        crate::init(&mut self.writer, &mut self.ctx, self.arch, cx.module)?;
+        crate::indirect_entries(
+            &mut self.writer,
+            &mut self.ctx,
+            self.arch,
+            &cx.sigs,
+            cx.module,
+        )
+    }
+    fn on_mach(
+        &mut self,
+        cx: &mut BackendContext<'_, R>,
+        op: &MachOperator<'_, Annot>,
+    ) -> Result<(), Self::Error> {
+        let Ok(op) = op
+            .as_ref()
+            .map(&mut |_| Ok::<_, core::convert::Infallible>(()));
+        self.writer.handle_mach(
+            &mut self.ctx,
+            self.arch,
+            &mut self.state,
+            &cx.sigs,
+            &cx.memories,
+            cx.module,
+            cx.func_imports(),
+            &op,
+            cx.rewriter,
+            self.target,
+        )
+    }
+    fn on_end(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        // The float routines the module calls, generated like its own
+        // functions.
+        let routines = core::mem::take(&mut self.state.routines);
+        let Self {
+            writer,
+            ctx,
+            arch,
+            state,
+            target,
+        } = self;
+        routines.compile(|rcx, float, op| {
+            state.builtin = Some(float.name);
+            writer.handle_mach(
+                ctx,
+                *arch,
+                state,
+                &rcx.sigs,
+                &rcx.memories,
+                rcx.module,
+                rcx.func_imports(),
+                op,
+                &mut *cx.rewriter,
+                *target,
+            )
+        })?;
+        state.builtin = None;
+        Ok(())
+    }
+}
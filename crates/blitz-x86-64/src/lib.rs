@@ -10,6 +10,24 @@
 //! - Stack-based execution model matching WASM semantics
 //! - Support for function calls, branches, and control flow
 //! - Register allocation for local variables
+//! - Traps lowered to jumps to an embedder-provided `blitz_trap` handler
+//! - Bounds-checked access to any number of 32- and 64-bit linear memories,
+//!   with every width of load and store
+//! - Globals, initialised by a `blitz_init` routine
+//! - Function references, `call_indirect` and access to tables
+//! - Data and element segments, copied in by `blitz_init`, and
+//!   `memory.init`/`data.drop`
+//! - `memory.size`, `memory.grow`, `memory.fill` and `memory.copy`
+//! - Start functions, called by `blitz_init`, and the symbols exports are
+//!   exposed under, from [`exports`]
+//! - Calls to imported functions through labels the embedder binds, listed
+//!   by [`imports`]
+//! - f32 and f64 `abs`, `neg` and `copysign` on the bits; every other float
+//!   operator calls a routine generated in software, without SSE, with NaN
+//!   results optionally canonicalised
+//! - Every MVP integer operator and the sign-extension operators, with
+//!   `clz`, `ctz` and `popcnt` counted without LZCNT or POPCNT, and
+//!   division by zero and signed overflow jumping to the trap handler
 //!
 //! # Architecture
 //!
@@ -18,6 +36,34 @@
 //! - Stack pointer (RSP) for the execution stack
 //! - Context register for local variable frame pointer
 //! - Dedicated registers for temporary values
+//! - Embedder-provided descriptors `blitz_mem_N` for linear memory `N`, each
+//!   holding the memory's base address, its length in bytes and the
+//!   capacity in bytes reserved at that address. `memory.grow` succeeds
+//!   while the new length fits in the capacity, which must be zero-filled
+//!   past the length
+//! - An embedder-provided area `blitz_globals` holding global `N` in the
+//!   8 bytes at offset `8 * N`, imports included; `blitz_init` stores the
+//!   initial value of every defined global and must be called first
+//! - Embedder-provided descriptors `blitz_table_N` for table `N`, each
+//!   holding the address of its slots followed by its length. A reference
+//!   is the address of the function's indirect entry, or `0` for null; the
+//!   entry checks the caller's signature id in `Reg(2)` before jumping to
+//!   the function
+//! - `blitz_init` also copies every active element and data segment into
+//!   its table or memory, jumping to the trap handler when one does not fit
+//! - Embedder-provided descriptors `blitz_data_N` for passive data segment
+//!   `N`, each holding the address of a buffer the size of the segment
+//!   followed by its length; `blitz_init` fills the buffer and sets the
+//!   length, which `data.drop` clears
+//! - Routines `blitz_NAME` for every float operator but `abs`, `neg` and
+//!   `copysign`, which are done on the bits, where `NAME` is its
+//!   [`FloatOp::name`](portal_solutions_blitz_common::float::FloatOp::name).
+//!   Both backends emit the routines the module calls after its functions,
+//!   compiling the integer code
+//!   [`softfloat`](portal_solutions_blitz_common::softfloat) builds like
+//!   any other function. They follow the calling convention of the module's
+//!   own functions, with floats as their bits, and conversions to integers
+//!   jump to the trap handler themselves
 //!
 //! # Example
 //!
@@ -34,10 +80,19 @@ use core::{
     fmt::{Display, Formatter, Write},
 };
 use portal_solutions_blitz_common::{
+    CompileError,
     asm::Reg,
     asm::common::mem::MemorySize,
+    export::{Exported, Symbol},
+    float::{self, FloatKind, FloatOp, Nans},
+    global::{ConstValue, Global},
+    import::{Binding, Imports},
+    memory::{self, DataMode, LinearMemory},
+    module::Module,
     ops::{FnData, MachOperator},
-    wasmparser::Operator,
+    table::{self, ElemMode, Table},
+    wasm_encoder::{FuncType, Instruction},
+    wasmparser::{Operator, ValType},
 };
 extern crate alloc;
 pub use portal_solutions_asm_x86_64::*;
@@ -53,6 +108,26 @@ pub enum X64Label {
     Indexed { idx: usize },
     /// A function entry point label.
     Func { r#fn: u32 },
+    /// The trap handler, provided by the embedder.
+    Trap,
+    /// The descriptor of a linear memory, provided by the embedder.
+    Memory { index: u32 },
+    /// The area holding every global, provided by the embedder.
+    Globals,
+    /// The module's initialisation routine.
+    Init,
+    /// The descriptor of a table, provided by the embedder.
+    Table { index: u32 },
+    /// The entry point of a function called through a reference, which
+    /// checks the caller's signature.
+    Indirect { r#fn: u32 },
+    /// The descriptor of a passive data segment, provided by the embedder.
+    Data { index: u32 },
+    /// An imported function, provided by the embedder.
+    Import { index: u32 },
+    /// The runtime routine for a float operator, generated after the
+    /// module's functions.
+    Builtin { name: &'static str },
 }
 
 impl Display for X64Label {
@@ -60,16 +135,1388 @@ impl Display for X64Label {
         match self {
             X64Label::Indexed { idx } => write!(f, "_idx_{idx}"),
             X64Label::Func { r#fn } => write!(f, "f{}", r#fn),
+            X64Label::Trap => write!(f, "blitz_trap"),
+            X64Label::Memory { index } => write!(f, "blitz_mem_{index}"),
+            X64Label::Globals => write!(f, "blitz_globals"),
+            X64Label::Init => write!(f, "blitz_init"),
+            X64Label::Table { index } => write!(f, "blitz_table_{index}"),
+            X64Label::Indirect { r#fn } => write!(f, "fi{}", r#fn),
+            X64Label::Data { index } => write!(f, "blitz_data_{index}"),
+            X64Label::Import { index } => write!(f, "blitz_import_{index}"),
+            X64Label::Builtin { name } => write!(f, "blitz_{name}"),
         }
     }
 }
 
+/// The symbol every export of `module` is exposed under, with the label it
+/// names and the offset from that label in bytes.
+///
+/// Functions name their entry point, tables and memories their descriptor,
+/// and globals their slot in the globals area. Embedders define each symbol
+/// as the label plus the offset, for example with `.set`.
+pub fn exports<'a>(
+    module: &Module<'a>,
+) -> impl Iterator<Item = Result<(Symbol<'a>, X64Label, u32), CompileError>> {
+    let num_func_imports = module.num_func_imports();
+    module.export_items().map(move |export| {
+        let export = export?;
+        let (label, offset) = match export.item {
+            Exported::Func(f) => (callee(f, num_func_imports), 0),
+            Exported::Table(index) => (X64Label::Table { index }, 0),
+            Exported::Memory(index) => (X64Label::Memory { index }, 0),
+            Exported::Global(index) => (X64Label::Globals, index * 8),
+        };
+        Ok((export.symbol(), label, offset))
+    })
+}
+
+/// The label every imported function of `module` is called through, with
+/// what `imports` binds it to.
+///
+/// Embedders define each label as the host function's
+/// [`Symbol`](portal_solutions_blitz_common::export::Symbol), or as
+/// `blitz_trap` for unbound imports. Host functions follow the calling
+/// convention of the module's own functions.
+pub fn imports<'a>(
+    module: &Module<'a>,
+    imports: &Imports,
+) -> impl Iterator<Item = (X64Label, Binding<'a>)> {
+    module.func_import_items().map(move |import| {
+        (
+            X64Label::Import {
+                index: import.index,
+            },
+            imports.bind(&import),
+        )
+    })
+}
+
+/// The label calls of function `index` in the function index space jump to.
+fn callee(index: u32, num_func_imports: u32) -> X64Label {
+    match index.checked_sub(num_func_imports) {
+        Some(r#fn) => X64Label::Func { r#fn },
+        None => X64Label::Import { index },
+    }
+}
+
 /// Label trait specialization for x86-64.
 ///
 /// This trait extends the common Label trait with x86-64 specific functionality.
 pub trait Label: portal_solutions_blitz_common::Label<X64Label> {}
 impl<T: portal_solutions_blitz_common::Label<X64Label> + ?Sized> Label for T {}
 
+/// Moves the top `count` values of the operand stack so that they end just
+/// below the address in `Reg(1)`, leaving RSP pointing at the topmost.
+///
+/// Branches use this to carry a label's values down to the stack height the
+/// target frame was entered at. Clobbers `Reg(2)` and `Reg(3)`.
+fn carry<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    count: usize,
+) -> Result<(), W::Error> {
+    let at = |base: Reg, disp: usize| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp: disp as u32,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    w.mov(ctx, arch, &Reg(2), &RSP)?;
+    // Deepest value first: no destination lies below its source, so no
+    // value is overwritten before it is moved.
+    for i in (0..count).rev() {
+        w.lea(ctx, arch, &RSP, &at(Reg(2), i * 8))?;
+        w.pop(ctx, arch, &Reg(3))?;
+        w.lea(
+            ctx,
+            arch,
+            &RSP,
+            &at(Reg(1), ((i + 1) * 8).wrapping_sub(count * 8)),
+        )?;
+        w.push(ctx, arch, &Reg(3))?;
+    }
+    w.lea(ctx, arch, &RSP, &at(Reg(1), 0usize.wrapping_sub(count * 8)))
+}
+
+/// Leaves in `Reg(0)` the host address of an access of `size` bytes at
+/// static `offset` into `mem`, jumping to the trap handler when it is out of
+/// bounds.
+///
+/// The address is read `depth` slots down the operand stack and left there.
+/// Addresses into 32-bit memories are truncated to 32 bits. Clobbers
+/// `Reg(1)` to `Reg(3)`.
+fn address<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    mem: &LinearMemory,
+    offset: u64,
+    size: u64,
+    depth: usize,
+) -> Result<(), W::Error> {
+    let at = |base: Reg, disp: usize| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp: disp as u32,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let add = |base: Reg, index: Reg| out::arg::MemArgKind::Mem {
+        base,
+        offset: Some((index, 0)),
+        disp: 0,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    w.mov(ctx, arch, &Reg(0), &at(RSP, depth * 8))?;
+    if !mem.memory64 {
+        w.u32(ctx, arch, &Reg(0))?;
+    }
+    w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+    let Some(end) = mem.access_end(offset, size) else {
+        // No address can bring the access in bounds.
+        return w.jmp(ctx, arch, &Reg(3));
+    };
+    w.mov64(ctx, arch, &Reg(1), end)?;
+    w.lea(ctx, arch, &Reg(1), &add(Reg(0), Reg(1)))?;
+    if mem.memory64 {
+        // The end of the access wrapped around.
+        w.cmp(ctx, arch, &Reg(1), &Reg(0))?;
+        w.jcc(ctx, arch, ConditionCode::B, &Reg(3))?;
+    }
+    w.lea_label(ctx, arch, &Reg(2), X64Label::Memory { index: mem.index })?;
+    w.cmp(ctx, arch, &Reg(1), &at(Reg(2), 8))?;
+    w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+    w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+    w.lea(ctx, arch, &Reg(0), &add(Reg(2), Reg(0)))?;
+    w.mov64(ctx, arch, &Reg(1), offset)?;
+    w.lea(ctx, arch, &Reg(0), &add(Reg(0), Reg(1)))
+}
+
+/// Generates code for the load or store `access`, with its operands on the
+/// operand stack.
+///
+/// Narrow loads are zero-extended, then sign-extended when signed; narrow
+/// stores keep the low bytes of the value.
+fn access_op<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    memories: &[LinearMemory],
+    access: &memory::Access,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    let mem = memory::lookup(memories, access.memarg.memory_index)?;
+    let bytes = access.size;
+    let value = out::arg::MemArgKind::Mem {
+        base: Reg(0),
+        offset: None,
+        disp: 0,
+        size: match bytes {
+            1 => MemorySize::_8,
+            2 => MemorySize::_16,
+            4 => MemorySize::_32,
+            _ => MemorySize::_64,
+        },
+        reg_class: RegisterClass::Gpr,
+    };
+    if access.store {
+        address(w, ctx, arch, mem, access.memarg.offset, bytes, 1)?;
+        w.pop(ctx, arch, &Reg(1))?;
+        w.pop(ctx, arch, &Reg(2))?;
+        return w.mov(ctx, arch, &value, &Reg(1));
+    }
+    address(w, ctx, arch, mem, access.memarg.offset, bytes, 0)?;
+    w.mov64(ctx, arch, &Reg(1), 0)?;
+    w.mov(ctx, arch, &Reg(1), &value)?;
+    if bytes < 8 {
+        w.mov64(ctx, arch, &Reg(2), (1 << (bytes * 8)) - 1)?;
+        w.and(ctx, arch, &Reg(1), &Reg(2))?;
+    }
+    if access.signed {
+        sign_extend(w, ctx, arch, Reg(1), Reg(2), bytes as u32 * 8)?;
+        if !access.wide {
+            w.u32(ctx, arch, &Reg(1))?;
+        }
+    }
+    w.pop(ctx, arch, &Reg(2))?;
+    w.push(ctx, arch, &Reg(1))
+}
+
+/// Sign-extends the low `bits` bits of `reg`, whose other bits must be
+/// clear, to 64 bits. Clobbers `scratch`.
+fn sign_extend<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    reg: Reg,
+    scratch: Reg,
+    bits: u32,
+) -> Result<(), W::Error> {
+    // `(v ^ sign) - sign`, where `sign` is the top bit.
+    let sign = 1usize << (bits - 1);
+    w.mov64(ctx, arch, &scratch, sign as u64)?;
+    w.eor(ctx, arch, &reg, &scratch)?;
+    w.lea(
+        ctx,
+        arch,
+        &reg,
+        &out::arg::MemArgKind::Mem {
+            base: reg,
+            offset: None,
+            disp: 0usize.wrapping_sub(sign) as u32,
+            size: MemorySize::_64,
+            reg_class: RegisterClass::Gpr,
+        },
+    )
+}
+
+/// Shifts `reg` right by the constant `count`, through `Reg(1)`.
+fn shr_by<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    reg: Reg,
+    count: u64,
+) -> Result<(), W::Error> {
+    w.mov64(ctx, arch, &Reg(1), count)?;
+    w.shr(ctx, arch, &reg, &Reg(1))
+}
+
+/// Replaces `Reg(0)` with the number of bits set in it. Clobbers `Reg(1)` to
+/// `Reg(3)`.
+fn popcnt<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+) -> Result<(), W::Error> {
+    let sum = |disp: u32| out::arg::MemArgKind::Mem {
+        base: Reg(0),
+        offset: Some((Reg(2), 0)),
+        disp,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    // Count in pairs, nibbles and bytes, then add the bytes up with a
+    // multiplication.
+    w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+    shr_by(w, ctx, arch, Reg(2), 1)?;
+    w.mov64(ctx, arch, &Reg(3), 0x5555_5555_5555_5555)?;
+    w.and(ctx, arch, &Reg(2), &Reg(3))?;
+    w.not(ctx, arch, &Reg(2))?;
+    w.lea(ctx, arch, &Reg(0), &sum(1))?;
+    w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+    shr_by(w, ctx, arch, Reg(2), 2)?;
+    w.mov64(ctx, arch, &Reg(3), 0x3333_3333_3333_3333)?;
+    w.and(ctx, arch, &Reg(2), &Reg(3))?;
+    w.and(ctx, arch, &Reg(0), &Reg(3))?;
+    w.lea(ctx, arch, &Reg(0), &sum(0))?;
+    w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+    shr_by(w, ctx, arch, Reg(2), 4)?;
+    w.lea(ctx, arch, &Reg(0), &sum(0))?;
+    w.mov64(ctx, arch, &Reg(3), 0x0f0f_0f0f_0f0f_0f0f)?;
+    w.and(ctx, arch, &Reg(0), &Reg(3))?;
+    w.mov64(ctx, arch, &Reg(1), 0x0101_0101_0101_0101)?;
+    w.mul(ctx, arch, &Reg(0), &Reg(1))?;
+    shr_by(w, ctx, arch, Reg(0), 56)
+}
+
+/// Generates code for the integer or sign-extension operator `op`, with its
+/// operands on the operand stack.
+///
+/// i32 values are kept zero-extended. Division by zero and signed overflow
+/// jump to the trap handler. Clobbers `Reg(0)` to `Reg(3)`.
+fn int_op<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    op: &Instruction<'_>,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    let at = |base: Reg, disp: u32| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let add = |disp: u32| out::arg::MemArgKind::Mem {
+        base: Reg(0),
+        offset: Some((Reg(1), 0)),
+        disp,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let narrow = matches!(
+        op,
+        Instruction::I32Eqz
+            | Instruction::I32Eq
+            | Instruction::I32Ne
+            | Instruction::I32LtS
+            | Instruction::I32LtU
+            | Instruction::I32GtS
+            | Instruction::I32GtU
+            | Instruction::I32LeS
+            | Instruction::I32LeU
+            | Instruction::I32GeS
+            | Instruction::I32GeU
+            | Instruction::I32Clz
+            | Instruction::I32Ctz
+            | Instruction::I32Popcnt
+            | Instruction::I32Add
+            | Instruction::I32Sub
+            | Instruction::I32Mul
+            | Instruction::I32DivS
+            | Instruction::I32DivU
+            | Instruction::I32RemS
+            | Instruction::I32RemU
+            | Instruction::I32And
+            | Instruction::I32Or
+            | Instruction::I32Xor
+            | Instruction::I32Shl
+            | Instruction::I32ShrS
+            | Instruction::I32ShrU
+            | Instruction::I32Rotl
+            | Instruction::I32Rotr
+    );
+    let bits: u64 = if narrow { 32 } else { 64 };
+    let sign: u64 = 1 << (bits - 1);
+    let ones: u64 = u64::MAX >> (64 - bits);
+    match op {
+        Instruction::I32Const(value) => {
+            w.mov64(ctx, arch, &Reg(0), *value as u32 as u64)?;
+            return w.push(ctx, arch, &Reg(0));
+        }
+        Instruction::I64Const(value) => {
+            w.mov64(ctx, arch, &Reg(0), *value as u64)?;
+            return w.push(ctx, arch, &Reg(0));
+        }
+        Instruction::I32Eqz | Instruction::I64Eqz => {
+            w.pop(ctx, arch, &Reg(0))?;
+            w.mov64(ctx, arch, &Reg(1), 0)?;
+            w.cmp0(ctx, arch, &Reg(0))?;
+            w.cmovcc64(ctx, arch, ConditionCode::E, &Reg(1), &1u64)?;
+            return w.push(ctx, arch, &Reg(1));
+        }
+        Instruction::I32Clz
+        | Instruction::I64Clz
+        | Instruction::I32Ctz
+        | Instruction::I64Ctz
+        | Instruction::I32Popcnt
+        | Instruction::I64Popcnt => {
+            w.pop(ctx, arch, &Reg(0))?;
+            match op {
+                // Smear the highest set bit rightwards, then count the
+                // bits below and including it.
+                Instruction::I32Clz | Instruction::I64Clz => {
+                    for count in [1, 2, 4, 8, 16, 32] {
+                        w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+                        shr_by(w, ctx, arch, Reg(2), count)?;
+                        w.or(ctx, arch, &Reg(0), &Reg(2))?;
+                    }
+                    popcnt(w, ctx, arch)?;
+                    w.not(ctx, arch, &Reg(0))?;
+                    w.lea(ctx, arch, &Reg(0), &at(Reg(0), bits as u32 + 1))?;
+                }
+                // Count the bits below the lowest set one, as those set in
+                // `(v & -v) - 1`.
+                Instruction::I32Ctz | Instruction::I64Ctz => {
+                    w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+                    w.not(ctx, arch, &Reg(2))?;
+                    w.lea(ctx, arch, &Reg(2), &at(Reg(2), 1))?;
+                    w.and(ctx, arch, &Reg(0), &Reg(2))?;
+                    w.lea(ctx, arch, &Reg(0), &at(Reg(0), u32::MAX))?;
+                    if narrow {
+                        w.u32(ctx, arch, &Reg(0))?;
+                    }
+                    popcnt(w, ctx, arch)?;
+                }
+                _ => popcnt(w, ctx, arch)?,
+            }
+            return w.push(ctx, arch, &Reg(0));
+        }
+        Instruction::I32WrapI64 | Instruction::I64ExtendI32U => {
+            w.pop(ctx, arch, &Reg(0))?;
+            w.u32(ctx, arch, &Reg(0))?;
+            return w.push(ctx, arch, &Reg(0));
+        }
+        Instruction::I64ExtendI32S
+        | Instruction::I32Extend8S
+        | Instruction::I32Extend16S
+        | Instruction::I64Extend8S
+        | Instruction::I64Extend16S
+        | Instruction::I64Extend32S => {
+            let from = match op {
+                Instruction::I32Extend8S | Instruction::I64Extend8S => 8,
+                Instruction::I32Extend16S | Instruction::I64Extend16S => 16,
+                _ => 32,
+            };
+            w.pop(ctx, arch, &Reg(0))?;
+            w.mov64(ctx, arch, &Reg(1), (1 << from) - 1)?;
+            w.and(ctx, arch, &Reg(0), &Reg(1))?;
+            sign_extend(w, ctx, arch, Reg(0), Reg(1), from)?;
+            if let Instruction::I32Extend8S | Instruction::I32Extend16S = op {
+                w.u32(ctx, arch, &Reg(0))?;
+            }
+            return w.push(ctx, arch, &Reg(0));
+        }
+        _ => {}
+    }
+    // Binary operators: the right operand in `Reg(1)`, which shifts take
+    // their count from, and the left in `Reg(0)`.
+    w.pop(ctx, arch, &Reg(1))?;
+    w.pop(ctx, arch, &Reg(0))?;
+    match op {
+        Instruction::I32Add | Instruction::I64Add => w.lea(ctx, arch, &Reg(0), &add(0))?,
+        Instruction::I32Sub | Instruction::I64Sub => {
+            w.not(ctx, arch, &Reg(1))?;
+            w.lea(ctx, arch, &Reg(0), &add(1))?;
+        }
+        Instruction::I32Mul | Instruction::I64Mul => w.mul(ctx, arch, &Reg(0), &Reg(1))?,
+        Instruction::I32And | Instruction::I64And => w.and(ctx, arch, &Reg(0), &Reg(1))?,
+        Instruction::I32Or | Instruction::I64Or => w.or(ctx, arch, &Reg(0), &Reg(1))?,
+        Instruction::I32Xor | Instruction::I64Xor => w.eor(ctx, arch, &Reg(0), &Reg(1))?,
+        Instruction::I32DivU
+        | Instruction::I64DivU
+        | Instruction::I32RemU
+        | Instruction::I64RemU
+        | Instruction::I32DivS
+        | Instruction::I64DivS
+        | Instruction::I32RemS
+        | Instruction::I64RemS => {
+            let signed = matches!(
+                op,
+                Instruction::I32DivS
+                    | Instruction::I64DivS
+                    | Instruction::I32RemS
+                    | Instruction::I64RemS
+            );
+            let rem = matches!(
+                op,
+                Instruction::I32RemU
+                    | Instruction::I64RemU
+                    | Instruction::I32RemS
+                    | Instruction::I64RemS
+            );
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+            w.cmp0(ctx, arch, &Reg(1))?;
+            w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+            if signed {
+                if narrow {
+                    sign_extend(w, ctx, arch, Reg(0), Reg(2), 32)?;
+                    sign_extend(w, ctx, arch, Reg(1), Reg(2), 32)?;
+                }
+                if rem {
+                    // `x % -1` is zero, but dividing the minimum by -1
+                    // overflows.
+                    w.mov64(ctx, arch, &Reg(2), u64::MAX)?;
+                    w.cmp(ctx, arch, &Reg(1), &Reg(2))?;
+                    w.cmovcc64(ctx, arch, ConditionCode::E, &Reg(1), &1u64)?;
+                } else {
+                    // Trap when dividing the minimum by -1.
+                    w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+                    w.mov64(ctx, arch, &Reg(3), 0u64.wrapping_sub(sign))?;
+                    w.eor(ctx, arch, &Reg(2), &Reg(3))?;
+                    w.mov(ctx, arch, &Reg(3), &Reg(1))?;
+                    w.not(ctx, arch, &Reg(3))?;
+                    w.or(ctx, arch, &Reg(2), &Reg(3))?;
+                    w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+                    w.cmp0(ctx, arch, &Reg(2))?;
+                    w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+                }
+                w.idiv(ctx, arch, &Reg(0), &Reg(1))?;
+            } else {
+                w.div(ctx, arch, &Reg(0), &Reg(1))?;
+            }
+            if rem {
+                // The remainder is left in `Reg(3)`.
+                w.mov(ctx, arch, &Reg(0), &Reg(3))?;
+            }
+        }
+        Instruction::I32Shl | Instruction::I64Shl | Instruction::I32ShrU | Instruction::I64ShrU => {
+            // 64-bit shifts only mask the count to 6 bits.
+            if narrow {
+                w.mov64(ctx, arch, &Reg(2), 31)?;
+                w.and(ctx, arch, &Reg(1), &Reg(2))?;
+            }
+            if let Instruction::I32Shl | Instruction::I64Shl = op {
+                w.shl(ctx, arch, &Reg(0), &Reg(1))?;
+            } else {
+                w.shr(ctx, arch, &Reg(0), &Reg(1))?;
+            }
+        }
+        Instruction::I32ShrS | Instruction::I64ShrS => {
+            if narrow {
+                w.mov64(ctx, arch, &Reg(2), 31)?;
+                w.and(ctx, arch, &Reg(1), &Reg(2))?;
+            }
+            // Shift as `((v ^ m) >> n) ^ m`, where `m` is all ones when `v`
+            // is negative and zero otherwise.
+            w.mov64(ctx, arch, &Reg(2), 0)?;
+            w.mov64(ctx, arch, &Reg(3), sign)?;
+            w.cmp(ctx, arch, &Reg(0), &Reg(3))?;
+            w.cmovcc64(ctx, arch, ConditionCode::AE, &Reg(2), &ones)?;
+            w.eor(ctx, arch, &Reg(0), &Reg(2))?;
+            w.shr(ctx, arch, &Reg(0), &Reg(1))?;
+            w.eor(ctx, arch, &Reg(0), &Reg(2))?;
+        }
+        Instruction::I32Rotl
+        | Instruction::I64Rotl
+        | Instruction::I32Rotr
+        | Instruction::I64Rotr => {
+            if narrow {
+                w.mov64(ctx, arch, &Reg(2), 31)?;
+                w.and(ctx, arch, &Reg(1), &Reg(2))?;
+            }
+            // Shift one way by `n` and the other by `bits - n`; a count
+            // of `bits` shifts a zero-extended i32 out entirely and leaves
+            // an i64 in place, either of which is right.
+            w.mov(ctx, arch, &Reg(2), &Reg(0))?;
+            let left = matches!(op, Instruction::I32Rotl | Instruction::I64Rotl);
+            if left {
+                w.shl(ctx, arch, &Reg(0), &Reg(1))?;
+            } else {
+                w.shr(ctx, arch, &Reg(0), &Reg(1))?;
+            }
+            w.not(ctx, arch, &Reg(1))?;
+            w.lea(ctx, arch, &Reg(1), &at(Reg(1), bits as u32 + 1))?;
+            if left {
+                w.shr(ctx, arch, &Reg(2), &Reg(1))?;
+            } else {
+                w.shl(ctx, arch, &Reg(2), &Reg(1))?;
+            }
+            w.or(ctx, arch, &Reg(0), &Reg(2))?;
+        }
+        _ => {
+            // Comparisons: flip the sign bits to compare signed values as
+            // unsigned ones, then pick 1 or 0 on the flags.
+            let (signed, cc, set) = match op {
+                Instruction::I32Eq | Instruction::I64Eq => (false, ConditionCode::E, true),
+                Instruction::I32Ne | Instruction::I64Ne => (false, ConditionCode::E, false),
+                Instruction::I32LtS | Instruction::I64LtS => (true, ConditionCode::B, true),
+                Instruction::I32LtU | Instruction::I64LtU => (false, ConditionCode::B, true),
+                Instruction::I32GtS | Instruction::I64GtS => (true, ConditionCode::A, true),
+                Instruction::I32GtU | Instruction::I64GtU => (false, ConditionCode::A, true),
+                Instruction::I32LeS | Instruction::I64LeS => (true, ConditionCode::A, false),
+                Instruction::I32LeU | Instruction::I64LeU => (false, ConditionCode::A, false),
+                Instruction::I32GeS | Instruction::I64GeS => (true, ConditionCode::B, false),
+                Instruction::I32GeU | Instruction::I64GeU => (false, ConditionCode::B, false),
+                _ => return Err(CompileError::unsupported(op).into()),
+            };
+            if signed {
+                w.mov64(ctx, arch, &Reg(2), sign)?;
+                w.eor(ctx, arch, &Reg(0), &Reg(2))?;
+                w.eor(ctx, arch, &Reg(1), &Reg(2))?;
+            }
+            w.mov64(ctx, arch, &Reg(2), !set as u64)?;
+            w.cmp(ctx, arch, &Reg(0), &Reg(1))?;
+            w.cmovcc64(ctx, arch, cc, &Reg(2), &(set as u64))?;
+            return w.push(ctx, arch, &Reg(2));
+        }
+    }
+    if narrow {
+        w.u32(ctx, arch, &Reg(0))?;
+    }
+    w.push(ctx, arch, &Reg(0))
+}
+
+/// Generates code for the float operator `float`, with its operands on the
+/// operand stack.
+///
+/// Sign operations are done on the bits; every other operator calls the
+/// routine `blitz_NAME` for its [`FloatOp::name`], which the caller records
+/// for `on_end` to generate. Results that `nans` requires are then
+/// canonicalised.
+fn float_op<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    nans: Nans,
+    float: &FloatOp,
+) -> Result<(), W::Error> {
+    let wide = float.params[0] == ValType::F64;
+    let sign: u64 = if wide { 1 << 63 } else { 1 << 31 };
+    if float.kind == FloatKind::Sign {
+        w.pop(ctx, arch, &Reg(0))?;
+        match float.params.len() {
+            // copysign: the sign comes from the top operand.
+            2 => {
+                w.mov64(ctx, arch, &Reg(2), sign)?;
+                w.and(ctx, arch, &Reg(0), &Reg(2))?;
+                w.pop(ctx, arch, &Reg(1))?;
+                w.mov64(ctx, arch, &Reg(2), sign - 1)?;
+                w.and(ctx, arch, &Reg(1), &Reg(2))?;
+                w.or(ctx, arch, &Reg(0), &Reg(1))?;
+            }
+            _ if float.name.ends_with("abs") => {
+                w.mov64(ctx, arch, &Reg(1), sign - 1)?;
+                w.and(ctx, arch, &Reg(0), &Reg(1))?;
+            }
+            _ => {
+                w.mov64(ctx, arch, &Reg(1), sign)?;
+                w.eor(ctx, arch, &Reg(0), &Reg(1))?;
+            }
+        }
+        return w.push(ctx, arch, &Reg(0));
+    }
+    w.lea_label(ctx, arch, &Reg(0), X64Label::Builtin { name: float.name })?;
+    w.call(ctx, arch, &Reg(0))?;
+    if !float.canonicalises(nans) {
+        return Ok(());
+    }
+    // A float is a NaN when its bits without the sign are above those of
+    // infinity.
+    let (abs, infinity, nan) = if float.result == ValType::F64 {
+        (
+            u64::MAX >> 1,
+            f64::INFINITY.to_bits(),
+            float::CANONICAL_NAN_F64,
+        )
+    } else {
+        (
+            u32::MAX as u64 >> 1,
+            f32::INFINITY.to_bits() as u64,
+            float::CANONICAL_NAN_F32 as u64,
+        )
+    };
+    w.pop(ctx, arch, &Reg(0))?;
+    w.mov(ctx, arch, &Reg(1), &Reg(0))?;
+    w.mov64(ctx, arch, &Reg(2), abs)?;
+    w.and(ctx, arch, &Reg(1), &Reg(2))?;
+    w.mov64(ctx, arch, &Reg(2), infinity)?;
+    w.cmp(ctx, arch, &Reg(1), &Reg(2))?;
+    w.cmovcc64(ctx, arch, ConditionCode::A, &Reg(0), &nan)?;
+    w.push(ctx, arch, &Reg(0))
+}
+
+/// Generates `blitz_init`, which stores the initial value of every defined
+/// global of `module` into the globals area, copies its active element and
+/// data segments into their tables and memories and its passive data
+/// segments into their buffers, then calls its start function.
+fn init<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    module: &Module<'_>,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    let slot = |index: u32| out::arg::MemArgKind::Mem {
+        base: Reg(1),
+        offset: None,
+        disp: index * 8,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let num_func_imports = module.num_func_imports();
+    w.set_label(ctx, arch, X64Label::Init)?;
+    w.lea_label(ctx, arch, &Reg(1), X64Label::Globals)?;
+    for global in module.global_vars() {
+        let global = global?;
+        let Some(init) = global.init else {
+            continue;
+        };
+        const_value(w, ctx, arch, init, num_func_imports)?;
+        w.mov(ctx, arch, &slot(global.index), &Reg(0))?;
+    }
+    for elem in module.elem_segments() {
+        let elem = elem?;
+        let ElemMode::Active { table, offset } = elem.mode else {
+            continue;
+        };
+        let table = module.table(table)?;
+        segment_base(
+            w,
+            ctx,
+            arch,
+            offset,
+            table.table64,
+            elem.items.len() as u64,
+            X64Label::Table { index: table.index },
+            3,
+        )?;
+        for (i, item) in elem.items.iter().enumerate() {
+            w.push(ctx, arch, &Reg(0))?;
+            w.lea_label(ctx, arch, &Reg(1), X64Label::Globals)?;
+            const_value(w, ctx, arch, *item, num_func_imports)?;
+            w.mov(ctx, arch, &Reg(1), &Reg(0))?;
+            w.pop(ctx, arch, &Reg(0))?;
+            w.mov(
+                ctx,
+                arch,
+                &out::arg::MemArgKind::Mem {
+                    base: Reg(0),
+                    offset: None,
+                    disp: (i * 8) as u32,
+                    size: MemorySize::_64,
+                    reg_class: RegisterClass::Gpr,
+                },
+                &Reg(1),
+            )?;
+        }
+    }
+    for segment in module.data_segments() {
+        let segment = segment?;
+        match segment.mode {
+            DataMode::Active { memory, offset } => {
+                let mem = module
+                    .memory(memory)
+                    .ok_or_else(|| CompileError::unknown("memory", memory))?;
+                segment_base(
+                    w,
+                    ctx,
+                    arch,
+                    offset,
+                    mem.memory64,
+                    segment.bytes.len() as u64,
+                    X64Label::Memory { index: memory },
+                    0,
+                )?;
+                store_bytes(w, ctx, arch, segment.bytes)?;
+            }
+            DataMode::Passive => {
+                let desc = out::arg::MemArgKind::Mem {
+                    base: Reg(2),
+                    offset: None,
+                    disp: 0,
+                    size: MemorySize::_64,
+                    reg_class: RegisterClass::Gpr,
+                };
+                w.lea_label(
+                    ctx,
+                    arch,
+                    &Reg(2),
+                    X64Label::Data {
+                        index: segment.index,
+                    },
+                )?;
+                w.mov(ctx, arch, &Reg(0), &desc)?;
+                store_bytes(w, ctx, arch, segment.bytes)?;
+                w.mov64(ctx, arch, &Reg(1), segment.bytes.len() as u64)?;
+                w.mov(
+                    ctx,
+                    arch,
+                    &out::arg::MemArgKind::Mem {
+                        base: Reg(2),
+                        offset: None,
+                        disp: 8,
+                        size: MemorySize::_64,
+                        reg_class: RegisterClass::Gpr,
+                    },
+                    &Reg(1),
+                )?;
+            }
+        }
+    }
+    if let Some(start) = module.start_func()? {
+        w.lea_label(ctx, arch, &Reg(0), callee(start, num_func_imports))?;
+        w.call(ctx, arch, &Reg(0))?;
+    }
+    w.ret(ctx, arch)
+}
+
+/// Leaves the constant `value` in `Reg(0)`, reading globals from the area
+/// whose address is in `Reg(1)`.
+///
+/// `num_func_imports` maps function references to their labels.
+fn const_value<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    value: ConstValue,
+    num_func_imports: u32,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    match value {
+        ConstValue::I32(v) => w.mov64(ctx, arch, &Reg(0), v as u32 as u64),
+        ConstValue::I64(v) => w.mov64(ctx, arch, &Reg(0), v as u64),
+        ConstValue::F32(bits) => w.mov64(ctx, arch, &Reg(0), bits as u64),
+        ConstValue::F64(bits) => w.mov64(ctx, arch, &Reg(0), bits),
+        ConstValue::RefNull => w.mov64(ctx, arch, &Reg(0), 0),
+        ConstValue::RefFunc(f) => {
+            let Some(r#fn) = f.checked_sub(num_func_imports) else {
+                return Err(CompileError::feature("references to imported functions").into());
+            };
+            w.lea_label(ctx, arch, &Reg(0), X64Label::Indirect { r#fn })
+        }
+        ConstValue::GlobalGet(g) => w.mov(
+            ctx,
+            arch,
+            &Reg(0),
+            &out::arg::MemArgKind::Mem {
+                base: Reg(1),
+                offset: None,
+                disp: g * 8,
+                size: MemorySize::_64,
+                reg_class: RegisterClass::Gpr,
+            },
+        ),
+    }
+}
+
+/// Leaves in `Reg(0)` the host address of the `len` elements at `offset`
+/// into the table or memory described by `desc`, each `1 << shift` bytes,
+/// jumping to the trap handler when they do not fit.
+///
+/// Offsets into 32-bit tables and memories are truncated to 32 bits.
+/// Clobbers `Reg(1)` to `Reg(3)`.
+#[allow(clippy::too_many_arguments)]
+fn segment_base<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    offset: ConstValue,
+    wide: bool,
+    len: u64,
+    desc: X64Label,
+    shift: usize,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    let at = |base: Reg, disp: usize| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp: disp as u32,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let add = |base: Reg, index: Reg| out::arg::MemArgKind::Mem {
+        base,
+        offset: Some((index, 0)),
+        disp: 0,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    match offset {
+        ConstValue::I32(_) | ConstValue::I64(_) | ConstValue::GlobalGet(_) => {
+            w.lea_label(ctx, arch, &Reg(1), X64Label::Globals)?;
+            const_value(w, ctx, arch, offset, 0)?
+        }
+        _ => return Err(CompileError::malformed().into()),
+    }
+    if !wide {
+        w.u32(ctx, arch, &Reg(0))?;
+    }
+    w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+    w.mov64(ctx, arch, &Reg(1), len)?;
+    w.lea(ctx, arch, &Reg(1), &add(Reg(0), Reg(1)))?;
+    if wide {
+        // The end of the segment wrapped around.
+        w.cmp(ctx, arch, &Reg(1), &Reg(0))?;
+        w.jcc(ctx, arch, ConditionCode::B, &Reg(3))?;
+    }
+    w.lea_label(ctx, arch, &Reg(2), desc)?;
+    w.cmp(ctx, arch, &Reg(1), &at(Reg(2), 8))?;
+    w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+    // Scale the offset by the size of an element.
+    for _ in 0..shift {
+        w.lea(ctx, arch, &Reg(0), &add(Reg(0), Reg(0)))?;
+    }
+    w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+    w.lea(ctx, arch, &Reg(0), &add(Reg(2), Reg(0)))
+}
+
+/// Stores `bytes` at the host address in `Reg(0)`, eight at a time.
+/// Clobbers `Reg(1)`.
+fn store_bytes<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    bytes: &[u8],
+) -> Result<(), W::Error> {
+    let at = |disp: usize, size: MemorySize| out::arg::MemArgKind::Mem {
+        base: Reg(0),
+        offset: None,
+        disp: disp as u32,
+        size,
+        reg_class: RegisterClass::Gpr,
+    };
+    let chunks = bytes.chunks_exact(8);
+    let tail = chunks.remainder();
+    for (i, chunk) in chunks.enumerate() {
+        let value = u64::from_le_bytes(chunk.try_into().unwrap());
+        w.mov64(ctx, arch, &Reg(1), value)?;
+        w.mov(ctx, arch, &at(i * 8, MemorySize::_64), &Reg(1))?;
+    }
+    let start = bytes.len() - tail.len();
+    for (i, byte) in tail.iter().enumerate() {
+        w.mov64(ctx, arch, &Reg(1), *byte as u64)?;
+        w.mov(ctx, arch, &at(start + i, MemorySize::_8), &Reg(1))?;
+    }
+    Ok(())
+}
+
+/// Generates code for `memory.init`, `data.drop` or a bulk memory
+/// operation, with its operands on the operand stack.
+///
+/// `labels` is the next free [`X64Label::Indexed`] label, and is advanced
+/// past the labels used.
+fn data_op<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    memories: &[LinearMemory],
+    module: &Module<'_>,
+    op: &Instruction<'_>,
+    labels: &mut usize,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    let at = |base: Reg, disp: usize| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp: disp as u32,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let add = |base: Reg, index: Reg| out::arg::MemArgKind::Mem {
+        base,
+        offset: Some((index, 0)),
+        disp: 0,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let byte = |base: Reg| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp: 0,
+        size: MemorySize::_8,
+        reg_class: RegisterClass::Gpr,
+    };
+    match op {
+        Instruction::MemoryInit { mem, data_index } => {
+            let mem = memory::lookup(memories, *mem)?;
+            let segment = module.data_segment(*data_index)?;
+            let data = X64Label::Data { index: *data_index };
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+            // The source range must lie in the segment; an active segment
+            // has already been dropped.
+            w.mov(ctx, arch, &Reg(0), &at(RSP, 8))?;
+            w.u32(ctx, arch, &Reg(0))?;
+            w.mov(ctx, arch, &Reg(1), &at(RSP, 0))?;
+            w.u32(ctx, arch, &Reg(1))?;
+            w.lea(ctx, arch, &Reg(1), &add(Reg(0), Reg(1)))?;
+            if let DataMode::Passive = segment.mode {
+                w.lea_label(ctx, arch, &Reg(2), data)?;
+                w.cmp(ctx, arch, &Reg(1), &at(Reg(2), 8))?;
+                w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+            } else {
+                w.cmp0(ctx, arch, &Reg(1))?;
+                w.jcc(ctx, arch, ConditionCode::NE, &Reg(3))?;
+            }
+            // The destination range must lie in the memory.
+            w.mov(ctx, arch, &Reg(0), &at(RSP, 16))?;
+            if !mem.memory64 {
+                w.u32(ctx, arch, &Reg(0))?;
+            }
+            w.mov(ctx, arch, &Reg(1), &at(RSP, 0))?;
+            w.u32(ctx, arch, &Reg(1))?;
+            w.lea(ctx, arch, &Reg(1), &add(Reg(0), Reg(1)))?;
+            if mem.memory64 {
+                // The end of the range wrapped around.
+                w.cmp(ctx, arch, &Reg(1), &Reg(0))?;
+                w.jcc(ctx, arch, ConditionCode::B, &Reg(3))?;
+            }
+            w.lea_label(ctx, arch, &Reg(2), X64Label::Memory { index: mem.index })?;
+            w.cmp(ctx, arch, &Reg(1), &at(Reg(2), 8))?;
+            w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+            if let DataMode::Passive = segment.mode {
+                let i = *labels;
+                *labels += 2;
+                w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+                w.lea(ctx, arch, &Reg(0), &add(Reg(2), Reg(0)))?;
+                w.lea_label(ctx, arch, &Reg(2), data)?;
+                w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+                w.mov(ctx, arch, &Reg(1), &at(RSP, 8))?;
+                w.u32(ctx, arch, &Reg(1))?;
+                w.lea(ctx, arch, &Reg(1), &add(Reg(2), Reg(1)))?;
+                w.mov(ctx, arch, &Reg(2), &at(RSP, 0))?;
+                w.u32(ctx, arch, &Reg(2))?;
+                // Copy a byte at a time, counting `Reg(2)` down to zero.
+                w.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
+                w.lea_label(ctx, arch, &Reg(3), X64Label::Indexed { idx: i + 1 })?;
+                w.cmp0(ctx, arch, &Reg(2))?;
+                w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+                w.mov(ctx, arch, &Reg(3), &byte(Reg(1)))?;
+                w.mov(ctx, arch, &byte(Reg(0)), &Reg(3))?;
+                w.lea(ctx, arch, &Reg(0), &at(Reg(0), 1))?;
+                w.lea(ctx, arch, &Reg(1), &at(Reg(1), 1))?;
+                w.lea(ctx, arch, &Reg(2), &at(Reg(2), usize::MAX))?;
+                w.jmp_label(ctx, arch, X64Label::Indexed { idx: i })?;
+                w.set_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
+            }
+            for _ in 0..3 {
+                w.pop(ctx, arch, &Reg(1))?;
+            }
+        }
+        Instruction::MemorySize(mem) => {
+            let mem = memory::lookup(memories, *mem)?;
+            w.lea_label(ctx, arch, &Reg(1), X64Label::Memory { index: mem.index })?;
+            w.mov(ctx, arch, &Reg(0), &at(Reg(1), 8))?;
+            w.mov64(ctx, arch, &Reg(1), mem.page_size_log2 as u64)?;
+            w.shr(ctx, arch, &Reg(0), &Reg(1))?;
+            w.push(ctx, arch, &Reg(0))?;
+        }
+        Instruction::MemoryGrow(mem) => {
+            let mem = memory::lookup(memories, *mem)?;
+            let desc = X64Label::Memory { index: mem.index };
+            let i = *labels;
+            *labels += 2;
+            w.pop(ctx, arch, &Reg(0))?;
+            if !mem.memory64 {
+                w.u32(ctx, arch, &Reg(0))?;
+            }
+            w.lea_label(ctx, arch, &Reg(2), desc)?;
+            w.mov(ctx, arch, &Reg(3), &at(Reg(2), 8))?;
+            w.mov64(ctx, arch, &Reg(1), mem.page_size_log2 as u64)?;
+            w.shr(ctx, arch, &Reg(3), &Reg(1))?;
+            w.lea(ctx, arch, &Reg(1), &add(Reg(3), Reg(0)))?;
+            // Fail when the new size wraps around, exceeds the maximum or
+            // does not fit in the capacity the embedder reserved.
+            w.lea_label(ctx, arch, &Reg(2), X64Label::Indexed { idx: i })?;
+            w.cmp(ctx, arch, &Reg(1), &Reg(3))?;
+            w.jcc(ctx, arch, ConditionCode::B, &Reg(2))?;
+            w.mov64(ctx, arch, &Reg(0), mem.max_size())?;
+            w.cmp(ctx, arch, &Reg(1), &Reg(0))?;
+            w.jcc(ctx, arch, ConditionCode::A, &Reg(2))?;
+            w.mov(ctx, arch, &Reg(0), &Reg(1))?;
+            w.mov64(ctx, arch, &Reg(1), mem.page_size_log2 as u64)?;
+            w.shl(ctx, arch, &Reg(0), &Reg(1))?;
+            w.lea_label(ctx, arch, &Reg(1), desc)?;
+            w.cmp(ctx, arch, &Reg(0), &at(Reg(1), 16))?;
+            w.jcc(ctx, arch, ConditionCode::A, &Reg(2))?;
+            w.mov(ctx, arch, &at(Reg(1), 8), &Reg(0))?;
+            w.push(ctx, arch, &Reg(3))?;
+            w.jmp_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
+            let fail = if mem.memory64 {
+                u64::MAX
+            } else {
+                u32::MAX as u64
+            };
+            w.mov64(ctx, arch, &Reg(0), fail)?;
+            w.push(ctx, arch, &Reg(0))?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
+        }
+        Instruction::MemoryFill(mem) => {
+            let mem = memory::lookup(memories, *mem)?;
+            let i = *labels;
+            *labels += 2;
+            // The range must lie in the memory.
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+            w.mov(ctx, arch, &Reg(0), &at(RSP, 16))?;
+            w.mov(ctx, arch, &Reg(1), &at(RSP, 0))?;
+            if !mem.memory64 {
+                w.u32(ctx, arch, &Reg(0))?;
+                w.u32(ctx, arch, &Reg(1))?;
+            }
+            w.lea(ctx, arch, &Reg(1), &add(Reg(0), Reg(1)))?;
+            if mem.memory64 {
+                // The end of the range wrapped around.
+                w.cmp(ctx, arch, &Reg(1), &Reg(0))?;
+                w.jcc(ctx, arch, ConditionCode::B, &Reg(3))?;
+            }
+            w.lea_label(ctx, arch, &Reg(2), X64Label::Memory { index: mem.index })?;
+            w.cmp(ctx, arch, &Reg(1), &at(Reg(2), 8))?;
+            w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+            w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+            w.lea(ctx, arch, &Reg(0), &add(Reg(2), Reg(0)))?;
+            w.mov(ctx, arch, &Reg(1), &at(RSP, 8))?;
+            w.mov(ctx, arch, &Reg(2), &at(RSP, 0))?;
+            if !mem.memory64 {
+                w.u32(ctx, arch, &Reg(2))?;
+            }
+            // Store a byte at a time, counting `Reg(2)` down to zero.
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Indexed { idx: i + 1 })?;
+            w.cmp0(ctx, arch, &Reg(2))?;
+            w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+            w.mov(ctx, arch, &byte(Reg(0)), &Reg(1))?;
+            w.lea(ctx, arch, &Reg(0), &at(Reg(0), 1))?;
+            w.lea(ctx, arch, &Reg(2), &at(Reg(2), usize::MAX))?;
+            w.jmp_label(ctx, arch, X64Label::Indexed { idx: i })?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
+            for _ in 0..3 {
+                w.pop(ctx, arch, &Reg(1))?;
+            }
+        }
+        Instruction::MemoryCopy { dst_mem, src_mem } => {
+            let dst = memory::lookup(memories, *dst_mem)?;
+            let src = memory::lookup(memories, *src_mem)?;
+            // The length is only 64-bit when both memories are.
+            let wide = dst.memory64 && src.memory64;
+            let i = *labels;
+            *labels += 4;
+            // Both ranges must lie in their memories.
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+            for (mem, disp) in [(src, 8), (dst, 16)] {
+                w.mov(ctx, arch, &Reg(0), &at(RSP, disp))?;
+                if !mem.memory64 {
+                    w.u32(ctx, arch, &Reg(0))?;
+                }
+                w.mov(ctx, arch, &Reg(1), &at(RSP, 0))?;
+                if !wide {
+                    w.u32(ctx, arch, &Reg(1))?;
+                }
+                w.lea(ctx, arch, &Reg(1), &add(Reg(0), Reg(1)))?;
+                if mem.memory64 {
+                    // The end of the range wrapped around.
+                    w.cmp(ctx, arch, &Reg(1), &Reg(0))?;
+                    w.jcc(ctx, arch, ConditionCode::B, &Reg(3))?;
+                }
+                w.lea_label(ctx, arch, &Reg(2), X64Label::Memory { index: mem.index })?;
+                w.cmp(ctx, arch, &Reg(1), &at(Reg(2), 8))?;
+                w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+            }
+            for (mem, disp, reg) in [(dst, 16, Reg(0)), (src, 8, Reg(1))] {
+                w.mov(ctx, arch, &reg, &at(RSP, disp))?;
+                if !mem.memory64 {
+                    w.u32(ctx, arch, &reg)?;
+                }
+                w.lea_label(ctx, arch, &Reg(2), X64Label::Memory { index: mem.index })?;
+                w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+                w.lea(ctx, arch, &reg, &add(Reg(2), reg))?;
+            }
+            w.mov(ctx, arch, &Reg(2), &at(RSP, 0))?;
+            if !wide {
+                w.u32(ctx, arch, &Reg(2))?;
+            }
+            // Copy backwards when the destination starts above the source,
+            // so overlapping ranges are not overwritten before being read.
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Indexed { idx: i + 1 })?;
+            w.cmp(ctx, arch, &Reg(0), &Reg(1))?;
+            w.jcc(ctx, arch, ConditionCode::A, &Reg(3))?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Indexed { idx: i + 3 })?;
+            w.cmp0(ctx, arch, &Reg(2))?;
+            w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+            w.mov(ctx, arch, &Reg(3), &byte(Reg(1)))?;
+            w.mov(ctx, arch, &byte(Reg(0)), &Reg(3))?;
+            w.lea(ctx, arch, &Reg(0), &at(Reg(0), 1))?;
+            w.lea(ctx, arch, &Reg(1), &at(Reg(1), 1))?;
+            w.lea(ctx, arch, &Reg(2), &at(Reg(2), usize::MAX))?;
+            w.jmp_label(ctx, arch, X64Label::Indexed { idx: i })?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
+            w.lea(ctx, arch, &Reg(0), &add(Reg(0), Reg(2)))?;
+            w.lea(ctx, arch, &Reg(1), &add(Reg(1), Reg(2)))?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i + 2 })?;
+            w.lea_label(ctx, arch, &Reg(3), X64Label::Indexed { idx: i + 3 })?;
+            w.cmp0(ctx, arch, &Reg(2))?;
+            w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+            w.lea(ctx, arch, &Reg(0), &at(Reg(0), usize::MAX))?;
+            w.lea(ctx, arch, &Reg(1), &at(Reg(1), usize::MAX))?;
+            w.mov(ctx, arch, &Reg(3), &byte(Reg(1)))?;
+            w.mov(ctx, arch, &byte(Reg(0)), &Reg(3))?;
+            w.lea(ctx, arch, &Reg(2), &at(Reg(2), usize::MAX))?;
+            w.jmp_label(ctx, arch, X64Label::Indexed { idx: i + 2 })?;
+            w.set_label(ctx, arch, X64Label::Indexed { idx: i + 3 })?;
+            for _ in 0..3 {
+                w.pop(ctx, arch, &Reg(1))?;
+            }
+        }
+        Instruction::DataDrop(data_index) => {
+            // Active segments are dropped by `blitz_init`.
+            if let DataMode::Passive = module.data_segment(*data_index)?.mode {
+                w.lea_label(ctx, arch, &Reg(1), X64Label::Data { index: *data_index })?;
+                w.mov64(ctx, arch, &Reg(0), 0)?;
+                w.mov(ctx, arch, &at(Reg(1), 8), &Reg(0))?;
+            }
+        }
+        _ => return Err(CompileError::unsupported(op).into()),
+    }
+    Ok(())
+}
+
+/// The id indirect calls check signatures by: the index of the first type
+/// in `sigs` equal to type `index`, so that equivalent types share an id.
+fn type_id(sigs: &[FuncType], index: u32) -> Result<u32, CompileError> {
+    let sig = sigs
+        .get(index as usize)
+        .ok_or_else(|| CompileError::unknown("type", index))?;
+    Ok(sigs.iter().position(|s| s == sig).unwrap_or(index as usize) as u32)
+}
+
+/// Generates the indirect entry of every function defined by `module`.
+///
+/// Each entry jumps to the trap handler unless `Reg(2)` holds the id of the
+/// function's type, then jumps to the function. Clobbers `Reg(1)` and
+/// `Reg(3)`.
+fn indirect_entries<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    sigs: &[FuncType],
+    module: &Module<'_>,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    for (r#fn, ty) in module.funcs.iter().enumerate() {
+        let r#fn = r#fn as u32;
+        w.set_label(ctx, arch, X64Label::Indirect { r#fn })?;
+        w.mov64(ctx, arch, &Reg(1), type_id(sigs, *ty)? as u64)?;
+        w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+        w.cmp(ctx, arch, &Reg(2), &Reg(1))?;
+        w.jcc(ctx, arch, ConditionCode::NE, &Reg(3))?;
+        w.jmp_label(ctx, arch, X64Label::Func { r#fn })?;
+    }
+    Ok(())
+}
+
+/// Leaves in `Reg(0)` the host address of the slot of `table` indexed by
+/// the value `depth` slots down the operand stack, jumping to the trap
+/// handler when it is out of bounds.
+///
+/// The index is left on the stack. Indices into 32-bit tables are truncated
+/// to 32 bits. Leaves the trap handler in `Reg(3)` and clobbers `Reg(2)`.
+fn table_slot<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    table: &Table,
+    depth: usize,
+) -> Result<(), W::Error> {
+    let at = |base: Reg, disp: usize| out::arg::MemArgKind::Mem {
+        base,
+        offset: None,
+        disp: disp as u32,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    let add = |base: Reg, index: Reg| out::arg::MemArgKind::Mem {
+        base,
+        offset: Some((index, 0)),
+        disp: 0,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    w.mov(ctx, arch, &Reg(0), &at(RSP, depth * 8))?;
+    if !table.table64 {
+        w.u32(ctx, arch, &Reg(0))?;
+    }
+    w.lea_label(ctx, arch, &Reg(3), X64Label::Trap)?;
+    w.lea_label(ctx, arch, &Reg(2), X64Label::Table { index: table.index })?;
+    w.cmp(ctx, arch, &Reg(0), &at(Reg(2), 8))?;
+    w.jcc(ctx, arch, ConditionCode::AE, &Reg(3))?;
+    // Scale the index by the size of a slot.
+    for _ in 0..3 {
+        w.lea(ctx, arch, &Reg(0), &add(Reg(0), Reg(0)))?;
+    }
+    w.mov(ctx, arch, &Reg(2), &at(Reg(2), 0))?;
+    w.lea(ctx, arch, &Reg(0), &add(Reg(2), Reg(0)))
+}
+
+/// Generates code for an instruction on tables or references, with its
+/// operands on the operand stack.
+///
+/// Instructions that copy between tables or change their size are reported
+/// as unsupported features.
+fn table_op<W: out::Writer<X64Label, Context> + ?Sized, Context>(
+    w: &mut W,
+    ctx: &mut Context,
+    arch: X64Arch,
+    sigs: &[FuncType],
+    module: &Module<'_>,
+    op: &Instruction<'_>,
+) -> Result<(), W::Error>
+where
+    W::Error: From<CompileError>,
+{
+    let slot = out::arg::MemArgKind::Mem {
+        base: Reg(0),
+        offset: None,
+        disp: 0,
+        size: MemorySize::_64,
+        reg_class: RegisterClass::Gpr,
+    };
+    match op {
+        Instruction::CallIndirect {
+            type_index,
+            table_index,
+        } => {
+            let id = type_id(sigs, *type_index)?;
+            table_slot(w, ctx, arch, &module.table(*table_index)?, 0)?;
+            w.mov(ctx, arch, &Reg(0), &slot)?;
+            w.pop(ctx, arch, &Reg(1))?;
+            w.cmp0(ctx, arch, &Reg(0))?;
+            w.jcc(ctx, arch, ConditionCode::E, &Reg(3))?;
+            w.mov64(ctx, arch, &Reg(2), id as u64)?;
+            w.call(ctx, arch, &Reg(0))?;
+        }
+        Instruction::RefNull(_) => {
+            w.mov64(ctx, arch, &Reg(0), 0)?;
+            w.push(ctx, arch, &Reg(0))?;
+        }
+        Instruction::RefIsNull => {
+            w.pop(ctx, arch, &Reg(0))?;
+            w.mov64(ctx, arch, &Reg(1), 0)?;
+            w.cmp0(ctx, arch, &Reg(0))?;
+            w.cmovcc64(ctx, arch, ConditionCode::E, &Reg(1), &1u64)?;
+            w.push(ctx, arch, &Reg(1))?;
+        }
+        Instruction::RefFunc(function_index) => {
+            let imported = module.num_func_imports();
+            let Some(r#fn) = function_index.checked_sub(imported) else {
+                return Err(CompileError::feature("references to imported functions").into());
+            };
+            if r#fn as usize >= module.funcs.len() {
+                return Err(CompileError::unknown("func", *function_index).into());
+            }
+            w.lea_label(ctx, arch, &Reg(0), X64Label::Indirect { r#fn })?;
+            w.push(ctx, arch, &Reg(0))?;
+        }
+        Instruction::TableGet(table) => {
+            table_slot(w, ctx, arch, &module.table(*table)?, 0)?;
+            w.mov(ctx, arch, &Reg(0), &slot)?;
+            w.pop(ctx, arch, &Reg(1))?;
+            w.push(ctx, arch, &Reg(0))?;
+        }
+        Instruction::TableSet(table) => {
+            table_slot(w, ctx, arch, &module.table(*table)?, 1)?;
+            w.pop(ctx, arch, &Reg(1))?;
+            w.pop(ctx, arch, &Reg(2))?;
+            w.mov(ctx, arch, &slot, &Reg(1))?;
+        }
+        Instruction::TableSize(table) => {
+            table::lookup(&module.tables, *table)?;
+            w.lea_label(ctx, arch, &Reg(1), X64Label::Table { index: *table })?;
+            w.mov(
+                ctx,
+                arch,
+                &Reg(0),
+                &out::arg::MemArgKind::Mem {
+                    base: Reg(1),
+                    offset: None,
+                    disp: 8,
+                    size: MemorySize::_64,
+                    reg_class: RegisterClass::Gpr,
+                },
+            )?;
+            w.push(ctx, arch, &Reg(0))?;
+        }
+        Instruction::TableGrow(_)
+        | Instruction::TableFill(_)
+        | Instruction::TableCopy { .. }
+        | Instruction::TableInit { .. }
+        | Instruction::ElemDrop(_) => {
+            return Err(CompileError::feature("bulk table operations").into());
+        }
+        _ => return Err(CompileError::unsupported(op).into()),
+    }
+    Ok(())
+}
+
 pub mod fast;
 /// Naive code generation implementation.
 ///
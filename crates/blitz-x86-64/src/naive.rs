@@ -4,8 +4,18 @@
 //! strategy for x86-64. It prioritizes simplicity and correctness over performance.
 
 use alloc::collections::btree_map::BTreeMap;
+use core::convert::Infallible;
 use portal_solutions_asm_x86_64::RegisterClass;
 use portal_solutions_asm_x86_64::out::arg::{MemArg, MemArgKind};
+use portal_solutions_blitz_common::CompileError;
+use portal_solutions_blitz_common::backend::{Backend, BackendContext};
+use portal_solutions_blitz_common::coverage::Group;
+use portal_solutions_blitz_common::float::{FloatKind, FloatOp, Nans};
+use portal_solutions_blitz_common::global;
+use portal_solutions_blitz_common::memory::{self, LinearMemory};
+use portal_solutions_blitz_common::module::Module;
+use portal_solutions_blitz_common::softfloat::Routines;
+use portal_solutions_blitz_common::typed::BlockArity;
 use portal_solutions_blitz_common::wasm_encoder::{self, Instruction, reencode::Reencode};
 
 use crate::{
@@ -26,14 +36,40 @@ pub struct State {
     if_stack: Vec<Endable>,
     body: u32,
     body_labels: BTreeMap<u32, usize>,
+    /// What NaNs produced by float arithmetic look like.
+    pub nans: Nans,
+    /// The float routines called so far, generated by `on_end`.
+    routines: Routines,
+    /// The float operator whose routine is being generated, labelling it
+    /// in place of its function index.
+    builtin: Option<&'static str>,
 }
 
 /// Represents a control flow structure that needs an end marker.
+///
+/// Every structure keeps an entry on the control stack holding the address
+/// of its label and the stack pointer below its parameters.
 enum Endable {
-    /// A branch target.
-    Br,
+    /// A block with its exit label index.
+    Block { idx: usize, arity: BlockArity },
+    /// A loop, whose label is its start.
+    Loop { arity: BlockArity },
     /// An if statement with its label index.
-    If { idx: usize },
+    If {
+        idx: usize,
+        arity: BlockArity,
+        has_else: bool,
+    },
+}
+
+impl Endable {
+    /// Number of values a branch to this structure carries.
+    fn carried(&self) -> usize {
+        match self {
+            Endable::Block { arity, .. } | Endable::If { arity, .. } => arity.label(false),
+            Endable::Loop { arity } => arity.label(true),
+        }
+    }
 }
 
 /// Extension trait for x86-64 code writers.
@@ -44,7 +80,8 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
     /// Generates code for a branch instruction.
     ///
     /// Emits x86-64 assembly to jump to the target label specified by the
-    /// relative depth in the control flow stack.
+    /// relative depth in the control flow stack, carrying the values the
+    /// label takes. A branch out of the function body is a return.
     ///
     /// # Arguments
     ///
@@ -58,17 +95,123 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
         state: &mut State,
         relative_depth: u32,
     ) -> Result<(), Self::Error> {
+        let Some(target) = state.if_stack.iter().rev().nth(relative_depth as usize) else {
+            return self.wasm_return(ctx, arch, state);
+        };
+        let carried = target.carried();
         self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
         for _ in 0..=relative_depth {
             self.pop(ctx, arch, &Reg(0))?;
             self.pop(ctx, arch, &Reg(1))?;
         }
         self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
-        self.mov(ctx, arch, &RSP, &Reg(1))?;
+        carry(self, ctx, arch, carried)?;
         self.jmp(ctx, arch, &Reg(0))?;
         Ok(())
     }
 
+    /// Generates code for a return from the current function.
+    fn wasm_return(
+        &mut self,
+        ctx: &mut Context,
+        arch: X64Arch,
+        state: &mut State,
+    ) -> Result<(), Self::Error> {
+        self.mov(ctx, arch, &Reg(1), &RSP)?;
+        self.mov(ctx, arch, &Reg(0), &Reg::CTX)?;
+        self.lea(
+            ctx,
+            arch,
+            &Reg(0),
+            // &Reg(0),
+            // (state.local_count + 3) as isize * 8,
+            // None,
+            &MemArgKind::Mem {
+                base: Reg(0),
+                offset: None,
+                disp: (state.local_count + 3 * 8) as u32,
+                size: MemorySize::_8,
+                reg_class: RegisterClass::Gpr,
+            },
+        )?;
+        self.mov(ctx, arch, &RSP, &Reg(0))?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        for a in 0..state.num_returns {
+            self.mov(ctx, arch, &Reg(2), &Reg(1))?;
+            self.push(ctx, arch, &Reg(2))?;
+        }
+        self.push(ctx, arch, &Reg(0))?;
+        self.ret(ctx, arch)?;
+        Ok(())
+    }
+
+    /// Opens a control structure: records the address of `label` and the
+    /// stack pointer below the structure's `params` on the control stack.
+    fn enter(
+        &mut self,
+        ctx: &mut Context,
+        arch: X64Arch,
+        label: X64Label,
+        params: usize,
+    ) -> Result<(), Self::Error> {
+        self.lea_label(ctx, arch, &Reg(0), label)?;
+        self.lea(
+            ctx,
+            arch,
+            &Reg(1),
+            &MemArgKind::Mem {
+                base: RSP,
+                offset: None,
+                disp: (params * 8) as u32,
+                size: MemorySize::_64,
+                reg_class: RegisterClass::Gpr,
+            },
+        )?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        self.push(ctx, arch, &Reg(1))?;
+        self.push(ctx, arch, &Reg(0))?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        Ok(())
+    }
+
+    /// Closes a control structure, dropping its control stack entry.
+    fn leave(&mut self, ctx: &mut Context, arch: X64Arch) -> Result<(), Self::Error> {
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.pop(ctx, arch, &Reg(1))?;
+        self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+        Ok(())
+    }
+
+    /// Generates code for a trap.
+    ///
+    /// Jumps to the embedder's trap handler; a conditional trap pops an
+    /// `i32` and only jumps when it is non-zero.
+    fn trap(
+        &mut self,
+        ctx: &mut Context,
+        arch: X64Arch,
+        state: &mut State,
+        conditional: bool,
+    ) -> Result<(), Self::Error> {
+        if !conditional {
+            return self.jmp_label(ctx, arch, X64Label::Trap);
+        }
+        let i = state.label_index;
+        state.label_index += 1;
+        self.lea_label(ctx, arch, &Reg(1), X64Label::Indexed { idx: i })?;
+        self.pop(ctx, arch, &Reg(0))?;
+        self.cmp0(ctx, arch, &Reg(0))?;
+        self.jcc(ctx, arch, ConditionCode::E, &Reg(1))?;
+        self.jmp_label(ctx, arch, X64Label::Trap)?;
+        self.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
+        Ok(())
+    }
+
     /// Generates code for a higher-order call (indirect call).
     ///
     /// Emits x86-64 assembly for calling a function through a function pointer,
@@ -107,6 +250,9 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
     ///
     /// * `arch` - The x86-64 architecture variant
     /// * `state` - Current compilation state
+    /// * `sigs` - The module's function types, for resolving block types
+    /// * `memories` - Every linear memory, imports included
+    /// * `module` - The module being compiled, for its globals and tables
     /// * `func_imports` - Information about imported functions
     /// * `op` - The machine operator to translate
     /// * `rewriter` - Re-encoder for instruction format conversion
@@ -115,6 +261,9 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
         ctx: &mut Context,
         arch: X64Arch,
         state: &mut State,
+        sigs: &[wasm_encoder::FuncType],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         func_imports: &[(&str, &str)],
         op: &MachOperator<'_>,
         rewriter: &mut (dyn Reencode<Error = E> + '_),
@@ -122,6 +271,7 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
     ) -> Result<(), Self::Error>
     where
         wasm_encoder::reencode::Error<E>: Into<Self::Error>,
+        Self::Error: From<CompileError>,
     {
         if target != state.body {
             self.jmp_label(
@@ -168,7 +318,11 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                     },
                 )?;
                 self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
-                self.set_label(ctx, arch, X64Label::Func { r#fn: *id })?;
+                let label = match state.builtin {
+                    Some(name) => X64Label::Builtin { name },
+                    None => X64Label::Func { r#fn: *id },
+                };
+                self.set_label(ctx, arch, label)?;
             }
             MachOperator::Local { count, ty } => {
                 for _ in 0..*count {
@@ -199,9 +353,17 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                     }
                 }
             }
-            MachOperator::Instruction { op, .. } => {
-                self._handle_op(ctx, arch, state, func_imports, op, target)?
-            }
+            MachOperator::Instruction { op, .. } => self._handle_op(
+                ctx,
+                arch,
+                state,
+                sigs,
+                memories,
+                module,
+                func_imports,
+                op,
+                target,
+            )?,
             MachOperator::Operator { op, annot } => match match op.as_ref() {
                 None => return Ok(()),
                 Some(a) => a,
@@ -210,12 +372,17 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                     ctx,
                     arch,
                     state,
+                    sigs,
+                    memories,
+                    module,
                     func_imports,
                     &rewriter.instruction(op.clone()).map_err(|e| e.into())?,
                     target,
                 )?,
             },
-            _ => todo!(),
+            MachOperator::Trap { conditional, .. } => self.trap(ctx, arch, state, *conditional)?,
+            MachOperator::EndBody => {}
+            _ => return Err(CompileError::unsupported_mach(op).into()),
         }
         Ok(())
     }
@@ -224,10 +391,16 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
         ctx: &mut Context,
         arch: X64Arch,
         state: &mut State,
+        sigs: &[wasm_encoder::FuncType],
+        memories: &[LinearMemory],
+        module: &Module<'_>,
         func_imports: &[(&str, &str)],
         op: &Instruction<'_>,
         target: u32,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<(), Self::Error>
+    where
+        Self::Error: From<CompileError>,
+    {
         if target != state.body {
             self.jmp_label(
                 ctx,
@@ -244,15 +417,19 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                 self.set_label(ctx, arch, X64Label::Indexed { idx })?;
             }
         }
-        match op {
-            Instruction::I32Const(value) => {
-                self.mov64(ctx, arch, &Reg(0), *value as u32 as u64)?;
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I64Const(value) => {
-                self.mov64(ctx, arch, &Reg(0), *value as u64)?;
-                self.push(ctx, arch, &Reg(0))?;
+        if let Some(access) = memory::Access::of(op) {
+            return access_op(self, ctx, arch, memories, &access);
+        }
+        if let Some(float) = FloatOp::of(op) {
+            if float.kind != FloatKind::Sign {
+                state.routines.call(&float);
             }
+            return float_op(self, ctx, arch, state.nans, &float);
+        }
+        if let Some(Group::Int | Group::SignExt) = Group::of(op) {
+            return int_op(self, ctx, arch, op);
+        }
+        match op {
             Instruction::F32Const(value) => {
                 self.mov64(ctx, arch, &Reg(0), value.bits() as u64)?;
                 self.push(ctx, arch, &Reg(0))?;
@@ -264,229 +441,55 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
             Instruction::I64ReinterpretF64
             | Instruction::F64ReinterpretI64
             | Instruction::I32ReinterpretF32
-            | Instruction::F32ReinterpretI32 => {}
-            Instruction::I32Add | Instruction::I64Add => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: Some((Reg(1), 0)),
-                        disp: 0,
-                        size: MemorySize::_64,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                if let Instruction::I32Add = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32Sub | Instruction::I64Sub => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.not(ctx, arch, &Reg(1))?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: Some((Reg(1), 0)),
-                        disp: 1,
-                        size: MemorySize::_64,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                if let Instruction::I32Sub = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32Mul | Instruction::I64Mul => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.mul(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32Mul = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32DivU | Instruction::I64DivU => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.div(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32DivU = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32DivS | Instruction::I64DivS => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.idiv(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32DivS = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32RemU | Instruction::I64RemU => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.div(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32RemU = op {
-                    self.u32(ctx, arch, &Reg(3))?;
-                }
-                self.push(ctx, arch, &Reg(3))?;
-            }
-            Instruction::I32RemS | Instruction::I64RemS => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.idiv(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32RemS = op {
-                    self.u32(ctx, arch, &Reg(3))?;
-                }
-                self.push(ctx, arch, &Reg(3))?;
-            }
-            Instruction::I32And | Instruction::I64And => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.and(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32And = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32Or | Instruction::I64Or => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.or(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32Or = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32Xor | Instruction::I64Xor => {
+            | Instruction::F32ReinterpretI32
+            | Instruction::Nop => {}
+            Instruction::Drop => {
                 self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.eor(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32Xor = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32Shl | Instruction::I64Shl => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.shl(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32Shl = op {
-                    self.u32(ctx, arch, &Reg(0))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
             }
-            Instruction::I32ShrU | Instruction::I64ShrU => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.shr(ctx, arch, &Reg(0), &Reg(1))?;
-                if let Instruction::I32ShrU = op {
-                    self.u32(ctx, arch, &Reg(0))?;
+            Instruction::GlobalGet(global_index) | Instruction::GlobalSet(global_index) => {
+                global::lookup(&module.globals, *global_index)?;
+                let slot = MemArgKind::Mem {
+                    base: Reg(1),
+                    offset: None,
+                    disp: *global_index * 8,
+                    size: MemorySize::_64,
+                    reg_class: RegisterClass::Gpr,
+                };
+                self.lea_label(ctx, arch, &Reg(1), X64Label::Globals)?;
+                if let Instruction::GlobalGet(_) = op {
+                    self.mov(ctx, arch, &Reg(0), &slot)?;
+                    self.push(ctx, arch, &Reg(0))?;
+                } else {
+                    self.pop(ctx, arch, &Reg(0))?;
+                    self.mov(ctx, arch, &slot, &Reg(0))?;
                 }
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32WrapI64 => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.u32(ctx, arch, &Reg(0))?;
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I32Eqz | Instruction::I64Eqz => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.mov64(ctx, arch, &Reg(1), 0)?;
-                self.cmp0(ctx, arch, &Reg(0))?;
-                self.cmovcc64(ctx, arch, ConditionCode::E, &Reg(1), &1u64)?;
-                self.push(ctx, arch, &Reg(1))?;
-            }
-            Instruction::I32Eq | Instruction::I64Eq => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.not(ctx, arch, &Reg(1))?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: Some((Reg(1), 0)),
-                        disp: 1,
-                        size: MemorySize::_64,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                self.mov64(ctx, arch, &Reg(1), 0)?;
-                self.cmp0(ctx, arch, &Reg(0))?;
-                self.cmovcc64(ctx, arch, ConditionCode::E, &Reg(1), &1u64)?;
-                self.push(ctx, arch, &Reg(1))?;
-            }
-            Instruction::I32Ne | Instruction::I64Ne => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(1))?;
-                self.not(ctx, arch, &Reg(1))?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: Some((Reg(1), 0)),
-                        disp: 1,
-                        size: MemorySize::_64,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                self.mov64(ctx, arch, &Reg(1), 1)?;
-                self.cmp0(ctx, arch, &Reg(0))?;
-                self.cmovcc64(ctx, arch, ConditionCode::E, &Reg(1), &0u64)?;
-                self.push(ctx, arch, &Reg(1))?;
-            }
-            Instruction::I64Load(memarg) => {
-                self.pop(ctx, arch, &Reg(0))?;
-                self.mov64(ctx, arch, &Reg(1), memarg.offset)?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: Some((Reg(1), 0)),
-                        disp: 0,
-                        size: MemorySize::_64,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                self.mov(ctx, arch, &Reg(0), &Reg(0))?;
-                self.push(ctx, arch, &Reg(0))?;
-            }
-            Instruction::I64Store(memarg) => {
-                self.pop(ctx, arch, &Reg(2))?;
-                self.pop(ctx, arch, &Reg(0))?;
-                self.mov64(ctx, arch, &Reg(1), memarg.offset)?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: Some((Reg(1), 0)),
-                        disp: 0,
-                        size: MemorySize::_64,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                self.xchg(ctx, arch, &Reg(2), &Reg(0))?;
-                // self.push(ctx,arch,&Reg(0))?;
             }
+            op @ (Instruction::CallIndirect { .. }
+            | Instruction::RefNull(_)
+            | Instruction::RefIsNull
+            | Instruction::RefFunc(_)
+            | Instruction::TableGet(_)
+            | Instruction::TableSet(_)
+            | Instruction::TableSize(_)
+            | Instruction::TableGrow(_)
+            | Instruction::TableFill(_)
+            | Instruction::TableCopy { .. }
+            | Instruction::TableInit { .. }
+            | Instruction::ElemDrop(_)) => table_op(self, ctx, arch, sigs, module, op)?,
+            op @ (Instruction::MemorySize(_)
+            | Instruction::MemoryGrow(_)
+            | Instruction::MemoryFill(_)
+            | Instruction::MemoryCopy { .. }
+            | Instruction::MemoryInit { .. }
+            | Instruction::DataDrop(_)) => data_op(
+                self,
+                ctx,
+                arch,
+                memories,
+                module,
+                op,
+                &mut state.label_index,
+            )?,
             Instruction::LocalGet(local_index) => {
                 self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
                 self.lea(
@@ -578,37 +581,8 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                 )?;
                 self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
             }
-            Instruction::Return => {
-                self.mov(ctx, arch, &Reg(1), &RSP)?;
-                self.mov(ctx, arch, &Reg(0), &Reg::CTX)?;
-                self.lea(
-                    ctx,
-                    arch,
-                    &Reg(0),
-                    // &Reg(0),
-                    // (state.local_count + 3) as isize * 8,
-                    // None,
-                    &MemArgKind::Mem {
-                        base: Reg(0),
-                        offset: None,
-                        disp: (state.local_count + 3 * 8) as u32,
-                        size: MemorySize::_8,
-                        reg_class: RegisterClass::Gpr,
-                    },
-                )?;
-                self.mov(ctx, arch, &RSP, &Reg(0))?;
-                self.pop(ctx, arch, &Reg(0))?;
-                self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
-                self.pop(ctx, arch, &Reg(0))?;
-                self.xchg(ctx, arch, &Reg(0), &Reg::CTX)?;
-                self.pop(ctx, arch, &Reg(0))?;
-                for a in 0..state.num_returns {
-                    self.mov(ctx, arch, &Reg(2), &Reg(1))?;
-                    self.push(ctx, arch, &Reg(2))?;
-                }
-                self.push(ctx, arch, &Reg(0))?;
-                self.ret(ctx, arch)?;
-            }
+            Instruction::Return => self.wasm_return(ctx, arch, state)?,
+            Instruction::Unreachable => self.trap(ctx, arch, state, false)?,
             Instruction::Br(relative_depth) => {
                 self.br(ctx, arch, state, *relative_depth)?;
             }
@@ -650,24 +624,28 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                 self.br(ctx, arch, state, *default)?;
             }
             Instruction::Block(blockty) => {
-                state.if_stack.push(Endable::Br);
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
                 let i = state.label_index;
                 state.label_index += 1;
-                self.lea_label(ctx, arch, &Reg(0), X64Label::Indexed { idx: i })?;
-                self.mov(ctx, arch, &Reg(1), &RSP)?;
-                self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
-                // for _ in &Reg(0)..=(*relative_depth) {
-                self.push(ctx, arch, &Reg(1))?;
-                self.push(ctx, arch, &Reg(0))?;
-                // }
-                self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
-                self.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
+                state.if_stack.push(Endable::Block { idx: i, arity });
+                self.enter(ctx, arch, X64Label::Indexed { idx: i }, arity.params)?;
             }
             Instruction::If(blockty) => {
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                // `i` starts the then arm, `i + 1` the else arm, `i + 3`
+                // closes the if and `i + 2`, after its control stack entry
+                // is dropped, is the branch target.
                 let i = state.label_index;
-                state.label_index += 3;
-                state.if_stack.push(Endable::If { idx: i });
+                state.label_index += 4;
+                state.if_stack.push(Endable::If {
+                    idx: i,
+                    arity,
+                    has_else: false,
+                });
                 self.pop(ctx, arch, &Reg(2))?;
+                self.enter(ctx, arch, X64Label::Indexed { idx: i + 2 }, arity.params)?;
                 self.lea_label(ctx, arch, &Reg(0), X64Label::Indexed { idx: i })?;
                 self.lea_label(ctx, arch, &Reg(1), X64Label::Indexed { idx: i + 1 })?;
                 self.cmp0(ctx, arch, &Reg(2))?;
@@ -676,62 +654,162 @@ pub trait WriterExt<Context>: Writer<X64Label, Context> {
                 self.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
             }
             Instruction::Else => {
-                let Endable::If { idx: i } = state.if_stack.last().unwrap() else {
-                    todo!()
+                let Some(Endable::If {
+                    idx: i, has_else, ..
+                }) = state.if_stack.last_mut()
+                else {
+                    return Err(CompileError::malformed().into());
                 };
-                self.lea_label(ctx, arch, &Reg(0), X64Label::Indexed { idx: i + 2 })?;
+                *has_else = true;
+                let i = *i;
+                self.lea_label(ctx, arch, &Reg(0), X64Label::Indexed { idx: i + 3 })?;
                 self.jmp(ctx, arch, &Reg(0))?;
                 self.set_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
             }
             Instruction::Loop(blockty) => {
-                state.if_stack.push(Endable::Br);
+                let arity =
+                    BlockArity::of_encoded(blockty, sigs).ok_or_else(CompileError::malformed)?;
+                state.if_stack.push(Endable::Loop { arity });
                 let i = state.label_index;
                 state.label_index += 1;
                 self.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
-                self.lea_label(ctx, arch, &Reg(0), X64Label::Indexed { idx: i })?;
-                self.mov(ctx, arch, &Reg(1), &RSP)?;
-                self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
-                // for _ in &Reg(0)..=(*relative_depth) {
-                self.push(ctx, arch, &Reg(1))?;
-                self.push(ctx, arch, &Reg(0))?;
-                // }
-                self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
+                self.enter(ctx, arch, X64Label::Indexed { idx: i }, arity.params)?;
             }
             Instruction::End => {
-                self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
-                // for _ in &Reg(0)..=(*relative_depth) {
-                match state.if_stack.pop().unwrap() {
-                    Endable::Br => {
-                        self.pop(ctx, arch, &Reg(0))?;
-                        self.pop(ctx, arch, &Reg(1))?;
+                // The function-level `End` is followed by the trailing `Return`.
+                let Some(endable) = state.if_stack.pop() else {
+                    return Ok(());
+                };
+                // Branches drop the control stack entry themselves, so labels
+                // they target follow `leave`.
+                match endable {
+                    Endable::Block { idx: i, .. } => {
+                        self.leave(ctx, arch)?;
+                        self.set_label(ctx, arch, X64Label::Indexed { idx: i })?;
                     }
-                    Endable::If { idx: i } => {
+                    Endable::Loop { .. } => self.leave(ctx, arch)?,
+                    Endable::If {
+                        idx: i, has_else, ..
+                    } => {
+                        if !has_else {
+                            self.set_label(ctx, arch, X64Label::Indexed { idx: i + 1 })?;
+                        }
+                        self.set_label(ctx, arch, X64Label::Indexed { idx: i + 3 })?;
+                        self.leave(ctx, arch)?;
                         self.set_label(ctx, arch, X64Label::Indexed { idx: i + 2 })?;
                     }
                 }
-                // }
-                self.xchg(ctx, arch, &RSP, &Reg::CTX)?;
             }
             Instruction::Call(function_index) => match func_imports.get(*function_index as usize) {
                 Some(("blitz", h)) if h.starts_with("hypercall") => {
                     self.hcall(ctx, arch, state)?;
                 }
                 _ => {
-                    let function_index = *function_index - func_imports.len() as u32;
-                    self.lea_label(
-                        ctx,
-                        arch,
-                        &Reg(0),
-                        X64Label::Func {
-                            r#fn: function_index,
-                        },
-                    )?;
+                    let callee = crate::callee(*function_index, func_imports.len() as u32);
+                    self.lea_label(ctx, arch, &Reg(0), callee)?;
                     self.call(ctx, arch, &Reg(0))?;
                 }
             },
-            _ => {}
+            _ => return Err(CompileError::unsupported(op).into()),
         };
         Ok(())
     }
 }
 impl<T: Writer<X64Label, Context> + ?Sized, Context> WriterExt<Context> for T {}
+
+/// A [`Backend`] emitting x86-64 code with the naive strategy.
+///
+/// Bundles a writer with its context, target architecture and `State` so
+/// that the naive generator can be driven like any other backend.
+pub struct NaiveBackend<W, Context> {
+    /// The writer receiving machine code.
+    pub writer: W,
+    /// The writer's context.
+    pub ctx: Context,
+    /// The x86-64 architecture variant.
+    pub arch: X64Arch,
+    /// Code generation state.
+    pub state: State,
+    /// Body target passed to `handle_op`.
+    pub target: u32,
+}
+
+impl<W, Context> NaiveBackend<W, Context> {
+    /// Creates a backend writing to `writer`.
+    pub fn new(writer: W, ctx: Context, arch: X64Arch) -> Self {
+        Self {
+            writer,
+            ctx,
+            arch,
+            state: State::default(),
+            target: 0,
+        }
+    }
+}
+
+impl<W: WriterExt<Context>, Context, R: Reencode, Annot> Backend<R, Annot>
+    for NaiveBackend<W, Context>
+where
+    wasm_encoder::reencode::Error<R::Error>: Into<W::Error>,
+    W::Error: From<CompileError>,
+{
+    type Error = W::Error;
+    fn on_module(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        init(&mut self.writer, &mut self.ctx, self.arch, cx.module)?;
+        indirect_entries(
+            &mut self.writer,
+            &mut self.ctx,
+            self.arch,
+            &cx.sigs,
+            cx.module,
+        )
+    }
+    fn on_mach(
+        &mut self,
+        cx: &mut BackendContext<'_, R>,
+        op: &MachOperator<'_, Annot>,
+    ) -> Result<(), Self::Error> {
+        let Ok(op) = op.as_ref().map(&mut |_| Ok::<_, Infallible>(()));
+        self.writer.handle_op(
+            &mut self.ctx,
+            self.arch,
+            &mut self.state,
+            &cx.sigs,
+            &cx.memories,
+            cx.module,
+            cx.func_imports(),
+            &op,
+            cx.rewriter,
+            self.target,
+        )
+    }
+    fn on_end(&mut self, cx: &mut BackendContext<'_, R>) -> Result<(), Self::Error> {
+        // The float routines the module calls, generated like its own
+        // functions.
+        let routines = core::mem::take(&mut self.state.routines);
+        let Self {
+            writer,
+            ctx,
+            arch,
+            state,
+            target,
+        } = self;
+        routines.compile(|rcx, float, op| {
+            state.builtin = Some(float.name);
+            writer.handle_op(
+                ctx,
+                *arch,
+                state,
+                &rcx.sigs,
+                &rcx.memories,
+                rcx.module,
+                rcx.func_imports(),
+                op,
+                &mut *cx.rewriter,
+                *target,
+            )
+        })?;
+        state.builtin = None;
+        Ok(())
+    }
+}